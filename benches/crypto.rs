@@ -0,0 +1,109 @@
+//! Benchmarks for the crypto and parsing hot paths.
+//!
+//! Run with `cargo bench --features test-support`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{rngs::StdRng, SeedableRng};
+use threema_gateway::{
+    encrypt_file_data_with_rng, encrypt_with_rng, test_support, BlobId, FileData, IncomingMessage,
+    Key, MessageType, RecipientKey,
+};
+
+fn bench_encrypt(c: &mut Criterion) {
+    let private_key = test_support::gateway_private_key();
+    let public_key = test_support::recipient_public_key();
+    let mut group = c.benchmark_group("encrypt");
+    for size in [16, 1024, 3500] {
+        let data = vec![0x42; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            let mut rng = StdRng::seed_from_u64(0);
+            b.iter(|| {
+                encrypt_with_rng(
+                    black_box(data),
+                    MessageType::Text,
+                    &public_key,
+                    &private_key,
+                    &mut rng,
+                )
+                .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_encrypt_file_data(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encrypt_file_data");
+    for size in [64 * 1024, 1024 * 1024] {
+        let data = FileData {
+            file: vec![0x42; size],
+            thumbnail: None,
+        };
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            let mut rng = StdRng::seed_from_u64(0);
+            b.iter(|| encrypt_file_data_with_rng(black_box(data), &mut rng).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_incoming_message(c: &mut Criterion) {
+    let body = test_support::CALLBACK_BODY;
+    let mut group = c.benchmark_group("incoming_message");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+    group.bench_function("from_urlencoded_bytes", |b| {
+        b.iter(|| {
+            IncomingMessage::from_urlencoded_bytes(black_box(body), test_support::API_SECRET)
+                .unwrap()
+        })
+    });
+    let msg = IncomingMessage::from_urlencoded_bytes(body, test_support::API_SECRET).unwrap();
+    let public_key = test_support::recipient_public_key();
+    let private_key = test_support::gateway_private_key();
+    group.bench_function("decrypt_box", |b| {
+        b.iter(|| {
+            black_box(&msg)
+                .decrypt_box(&public_key, &private_key)
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn bench_hex(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hex");
+    let key = test_support::recipient_key();
+    group.bench_function("recipient_key_to_hex", |b| {
+        b.iter(|| black_box(&key).to_hex_string())
+    });
+    group.bench_function("recipient_key_from_hex", |b| {
+        b.iter(|| {
+            black_box(test_support::RECIPIENT_PUBLIC_KEY)
+                .parse::<RecipientKey>()
+                .unwrap()
+        })
+    });
+    group.bench_function("key_from_hex", |b| {
+        b.iter(|| {
+            black_box(test_support::GATEWAY_PRIVATE_KEY)
+                .parse::<Key>()
+                .unwrap()
+        })
+    });
+    let blob_id = BlobId::new([0x42; 16]);
+    group.bench_function("blob_id_to_string", |b| {
+        b.iter(|| black_box(&blob_id).to_string())
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_encrypt,
+    bench_encrypt_file_data,
+    bench_incoming_message,
+    bench_hex
+);
+criterion_main!(benches);
@@ -23,7 +23,7 @@ async fn main() {
     println!("Looking up credits");
 
     // Look up ID
-    let api = ApiBuilder::new(from, secret).into_simple();
+    let api = ApiBuilder::new(from, secret).into_simple().unwrap();
     match api.lookup_credits().await {
         Err(e) => {
             println!("Could not look up credits: {}", e);
@@ -45,7 +45,7 @@ async fn main() {
     );
 
     // Look up ID
-    let api = ApiBuilder::new(from, secret).into_simple();
+    let api = ApiBuilder::new(from, secret).into_simple().unwrap();
     match api.lookup_id(&criterion).await {
         Err(e) => {
             println!("Could not look up id: {}", e);
@@ -21,7 +21,7 @@ async fn main() {
     let simulate_cache = args.get_bool("--with-cache");
 
     // Fetch recipient public key
-    let api = ApiBuilder::new(our_id, secret).into_simple();
+    let api = ApiBuilder::new(our_id, secret).into_simple().unwrap();
     let pubkey = if simulate_cache {
         let cache = SimulatedCache;
         api.lookup_pubkey_with_cache(their_id, &cache)
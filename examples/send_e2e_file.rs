@@ -1,7 +1,7 @@
-use std::{ffi::OsStr, fs::File, io::Read, path::Path, process};
+use std::{ffi::OsStr, fs, path::Path, process};
 
 use docopt::Docopt;
-use threema_gateway::{encrypt_file_data, ApiBuilder, FileData, FileMessage, RenderingType};
+use threema_gateway::{ApiBuilder, FileData, FileSendOptions, RenderingType};
 
 const USAGE: &str = "
 Usage: send_e2e_file [options] <from> <to> <secret> <private-key> <path-to-file>
@@ -50,7 +50,7 @@ async fn main() {
     };
     let caption = match args.get_str("--caption") {
         "" => None,
-        c => Some(c),
+        c => Some(c.to_string()),
     };
 
     // Verify thumbnail file type
@@ -72,69 +72,30 @@ async fn main() {
     let recipient_key = etry!(api.lookup_pubkey(to).await, "Could not fetch public key");
 
     // Read files
-    let mut file = etry!(File::open(filepath), "Could not open file");
-    let mut file_bytes: Vec<u8> = vec![];
-    etry!(file.read_to_end(&mut file_bytes), "Could not read file");
-    let thumbnail_bytes = match thumbpath {
-        Some(p) => {
-            let mut thumb = etry!(File::open(p), format!("Could not open thumbnail {:?}", p));
-            let mut thumb_data: Vec<u8> = vec![];
-            etry!(
-                thumb.read_to_end(&mut thumb_data),
-                format!("Could not read thumbnail {:?}", p)
-            );
-            Some(thumb_data)
-        }
-        None => None,
-    };
-
-    // Encrypt file data
     let file_data = FileData {
-        file: file_bytes,
-        thumbnail: thumbnail_bytes,
+        file: etry!(fs::read(filepath), "Could not read file"),
+        thumbnail: thumbpath
+            .map(|p| etry!(fs::read(p), format!("Could not read thumbnail {:?}", p))),
     };
-    let (encrypted, key) = etry!(encrypt_file_data(&file_data), "Could not encrypt file");
 
-    // Upload files to blob server
-    let file_blob_id = etry!(
-        api.blob_upload_raw(&encrypted.file, false).await,
-        "Could not upload file to blob server"
-    );
-    let thumb_blob_id = if let Some(et) = encrypted.thumbnail {
-        let blob_id = etry!(
-            api.blob_upload_raw(&et, false).await,
-            "Could not upload thumbnail to blob server"
-        );
-        let thumbnail_media_type = mime_guess::from_path(thumbpath.unwrap())
-            .first_or_octet_stream()
-            .to_string();
-        Some((blob_id, thumbnail_media_type))
-    } else {
-        None
+    // Encrypt, upload and send
+    let options = FileSendOptions {
+        media_type: Some(
+            mime_guess::from_path(filepath)
+                .first_or_octet_stream()
+                .to_string(),
+        ),
+        file_name: filepath
+            .file_name()
+            .and_then(OsStr::to_str)
+            .map(ToOwned::to_owned),
+        description: caption,
+        rendering_type,
+        ..Default::default()
     };
-
-    // Create file message
-    let file_media_type = mime_guess::from_path(filepath)
-        .first_or_octet_stream()
-        .to_string();
-    let file_name = filepath.file_name().and_then(OsStr::to_str);
-    let file_size_bytes = file_data.file.len() as u32;
-    let msg = FileMessage::builder(file_blob_id, key, file_media_type, file_size_bytes)
-        .thumbnail_opt(thumb_blob_id)
-        .file_name_opt(file_name)
-        .description_opt(caption)
-        .rendering_type(rendering_type)
-        .build()
-        .expect("Could not build FileMessage");
-    let encrypted = etry!(
-        api.encrypt_file_msg(&msg, &recipient_key),
-        "Could not encrypt file msg"
-    );
-
-    // Send
-    let msg_id = api.send(to, &encrypted, false).await;
+    let msg_id = api.send_file(to, &recipient_key, file_data, options).await;
     match msg_id {
-        Ok(id) => println!("Sent. Message id is {}.", id),
+        Ok(result) => println!("Sent. Message id is {}.", result.message_id),
         Err(e) => println!("Could not send message: {}", e),
     }
 }
@@ -1,6 +1,5 @@
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::Read;
+use std::fs;
 use std::path::Path;
 use std::process;
 
@@ -50,49 +49,16 @@ async fn main() {
         process::exit(1);
     });
 
-    // Encrypt image
-    let mut file = File::open(path).unwrap_or_else(|e| {
-        println!("Could not open file: {}", e);
-        process::exit(1);
-    });
-    let mut img_data: Vec<u8> = vec![];
-    file.read_to_end(&mut img_data).unwrap_or_else(|e| {
+    // Read image
+    let img_data = fs::read(path).unwrap_or_else(|e| {
         println!("Could not read file: {}", e);
         process::exit(1);
     });
-    let encrypted_image = api
-        .encrypt_raw(&img_data, &recipient_key)
-        .unwrap_or_else(|_| {
-            println!("Could encrypt raw msg");
-            process::exit(1);
-        });
-
-    // Upload image to blob server
-    let blob_id = api
-        .blob_upload(&encrypted_image, false)
-        .await
-        .unwrap_or_else(|e| {
-            println!("Could not upload image to blob server: {}", e);
-            process::exit(1);
-        });
-
-    // Create image message
-    let msg = api
-        .encrypt_image_msg(
-            &blob_id,
-            img_data.len() as u32,
-            &encrypted_image.nonce,
-            &recipient_key,
-        )
-        .unwrap_or_else(|e| {
-            println!("Could not encrypt image msg: {e}");
-            process::exit(1);
-        });
 
-    // Send
-    let msg_id = api.send(to, &msg, false).await;
+    // Encrypt, upload and send
+    let msg_id = api.send_image(to, &recipient_key, img_data).await;
     match msg_id {
-        Ok(id) => println!("Sent. Message id is {}.", id),
+        Ok(result) => println!("Sent. Message id is {}.", result.message_id),
         Err(e) => println!("Could not send message: {}", e),
     }
 }
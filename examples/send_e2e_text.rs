@@ -46,7 +46,7 @@ async fn main() {
     let msg_id = api.send(to, &encrypted, false).await;
 
     match msg_id {
-        Ok(id) => println!("Sent. Message id is {}.", id),
+        Ok(result) => println!("Sent. Message id is {}.", result.message_id),
         Err(e) => println!("Could not send message: {}", e),
     }
 }
@@ -35,10 +35,10 @@ async fn main() {
     };
 
     // Send
-    let api = ApiBuilder::new(from, secret).into_simple();
+    let api = ApiBuilder::new(from, secret).into_simple().unwrap();
     let msg_id = api.send(&recipient, &text).await;
     match msg_id {
-        Ok(id) => println!("Sent. Message id is {}.", id),
+        Ok(result) => println!("Sent. Message id is {}.", result.message_id),
         Err(e) => println!("Could not send message: {}", e),
     }
 }
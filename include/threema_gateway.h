@@ -0,0 +1,207 @@
+#ifndef THREEMA_GATEWAY_H
+#define THREEMA_GATEWAY_H
+
+/* Generated with cbindgen, do not edit manually. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Status codes returned by the C API.
+typedef enum ThreemaStatus {
+  // The call succeeded.
+  THREEMA_STATUS_OK = 0,
+  // A null pointer, an invalid UTF-8 string or an invalid key was passed.
+  THREEMA_STATUS_INVALID_ARGUMENT = 1,
+  // The API object could not be built.
+  THREEMA_STATUS_BUILDER_ERROR = 2,
+  // Encryption or decryption failed.
+  THREEMA_STATUS_CRYPTO_ERROR = 3,
+  // The request to the gateway failed.
+  THREEMA_STATUS_API_ERROR = 4,
+  // The MAC of an incoming message is invalid.
+  THREEMA_STATUS_INVALID_MAC = 5,
+  // The call panicked.
+  THREEMA_STATUS_PANIC = 6,
+} ThreemaStatus;
+
+// An E2E API object. Create it with [`threema_e2e_api_new`] and free it
+// with [`threema_e2e_api_free`].
+typedef struct ThreemaE2eApi ThreemaE2eApi;
+
+// An encrypted message.
+typedef struct ThreemaEncryptedMessage {
+  // The ciphertext.
+  uint8_t *ciphertext;
+  // The length of the ciphertext in bytes.
+  size_t ciphertext_len;
+  // The nonce used for encryption.
+  uint8_t nonce[24];
+} ThreemaEncryptedMessage;
+
+// An incoming message with a valid MAC. The box is not decrypted yet, see
+// [`threema_decrypt_incoming`].
+typedef struct ThreemaIncomingMessage {
+  // Sender identity.
+  char *from;
+  // The gateway ID the message is addressed to.
+  char *to;
+  // The hex encoded message ID.
+  char *message_id;
+  // Message date set by the sender (UNIX timestamp).
+  uint64_t date;
+  // Public nickname of the sender, or null if not set.
+  char *nickname;
+  // The nonce used for encryption.
+  uint8_t *nonce;
+  // The length of the nonce in bytes.
+  size_t nonce_len;
+  // The encrypted message data.
+  uint8_t *box_data;
+  // The length of the encrypted message data in bytes.
+  size_t box_len;
+} ThreemaIncomingMessage;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Return the description of the last error that occurred on the calling
+// thread, or null if the last call succeeded.
+//
+// The string is valid until the next call to this library on the same
+// thread.
+const char *threema_last_error(void);
+
+// Create an E2E API object.
+//
+// `endpoint` may be null to use the default API endpoint. `private_key` is
+// the hex encoded private key, optionally prefixed with `private:`.
+//
+// # Safety
+//
+// All strings must be null terminated. `out` must point to writable memory
+// for a pointer.
+enum ThreemaStatus threema_e2e_api_new(const char *id,
+                                       const char *secret,
+                                       const char *private_key,
+                                       const char *endpoint,
+                                       struct ThreemaE2eApi **out);
+
+// Free an E2E API object. Passing null is a no-op.
+//
+// # Safety
+//
+// `api` must have been created with [`threema_e2e_api_new`] and must not
+// be used afterwards.
+void threema_e2e_api_free(struct ThreemaE2eApi *api);
+
+// Look up the public key of a Threema ID and write it to `out` (32 bytes).
+//
+// # Safety
+//
+// `api` must be a valid API object, `id` a null terminated string and `out`
+// must point to 32 writable bytes.
+enum ThreemaStatus threema_lookup_pubkey(const struct ThreemaE2eApi *api,
+                                         const char *id,
+                                         uint8_t *out);
+
+// Encrypt a text message for the recipient with the specified public key
+// (32 bytes).
+//
+// The message must be released with [`threema_encrypted_message_free`].
+//
+// # Safety
+//
+// `api` must be a valid API object, `text` a null terminated string,
+// `recipient_key` must point to 32 readable bytes and `out` to a writable
+// [`ThreemaEncryptedMessage`].
+enum ThreemaStatus threema_encrypt_text(const struct ThreemaE2eApi *api,
+                                        const char *text,
+                                        const uint8_t *recipient_key,
+                                        struct ThreemaEncryptedMessage *out);
+
+// Free the ciphertext of an encrypted message. Passing null is a no-op.
+//
+// # Safety
+//
+// `msg` must have been filled by [`threema_encrypt_text`].
+void threema_encrypted_message_free(struct ThreemaEncryptedMessage *msg);
+
+// Send an encrypted message and write the hex encoded message ID to
+// `out_message_id`.
+//
+// # Safety
+//
+// `api` must be a valid API object, `to` a null terminated string, `msg` a
+// valid encrypted message and `out_message_id` must point to 17 writable
+// bytes.
+enum ThreemaStatus threema_send(const struct ThreemaE2eApi *api,
+                                const char *to,
+                                const struct ThreemaEncryptedMessage *msg,
+                                bool delivery_receipts,
+                                char (*out_message_id)[17]);
+
+// Look up the public key of the recipient, encrypt and send a text message
+// and write the hex encoded message ID to `out_message_id`.
+//
+// # Safety
+//
+// `api` must be a valid API object, `to` and `text` null terminated strings
+// and `out_message_id` must point to 17 writable bytes.
+enum ThreemaStatus threema_send_text(const struct ThreemaE2eApi *api,
+                                     const char *to,
+                                     const char *text,
+                                     char (*out_message_id)[17]);
+
+// Validate the MAC of an incoming callback request body and parse it.
+//
+// The message must be released with [`threema_incoming_message_free`].
+//
+// # Safety
+//
+// `api` must be a valid API object, `body` must point to `body_len`
+// readable bytes and `out` to a writable [`ThreemaIncomingMessage`].
+enum ThreemaStatus threema_decode_incoming(const struct ThreemaE2eApi *api,
+                                           const uint8_t *body,
+                                           size_t body_len,
+                                           struct ThreemaIncomingMessage *out);
+
+// Decrypt the box of an incoming message using the public key of the
+// sender (32 bytes) and remove the padding.
+//
+// The decrypted data (starting with the message type byte) must be released
+// with [`threema_bytes_free`].
+//
+// # Safety
+//
+// `api` must be a valid API object, `msg` a message filled by
+// [`threema_decode_incoming`], `sender_key` must point to 32 readable bytes
+// and `out_data` and `out_len` must be writable.
+enum ThreemaStatus threema_decrypt_incoming(const struct ThreemaE2eApi *api,
+                                            const struct ThreemaIncomingMessage *msg,
+                                            const uint8_t *sender_key,
+                                            uint8_t **out_data,
+                                            size_t *out_len);
+
+// Free the fields of an incoming message. Passing null is a no-op.
+//
+// # Safety
+//
+// `msg` must have been filled by [`threema_decode_incoming`].
+void threema_incoming_message_free(struct ThreemaIncomingMessage *msg);
+
+// Free a byte buffer returned by this library. Passing null is a no-op.
+//
+// # Safety
+//
+// `data` and `len` must have been returned by this library.
+void threema_bytes_free(uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* THREEMA_GATEWAY_H */
@@ -1,36 +1,59 @@
 use std::{
-    borrow::{Borrow, Cow},
-    collections::HashMap,
-    time::Duration,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use crypto_box::SecretKey;
+use bytes::Bytes;
+use crypto_box::{PublicKey, SecretKey};
 use crypto_secretbox::Nonce;
-use data_encoding::HEXLOWER_PERMISSIVE;
-use reqwest::Client;
+use data_encoding::HEXLOWER;
+use futures_util::{stream, StreamExt};
+use reqwest::{header::HeaderMap, Client, StatusCode};
+use zeroize::Zeroizing;
 
 use crate::{
-    cache::PublicKeyCache,
-    connection::{blob_download, blob_upload, send_e2e, send_simple, Recipient},
+    audit::{AuditOperation, AuditSink, Auditor},
+    cache::{BlobCache, PublicKeyCache, SharedBlobCache, SharedPublicKeyCache},
+    connection::{blob_download, blob_upload, send_e2e, send_simple, Recipient, Timeouts},
+    contacts::{
+        parse_csv, Contact, ContactImport, ContactStore, SharedContactStore, VerificationLevel,
+    },
+    credits::{Cost, CreditCounter, CreditReconciliation, CreditUsage},
     crypto::{
-        encrypt, encrypt_file_msg, encrypt_image_msg, encrypt_raw, EncryptedMessage, RecipientKey,
+        decode_base64_ct, decode_hex_ct, encrypt_image_msg, encrypt_raw, encrypt_with_padding,
+        EncryptedMessage, PaddingPolicy, RecipientKey,
     },
+    endpoint::{EndpointStatus, Endpoints, DEFAULT_ENDPOINT_COOLDOWN},
     errors::{ApiBuilderError, ApiError, ApiOrCacheError, CryptoError},
+    gateway,
+    history::{Direction, MessageStore, SharedMessageStore, StoredMessage},
+    hooks::Hooks,
+    key_provider::KeyProvider,
+    ledger::{LedgerEntry, SendLedger, SharedSendLedger},
     lookup::{
-        lookup_capabilities, lookup_credits, lookup_id, lookup_pubkey, Capabilities,
-        LookupCriterion,
+        lookup_bulk, lookup_capabilities, lookup_credits, lookup_id, lookup_pubkey,
+        BulkLookupMatch, Capabilities, LookupCriterion, BULK_LOOKUP_MAX_HASHES,
+    },
+    receive::{IncomingMessage, NicknamePolicy},
+    redact::Redacted,
+    reporting::ErrorReporter,
+    retry::RetryPolicy,
+    status::{DeliveryReceipt, MessageStatus},
+    types::{
+        BlobId, FileMessage, FileSendOptions, FileSource, MessageId, MessageType, SendOptions,
+        SendResult,
     },
-    receive::IncomingMessage,
-    types::{BlobId, FileMessage, MessageType},
     MSGAPI_URL,
 };
-
-fn make_reqwest_client() -> Client {
-    Client::builder()
-        .timeout(Duration::from_secs(10))
-        .build()
-        .expect("Could not build client")
-}
+#[cfg(feature = "parallel")]
+use crate::{
+    errors::TemplateError,
+    template::{check_length, Template},
+};
 
 /// Implement methods available on both the simple and the e2e API objects.
 macro_rules! impl_common_functionality {
@@ -46,15 +69,32 @@ macro_rules! impl_common_functionality {
         /// *Note:* It is strongly recommended that you cache the public keys to avoid
         /// querying the API for each message. To simplify this, the
         /// `lookup_pubkey_with_cache` method can be used instead.
+        ///
+        /// Cost: 1 credit.
         pub async fn lookup_pubkey(&self, id: &str) -> Result<RecipientKey, ApiError> {
-            lookup_pubkey(
-                &self.client,
-                self.endpoint.borrow(),
-                &self.id,
-                id,
-                &self.secret,
-            )
-            .await
+            let result = self
+                .endpoints
+                .run(self.deadline, |endpoint| {
+                    lookup_pubkey(
+                        &self.client,
+                        endpoint,
+                        self.deadline,
+                        &self.hooks,
+                        &self.id,
+                        id,
+                        &self.secret,
+                    )
+                })
+                .await;
+            self.hooks.operation_finished(
+                AuditOperation::Lookup,
+                Some(id),
+                None,
+                result.as_ref().err(),
+            );
+            let key = result?;
+            self.credits.charge(Cost::Lookup);
+            Ok(key)
         }
 
         /// Fetch the recipient public key for the specified Threema ID and store it
@@ -90,15 +130,79 @@ macro_rules! impl_common_functionality {
         /// address, in plaintext or hashed form. You can specify one of those
         /// criteria using the [`LookupCriterion`](enum.LookupCriterion.html)
         /// enum.
+        ///
+        /// Cost: 1 credit.
         pub async fn lookup_id(&self, criterion: &LookupCriterion) -> Result<String, ApiError> {
-            lookup_id(
-                &self.client,
-                self.endpoint.borrow(),
-                criterion,
-                &self.id,
-                &self.secret,
-            )
-            .await
+            let result = self
+                .endpoints
+                .run(self.deadline, |endpoint| {
+                    lookup_id(
+                        &self.client,
+                        endpoint,
+                        self.deadline,
+                        &self.hooks,
+                        criterion,
+                        &self.id,
+                        &self.secret,
+                    )
+                })
+                .await;
+            self.hooks.operation_finished(
+                AuditOperation::Lookup,
+                Some(&criterion.hashed().to_string()),
+                None,
+                result.as_ref().err(),
+            );
+            let id = result?;
+            self.credits.charge(Cost::Lookup);
+            Ok(id)
+        }
+
+        /// Look up the Threema IDs and public keys for many phone numbers and
+        /// e-mail addresses at once.
+        ///
+        /// Plaintext criteria are hashed locally (see
+        /// [`LookupCriterion::hashed`]), so they are never sent to the server.
+        /// The criteria are split into requests of at most
+        /// [`BULK_LOOKUP_MAX_HASHES`](crate::BULK_LOOKUP_MAX_HASHES) hashes.
+        /// Criteria without a match are not included in the result.
+        ///
+        /// Cost: 1 credit per found ID.
+        pub async fn lookup_ids_bulk(
+            &self,
+            criteria: &[LookupCriterion],
+        ) -> Result<Vec<BulkLookupMatch>, ApiError> {
+            let hashed: Vec<LookupCriterion> =
+                criteria.iter().map(LookupCriterion::hashed).collect();
+            let mut matches = Vec::new();
+            for chunk in hashed.chunks(BULK_LOOKUP_MAX_HASHES) {
+                let result = self
+                    .endpoints
+                    .run(self.deadline, |endpoint| {
+                        lookup_bulk(
+                            &self.client,
+                            endpoint,
+                            self.deadline,
+                            &self.hooks,
+                            chunk,
+                            &self.id,
+                            &self.secret,
+                        )
+                    })
+                    .await;
+                self.hooks.operation_finished(
+                    AuditOperation::Lookup,
+                    None,
+                    None,
+                    result.as_ref().err(),
+                );
+                let found = result?;
+                for _ in &found {
+                    self.credits.charge(Cost::Lookup);
+                }
+                matches.extend(found);
+            }
+            Ok(matches)
         }
 
         /// Look up the capabilities of a certain Threema ID.
@@ -109,45 +213,280 @@ macro_rules! impl_common_functionality {
         /// using an old version, or a platform where file reception is not
         /// supported.
         pub async fn lookup_capabilities(&self, id: &str) -> Result<Capabilities, ApiError> {
-            lookup_capabilities(
-                &self.client,
-                self.endpoint.borrow(),
-                &self.id,
-                id,
-                &self.secret,
-            )
-            .await
+            let result = self
+                .endpoints
+                .run(self.deadline, |endpoint| {
+                    lookup_capabilities(
+                        &self.client,
+                        endpoint,
+                        self.deadline,
+                        &self.hooks,
+                        &self.id,
+                        id,
+                        &self.secret,
+                    )
+                })
+                .await;
+            self.hooks.operation_finished(
+                AuditOperation::Lookup,
+                Some(id),
+                None,
+                result.as_ref().err(),
+            );
+            let capabilities = result?;
+            self.credits.charge(Cost::Lookup);
+            Ok(capabilities)
+        }
+
+        /// Return a copy of the API object whose calls fail with
+        /// [`ApiError::DeadlineExceeded`] if they don't complete before
+        /// `deadline`.
+        ///
+        /// The deadline covers all requests of a call, including failover to
+        /// other endpoints, and overrides the timeout of the HTTP client. The
+        /// API object is cheap to clone, so this is meant to be used for
+        /// individual calls:
+        ///
+        /// ```no_run
+        /// # async fn f(api: threema_gateway::SimpleApi) {
+        /// use std::time::{Duration, Instant};
+        ///
+        /// let deadline = Instant::now() + Duration::from_secs(2);
+        /// let credits = api.with_deadline(deadline).lookup_credits().await;
+        /// # }
+        /// ```
+        ///
+        /// To cancel a call, drop its future (e.g. in a `tokio::select!`).
+        pub fn with_deadline(&self, deadline: Instant) -> Self {
+            Self {
+                deadline: Some(deadline),
+                ..self.clone()
+            }
+        }
+
+        /// Return a copy of the API object whose calls fail with
+        /// [`ApiError::DeadlineExceeded`] if they don't complete within
+        /// `timeout`, starting now. See [`with_deadline`](Self::with_deadline).
+        pub fn with_timeout(&self, timeout: Duration) -> Self {
+            self.with_deadline(Instant::now() + timeout)
+        }
+
+        /// Return a Threema Broadcast API object for the specified API key
+        /// that shares the HTTP client of this API object.
+        #[cfg(feature = "broadcast")]
+        pub fn broadcast_api<K: Into<String>>(&self, api_key: K) -> crate::broadcast::BroadcastApi {
+            crate::broadcast::BroadcastApi::with_client(api_key, self.client.clone())
+        }
+
+        /// Return a Threema Work API object for the specified API key that
+        /// shares the HTTP client of this API object.
+        #[cfg(feature = "work")]
+        pub fn work_api<K: Into<String>>(&self, api_key: K) -> crate::work::WorkApi {
+            crate::work::WorkApi::with_client(api_key, self.client.clone())
+        }
+
+        /// Return the health of the configured API endpoints, in order of
+        /// priority (see [`ApiBuilder::with_fallback_endpoint`]).
+        pub fn endpoint_status(&self) -> Vec<EndpointStatus> {
+            self.endpoints.status()
         }
 
         /// Look up a remaining gateway credits.
         pub async fn lookup_credits(&self) -> Result<i64, ApiError> {
-            lookup_credits(&self.client, self.endpoint.borrow(), &self.id, &self.secret).await
+            let result = self
+                .endpoints
+                .run(self.deadline, |endpoint| {
+                    lookup_credits(
+                        &self.client,
+                        endpoint,
+                        self.deadline,
+                        &self.hooks,
+                        &self.id,
+                        &self.secret,
+                    )
+                })
+                .await;
+            self.hooks.operation_finished(
+                AuditOperation::Lookup,
+                None,
+                None,
+                result.as_ref().err(),
+            );
+            result
+        }
+
+        /// Register a callback that is called after a message was sent
+        /// successfully.
+        ///
+        /// Callbacks are called synchronously, so they should return quickly.
+        /// They are shared with the clones of the API object created
+        /// afterwards (e.g. with [`with_deadline`](Self::with_deadline)).
+        pub fn on_send<F>(mut self, callback: F) -> Self
+        where
+            F: Fn(&Recipient<'_>, &SendResult) + Send + Sync + 'static,
+        {
+            Arc::make_mut(&mut self.hooks)
+                .on_send
+                .push(Arc::new(callback));
+            self
+        }
+
+        /// Register a callback that is called if sending a message failed.
+        ///
+        /// See [`on_send`](Self::on_send).
+        pub fn on_error<F>(mut self, callback: F) -> Self
+        where
+            F: Fn(&Recipient<'_>, &ApiError) + Send + Sync + 'static,
+        {
+            Arc::make_mut(&mut self.hooks)
+                .on_error
+                .push(Arc::new(callback));
+            self
+        }
+
+        /// Register a callback that is called with the status code and the
+        /// headers of every HTTP response received from the gateway, e.g. to
+        /// capture rate limit or diagnostic headers.
+        ///
+        /// The callback is called before the response is processed, also for
+        /// error responses. See [`on_send`](Self::on_send).
+        pub fn on_response<F>(mut self, callback: F) -> Self
+        where
+            F: Fn(StatusCode, &HeaderMap) + Send + Sync + 'static,
+        {
+            Arc::make_mut(&mut self.hooks)
+                .on_response
+                .push(Arc::new(callback));
+            self
+        }
+
+        /// Register a transformation of the text of every outgoing text
+        /// message, e.g. to append a signature or to redact patterns.
+        ///
+        /// The transformation is applied before a message is sent (or, in
+        /// E2E mode, encrypted), on all paths that send text: `send`,
+        /// `send_text`, `encrypt_text_msg`, `encrypt` with
+        /// [`MessageType::Text`] and the bulk variants, and thus also the
+        /// replies of a bot. In E2E mode, the captions of file messages
+        /// (`send_file`, `encrypt_file_msg` and `encrypt` with
+        /// [`MessageType::File`]) are transformed as well. Transformations
+        /// are applied in the order of registration. See
+        /// [`on_send`](Self::on_send).
+        ///
+        /// ```
+        /// use threema_gateway::ApiBuilder;
+        ///
+        /// let api = ApiBuilder::new("*3MAGWID", "secret")
+        ///     .into_simple()
+        ///     .unwrap()
+        ///     .transform_text(|text| format!("{}\n-- ACME Support", text));
+        /// ```
+        pub fn transform_text<F>(mut self, transform: F) -> Self
+        where
+            F: Fn(&str) -> String + Send + Sync + 'static,
+        {
+            Arc::make_mut(&mut self.hooks)
+                .text_transforms
+                .push(Arc::new(transform));
+            self
+        }
+
+        /// Record every send, lookup, blob transfer and decoded incoming
+        /// message in `sink`.
+        ///
+        /// The events contain no message content, and identifiers are hashed
+        /// with the API secret, see the [`audit`](crate::audit) module.
+        /// Registering a sink replaces the previous one. See
+        /// [`on_send`](Self::on_send).
+        pub fn with_audit_sink<S: AuditSink + 'static>(mut self, sink: S) -> Self {
+            Arc::make_mut(&mut self.hooks).audit = Some(Auditor::new(sink, &self.secret));
+            self
+        }
+
+        /// Report every failed send, lookup and blob transfer, and every
+        /// incoming message that could not be decoded or decrypted, to
+        /// `reporter`.
+        ///
+        /// See the [`reporting`](crate::reporting) module. Registering a
+        /// reporter replaces the previous one.
+        pub fn with_error_reporter<R: ErrorReporter + 'static>(mut self, reporter: R) -> Self {
+            Arc::make_mut(&mut self.hooks).error_reporter = Some(Arc::new(reporter));
+            self
+        }
+
+        /// Return the credits consumed by this API object and its clones,
+        /// counted locally since it was created or since the last
+        /// [`reset_credit_usage`](Self::reset_credit_usage).
+        ///
+        /// Only successful calls are counted, calls in dry run mode are free.
+        pub fn credit_usage(&self) -> CreditUsage {
+            self.credits.usage()
+        }
+
+        /// Reset the local credit counters and return their previous values.
+        pub fn reset_credit_usage(&self) -> CreditUsage {
+            self.credits.reset()
+        }
+
+        /// Look up the remaining credits and compare the credits consumed
+        /// since the previous reconciliation with the credits counted
+        /// locally.
+        ///
+        /// A discrepancy means that credits were consumed by other
+        /// applications using the same gateway ID, or that credits were added.
+        /// Calls that run concurrently with the reconciliation may be
+        /// attributed to the wrong period.
+        pub async fn reconcile_credits(&self) -> Result<CreditReconciliation, ApiError> {
+            let balance = self.lookup_credits().await?;
+            Ok(self.credits.reconcile(balance))
         }
     };
 }
 
 /// Struct to talk to the simple API (without end-to-end encryption).
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct SimpleApi {
     id: String,
     secret: String,
-    endpoint: Cow<'static, str>,
+    endpoints: Endpoints,
     client: Client,
+    dry_run: bool,
+    deadline: Option<Instant>,
+    credits: CreditCounter,
+    hooks: Arc<Hooks>,
+}
+
+impl fmt::Debug for SimpleApi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimpleApi")
+            .field("id", &self.id)
+            .field("secret", &Redacted(&self.secret))
+            .field("endpoints", &self.endpoints)
+            .field("dry_run", &self.dry_run)
+            .field("deadline", &self.deadline)
+            .field("credits", &self.credits)
+            .finish_non_exhaustive()
+    }
 }
 
 impl SimpleApi {
     /// Initialize the simple API with the Gateway ID and the Gateway Secret.
     pub(crate) fn new<I: Into<String>, S: Into<String>>(
-        endpoint: Cow<'static, str>,
+        endpoints: Endpoints,
         id: I,
         secret: S,
         client: Client,
+        dry_run: bool,
     ) -> Self {
         SimpleApi {
             id: id.into(),
             secret: secret.into(),
-            endpoint,
+            endpoints,
             client,
+            dry_run,
+            deadline: None,
+            credits: CreditCounter::default(),
+            hooks: Arc::default(),
         }
     }
 
@@ -158,59 +497,246 @@ impl SimpleApi {
     /// Gateway server.
     ///
     /// Cost: 1 credit.
-    pub async fn send(&self, to: &Recipient<'_>, text: &str) -> Result<String, ApiError> {
-        send_simple(
-            &self.client,
-            self.endpoint.borrow(),
-            &self.id,
-            to,
-            &self.secret,
-            text,
-        )
-        .await
+    pub async fn send(&self, to: &Recipient<'_>, text: &str) -> Result<SendResult, ApiError> {
+        let text = &*self.hooks.transform_text(text);
+        let result = self
+            .endpoints
+            .run(self.deadline, |endpoint| {
+                send_simple(
+                    &self.client,
+                    endpoint,
+                    self.deadline,
+                    &self.hooks,
+                    &self.id,
+                    to,
+                    &self.secret,
+                    text,
+                    self.dry_run,
+                )
+            })
+            .await;
+        self.hooks.send_finished(to, &result);
+        let result = result?;
+        self.charge(Cost::Message);
+        Ok(result)
+    }
+
+    /// Count the credits of a successful call, unless in dry run mode.
+    fn charge(&self, cost: Cost) {
+        if !self.dry_run {
+            self.credits.charge(cost);
+        }
+    }
+
+    /// Upgrade to an [`E2eApi`] with the specified private key (or other
+    /// [`KeyProvider`]).
+    ///
+    /// The endpoint, the credentials, the HTTP client, the deadline, the
+    /// credit counters and the registered callbacks are re-used. All other
+    /// settings of the E2E API use their default values.
+    pub fn into_e2e<K: KeyProvider + 'static>(self, private_key: K) -> E2eApi {
+        let api = E2eApi::new(
+            self.endpoints,
+            self.id,
+            self.secret,
+            Arc::new(private_key),
+            self.client,
+            E2eConfig {
+                dry_run: self.dry_run,
+                ..E2eConfig::default()
+            },
+        );
+        E2eApi {
+            deadline: self.deadline,
+            credits: self.credits,
+            hooks: self.hooks,
+            ..api
+        }
     }
 
     impl_common_functionality!();
 }
 
+/// The [`SendOptions`] used by [`E2eApi::send_text`] unless configured
+/// otherwise: Delivery receipts are requested.
+fn default_send_options() -> SendOptions {
+    SendOptions {
+        delivery_receipts: true,
+        ..SendOptions::default()
+    }
+}
+
+/// The settings of an [`E2eApi`], apart from the credentials and the HTTP
+/// client.
+pub(crate) struct E2eConfig {
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) blob_endpoint: Option<Cow<'static, str>>,
+    pub(crate) padding_policy: PaddingPolicy,
+    pub(crate) public_key_cache: Option<SharedPublicKeyCache>,
+    pub(crate) message_store: Option<SharedMessageStore>,
+    pub(crate) blob_cache: Option<SharedBlobCache>,
+    pub(crate) contact_store: Option<SharedContactStore>,
+    pub(crate) send_ledger: Option<SharedSendLedger>,
+    pub(crate) dry_run: bool,
+    pub(crate) credits_check: bool,
+    pub(crate) strict_recipient_check: bool,
+    pub(crate) nickname_policy: NicknamePolicy,
+    pub(crate) send_options: SendOptions,
+}
+
+impl Default for E2eConfig {
+    fn default() -> Self {
+        E2eConfig {
+            retry_policy: RetryPolicy::default(),
+            blob_endpoint: None,
+            padding_policy: PaddingPolicy::default(),
+            public_key_cache: None,
+            message_store: None,
+            blob_cache: None,
+            contact_store: None,
+            send_ledger: None,
+            dry_run: false,
+            credits_check: false,
+            strict_recipient_check: false,
+            nickname_policy: NicknamePolicy::default(),
+            send_options: default_send_options(),
+        }
+    }
+}
+
 /// Struct to talk to the E2E API (with end-to-end encryption).
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct E2eApi {
     id: String,
     secret: String,
-    private_key: SecretKey,
-    endpoint: Cow<'static, str>,
+    key_provider: Arc<dyn KeyProvider>,
+    endpoints: Endpoints,
     client: Client,
+    retry_policy: RetryPolicy,
+    blob_endpoints: Endpoints,
+    padding_policy: PaddingPolicy,
+    public_key_cache: Option<SharedPublicKeyCache>,
+    message_store: Option<SharedMessageStore>,
+    blob_cache: Option<SharedBlobCache>,
+    contact_store: Option<SharedContactStore>,
+    send_ledger: Option<SharedSendLedger>,
+    dry_run: bool,
+    credits_check: bool,
+    strict_recipient_check: bool,
+    nickname_policy: NicknamePolicy,
+    send_options: SendOptions,
+    deadline: Option<Instant>,
+    credits: CreditCounter,
+    hooks: Arc<Hooks>,
+}
+
+impl fmt::Debug for E2eApi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("E2eApi")
+            .field("id", &self.id)
+            .field("secret", &Redacted(&self.secret))
+            .field("key_provider", &self.key_provider)
+            .field("endpoints", &self.endpoints)
+            .field("blob_endpoints", &self.blob_endpoints)
+            .field("retry_policy", &self.retry_policy)
+            .field("padding_policy", &self.padding_policy)
+            .field("public_key_cache", &self.public_key_cache)
+            .field("message_store", &self.message_store)
+            .field("blob_cache", &self.blob_cache)
+            .field("contact_store", &self.contact_store)
+            .field("send_ledger", &self.send_ledger)
+            .field("dry_run", &self.dry_run)
+            .field("credits_check", &self.credits_check)
+            .field("strict_recipient_check", &self.strict_recipient_check)
+            .field("nickname_policy", &self.nickname_policy)
+            .field("send_options", &self.send_options)
+            .field("deadline", &self.deadline)
+            .field("credits", &self.credits)
+            .finish_non_exhaustive()
+    }
 }
 
 impl E2eApi {
     /// Initialize the simple API with the Gateway ID, the Gateway Secret and
     /// the Private Key.
     pub(crate) fn new<I: Into<String>, S: Into<String>>(
-        endpoint: Cow<'static, str>,
+        endpoints: Endpoints,
         id: I,
         secret: S,
-        private_key: SecretKey,
+        key_provider: Arc<dyn KeyProvider>,
         client: Client,
+        config: E2eConfig,
     ) -> Self {
         E2eApi {
             id: id.into(),
             secret: secret.into(),
-            private_key,
-            endpoint,
+            key_provider,
+            blob_endpoints: match config.blob_endpoint {
+                Some(blob_endpoint) => {
+                    Endpoints::new(blob_endpoint, Vec::new(), DEFAULT_ENDPOINT_COOLDOWN)
+                }
+                None => endpoints.clone(),
+            },
+            endpoints,
             client,
+            retry_policy: config.retry_policy,
+            padding_policy: config.padding_policy,
+            public_key_cache: config.public_key_cache,
+            message_store: config.message_store,
+            blob_cache: config.blob_cache,
+            contact_store: config.contact_store,
+            send_ledger: config.send_ledger,
+            dry_run: config.dry_run,
+            credits_check: config.credits_check,
+            strict_recipient_check: config.strict_recipient_check,
+            nickname_policy: config.nickname_policy,
+            send_options: config.send_options,
+            deadline: None,
+            credits: CreditCounter::default(),
+            hooks: Arc::default(),
         }
     }
 
+    /// Return the public key of the gateway identity, derived from the
+    /// private key.
+    pub fn public_key(&self) -> PublicKey {
+        self.key_provider.public_key()
+    }
+
+    /// Return the public key of the gateway identity as lowercase hex string.
+    pub fn public_key_hex(&self) -> String {
+        HEXLOWER.encode(self.public_key().as_bytes())
+    }
+
+    /// Return the payload of the QR code that can be scanned with the Threema
+    /// apps to verify the public key of the gateway identity.
+    pub fn qr_payload(&self) -> String {
+        RecipientKey::from(self.public_key()).qr_payload(&self.id)
+    }
+
     /// Encrypt a text message for the specified recipient public key.
     pub fn encrypt_text_msg(
         &self,
         text: &str,
         recipient_key: &RecipientKey,
     ) -> Result<EncryptedMessage, CryptoError> {
-        let data = text.as_bytes();
-        let msgtype = MessageType::Text;
-        encrypt(data, msgtype, &recipient_key.0, &self.private_key)
+        self.encrypt_transformed_text(&self.hooks.transform_text(text), recipient_key)
+    }
+
+    /// Encrypt a text message to which the text transformations have
+    /// already been applied.
+    pub(crate) fn encrypt_transformed_text(
+        &self,
+        text: &str,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        encrypt_with_padding(
+            text.as_bytes(),
+            MessageType::Text,
+            self.padding_policy,
+            &recipient_key.0,
+            &*self.key_provider,
+        )
     }
 
     /// Encrypt an image message for the specified recipient public key.
@@ -233,8 +759,9 @@ impl E2eApi {
             blob_id,
             img_size_bytes,
             image_data_nonce,
+            self.padding_policy,
             &recipient_key.0,
-            &self.private_key,
+            &*self.key_provider,
         )
     }
 
@@ -249,12 +776,14 @@ impl E2eApi {
         msg: &FileMessage,
         recipient_key: &RecipientKey,
     ) -> Result<EncryptedMessage, CryptoError> {
-        encrypt_file_msg(msg, &recipient_key.0, &self.private_key)
+        let data = serde_json::to_vec(msg).expect("Could not serialize file message");
+        self.encrypt(&data, MessageType::File, recipient_key)
     }
 
     /// Encrypt an arbitrary message for the specified recipient public key.
     ///
-    /// The encrypted data will include PKCS#7 style random padding.
+    /// The encrypted data will include PKCS#7 style padding according to the
+    /// configured [`PaddingPolicy`].
     ///
     /// Note: In almost all cases you should use [`encrypt_text_msg`],
     /// [`encrypt_file_msg`] or [`encrypt_image_msg`] instead.
@@ -268,7 +797,159 @@ impl E2eApi {
         msgtype: MessageType,
         recipient_key: &RecipientKey,
     ) -> Result<EncryptedMessage, CryptoError> {
-        encrypt(raw_data, msgtype, &recipient_key.0, &self.private_key)
+        encrypt_with_padding(
+            &self.hooks.transform_message(raw_data, msgtype),
+            msgtype,
+            self.padding_policy,
+            &recipient_key.0,
+            &*self.key_provider,
+        )
+    }
+
+    /// Encrypt the same message for many recipients (e.g. a broadcast or a
+    /// group fan-out) on the blocking thread pool of the tokio runtime.
+    ///
+    /// The recipients are split into at most `max_parallelism` batches that
+    /// are encrypted concurrently. The messages are returned in the order of
+    /// `recipient_keys`. If the encryption fails for any recipient, the error
+    /// is returned.
+    ///
+    /// See [`encrypt`](Self::encrypt) for the single recipient variant.
+    #[cfg(feature = "parallel")]
+    pub async fn encrypt_bulk(
+        &self,
+        raw_data: &[u8],
+        msgtype: MessageType,
+        recipient_keys: &[RecipientKey],
+        max_parallelism: NonZeroUsize,
+    ) -> Result<Vec<EncryptedMessage>, CryptoError> {
+        let data: Arc<[u8]> = self.hooks.transform_message(raw_data, msgtype).into();
+        let items = recipient_keys
+            .iter()
+            .map(|key| (data.clone(), key.clone()))
+            .collect();
+        self.encrypt_parallel(items, msgtype, max_parallelism).await
+    }
+
+    /// Render a text template with the variables of every recipient and
+    /// encrypt the personalized messages on the blocking thread pool of the
+    /// tokio runtime.
+    ///
+    /// The [text transformations](Self::transform_text) are applied to the
+    /// rendered texts before their length is validated. All messages are
+    /// rendered and validated before any of them is encrypted, so an invalid
+    /// template or missing variable fails the whole batch. The messages are
+    /// returned in the order of `recipients` and can then be sent with
+    /// [`send`](Self::send).
+    ///
+    /// See [`encrypt_bulk`](Self::encrypt_bulk) for the parallelism.
+    #[cfg(feature = "parallel")]
+    pub async fn encrypt_template_bulk(
+        &self,
+        template: &Template,
+        recipients: &[(RecipientKey, HashMap<String, String>)],
+        max_parallelism: NonZeroUsize,
+    ) -> Result<Vec<EncryptedMessage>, ApiError> {
+        let items = recipients
+            .iter()
+            .map(|(key, variables)| {
+                let text = template.render_unchecked(variables)?;
+                let text = self.hooks.transform_text(&text).into_owned();
+                check_length(&text)?;
+                Ok((Arc::from(text.into_bytes()), key.clone()))
+            })
+            .collect::<Result<_, TemplateError>>()?;
+        Ok(self
+            .encrypt_parallel(items, MessageType::Text, max_parallelism)
+            .await?)
+    }
+
+    /// Encrypt each message for its recipient in at most `max_parallelism`
+    /// batches on the blocking thread pool.
+    #[cfg(feature = "parallel")]
+    async fn encrypt_parallel(
+        &self,
+        items: Vec<(Arc<[u8]>, RecipientKey)>,
+        msgtype: MessageType,
+        max_parallelism: NonZeroUsize,
+    ) -> Result<Vec<EncryptedMessage>, CryptoError> {
+        let batch_size = items.len().div_ceil(max_parallelism.get()).max(1);
+        let tasks: Vec<_> = items
+            .chunks(batch_size)
+            .map(|batch| {
+                let batch = batch.to_vec();
+                let key_provider = self.key_provider.clone();
+                let padding_policy = self.padding_policy;
+                tokio::task::spawn_blocking(move || {
+                    batch
+                        .iter()
+                        .map(|(data, key)| {
+                            encrypt_with_padding(
+                                data,
+                                msgtype,
+                                padding_policy,
+                                &key.0,
+                                &*key_provider,
+                            )
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                })
+            })
+            .collect();
+
+        let mut messages = Vec::with_capacity(items.len());
+        for task in tasks {
+            match task.await {
+                Ok(batch) => messages.extend(batch?),
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Encrypt a delivery receipt for one or more messages of the recipient.
+    ///
+    /// A single receipt can acknowledge any number of messages with the same
+    /// status. Fails if `message_ids` is empty or if the status is
+    /// [`MessageStatus::Sent`], which cannot be reported in a receipt.
+    pub fn encrypt_delivery_receipt(
+        &self,
+        status: MessageStatus,
+        message_ids: &[MessageId],
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, ApiError> {
+        if message_ids.is_empty() {
+            return Err(ApiError::Other(
+                "Cannot send a receipt without message IDs".to_string(),
+            ));
+        }
+        let receipt = DeliveryReceipt {
+            status,
+            message_ids: message_ids.to_vec(),
+        };
+        let data = receipt
+            .to_bytes()
+            .ok_or_else(|| ApiError::Other("Cannot send a receipt with status Sent".to_string()))?;
+        Ok(self.encrypt(&data, MessageType::DeliveryReceipt, recipient_key)?)
+    }
+
+    /// Send a delivery receipt for one or more messages to the specified
+    /// Threema ID.
+    ///
+    /// Acknowledging a burst of messages with one receipt costs a single
+    /// credit. See [`encrypt_delivery_receipt`](Self::encrypt_delivery_receipt)
+    /// for the restrictions.
+    ///
+    /// Cost: 1 credit.
+    pub async fn send_delivery_receipt(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        status: MessageStatus,
+        message_ids: &[MessageId],
+    ) -> Result<SendResult, ApiError> {
+        let encrypted = self.encrypt_delivery_receipt(status, message_ids, recipient_key)?;
+        self.send(to, &encrypted, false).await
     }
 
     /// Encrypt raw bytes for the specified recipient public key.
@@ -277,7 +958,7 @@ impl E2eApi {
         raw_data: &[u8],
         recipient_key: &RecipientKey,
     ) -> Result<EncryptedMessage, CryptoError> {
-        encrypt_raw(raw_data, &recipient_key.0, &self.private_key)
+        encrypt_raw(raw_data, &recipient_key.0, &*self.key_provider)
     }
 
     /// Send an encrypted E2E message to the specified Threema ID.
@@ -287,25 +968,121 @@ impl E2eApi {
     /// one-way communication where the delivery receipt will be discarded. If
     /// you're unsure what value to use, set the flag to `false`.
     ///
+    /// To set further options, use [`send_with_options`](Self::send_with_options).
+    ///
     /// Cost: 1 credit.
     pub async fn send(
         &self,
         to: &str,
         message: &EncryptedMessage,
         delivery_receipts: bool,
-    ) -> Result<String, ApiError> {
-        send_e2e(
-            &self.client,
-            self.endpoint.borrow(),
-            &self.id,
-            to,
-            &self.secret,
-            &message.nonce,
-            &message.ciphertext,
+    ) -> Result<SendResult, ApiError> {
+        let options = SendOptions {
             delivery_receipts,
-            None,
-        )
-        .await
+            ..Default::default()
+        };
+        self.send_with_options(to, message, options).await
+    }
+
+    /// Send an encrypted E2E message to the specified Threema ID, with the
+    /// given [`SendOptions`].
+    ///
+    /// Cost: 1 credit.
+    pub async fn send_with_options(
+        &self,
+        to: &str,
+        message: &EncryptedMessage,
+        options: SendOptions,
+    ) -> Result<SendResult, ApiError> {
+        let result = self
+            .endpoints
+            .run(self.deadline, |endpoint| {
+                send_e2e(
+                    &self.client,
+                    endpoint,
+                    self.deadline,
+                    &self.hooks,
+                    &self.id,
+                    to,
+                    &self.secret,
+                    &message.nonce,
+                    &message.ciphertext,
+                    &options,
+                    None,
+                    self.dry_run,
+                )
+            })
+            .await;
+        self.hooks.send_finished(&Recipient::new_id(to), &result);
+        let result = result?;
+        self.charge(Cost::Message);
+        Ok(result)
+    }
+
+    /// Send an encrypted E2E message to the specified Threema ID, unless a
+    /// message with the deduplication key `key` was sent before.
+    ///
+    /// The key is recorded in the [`SendLedger`] configured with
+    /// [`ApiBuilder::with_send_ledger`]. If the message with this key was
+    /// already sent, its original [`SendResult`] is returned without sending
+    /// again. If a send with this key is in progress, or was interrupted
+    /// before its outcome was known, the call fails with
+    /// [`ApiError::SendPending`]. Release the key in the ledger to send
+    /// anyway.
+    ///
+    /// If the gateway rejects the message, the key is released, so that the
+    /// send can be retried. After network errors and timeouts, the message
+    /// may have been delivered, so the key stays pending.
+    ///
+    /// In [dry-run mode](ApiBuilder::with_dry_run), the ledger is neither
+    /// consulted nor updated, so that the fake message IDs don't prevent
+    /// real sends later on.
+    ///
+    /// Cost: 1 credit, if the message is sent.
+    pub async fn send_once(
+        &self,
+        key: &str,
+        to: &str,
+        message: &EncryptedMessage,
+        options: SendOptions,
+    ) -> Result<SendResult, ApiError> {
+        let ledger = self
+            .send_ledger
+            .as_ref()
+            .ok_or_else(|| ApiError::Other("No send ledger configured".to_string()))?;
+        if self.dry_run {
+            return self.send_with_options(to, message, options).await;
+        }
+        let entry = ledger.reserve(key).await.map_err(|e| {
+            ApiError::Other(format!(
+                "Could not reserve deduplication key {}: {}",
+                key, e
+            ))
+        })?;
+        match entry {
+            Some(LedgerEntry::Sent(message_id)) => {
+                debug!("Message with deduplication key {} was already sent", key);
+                return Ok(SendResult { message_id });
+            }
+            Some(LedgerEntry::Pending) => return Err(ApiError::SendPending(key.to_string())),
+            None => {}
+        }
+
+        let result = self.send_with_options(to, message, options).await;
+        match result {
+            Ok(ref sent) => {
+                if let Err(e) = ledger.complete(key, &sent.message_id).await {
+                    warn!("Could not record deduplication key {}: {}", key, e);
+                }
+            }
+            Err(ref e) if !e.is_ambiguous() => {
+                if let Err(e) = ledger.release(key).await {
+                    warn!("Could not release deduplication key {}: {}", key, e);
+                }
+            }
+            Err(_) => {}
+        }
+        result
     }
 
     /// Used for testing purposes. Not intended to be called by end users.
@@ -316,19 +1093,287 @@ impl E2eApi {
         message: &EncryptedMessage,
         delivery_receipts: bool,
         additional_params: HashMap<String, String>,
-    ) -> Result<String, ApiError> {
-        send_e2e(
-            &self.client,
-            self.endpoint.borrow(),
-            &self.id,
-            to,
-            &self.secret,
-            &message.nonce,
-            &message.ciphertext,
+    ) -> Result<SendResult, ApiError> {
+        let options = SendOptions {
             delivery_receipts,
-            Some(additional_params),
-        )
-        .await
+            ..Default::default()
+        };
+        let result = self
+            .endpoints
+            .run(self.deadline, |endpoint| {
+                send_e2e(
+                    &self.client,
+                    endpoint,
+                    self.deadline,
+                    &self.hooks,
+                    &self.id,
+                    to,
+                    &self.secret,
+                    &message.nonce,
+                    &message.ciphertext,
+                    &options,
+                    Some(additional_params.clone()),
+                    self.dry_run,
+                )
+            })
+            .await;
+        self.hooks.send_finished(&Recipient::new_id(to), &result);
+        let result = result?;
+        self.charge(Cost::Message);
+        Ok(result)
+    }
+
+    /// Encrypt and send a text message to the specified Threema ID.
+    ///
+    /// The public key of the recipient is loaded from the configured
+    /// [`PublicKeyCache`] (see [`ApiBuilder::with_public_key_cache`]). If it
+    /// is not cached, it is looked up from the server (and stored in the
+    /// cache). Cache errors are logged, but do not cause the message sending
+    /// to fail.
+    ///
+    /// If a [`ContactStore`] is configured (see
+    /// [`ApiBuilder::with_contact_store`]), `to` may also be the nickname of
+    /// a stored contact, and the public key of a stored contact is used
+    /// without consulting the cache.
+    ///
+    /// If a [`MessageStore`] is configured (see
+    /// [`ApiBuilder::with_message_store`]), the sent message is recorded in
+    /// the history. Like cache errors, store errors are only logged.
+    ///
+    /// Cost: 1 credit (2 credits if the public key needs to be looked up).
+    pub async fn send_text(&self, to: &str, text: &str) -> Result<SendResult, ApiError> {
+        let (to, recipient_key) = match self.find_contact(to).await {
+            Some(contact) => (contact.id, contact.public_key),
+            None => (to.to_string(), self.resolve_public_key(to).await?),
+        };
+        let text = self.hooks.transform_text(text);
+        let message = self.encrypt_transformed_text(&text, &recipient_key)?;
+        let result = self
+            .send_with_options(&to, &message, self.send_options.clone())
+            .await?;
+        self.record_message(&StoredMessage::text_message(
+            &to,
+            Direction::Outgoing,
+            Some(result.message_id),
+            &text,
+        ))
+        .await;
+        Ok(result)
+    }
+
+    /// Look up a Threema ID and store it as contact in the configured
+    /// [`ContactStore`] (see [`ApiBuilder::with_contact_store`]).
+    ///
+    /// The public key and the capabilities are fetched from the directory,
+    /// the contact is stored as [`VerificationLevel::Unverified`]. An
+    /// existing contact with the same ID is replaced.
+    ///
+    /// Cost: 2 credits.
+    pub async fn add_contact(&self, id: &str, nickname: Option<&str>) -> Result<Contact, ApiError> {
+        self.store_contact(id, nickname, VerificationLevel::Unverified)
+            .await
+    }
+
+    /// Look up the Threema ID linked to a phone number or e-mail address and
+    /// store it as contact (see [`add_contact`](Self::add_contact)).
+    ///
+    /// The contact is stored as [`VerificationLevel::ServerVerified`].
+    ///
+    /// Cost: 3 credits.
+    pub async fn add_contact_by(
+        &self,
+        criterion: &LookupCriterion,
+        nickname: Option<&str>,
+    ) -> Result<Contact, ApiError> {
+        let id = self.lookup_id(criterion).await?;
+        self.store_contact(&id, nickname, VerificationLevel::ServerVerified)
+            .await
+    }
+
+    /// Import contacts from a CSV file with phone numbers and e-mail
+    /// addresses.
+    ///
+    /// Every field containing a phone number in international format (e.g.
+    /// `+41 79 123 45 67`) or an e-mail address is normalized and looked up
+    /// with [`lookup_ids_bulk`](Self::lookup_ids_bulk). Other fields are
+    /// reported as invalid, unless they are in the first line, which is then
+    /// treated as header.
+    ///
+    /// The Threema IDs found are stored as
+    /// [`VerificationLevel::ServerVerified`] contacts if a [`ContactStore`] is
+    /// configured. Contacts that are already stored are kept unchanged.
+    ///
+    /// Cost: 1 credit per found ID.
+    pub async fn import_contacts_csv(&self, csv: &str) -> Result<ContactImport, ApiError> {
+        let (entries, invalid) = parse_csv(csv);
+
+        // Look up every hash only once
+        let hashed: Vec<LookupCriterion> = entries
+            .iter()
+            .map(|entry| entry.criterion.hashed())
+            .collect();
+        let mut seen = HashSet::new();
+        let unique: Vec<LookupCriterion> = hashed
+            .iter()
+            .filter(|criterion| seen.insert(criterion.to_string()))
+            .cloned()
+            .collect();
+        let matches: HashMap<String, BulkLookupMatch> = self
+            .lookup_ids_bulk(&unique)
+            .await?
+            .into_iter()
+            .map(|found| (found.criterion.to_string(), found))
+            .collect();
+
+        let mut import = ContactImport {
+            invalid,
+            ..Default::default()
+        };
+        for (entry, criterion) in entries.into_iter().zip(hashed) {
+            let found = match matches.get(&criterion.to_string()) {
+                Some(found) => found,
+                None => {
+                    import.unmatched.push(entry);
+                    continue;
+                }
+            };
+            let mut contact = Contact {
+                verification_level: VerificationLevel::ServerVerified,
+                ..Contact::new(&found.id, found.public_key.clone())
+            };
+            if let Some(ref store) = self.contact_store {
+                let error =
+                    |e| ApiError::Other(format!("Could not import contact {}: {}", found.id, e));
+                match store.load(&found.id).await.map_err(error)? {
+                    Some(existing) => contact = existing,
+                    None => store.store(&contact).await.map_err(error)?,
+                }
+            }
+            import.matched.push((entry, contact));
+        }
+        Ok(import)
+    }
+
+    async fn store_contact(
+        &self,
+        id: &str,
+        nickname: Option<&str>,
+        verification_level: VerificationLevel,
+    ) -> Result<Contact, ApiError> {
+        let store = self
+            .contact_store
+            .as_ref()
+            .ok_or_else(|| ApiError::Other("No contact store configured".to_string()))?;
+        let id = id.to_uppercase();
+        let contact = Contact {
+            public_key: self.lookup_pubkey(&id).await?,
+            capabilities: Some(self.lookup_capabilities(&id).await?),
+            id,
+            nickname: nickname.map(Into::into),
+            verification_level,
+        };
+        store.store(&contact).await.map_err(|e| {
+            ApiError::Other(format!("Could not store contact {}: {}", contact.id, e))
+        })?;
+        Ok(contact)
+    }
+
+    /// Return the stored contact with the Threema ID or nickname `name`.
+    ///
+    /// Returns `None` if no [`ContactStore`] is configured.
+    pub async fn contact(&self, name: &str) -> Result<Option<Contact>, ApiError> {
+        match self.contact_store {
+            Some(ref store) => store
+                .find(name)
+                .await
+                .map_err(|e| ApiError::Other(format!("Could not load contact {}: {}", name, e))),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the stored contact with the Threema ID or nickname `name`,
+    /// logging store errors.
+    async fn find_contact(&self, name: &str) -> Option<Contact> {
+        self.contact(name).await.unwrap_or_else(|e| {
+            warn!("{}", e);
+            None
+        })
+    }
+
+    /// Return the public key for `id`, from the contacts or the cache if
+    /// possible.
+    pub(crate) async fn resolve_public_key(&self, id: &str) -> Result<RecipientKey, ApiError> {
+        if let Some(ref store) = self.contact_store {
+            match store.load(id).await {
+                Ok(Some(contact)) => return Ok(contact.public_key),
+                Ok(None) => {}
+                Err(e) => warn!("Could not load contact {}: {}", id, e),
+            }
+        }
+        let cache = match self.public_key_cache {
+            Some(ref cache) => cache,
+            None => return self.lookup_pubkey(id).await,
+        };
+        match cache.load(id).await {
+            Ok(Some(key)) => return Ok(key),
+            Ok(None) => {}
+            Err(e) => warn!("Could not load public key for {} from cache: {}", id, e),
+        }
+        let key = self.lookup_pubkey(id).await?;
+        if let Err(e) = cache.store(id, &key).await {
+            warn!("Could not store public key for {} in cache: {}", id, e);
+        }
+        Ok(key)
+    }
+
+    /// Register a callback that is called after a blob was uploaded
+    /// successfully.
+    ///
+    /// See [`on_send`](Self::on_send).
+    pub fn on_blob_upload<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&BlobId) + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.hooks)
+            .on_blob_upload
+            .push(Arc::new(callback));
+        self
+    }
+
+    /// Record a blob upload in the audit sink.
+    fn blob_upload_finished(&self, result: &Result<BlobId, ApiError>) {
+        let blob_id = result.as_ref().ok().map(BlobId::to_string);
+        self.hooks.operation_finished(
+            AuditOperation::BlobUpload,
+            blob_id.as_deref(),
+            None,
+            result.as_ref().err(),
+        );
+    }
+
+    /// Count the credits of a successful call, unless in dry run mode.
+    fn charge(&self, cost: Cost) {
+        if !self.dry_run {
+            self.credits.charge(cost);
+        }
+    }
+
+    /// The hooks registered on this API object.
+    #[cfg(feature = "bot")]
+    pub(crate) fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    /// Record a message in the [`MessageStore`], if one is configured.
+    pub(crate) async fn record_message(&self, message: &StoredMessage) {
+        if let Some(ref store) = self.message_store {
+            if let Err(e) = store.append(message).await {
+                warn!(
+                    "Could not store message for {} in history: {}",
+                    message.contact, e
+                );
+            }
+        }
     }
 
     impl_common_functionality!();
@@ -345,16 +1390,8 @@ impl E2eApi {
         data: &EncryptedMessage,
         persist: bool,
     ) -> Result<BlobId, ApiError> {
-        blob_upload(
-            &self.client,
-            self.endpoint.borrow(),
-            &self.id,
-            &self.secret,
-            &data.ciphertext,
-            persist,
-            None,
-        )
-        .await
+        self.check_credits(1).await?;
+        self.upload_blob(&data.ciphertext, persist, None).await
     }
 
     /// Used for testing purposes. Not intended to be called by end users.
@@ -365,16 +1402,8 @@ impl E2eApi {
         persist: bool,
         additional_params: HashMap<String, String>,
     ) -> Result<BlobId, ApiError> {
-        blob_upload(
-            &self.client,
-            self.endpoint.borrow(),
-            &self.id,
-            &self.secret,
-            &data.ciphertext,
-            persist,
-            Some(additional_params),
-        )
-        .await
+        self.upload_blob(&data.ciphertext, persist, Some(additional_params))
+            .await
     }
 
     /// Upload raw data to the blob server.
@@ -385,16 +1414,50 @@ impl E2eApi {
     ///
     /// Cost: 1 credit.
     pub async fn blob_upload_raw(&self, data: &[u8], persist: bool) -> Result<BlobId, ApiError> {
-        blob_upload(
-            &self.client,
-            self.endpoint.borrow(),
-            &self.id,
-            &self.secret,
-            data,
-            persist,
-            None,
-        )
-        .await
+        self.blob_upload_bytes(Bytes::copy_from_slice(data), persist)
+            .await
+    }
+
+    /// Upload raw data to the blob server, taking ownership of the data.
+    ///
+    /// Unlike [`blob_upload_raw`](Self::blob_upload_raw), this doesn't copy
+    /// the data. A `Vec<u8>` can be converted into [`Bytes`] without copying.
+    ///
+    /// Cost: 1 credit.
+    pub async fn blob_upload_bytes(&self, data: Bytes, persist: bool) -> Result<BlobId, ApiError> {
+        self.check_credits(1).await?;
+        self.upload_blob(&data, persist, None).await
+    }
+
+    /// Upload many blobs (e.g. the encrypted files of an album), running up
+    /// to `concurrency` uploads at the same time.
+    ///
+    /// The results are returned in the order of `items`. A failed upload
+    /// does not abort the others. See
+    /// [`blob_upload_bytes`](Self::blob_upload_bytes) for the meaning of
+    /// `persist`.
+    ///
+    /// The credits pre-check is done once for all blobs. If it fails, no
+    /// blob is uploaded and its error is returned.
+    ///
+    /// Cost: 1 credit per uploaded blob.
+    pub async fn blob_upload_many<I>(
+        &self,
+        items: I,
+        persist: bool,
+        concurrency: NonZeroUsize,
+    ) -> Result<Vec<Result<BlobId, ApiError>>, ApiError>
+    where
+        I: IntoIterator<Item = Bytes>,
+    {
+        let items: Vec<Bytes> = items.into_iter().collect();
+        let cost = i64::try_from(items.len()).unwrap_or(i64::MAX);
+        self.check_credits(cost).await?;
+        Ok(stream::iter(items)
+            .map(|data| async move { self.upload_blob(&data, persist, None).await })
+            .buffered(concurrency.get())
+            .collect()
+            .await)
     }
 
     /// Used for testing purposes. Not intended to be called by end users.
@@ -405,30 +1468,209 @@ impl E2eApi {
         persist: bool,
         additional_params: HashMap<String, String>,
     ) -> Result<BlobId, ApiError> {
-        blob_upload(
-            &self.client,
-            self.endpoint.borrow(),
-            &self.id,
-            &self.secret,
-            data,
-            persist,
-            Some(additional_params),
-        )
-        .await
+        let data = Bytes::copy_from_slice(data);
+        self.upload_blob(&data, persist, Some(additional_params))
+            .await
     }
 
-    /// Download a blob from the blob server and return the encrypted bytes.
-    ///
-    /// Cost: 0 credits.
-    pub async fn blob_download(&self, blob_id: &BlobId) -> Result<Vec<u8>, ApiError> {
-        blob_download(
-            &self.client,
-            self.endpoint.borrow(),
-            &self.id,
-            &self.secret,
-            blob_id,
-        )
-        .await
+    /// Upload data to the blob server, without the credits pre-check.
+    pub(crate) async fn upload_blob(
+        &self,
+        data: &Bytes,
+        persist: bool,
+        additional_params: Option<HashMap<String, String>>,
+    ) -> Result<BlobId, ApiError> {
+        let result = self
+            .blob_endpoints
+            .run(self.deadline, |endpoint| {
+                blob_upload(
+                    &self.client,
+                    endpoint,
+                    self.deadline,
+                    &self.hooks,
+                    &self.id,
+                    &self.secret,
+                    data,
+                    persist,
+                    additional_params.clone(),
+                    &self.retry_policy,
+                    self.dry_run,
+                )
+            })
+            .await;
+        self.blob_upload_finished(&result);
+        let blob_id = result?;
+        self.charge(Cost::BlobUpload);
+        self.hooks.blob_uploaded(&blob_id);
+        Ok(blob_id)
+    }
+
+    /// Download a blob from the blob server and return the encrypted bytes.
+    ///
+    /// If a [`BlobCache`] is configured (see
+    /// [`ApiBuilder::with_blob_cache`]), the blob is taken from the cache if
+    /// possible, and downloaded blobs are stored in it.
+    ///
+    /// Cost: 0 credits.
+    pub async fn blob_download(&self, blob_id: &BlobId) -> Result<Vec<u8>, ApiError> {
+        self.download_blob(blob_id, None).await
+    }
+
+    /// Download a blob from the blob server and return the encrypted bytes,
+    /// failing with [`ApiError::BlobTooLarge`] if the blob is larger than
+    /// `max_size` bytes.
+    ///
+    /// The download is aborted as soon as the limit is exceeded.
+    ///
+    /// Cost: 0 credits.
+    pub async fn blob_download_limited(
+        &self,
+        blob_id: &BlobId,
+        max_size: u64,
+    ) -> Result<Vec<u8>, ApiError> {
+        self.download_blob(blob_id, Some(max_size)).await
+    }
+
+    /// Download a blob, from the cache if possible.
+    async fn download_blob(
+        &self,
+        blob_id: &BlobId,
+        max_size: Option<u64>,
+    ) -> Result<Vec<u8>, ApiError> {
+        let cache = match self.blob_cache {
+            Some(ref cache) => cache,
+            None => return self.fetch_blob(blob_id, max_size).await,
+        };
+        match cache.load(blob_id).await {
+            Ok(Some(data)) => {
+                let size = data.len() as u64;
+                return match max_size {
+                    Some(limit) if size > limit => Err(ApiError::BlobTooLarge { size, limit }),
+                    _ => Ok(data),
+                };
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Could not load blob {} from cache: {}", blob_id, e),
+        }
+        let data = self.fetch_blob(blob_id, max_size).await?;
+        if let Err(e) = cache.store(blob_id, &data).await {
+            warn!("Could not store blob {} in cache: {}", blob_id, e);
+        }
+        Ok(data)
+    }
+
+    /// Download a blob from the blob server.
+    async fn fetch_blob(
+        &self,
+        blob_id: &BlobId,
+        max_size: Option<u64>,
+    ) -> Result<Vec<u8>, ApiError> {
+        let result = self
+            .blob_endpoints
+            .run(self.deadline, |endpoint| {
+                blob_download(
+                    &self.client,
+                    endpoint,
+                    self.deadline,
+                    &self.hooks,
+                    &self.id,
+                    &self.secret,
+                    blob_id,
+                    max_size,
+                    &self.retry_policy,
+                )
+            })
+            .await;
+        self.hooks.operation_finished(
+            AuditOperation::BlobDownload,
+            Some(&blob_id.to_string()),
+            None,
+            result.as_ref().err(),
+        );
+        result
+    }
+
+    /// Encrypt, upload and send a file to the specified Threema ID.
+    ///
+    /// This performs the full pipeline in one call: The file data (and the
+    /// optional thumbnail) are encrypted with a random symmetric key and
+    /// uploaded to the blob server, then a [`FileMessage`] referencing the
+    /// blobs is built, encrypted for the recipient and sent.
+    ///
+    /// The file can be passed in either as [`FileData`](crate::FileData) or as
+    /// a path (see [`FileSource`]).
+    ///
+    /// If the `media-duration` feature is enabled and the rendering type is
+    /// [`RenderingType::Media`], the duration of audio and video files is
+    /// extracted and included in the file message metadata. Similarly, if the
+    /// `image` feature is enabled, animated GIF, APNG and WebP images sent
+    /// as media or sticker are marked as animated.
+    ///
+    /// Cost: 2 credits (3 credits if a thumbnail is included).
+    pub async fn send_file(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        file: impl Into<FileSource>,
+        mut options: FileSendOptions,
+    ) -> Result<SendResult, ApiError> {
+        let data = file.into().resolve(&mut options)?;
+        let cost = if data.thumbnail.is_some() { 3 } else { 2 };
+        self.check_credits(cost).await?;
+        // The blobs are uploaded with `BlobUploader::upload_blob`, which
+        // doesn't repeat the credits pre-check
+        gateway::send_file(self, to, recipient_key, data.into(), options).await
+    }
+
+    /// Encrypt, upload and send a JPEG image to the specified Threema ID.
+    ///
+    /// If the recipient supports file messages (according to
+    /// [`lookup_capabilities`](Self::lookup_capabilities)), the image is sent
+    /// as a file message with rendering type [`RenderingType::Media`].
+    /// Otherwise, a legacy image message is sent.
+    ///
+    /// Cost: 3 credits (including the capabilities lookup).
+    pub async fn send_image(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        jpeg_data: Vec<u8>,
+    ) -> Result<SendResult, ApiError> {
+        self.check_credits(3).await?;
+        gateway::send_image(self, to, recipient_key, jpeg_data, None).await
+    }
+
+    /// Like [`send_image`](Self::send_image), but with the already known
+    /// `capabilities` of the recipient instead of looking them up.
+    ///
+    /// Cost: 2 credits.
+    pub async fn send_image_with_capabilities(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        jpeg_data: Vec<u8>,
+        capabilities: &Capabilities,
+    ) -> Result<SendResult, ApiError> {
+        self.check_credits(2).await?;
+        gateway::send_image(self, to, recipient_key, jpeg_data, Some(capabilities)).await
+    }
+
+    /// Ensure that at least `cost` credits remain, if the credits pre-check
+    /// is enabled (see [`ApiBuilder::with_credits_check`]).
+    ///
+    /// Otherwise, or in dry-run mode, this does nothing.
+    pub async fn check_credits(&self, cost: i64) -> Result<(), ApiError> {
+        if !self.credits_check || self.dry_run {
+            return Ok(());
+        }
+        let available = self.lookup_credits().await?;
+        if available < cost {
+            return Err(ApiError::InsufficientCredits {
+                required: cost,
+                available,
+            });
+        }
+        Ok(())
     }
 
     /// Deserialize an incoming Threema Gateway message in
@@ -436,11 +1678,86 @@ impl E2eApi {
     ///
     /// This will validate the MAC. If the MAC is invalid,
     /// [`ApiError::InvalidMac`] will be returned.
+    ///
+    /// If the strict recipient check is enabled (see
+    /// [`ApiBuilder::with_strict_recipient_check`]), messages that are not
+    /// addressed to our own gateway ID are rejected with
+    /// [`ApiError::UnexpectedRecipient`].
+    ///
+    /// The nickname is sanitized according to the [`NicknamePolicy`] (see
+    /// [`ApiBuilder::with_nickname_policy`]).
     pub fn decode_incoming_message(
         &self,
         bytes: impl AsRef<[u8]>,
     ) -> Result<IncomingMessage, ApiError> {
-        IncomingMessage::from_urlencoded_bytes(bytes, &self.secret)
+        self.decode_incoming_message_with_limit(bytes, IncomingMessage::DEFAULT_MAX_SIZE)
+    }
+
+    /// Like [`decode_incoming_message`](Self::decode_incoming_message), but
+    /// with a custom maximum body size in bytes.
+    pub fn decode_incoming_message_with_limit(
+        &self,
+        bytes: impl AsRef<[u8]>,
+        max_size: usize,
+    ) -> Result<IncomingMessage, ApiError> {
+        let result =
+            IncomingMessage::from_urlencoded_bytes_with_limit(bytes, &self.secret, max_size)
+                .and_then(|message| self.check_incoming_message(message));
+        self.incoming_message_decoded(&result);
+        result
+    }
+
+    /// Deserialize an incoming Threema Gateway message that was re-encoded
+    /// as JSON object (see [`IncomingMessage::from_json_bytes`]).
+    ///
+    /// The MAC, the recipient and the nickname are handled like in
+    /// [`decode_incoming_message`](Self::decode_incoming_message).
+    pub fn decode_incoming_json_message(
+        &self,
+        bytes: impl AsRef<[u8]>,
+    ) -> Result<IncomingMessage, ApiError> {
+        self.decode_incoming_json_message_with_limit(bytes, IncomingMessage::DEFAULT_MAX_SIZE)
+    }
+
+    /// Like [`decode_incoming_json_message`](Self::decode_incoming_json_message),
+    /// but with a custom maximum body size in bytes.
+    pub fn decode_incoming_json_message_with_limit(
+        &self,
+        bytes: impl AsRef<[u8]>,
+        max_size: usize,
+    ) -> Result<IncomingMessage, ApiError> {
+        let result = IncomingMessage::from_json_bytes_with_limit(bytes, &self.secret, max_size)
+            .and_then(|message| self.check_incoming_message(message));
+        self.incoming_message_decoded(&result);
+        result
+    }
+
+    /// Apply the strict recipient check and the nickname policy to a
+    /// decoded incoming message.
+    fn check_incoming_message(
+        &self,
+        mut message: IncomingMessage,
+    ) -> Result<IncomingMessage, ApiError> {
+        if self.strict_recipient_check && message.to != self.id {
+            return Err(ApiError::UnexpectedRecipient(message.to));
+        }
+        message.sanitize_nickname(&self.nickname_policy);
+        Ok(message)
+    }
+
+    /// Record a decoded incoming message in the audit sink.
+    fn incoming_message_decoded(&self, result: &Result<IncomingMessage, ApiError>) {
+        match result {
+            Ok(message) => self.hooks.operation_finished(
+                AuditOperation::Receive,
+                Some(&message.from),
+                Some(message.message_id.clone()),
+                None,
+            ),
+            Err(e) => self
+                .hooks
+                .operation_finished(AuditOperation::Receive, None, None, Some(e)),
+        }
     }
 
     /// Decrypt an [`IncomingMessage`] using the provided public key and our
@@ -453,7 +1770,33 @@ impl E2eApi {
         message: &IncomingMessage,
         recipient_key: &RecipientKey,
     ) -> Result<Vec<u8>, CryptoError> {
-        message.decrypt_box(&recipient_key.0, &self.private_key)
+        message
+            .decrypt_box(&recipient_key.0, &*self.key_provider)
+            .map_err(|e| {
+                self.hooks.report_error(
+                    AuditOperation::Receive,
+                    Some(&message.from),
+                    &ApiError::CryptoError(e.clone()),
+                );
+                e
+            })
+    }
+
+    /// Decrypt an [`IncomingMessage`] like
+    /// [`decrypt_incoming_message`](Self::decrypt_incoming_message) and split
+    /// the decrypted data into the message type and the payload.
+    ///
+    /// The payload does not include the message type byte and the padding.
+    pub fn decrypt_incoming_payload(
+        &self,
+        message: &IncomingMessage,
+        recipient_key: &RecipientKey,
+    ) -> Result<(MessageType, Vec<u8>), ApiError> {
+        let data = self.decrypt_incoming_message(message, recipient_key)?;
+        let (&msgtype, payload) = data
+            .split_first()
+            .ok_or_else(|| ApiError::ParseError("Empty message".to_string()))?;
+        Ok((MessageType::from(msgtype), payload.to_vec()))
     }
 }
 
@@ -469,7 +1812,7 @@ impl E2eApi {
 /// let gateway_id = "*3MAGWID";
 /// let api_secret = "hihghrg98h00ghrg";
 ///
-/// let api: SimpleApi = ApiBuilder::new(gateway_id, api_secret).into_simple();
+/// let api: SimpleApi = ApiBuilder::new(gateway_id, api_secret).into_simple().unwrap();
 /// ```
 ///
 /// ## E2E API
@@ -486,13 +1829,66 @@ impl E2eApi {
 ///                              .and_then(|builder| builder.into_e2e())
 ///                              .unwrap();
 /// ```
-#[derive(Debug)]
 pub struct ApiBuilder {
     pub id: String,
     pub secret: String,
     pub private_key: Option<SecretKey>,
+    pub key_provider: Option<Arc<dyn KeyProvider>>,
     pub endpoint: Cow<'static, str>,
+    pub fallback_endpoints: Vec<Cow<'static, str>>,
+    pub endpoint_cooldown: Duration,
     pub client: Option<Client>,
+    pub timeouts: Timeouts,
+    #[cfg(feature = "compression")]
+    pub gzip: bool,
+    #[cfg(feature = "compression")]
+    pub brotli: bool,
+    pub retry_policy: RetryPolicy,
+    pub blob_endpoint: Option<Cow<'static, str>>,
+    pub padding_policy: PaddingPolicy,
+    public_key_cache: Option<SharedPublicKeyCache>,
+    message_store: Option<SharedMessageStore>,
+    blob_cache: Option<SharedBlobCache>,
+    contact_store: Option<SharedContactStore>,
+    send_ledger: Option<SharedSendLedger>,
+    pub dry_run: bool,
+    pub credits_check: bool,
+    pub strict_recipient_check: bool,
+    pub nickname_policy: NicknamePolicy,
+    pub send_options: SendOptions,
+    pub allow_insecure_http: bool,
+}
+
+impl fmt::Debug for ApiBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("ApiBuilder");
+        f.field("id", &self.id)
+            .field("secret", &Redacted(&self.secret))
+            .field("private_key", &self.private_key.as_ref().map(Redacted))
+            .field("key_provider", &self.key_provider)
+            .field("endpoint", &self.endpoint)
+            .field("fallback_endpoints", &self.fallback_endpoints)
+            .field("endpoint_cooldown", &self.endpoint_cooldown)
+            .field("client", &self.client)
+            .field("timeouts", &self.timeouts);
+        #[cfg(feature = "compression")]
+        f.field("gzip", &self.gzip).field("brotli", &self.brotli);
+        f.field("retry_policy", &self.retry_policy)
+            .field("blob_endpoint", &self.blob_endpoint)
+            .field("padding_policy", &self.padding_policy)
+            .field("public_key_cache", &self.public_key_cache)
+            .field("message_store", &self.message_store)
+            .field("blob_cache", &self.blob_cache)
+            .field("contact_store", &self.contact_store)
+            .field("send_ledger", &self.send_ledger)
+            .field("dry_run", &self.dry_run)
+            .field("credits_check", &self.credits_check)
+            .field("strict_recipient_check", &self.strict_recipient_check)
+            .field("nickname_policy", &self.nickname_policy)
+            .field("send_options", &self.send_options)
+            .field("allow_insecure_http", &self.allow_insecure_http)
+            .finish()
+    }
 }
 
 impl ApiBuilder {
@@ -502,14 +1898,38 @@ impl ApiBuilder {
             id: id.into(),
             secret: secret.into(),
             private_key: None,
+            key_provider: None,
             endpoint: Cow::Borrowed(MSGAPI_URL),
+            fallback_endpoints: Vec::new(),
+            endpoint_cooldown: DEFAULT_ENDPOINT_COOLDOWN,
             client: None,
+            timeouts: Timeouts::default(),
+            #[cfg(feature = "compression")]
+            gzip: true,
+            #[cfg(feature = "compression")]
+            brotli: true,
+            retry_policy: RetryPolicy::default(),
+            blob_endpoint: None,
+            padding_policy: PaddingPolicy::default(),
+            public_key_cache: None,
+            message_store: None,
+            blob_cache: None,
+            contact_store: None,
+            send_ledger: None,
+            dry_run: false,
+            credits_check: false,
+            strict_recipient_check: false,
+            nickname_policy: NicknamePolicy::default(),
+            send_options: default_send_options(),
+            allow_insecure_http: false,
         }
     }
 
     /// Set a custom API endpoint.
     ///
-    /// The API endpoint should be a HTTPS URL without trailing slash.
+    /// The API endpoint should be a HTTPS URL without trailing slash. Plain
+    /// HTTP endpoints are rejected unless
+    /// [`allow_insecure_http`](Self::allow_insecure_http) is called.
     pub fn with_custom_endpoint<E: Into<Cow<'static, str>>>(mut self, endpoint: E) -> Self {
         let endpoint = endpoint.into();
         debug!("Using custom endpoint: {}", endpoint);
@@ -520,6 +1940,82 @@ impl ApiBuilder {
         self
     }
 
+    /// Add a fallback API endpoint.
+    ///
+    /// If the API endpoint (see [`with_custom_endpoint`](Self::with_custom_endpoint))
+    /// cannot be reached, requests are sent to the fallback endpoints, in the
+    /// order they were added. An endpoint that could not be reached is
+    /// considered unhealthy and skipped for the duration of the endpoint
+    /// cooldown (see [`with_endpoint_cooldown`](Self::with_endpoint_cooldown)).
+    ///
+    /// Only connection errors cause a failover, so that a message is never
+    /// sent twice.
+    pub fn with_fallback_endpoint<E: Into<Cow<'static, str>>>(mut self, endpoint: E) -> Self {
+        let endpoint = endpoint.into();
+        debug!("Using fallback endpoint: {}", endpoint);
+        if !(endpoint.starts_with("http:") || endpoint.starts_with("https:")) {
+            warn!("Fallback endpoint seems invalid!");
+        }
+        self.fallback_endpoints.push(endpoint);
+        self
+    }
+
+    /// Set how long an unreachable endpoint is skipped before it is tried
+    /// again. Only relevant if fallback endpoints are configured.
+    ///
+    /// By default, the cooldown is 30 seconds.
+    pub fn with_endpoint_cooldown(mut self, cooldown: Duration) -> Self {
+        self.endpoint_cooldown = cooldown;
+        self
+    }
+
+    /// Set a custom blob server endpoint.
+    ///
+    /// By default, blobs are uploaded to and downloaded from the API endpoint.
+    /// Use this if your (on-premises) gateway installation serves blobs from a
+    /// different host. The blob endpoint should be a HTTPS URL without
+    /// trailing slash.
+    pub fn with_blob_endpoint<E: Into<Cow<'static, str>>>(mut self, endpoint: E) -> Self {
+        let endpoint = endpoint.into();
+        debug!("Using custom blob endpoint: {}", endpoint);
+        if !(endpoint.starts_with("http:") || endpoint.starts_with("https:")) {
+            warn!("Custom blob endpoint seems invalid!");
+        }
+        self.blob_endpoint = Some(endpoint);
+        self
+    }
+
+    /// Allow plain HTTP endpoints.
+    ///
+    /// By default, building the API object fails with
+    /// [`ApiBuilderError::InsecureEndpoint`] if one of the endpoints is a
+    /// plain HTTP URL, because the API secret would be transmitted
+    /// unencrypted. Only use this for local testing (e.g. with a mock
+    /// server).
+    pub fn allow_insecure_http(mut self) -> Self {
+        self.allow_insecure_http = true;
+        self
+    }
+
+    /// Ensure that all endpoints use HTTPS, unless insecure HTTP is allowed.
+    fn check_endpoints(&self) -> Result<(), ApiBuilderError> {
+        if self.allow_insecure_http {
+            return Ok(());
+        }
+        let endpoints = std::iter::once(&self.endpoint)
+            .chain(&self.fallback_endpoints)
+            .chain(&self.blob_endpoint);
+        for endpoint in endpoints {
+            if endpoint
+                .get(..5)
+                .is_some_and(|scheme| scheme.eq_ignore_ascii_case("http:"))
+            {
+                return Err(ApiBuilderError::InsecureEndpoint(endpoint.to_string()));
+            }
+        }
+        Ok(())
+    }
+
     /// Set a custom reqwest [`Client`][reqwest::Client] that will be re-used
     /// for all connections.
     pub fn with_client(mut self, client: Client) -> Self {
@@ -527,14 +2023,214 @@ impl ApiBuilder {
         self
     }
 
+    /// Set the [`Timeouts`] of the HTTP client.
+    ///
+    /// By default, requests time out after 10 seconds in total. The timeouts
+    /// are ignored if a custom client is set with
+    /// [`with_client`](Self::with_client).
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Set the timeout for establishing a connection. See [`Timeouts`].
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.connect = Some(timeout);
+        self
+    }
+
+    /// Set the timeout for every read from the connection. See [`Timeouts`].
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.read = Some(timeout);
+        self
+    }
+
+    /// Set the timeout for whole requests. See [`Timeouts`].
+    pub fn with_total_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.total = Some(timeout);
+        self
+    }
+
+    /// Enable or disable transparent gzip decompression of responses.
+    /// Enabled by default.
+    ///
+    /// Ignored if a custom client is set with
+    /// [`with_client`](Self::with_client), configure the client instead.
+    #[cfg(feature = "compression")]
+    pub fn with_gzip(mut self, enable: bool) -> Self {
+        self.gzip = enable;
+        self
+    }
+
+    /// Enable or disable transparent brotli decompression of responses.
+    /// Enabled by default.
+    ///
+    /// Ignored if a custom client is set with
+    /// [`with_client`](Self::with_client), configure the client instead.
+    #[cfg(feature = "compression")]
+    pub fn with_brotli(mut self, enable: bool) -> Self {
+        self.brotli = enable;
+        self
+    }
+
+    /// Return the custom client, or build one with the configured timeouts
+    /// and compression settings.
+    fn take_client(&mut self) -> Result<Client, ApiBuilderError> {
+        #[cfg(feature = "compression")]
+        let default_compression = self.gzip && self.brotli;
+        #[cfg(not(feature = "compression"))]
+        let default_compression = true;
+        match self.client.take() {
+            Some(client) => {
+                if self.timeouts != Timeouts::default() || !default_compression {
+                    warn!("Custom client set, ignoring timeout and compression settings");
+                }
+                Ok(client)
+            }
+            None => {
+                #[allow(unused_mut)]
+                let mut builder = self.timeouts.client_builder();
+                #[cfg(feature = "compression")]
+                {
+                    builder = builder.gzip(self.gzip).brotli(self.brotli);
+                }
+                builder
+                    .build()
+                    .map_err(|e| ApiBuilderError::Client(e.to_string()))
+            }
+        }
+    }
+
+    /// Set the [`RetryPolicy`] used for blob uploads and downloads.
+    ///
+    /// By default, [`RetryPolicy::default()`] is used. To disable retrying,
+    /// pass in [`RetryPolicy::no_retries()`].
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Set the [`PaddingPolicy`] used when encrypting messages. Only needed
+    /// for E2e mode.
+    ///
+    /// By default, a random amount of padding is added
+    /// ([`PaddingPolicy::Random`]).
+    pub fn with_padding_policy(mut self, padding_policy: PaddingPolicy) -> Self {
+        self.padding_policy = padding_policy;
+        self
+    }
+
+    /// Enable or disable the dry-run mode.
+    ///
+    /// In dry-run mode, messages are validated and encrypted as usual, but
+    /// sending messages and uploading blobs skips the HTTP request. Instead, a
+    /// random message ID or blob ID is returned. No credits are spent. Lookups
+    /// and blob downloads are still performed.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Enable or disable the credits pre-check. Only relevant for E2e mode.
+    ///
+    /// If enabled, blob uploads and operations that cost more than one
+    /// credit (like [`E2eApi::send_file`]) first look up the remaining
+    /// credits, and fail with [`ApiError::InsufficientCredits`] without
+    /// spending any credits if they don't suffice.
+    pub fn with_credits_check(mut self, credits_check: bool) -> Self {
+        self.credits_check = credits_check;
+        self
+    }
+
+    /// Enable or disable the strict recipient check. Only relevant for E2e
+    /// mode.
+    ///
+    /// If enabled, [`E2eApi::decode_incoming_message`] rejects messages
+    /// whose `to` field doesn't match the gateway ID, e.g. messages that
+    /// were misrouted or forwarded from another identity sharing the same
+    /// secret.
+    pub fn with_strict_recipient_check(mut self, strict_recipient_check: bool) -> Self {
+        self.strict_recipient_check = strict_recipient_check;
+        self
+    }
+
+    /// Set the [`NicknamePolicy`] used to sanitize the nickname of incoming
+    /// messages in [`E2eApi::decode_incoming_message`]. Only relevant for
+    /// E2e mode.
+    pub fn with_nickname_policy(mut self, nickname_policy: NicknamePolicy) -> Self {
+        self.nickname_policy = nickname_policy;
+        self
+    }
+
+    /// Set the [`SendOptions`] used by [`E2eApi::send_text`]. Only relevant
+    /// for E2e mode.
+    ///
+    /// By default, delivery receipts are requested.
+    pub fn with_send_options(mut self, send_options: SendOptions) -> Self {
+        self.send_options = send_options;
+        self
+    }
+
+    /// Set the [`PublicKeyCache`] used to look up recipient public keys in
+    /// [`E2eApi::send_text`]. Only needed for E2e mode.
+    pub fn with_public_key_cache<C: PublicKeyCache + 'static>(mut self, cache: C) -> Self {
+        self.public_key_cache = Some(SharedPublicKeyCache::new(cache));
+        self
+    }
+
+    /// Set the [`BlobCache`] consulted by [`E2eApi::blob_download`] before
+    /// downloading a blob from the blob server. Only needed for E2e mode.
+    pub fn with_blob_cache<C: BlobCache + 'static>(mut self, cache: C) -> Self {
+        self.blob_cache = Some(SharedBlobCache::new(cache));
+        self
+    }
+
+    /// Set the [`MessageStore`] that text messages sent with
+    /// [`E2eApi::send_text`] are recorded in. Only needed for E2e mode.
+    ///
+    /// To query the history, keep a reference to the store, e.g. by passing
+    /// an `Arc`.
+    pub fn with_message_store<S: MessageStore + 'static>(mut self, store: S) -> Self {
+        self.message_store = Some(SharedMessageStore::new(store));
+        self
+    }
+
+    /// Set the [`ContactStore`] with the known contacts. Only needed for E2e
+    /// mode.
+    ///
+    /// Stored contacts can be addressed by nickname in
+    /// [`E2eApi::send_text`], and their public keys are used without a
+    /// lookup.
+    pub fn with_contact_store<S: ContactStore + 'static>(mut self, store: S) -> Self {
+        self.contact_store = Some(SharedContactStore::new(store));
+        self
+    }
+
+    /// Set the [`SendLedger`] that deduplicates the sends of
+    /// [`E2eApi::send_once`]. Only needed for E2e mode.
+    pub fn with_send_ledger<L: SendLedger + 'static>(mut self, ledger: L) -> Self {
+        self.send_ledger = Some(SharedSendLedger::new(ledger));
+        self
+    }
+
     /// Return a [`SimpleAPI`](struct.SimpleApi.html) instance.
-    pub fn into_simple(self) -> SimpleApi {
-        SimpleApi::new(
-            self.endpoint,
+    ///
+    /// This will fail if a plain HTTP endpoint was configured without
+    /// allowing insecure HTTP.
+    pub fn into_simple(mut self) -> Result<SimpleApi, ApiBuilderError> {
+        self.check_endpoints()?;
+        let client = self.take_client()?;
+        Ok(SimpleApi::new(
+            Endpoints::new(
+                self.endpoint,
+                self.fallback_endpoints,
+                self.endpoint_cooldown,
+            ),
             self.id,
             self.secret,
-            self.client.unwrap_or_else(make_reqwest_client),
-        )
+            client,
+            self.dry_run,
+        ))
     }
 
     /// Set the private key. Only needed for E2e mode.
@@ -543,6 +2239,16 @@ impl ApiBuilder {
         self
     }
 
+    /// Set a custom [`KeyProvider`] that performs the private key operations,
+    /// e.g. one that is backed by an HSM. Only needed for E2e mode.
+    ///
+    /// If set, this takes precedence over a private key set with one of the
+    /// `with_private_key*` methods.
+    pub fn with_key_provider<K: KeyProvider + 'static>(mut self, key_provider: K) -> Self {
+        self.key_provider = Some(Arc::new(key_provider));
+        self
+    }
+
     /// Set the private key from a byte slice. Only needed for E2e mode.
     pub fn with_private_key_bytes(mut self, private_key: &[u8]) -> Result<Self, ApiBuilderError> {
         let private_key = SecretKey::from_slice(private_key).map_err(|e| {
@@ -552,32 +2258,911 @@ impl ApiBuilder {
         Ok(self)
     }
 
+    /// Trim a private key string and strip its optional `private:` prefix.
+    fn strip_private_key_prefix(private_key: &str) -> Result<&str, ApiBuilderError> {
+        let private_key = private_key.trim();
+        if private_key.starts_with("public:") {
+            return Err(ApiBuilderError::InvalidKey(
+                "Expected a private key, but got a public key".to_string(),
+            ));
+        }
+        Ok(private_key.strip_prefix("private:").unwrap_or(private_key))
+    }
+
     /// Set the private key from a hex-encoded string reference. Only needed
     /// for E2e mode.
+    ///
+    /// The key may optionally be prefixed with `private:`, which is the
+    /// format used by the Threema Gateway tools (e.g. when generating a key
+    /// pair).
     pub fn with_private_key_str(self, private_key: &str) -> Result<Self, ApiBuilderError> {
-        let private_key_bytes =
-            HEXLOWER_PERMISSIVE
-                .decode(private_key.as_bytes())
-                .map_err(|e| {
-                    let msg = format!("Could not decode private key hex string: {}", e);
-                    ApiBuilderError::InvalidKey(msg)
-                })?;
-        self.with_private_key_bytes(&private_key_bytes)
+        let private_key = Self::strip_private_key_prefix(private_key)?;
+        let mut private_key_bytes = Zeroizing::new([0; 32]);
+        decode_hex_ct(private_key.as_bytes(), &mut private_key_bytes[..]).map_err(|e| {
+            let msg = format!("Could not decode private key hex string: {}", e);
+            ApiBuilderError::InvalidKey(msg)
+        })?;
+        self.with_private_key_bytes(&private_key_bytes[..])
+    }
+
+    /// Set the private key from a base64-encoded string reference. Only
+    /// needed for E2e mode.
+    ///
+    /// Like with [`with_private_key_str`](Self::with_private_key_str), the
+    /// key may optionally be prefixed with `private:`.
+    pub fn with_private_key_base64(self, private_key: &str) -> Result<Self, ApiBuilderError> {
+        let private_key = Self::strip_private_key_prefix(private_key)?;
+        let mut private_key_bytes = Zeroizing::new([0; 32]);
+        decode_base64_ct(private_key.as_bytes(), &mut private_key_bytes[..]).map_err(|e| {
+            let msg = format!("Could not decode private key base64 string: {}", e);
+            ApiBuilderError::InvalidKey(msg)
+        })?;
+        self.with_private_key_bytes(&private_key_bytes[..])
     }
 
     /// Return a [`E2eAPI`](struct.SimpleApi.html) instance.
     ///
-    /// This will fail if no private key was set.
-    pub fn into_e2e(self) -> Result<E2eApi, ApiBuilderError> {
-        match self.private_key {
-            Some(key) => Ok(E2eApi::new(
-                self.endpoint,
+    /// This will fail if no private key was set, or if a plain HTTP endpoint
+    /// was configured without allowing insecure HTTP.
+    pub fn into_e2e(mut self) -> Result<E2eApi, ApiBuilderError> {
+        self.check_endpoints()?;
+        let client = self.take_client()?;
+        let key_provider = match (self.key_provider, self.private_key) {
+            (Some(key_provider), _) => Some(key_provider),
+            (None, Some(key)) => Some(Arc::new(key) as Arc<dyn KeyProvider>),
+            (None, None) => None,
+        };
+        match key_provider {
+            Some(key_provider) => Ok(E2eApi::new(
+                Endpoints::new(
+                    self.endpoint,
+                    self.fallback_endpoints,
+                    self.endpoint_cooldown,
+                ),
                 self.id,
                 self.secret,
-                key,
-                self.client.unwrap_or_else(make_reqwest_client),
+                key_provider,
+                client,
+                E2eConfig {
+                    retry_policy: self.retry_policy,
+                    blob_endpoint: self.blob_endpoint,
+                    padding_policy: self.padding_policy,
+                    public_key_cache: self.public_key_cache,
+                    message_store: self.message_store,
+                    blob_cache: self.blob_cache,
+                    contact_store: self.contact_store,
+                    send_ledger: self.send_ledger,
+                    dry_run: self.dry_run,
+                    credits_check: self.credits_check,
+                    strict_recipient_check: self.strict_recipient_check,
+                    nickname_policy: self.nickname_policy,
+                    send_options: self.send_options,
+                },
             )),
             None => Err(ApiBuilderError::MissingKey),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_KEY: &str = "998730fbcac1c57dbb181139de41d12835b3fae6af6acdf6ce91670262e88453";
+
+    #[test]
+    fn test_blob_endpoint_default() {
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_custom_endpoint("https://gateway.example.com")
+            .with_private_key_str(PRIVATE_KEY)
+            .and_then(|builder| builder.into_e2e())
+            .unwrap();
+        assert_eq!(api.endpoint_status()[0].url, "https://gateway.example.com");
+        assert_eq!(
+            api.blob_endpoints.status()[0].url,
+            "https://gateway.example.com"
+        );
+    }
+
+    #[test]
+    fn test_decrypt_incoming_payload() {
+        use crate::test_support;
+
+        let api = test_support::api_builder().into_e2e().unwrap();
+        let msg = api
+            .decode_incoming_message(test_support::CALLBACK_BODY)
+            .unwrap();
+        let (msgtype, payload) = api
+            .decrypt_incoming_payload(&msg, &test_support::recipient_key())
+            .unwrap();
+        assert_eq!(msgtype, MessageType::Text);
+        assert_eq!(payload, test_support::TEXT_MESSAGE.as_bytes());
+    }
+
+    #[test]
+    fn test_blob_endpoint_custom() {
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_custom_endpoint("https://gateway.example.com")
+            .with_blob_endpoint("https://blobs.example.com")
+            .with_private_key_str(PRIVATE_KEY)
+            .and_then(|builder| builder.into_e2e())
+            .unwrap();
+        assert_eq!(api.endpoint_status()[0].url, "https://gateway.example.com");
+        assert_eq!(
+            api.blob_endpoints.status()[0].url,
+            "https://blobs.example.com"
+        );
+    }
+
+    #[cfg(feature = "parallel")]
+    #[tokio::test]
+    async fn test_encrypt_bulk() {
+        use crate::crypto::decrypt;
+
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_str(PRIVATE_KEY)
+            .and_then(|builder| builder.into_e2e())
+            .unwrap();
+        let recipients: Vec<SecretKey> = (0..10u8).map(|i| SecretKey::from([i; 32])).collect();
+        let keys: Vec<RecipientKey> = recipients
+            .iter()
+            .map(|sk| RecipientKey::from(sk.public_key()))
+            .collect();
+        let messages = api
+            .encrypt_bulk(
+                b"hi",
+                MessageType::Text,
+                &keys,
+                NonZeroUsize::new(3).unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), keys.len());
+        for (msg, sk) in messages.iter().zip(&recipients) {
+            let (msgtype, data) =
+                decrypt(&msg.ciphertext, &msg.nonce, &api.public_key(), sk).unwrap();
+            assert_eq!(msgtype, MessageType::Text);
+            assert_eq!(data, b"hi");
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[tokio::test]
+    async fn test_encrypt_template_bulk() {
+        use crate::{crypto::decrypt, errors::TemplateError, template::Template};
+
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_str(PRIVATE_KEY)
+            .and_then(|builder| builder.into_e2e())
+            .unwrap();
+        let template = Template::parse("Hello {name}").unwrap();
+        let recipients: Vec<SecretKey> = (0..4u8).map(|i| SecretKey::from([i; 32])).collect();
+        let variables: Vec<(RecipientKey, HashMap<String, String>)> = recipients
+            .iter()
+            .enumerate()
+            .map(|(i, sk)| {
+                let name = HashMap::from([("name".to_string(), format!("user {}", i))]);
+                (RecipientKey::from(sk.public_key()), name)
+            })
+            .collect();
+        let messages = api
+            .encrypt_template_bulk(&template, &variables, NonZeroUsize::new(2).unwrap())
+            .await
+            .unwrap();
+        for (i, (msg, sk)) in messages.iter().zip(&recipients).enumerate() {
+            let (msgtype, data) =
+                decrypt(&msg.ciphertext, &msg.nonce, &api.public_key(), sk).unwrap();
+            assert_eq!(msgtype, MessageType::Text);
+            assert_eq!(data, format!("Hello user {}", i).as_bytes());
+        }
+
+        let mut missing = variables;
+        missing[2].1.clear();
+        let result = api
+            .encrypt_template_bulk(&template, &missing, NonZeroUsize::new(2).unwrap())
+            .await;
+        assert!(matches!(
+            result,
+            Err(ApiError::InvalidTemplate(TemplateError::MissingVariable(_)))
+        ));
+
+        // The length is validated after the text transformations
+        let api = api.transform_text(|text| format!("{}!!", text));
+        let template = Template::parse("{text}").unwrap();
+        let text = "x".repeat(crate::template::MAX_TEXT_LENGTH - 1);
+        let variables = vec![(
+            RecipientKey::from(recipients[0].public_key()),
+            HashMap::from([("text".to_string(), text)]),
+        )];
+        let result = api
+            .encrypt_template_bulk(&template, &variables, NonZeroUsize::new(1).unwrap())
+            .await;
+        assert!(matches!(
+            result,
+            Err(ApiError::InvalidTemplate(TemplateError::TooLong { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_encrypt_delivery_receipt() {
+        use crate::crypto::decrypt;
+
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_str(PRIVATE_KEY)
+            .and_then(|builder| builder.into_e2e())
+            .unwrap();
+        let recipient = SecretKey::from([7; 32]);
+        let key = RecipientKey::from(recipient.public_key());
+        let ids: Vec<MessageId> = (1..=3u8).map(|i| MessageId::new([i; 8])).collect();
+
+        let msg = api
+            .encrypt_delivery_receipt(MessageStatus::Read, &ids, &key)
+            .unwrap();
+        let (msgtype, data) =
+            decrypt(&msg.ciphertext, &msg.nonce, &api.public_key(), &recipient).unwrap();
+        assert_eq!(msgtype, MessageType::DeliveryReceipt);
+        let receipt = DeliveryReceipt::from_bytes(&data).unwrap();
+        assert_eq!(receipt.status, MessageStatus::Read);
+        assert_eq!(receipt.message_ids, ids);
+
+        assert!(api
+            .encrypt_delivery_receipt(MessageStatus::Read, &[], &key)
+            .is_err());
+        assert!(api
+            .encrypt_delivery_receipt(MessageStatus::Sent, &ids, &key)
+            .is_err());
+    }
+
+    #[test]
+    fn test_private_key_formats() {
+        let expected = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_str(PRIVATE_KEY)
+            .unwrap()
+            .private_key
+            .unwrap();
+
+        // Prefixed hex
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_str(&format!("private:{}\n", PRIVATE_KEY))
+            .unwrap();
+        assert_eq!(builder.private_key.unwrap(), expected);
+
+        // Base64
+        let base64 = data_encoding::BASE64.encode(&expected.to_bytes());
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_base64(&base64)
+            .unwrap();
+        assert_eq!(builder.private_key.unwrap(), expected);
+
+        // Prefixed base64
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_base64(&format!(" private:{}\n", base64))
+            .unwrap();
+        assert_eq!(builder.private_key.unwrap(), expected);
+    }
+
+    #[test]
+    fn test_private_key_formats_invalid() {
+        let err = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_str(&format!("public:{}", PRIVATE_KEY))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ApiBuilderError::InvalidKey("Expected a private key, but got a public key".into())
+        );
+
+        let err = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_str("private:xyz")
+            .unwrap_err();
+        assert!(
+            matches!(err, ApiBuilderError::InvalidKey(msg) if msg.starts_with("Could not decode private key hex string"))
+        );
+
+        let err = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_base64("not base64!")
+            .unwrap_err();
+        assert!(
+            matches!(err, ApiBuilderError::InvalidKey(msg) if msg.starts_with("Could not decode private key base64 string"))
+        );
+
+        let err = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_base64("AAAA")
+            .unwrap_err();
+        assert!(
+            matches!(err, ApiBuilderError::InvalidKey(msg) if msg.ends_with("Expected 44 base64 characters, but got 4"))
+        );
+
+        let err = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_base64("public:AAAA")
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ApiBuilderError::InvalidKey("Expected a private key, but got a public key".into())
+        );
+    }
+
+    #[test]
+    fn test_public_key() {
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_str(PRIVATE_KEY)
+            .unwrap()
+            .into_e2e()
+            .unwrap();
+        let private_key =
+            SecretKey::from_slice(&HEXLOWER.decode(PRIVATE_KEY.as_bytes()).unwrap()).unwrap();
+        assert_eq!(api.public_key(), private_key.public_key());
+        assert_eq!(
+            api.public_key_hex(),
+            HEXLOWER.encode(private_key.public_key().as_bytes())
+        );
+        assert_eq!(api.public_key_hex().len(), 64);
+        assert_eq!(
+            api.qr_payload(),
+            format!("3mid:*3MAGWID,{}", api.public_key_hex())
+        );
+    }
+
+    #[test]
+    fn test_key_provider() {
+        #[derive(Debug)]
+        struct Provider(SecretKey);
+
+        impl KeyProvider for Provider {
+            fn public_key(&self) -> PublicKey {
+                self.0.public_key()
+            }
+
+            fn encrypt_in_place_detached(
+                &self,
+                public_key: &PublicKey,
+                nonce: &Nonce,
+                buffer: &mut [u8],
+            ) -> Result<[u8; 16], CryptoError> {
+                self.0.encrypt_in_place_detached(public_key, nonce, buffer)
+            }
+
+            fn decrypt_in_place_detached(
+                &self,
+                public_key: &PublicKey,
+                nonce: &Nonce,
+                buffer: &mut [u8],
+                tag: &[u8; 16],
+            ) -> Result<(), CryptoError> {
+                self.0
+                    .decrypt_in_place_detached(public_key, nonce, buffer, tag)
+            }
+        }
+
+        let own_key = SecretKey::from([1; 32]);
+        let other_key = SecretKey::from([2; 32]);
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_key_provider(Provider(own_key.clone()))
+            .into_e2e()
+            .unwrap();
+        assert_eq!(api.public_key(), own_key.public_key());
+
+        let recipient = RecipientKey::from(other_key.public_key());
+        let msg = api.encrypt_text_msg("hello", &recipient).unwrap();
+        let (msgtype, data) = crate::decrypt(
+            &msg.ciphertext,
+            &msg.nonce,
+            &own_key.public_key(),
+            &other_key,
+        )
+        .unwrap();
+        assert_eq!(msgtype, MessageType::Text);
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn test_transform_text() {
+        let own_key = SecretKey::from([1; 32]);
+        let other_key = SecretKey::from([2; 32]);
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key(own_key.clone())
+            .into_e2e()
+            .unwrap()
+            .transform_text(|text| text.replace("secret", "******"))
+            .transform_text(|text| format!("{}\n-- Bot", text));
+        let recipient = RecipientKey::from(other_key.public_key());
+        let decrypt = |msg: EncryptedMessage| {
+            crate::decrypt(
+                &msg.ciphertext,
+                &msg.nonce,
+                &own_key.public_key(),
+                &other_key,
+            )
+            .unwrap()
+        };
+
+        let msg = api.encrypt_text_msg("a secret", &recipient).unwrap();
+        assert_eq!(decrypt(msg).1, b"a ******\n-- Bot");
+        let msg = api
+            .encrypt(b"secret", MessageType::Text, &recipient)
+            .unwrap();
+        assert_eq!(decrypt(msg).1, b"******\n-- Bot");
+        let msg = api
+            .encrypt(b"secret", MessageType::Other(0x42), &recipient)
+            .unwrap();
+        assert_eq!(decrypt(msg).1, b"secret");
+
+        // Captions of file messages are transformed as well
+        let blob_id: BlobId = "0123456789abcdef0123456789abcdef".parse().unwrap();
+        let file = FileMessage::builder(blob_id, [1; 32].into(), "image/jpeg", 2048)
+            .description("a secret")
+            .build()
+            .unwrap();
+        let msg = api.encrypt_file_msg(&file, &recipient).unwrap();
+        let file = FileMessage::from_json(&decrypt(msg).1).unwrap();
+        assert_eq!(file.description(), Some("a ******\n-- Bot"));
+    }
+
+    #[tokio::test]
+    async fn test_send_text_uses_cache() {
+        use std::{collections::HashMap, convert::Infallible, sync::Mutex};
+
+        #[derive(Default)]
+        struct Cache(Mutex<HashMap<String, RecipientKey>>);
+
+        impl PublicKeyCache for Cache {
+            type Error = Infallible;
+
+            async fn store(&self, identity: &str, key: &RecipientKey) -> Result<(), Infallible> {
+                self.0.lock().unwrap().insert(identity.into(), key.clone());
+                Ok(())
+            }
+
+            async fn load(&self, identity: &str) -> Result<Option<RecipientKey>, Infallible> {
+                Ok(self.0.lock().unwrap().get(identity).cloned())
+            }
+        }
+
+        let cache = Cache::default();
+        let key = RecipientKey::from([2; 32]);
+        cache
+            .0
+            .lock()
+            .unwrap()
+            .insert("ECHOECHO".into(), key.clone());
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key_str(PRIVATE_KEY)
+            .unwrap()
+            .with_public_key_cache(cache)
+            .into_e2e()
+            .unwrap();
+        assert_eq!(api.resolve_public_key("ECHOECHO").await.unwrap(), key);
+    }
+
+    #[test]
+    fn test_insecure_http() {
+        let builder = || {
+            ApiBuilder::new("*3MAGWID", "1234")
+                .with_custom_endpoint("http://gateway.example.com")
+                .with_private_key(SecretKey::from([1; 32]))
+        };
+        assert!(matches!(
+            builder().into_simple(),
+            Err(ApiBuilderError::InsecureEndpoint(e)) if e == "http://gateway.example.com"
+        ));
+        assert!(matches!(
+            builder().into_e2e(),
+            Err(ApiBuilderError::InsecureEndpoint(_))
+        ));
+        assert!(builder().allow_insecure_http().into_simple().is_ok());
+        assert!(builder().allow_insecure_http().into_e2e().is_ok());
+
+        // Fallback and blob endpoints are checked as well
+        let builder = ApiBuilder::new("*3MAGWID", "1234");
+        assert!(builder
+            .with_fallback_endpoint("HTTP://fallback.example.com")
+            .into_simple()
+            .is_err());
+        let builder = ApiBuilder::new("*3MAGWID", "1234");
+        assert!(builder
+            .with_blob_endpoint("http://blobs.example.com")
+            .into_simple()
+            .is_err());
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_connect_timeout(Duration::from_secs(2))
+            .with_read_timeout(Duration::from_secs(5));
+        assert_eq!(
+            builder.timeouts,
+            Timeouts {
+                connect: Some(Duration::from_secs(2)),
+                read: Some(Duration::from_secs(5)),
+                total: Some(Duration::from_secs(10)),
+            }
+        );
+        let timeouts = Timeouts {
+            total: None,
+            ..builder.timeouts
+        };
+        let builder = builder.with_timeouts(timeouts);
+        assert_eq!(builder.timeouts.total, None);
+        assert!(builder.into_simple().is_ok());
+    }
+
+    #[cfg(all(feature = "compression", feature = "mock-server"))]
+    #[tokio::test]
+    async fn test_compression() {
+        use crate::mock_server::MockServer;
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_gzip(true);
+        server.set_credits(42);
+        let builder = || {
+            ApiBuilder::new("*3MAGWID", "secret")
+                .with_custom_endpoint(server.url())
+                .allow_insecure_http()
+        };
+
+        // The compressed response is decoded
+        let api = builder().into_simple().unwrap();
+        assert_eq!(api.lookup_credits().await.unwrap(), 42);
+
+        // Without compression, the response is sent uncompressed
+        let api = builder()
+            .with_gzip(false)
+            .with_brotli(false)
+            .into_simple()
+            .unwrap();
+        assert_eq!(api.lookup_credits().await.unwrap(), 42);
+
+        let accept_encodings: Vec<_> = server
+            .request_headers()
+            .iter()
+            .map(|headers| {
+                headers
+                    .get("accept-encoding")
+                    .map(|value| value.to_str().unwrap().to_string())
+            })
+            .collect();
+        assert_eq!(accept_encodings.len(), 2);
+        let accepted = accept_encodings[0].as_deref().unwrap();
+        assert!(accepted.contains("gzip") && accepted.contains("br"));
+        assert_eq!(accept_encodings[1], None);
+    }
+
+    #[test]
+    fn test_debug_redacted() {
+        let builder = ApiBuilder::new("*3MAGWID", "hihghrg98h00ghrg")
+            .with_private_key_str(
+                "998730fbcac1c57dbb181139de41d12835b3fae6af6acdf6ce91670262e88453",
+            )
+            .unwrap();
+        let debug = format!("{:?}", builder);
+        assert!(debug.contains("*3MAGWID"));
+        assert!(!debug.contains("hihghrg98h00ghrg"));
+        assert!(debug.contains("private_key: Some([redacted])"));
+        let debug = format!("{:?}", builder.into_e2e().unwrap());
+        assert!(debug.contains("*3MAGWID"));
+        assert!(!debug.contains("hihghrg98h00ghrg"));
+        let api = ApiBuilder::new("*3MAGWID", "hihghrg98h00ghrg")
+            .into_simple()
+            .unwrap();
+        assert!(!format!("{:?}", api).contains("hihghrg98h00ghrg"));
+    }
+
+    #[test]
+    fn test_simple_into_e2e() {
+        let private_key = SecretKey::from([1; 32]);
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_custom_endpoint("https://example.com")
+            .into_simple()
+            .unwrap()
+            .into_e2e(private_key.clone());
+        assert_eq!(api.id, "*3MAGWID");
+        assert_eq!(api.secret, "1234");
+        assert_eq!(api.endpoint_status()[0].url, "https://example.com");
+        assert_eq!(api.blob_endpoints.status()[0].url, "https://example.com");
+        assert_eq!(api.public_key(), private_key.public_key());
+    }
+
+    #[test]
+    fn test_strict_recipient_check() {
+        use crate::test_support;
+
+        let builder = || {
+            ApiBuilder::new("*OTHERID", test_support::API_SECRET)
+                .with_private_key(test_support::gateway_private_key())
+        };
+        let api = builder().into_e2e().unwrap();
+        assert!(api
+            .decode_incoming_message(test_support::CALLBACK_BODY)
+            .is_ok());
+
+        let api = builder()
+            .with_strict_recipient_check(true)
+            .into_e2e()
+            .unwrap();
+        match api.decode_incoming_message(test_support::CALLBACK_BODY) {
+            Err(ApiError::UnexpectedRecipient(to)) => assert_eq!(to, test_support::GATEWAY_ID),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let api = test_support::api_builder()
+            .with_strict_recipient_check(true)
+            .into_e2e()
+            .unwrap();
+        assert!(api
+            .decode_incoming_message(test_support::CALLBACK_BODY)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_custom_endpoint("http://127.0.0.1:1")
+            .allow_insecure_http()
+            .with_private_key_str(PRIVATE_KEY)
+            .unwrap()
+            .with_dry_run(true)
+            .into_e2e()
+            .unwrap();
+        let recipient = RecipientKey::from([2; 32]);
+        let msg = api.encrypt_text_msg("hello", &recipient).unwrap();
+        let id = api.send("ECHOECHO", &msg, true).await.unwrap();
+        assert_eq!(id.message_id.to_string().len(), 16);
+        api.blob_upload_raw(&[1, 2, 3], false).await.unwrap();
+        assert_eq!(api.credit_usage().total(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_credits_check_disabled() {
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_custom_endpoint("http://127.0.0.1:1")
+            .allow_insecure_http()
+            .with_private_key_str(PRIVATE_KEY)
+            .unwrap()
+            .into_e2e()
+            .unwrap();
+        // No request is made, so this succeeds even though the endpoint is
+        // unreachable
+        api.check_credits(1000).await.unwrap();
+
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_custom_endpoint("http://127.0.0.1:1")
+            .allow_insecure_http()
+            .with_private_key_str(PRIVATE_KEY)
+            .unwrap()
+            .with_credits_check(true)
+            .into_e2e()
+            .unwrap();
+        assert!(matches!(
+            api.check_credits(1000).await.map_err(ApiError::into_inner),
+            Err(ApiError::RequestError(_))
+        ));
+    }
+
+    #[test]
+    fn test_into_e2e_keeps_deadline() {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_dry_run(true)
+            .into_simple()
+            .unwrap()
+            .with_deadline(deadline)
+            .into_e2e(SecretKey::from([1; 32]));
+        assert_eq!(api.deadline, Some(deadline));
+        assert!(api.dry_run);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_send_text_options() {
+        use crate::{
+            history::{MemoryMessageStore, MessageStore},
+            mock_server::{MockServer, ReceivedMessage},
+        };
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_public_key("ECHOECHO", RecipientKey::from([2; 32]));
+        let store = Arc::new(MemoryMessageStore::default());
+        let builder = || {
+            ApiBuilder::new("*3MAGWID", "secret")
+                .with_custom_endpoint(server.url())
+                .allow_insecure_http()
+                .with_private_key_str(PRIVATE_KEY)
+                .unwrap()
+                .with_message_store(store.clone())
+        };
+
+        // Delivery receipts are requested by default
+        let api = builder().into_e2e().unwrap();
+        api.send_text("ECHOECHO", "hi").await.unwrap();
+
+        let api = builder()
+            .with_send_options(SendOptions {
+                no_push: true,
+                ..SendOptions::default()
+            })
+            .into_e2e()
+            .unwrap()
+            .transform_text(|text| format!("{text}!"));
+        api.send_text("ECHOECHO", "hi").await.unwrap();
+
+        let flags: Vec<_> = server
+            .received_messages()
+            .into_iter()
+            .map(|message| match message {
+                ReceivedMessage::E2e {
+                    delivery_receipts,
+                    no_push,
+                    ..
+                } => (delivery_receipts, no_push),
+                ReceivedMessage::Simple { .. } => panic!("Unexpected simple message"),
+            })
+            .collect();
+        assert_eq!(flags, vec![(true, false), (false, true)]);
+        let history = store.history("ECHOECHO", 10).await.unwrap();
+        let texts: Vec<_> = history.iter().map(|message| message.text()).collect();
+        assert_eq!(texts, vec![Some("hi"), Some("hi!")]);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_send_file_credits_check() {
+        use crate::{mock_server::MockServer, FileData};
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_public_key("ECHOECHO", RecipientKey::from([2; 32]));
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key_str(PRIVATE_KEY)
+            .unwrap()
+            .with_credits_check(true)
+            .into_e2e()
+            .unwrap();
+        let file = FileData {
+            file: vec![1, 2, 3],
+            thumbnail: Some(vec![4, 5, 6]),
+        };
+        api.send_file(
+            "ECHOECHO",
+            &RecipientKey::from([2; 32]),
+            file,
+            FileSendOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let requests = server.requests();
+        let credits_requests = requests
+            .iter()
+            .filter(|request| *request == "GET /credits")
+            .count();
+        assert_eq!(credits_requests, 1);
+        assert_eq!(requests.len(), 4);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_send_image_credits() {
+        use crate::mock_server::MockServer;
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_public_key("ECHOECHO", RecipientKey::from([2; 32]));
+        server.set_credits(2);
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key_str(PRIVATE_KEY)
+            .unwrap()
+            .with_credits_check(true)
+            .into_e2e()
+            .unwrap();
+        let recipient_key = RecipientKey::from([2; 32]);
+
+        // The capabilities lookup costs an additional credit
+        assert!(matches!(
+            api.send_image("ECHOECHO", &recipient_key, vec![0xff, 0xd8])
+                .await
+                .map_err(ApiError::into_inner),
+            Err(ApiError::InsufficientCredits {
+                required: 3,
+                available: 2
+            })
+        ));
+        let capabilities = Capabilities {
+            file: true,
+            ..Default::default()
+        };
+        api.send_image_with_capabilities(
+            "ECHOECHO",
+            &recipient_key,
+            vec![0xff, 0xd8],
+            &capabilities,
+        )
+        .await
+        .unwrap();
+        assert_eq!(server.credits(), 0);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_send_options() {
+        use crate::mock_server::{MockServer, ReceivedMessage};
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let recipient_key = RecipientKey::from([2; 32]);
+        server.set_public_key("ECHOECHO", recipient_key.clone());
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap();
+
+        let msg = api.encrypt_text_msg("hello", &recipient_key).unwrap();
+        let options = SendOptions {
+            no_push: true,
+            group: true,
+            ..Default::default()
+        };
+        api.send_with_options("ECHOECHO", &msg, options)
+            .await
+            .unwrap();
+        match &server.received_messages()[..] {
+            [ReceivedMessage::E2e {
+                delivery_receipts,
+                no_push,
+                group,
+                ..
+            }] => {
+                assert!(!delivery_receipts);
+                assert!(no_push);
+                assert!(group);
+            }
+            other => panic!("Unexpected messages: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_blob_upload_many() {
+        use crate::mock_server::MockServer;
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .with_credits_check(true)
+            .into_e2e()
+            .unwrap();
+        let items: Vec<Bytes> = vec![vec![1; 10].into(), Bytes::new(), vec![3; 30].into()];
+        let results = api
+            .blob_upload_many(items.clone(), false, NonZeroUsize::new(2).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            server.blob(results[0].as_ref().unwrap()).unwrap(),
+            vec![1; 10]
+        );
+        assert!(matches!(results[1], Err(ref e) if matches!(e.inner(), ApiError::BadBlob)));
+        assert_eq!(
+            server.blob(results[2].as_ref().unwrap()).unwrap(),
+            vec![3; 30]
+        );
+
+        // A failed pre-check is returned once
+        server.set_credits(2);
+        let err = api
+            .blob_upload_many(items, false, NonZeroUsize::new(2).unwrap())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ApiError::InsufficientCredits {
+                required: 3,
+                available: 2
+            }
+        ));
+    }
+}
@@ -0,0 +1,323 @@
+//! Audit trail of the gateway operations.
+//!
+//! An [`AuditSink`] registered with `with_audit_sink` on an API object is
+//! notified about every send, lookup, blob transfer and decoded incoming
+//! message. The events contain no message content, and the identifiers of
+//! the involved parties (Threema IDs, phone numbers, e-mail addresses) are
+//! replaced by an HMAC-SHA256 hash keyed with the API secret. This allows
+//! correlating the events of a party without keeping the identifier itself.
+//!
+//! [`JsonLinesAuditSink`] writes the events as JSON lines to a file:
+//!
+//! ```no_run
+//! use threema_gateway::{audit::JsonLinesAuditSink, ApiBuilder};
+//!
+//! let api = ApiBuilder::new("*3MAGWID", "secret")
+//!     .into_simple()
+//!     .unwrap()
+//!     .with_audit_sink(JsonLinesAuditSink::open("audit.jsonl").unwrap());
+//! ```
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use crate::{errors::ApiError, lookup::hmac_hex};
+
+/// The kind of an audited operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    /// A message was sent.
+    Send,
+    /// A Threema ID, public key, capabilities or the credits were looked up.
+    Lookup,
+    /// A blob was uploaded.
+    BlobUpload,
+    /// A blob was downloaded.
+    BlobDownload,
+    /// An incoming message was decoded.
+    Receive,
+}
+
+/// An audited operation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEvent {
+    /// When the operation finished (UNIX timestamp).
+    pub timestamp: u64,
+    /// The kind of operation.
+    pub operation: AuditOperation,
+    /// The hashed identifier the operation refers to (the recipient, sender,
+    /// lookup criterion or blob ID), as hex encoded HMAC-SHA256 keyed with
+    /// the API secret.
+    pub subject: Option<String>,
+    /// The ID of the sent or received message.
+    pub message_id: Option<String>,
+    /// The kind of error (the [`ApiError`] variant name) if the operation
+    /// failed.
+    pub error: Option<String>,
+}
+
+/// Receiver of the [`AuditEvent`]s of an API object.
+///
+/// The sink is called synchronously after every operation, so it should
+/// not block for long.
+pub trait AuditSink: Send + Sync {
+    /// Record an event.
+    fn record(&self, event: &AuditEvent);
+}
+
+impl<S: AuditSink + ?Sized> AuditSink for Arc<S> {
+    fn record(&self, event: &AuditEvent) {
+        (**self).record(event)
+    }
+}
+
+/// An [`AuditSink`] that writes every event as a line of JSON.
+///
+/// The writer is flushed after every event. Write errors are logged and
+/// otherwise ignored, so that auditing never fails an operation.
+#[derive(Debug)]
+pub struct JsonLinesAuditSink<W = File> {
+    writer: Mutex<W>,
+}
+
+impl JsonLinesAuditSink {
+    /// Append the events to the file at `path`, creating it if necessary.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+}
+
+impl<W: Write + Send> JsonLinesAuditSink<W> {
+    /// Write the events to `writer`.
+    pub fn new(writer: W) -> Self {
+        JsonLinesAuditSink {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Return the writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<W: Write + Send> AuditSink for JsonLinesAuditSink<W> {
+    fn record(&self, event: &AuditEvent) {
+        let mut line = match serde_json::to_vec(event) {
+            Ok(line) => line,
+            Err(e) => return warn!("Could not serialize audit event: {}", e),
+        };
+        line.push(b'\n');
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = writer.write_all(&line).and_then(|()| writer.flush()) {
+            warn!("Could not write audit event: {}", e);
+        }
+    }
+}
+
+/// Return the name of the variant of `error` (without the request ID
+/// wrapper), without its fields, which may contain identifiers.
+fn error_kind(error: &ApiError) -> &'static str {
+    match error {
+        ApiError::BadSenderOrRecipient => "BadSenderOrRecipient",
+        ApiError::BadCredentials => "BadCredentials",
+        ApiError::NoCredits => "NoCredits",
+        ApiError::InsufficientCredits { .. } => "InsufficientCredits",
+        ApiError::IdNotFound => "IdNotFound",
+        ApiError::MessageTooLong => "MessageTooLong",
+        ApiError::ServerError => "ServerError",
+        ApiError::BadHashLength => "BadHashLength",
+        ApiError::BadBlob => "BadBlob",
+        ApiError::BadBlobId => "BadBlobId",
+        ApiError::BadMessageId => "BadMessageId",
+        ApiError::BlobTooLarge { .. } => "BlobTooLarge",
+        ApiError::UnexpectedRecipient(_) => "UnexpectedRecipient",
+        ApiError::BodyTooLarge { .. } => "BodyTooLarge",
+        ApiError::InvalidMac => "InvalidMac",
+        ApiError::CryptoError(_) => "CryptoError",
+        ApiError::InvalidFileMessage(_) => "InvalidFileMessage",
+        ApiError::InvalidTemplate(_) => "InvalidTemplate",
+        ApiError::DeadlineExceeded => "DeadlineExceeded",
+        ApiError::RequestError(_) => "RequestError",
+        ApiError::IoError(_) => "IoError",
+        ApiError::ParseError(_) => "ParseError",
+        ApiError::Deferred(_) => "Deferred",
+        ApiError::SendPending(_) => "SendPending",
+        ApiError::Other(_) => "Other",
+        ApiError::Request { error, .. } => error_kind(error),
+    }
+}
+
+/// An [`AuditSink`] with the key for hashing the identifiers.
+#[derive(Clone)]
+pub(crate) struct Auditor {
+    sink: Arc<dyn AuditSink>,
+    key: Arc<[u8]>,
+}
+
+impl Auditor {
+    pub(crate) fn new<S: AuditSink + 'static>(sink: S, secret: &str) -> Self {
+        Auditor {
+            sink: Arc::new(sink),
+            key: secret.as_bytes().into(),
+        }
+    }
+
+    /// Record an operation.
+    pub(crate) fn record(
+        &self,
+        operation: AuditOperation,
+        subject: Option<&str>,
+        message_id: Option<String>,
+        error: Option<&ApiError>,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        self.sink.record(&AuditEvent {
+            timestamp,
+            operation,
+            subject: subject.map(|subject| hmac_hex(&self.key, subject)),
+            message_id,
+            error: error.map(|e| error_kind(e).to_string()),
+        });
+    }
+}
+
+impl fmt::Debug for Auditor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Auditor")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_lines() {
+        let sink = Arc::new(JsonLinesAuditSink::new(Vec::new()));
+        let auditor = Auditor::new(sink.clone(), "secret");
+        auditor.record(
+            AuditOperation::Send,
+            Some("ECHOECHO"),
+            Some("0102030405060708".into()),
+            None,
+        );
+        auditor.record(
+            AuditOperation::Lookup,
+            Some("ECHOECHO"),
+            None,
+            Some(&ApiError::BadHashLength),
+        );
+        auditor.record(
+            AuditOperation::BlobDownload,
+            None,
+            None,
+            Some(&ApiError::Other("ECHOECHO".into())),
+        );
+
+        drop(auditor);
+        let sink = Arc::try_unwrap(sink).unwrap();
+        let output = String::from_utf8(sink.into_inner()).unwrap();
+        assert!(!output.contains("ECHOECHO"));
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["operation"], "send");
+        assert_eq!(events[0]["subject"], hmac_hex(b"secret", "ECHOECHO"));
+        assert_eq!(events[0]["message_id"], "0102030405060708");
+        assert_eq!(events[0]["error"], serde_json::Value::Null);
+        assert_eq!(events[1]["operation"], "lookup");
+        assert_eq!(events[1]["subject"], events[0]["subject"]);
+        assert_eq!(events[1]["error"], "BadHashLength");
+        assert_eq!(events[2]["operation"], "blob_download");
+        assert_eq!(events[2]["subject"], serde_json::Value::Null);
+        assert_eq!(events[2]["error"], "Other");
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let error = ApiError::InsufficientCredits {
+            required: 2,
+            available: 1,
+        };
+        assert_eq!(error_kind(&error), "InsufficientCredits");
+        let error = ApiError::UnexpectedRecipient("ECHOECHO".into());
+        assert_eq!(error_kind(&error), "UnexpectedRecipient");
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_audit_sink() {
+        use crate::{
+            mock_server::MockServer, ApiBuilder, LookupCriterion, RecipientKey, SecretKey,
+        };
+
+        struct Events(Mutex<Vec<AuditEvent>>);
+
+        impl AuditSink for Events {
+            fn record(&self, event: &AuditEvent) {
+                self.0.lock().unwrap().push(event.clone());
+            }
+        }
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_public_key("ECHOECHO", RecipientKey::from([2; 32]));
+        let events = Arc::new(Events(Mutex::default()));
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap()
+            .with_audit_sink(events.clone());
+
+        let result = api.send_text("ECHOECHO", "hi").await.unwrap();
+        let blob_id = api.blob_upload_raw(&[1, 2, 3], false).await.unwrap();
+        api.blob_download(&blob_id).await.unwrap();
+        assert!(api
+            .lookup_id(&LookupCriterion::Email("nobody@example.com".into()))
+            .await
+            .is_err());
+
+        let events = events.0.lock().unwrap();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|event| (event.operation, event.error.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (AuditOperation::Lookup, None),
+                (AuditOperation::Send, None),
+                (AuditOperation::BlobUpload, None),
+                (AuditOperation::BlobDownload, None),
+                (AuditOperation::Lookup, Some("IdNotFound")),
+            ]
+        );
+        assert_eq!(events[0].subject, events[1].subject);
+        assert_eq!(events[2].subject, events[3].subject);
+        assert_eq!(events[1].message_id, Some(result.message_id.to_string()));
+        let subject = events[1].subject.as_deref().unwrap();
+        assert_eq!(subject.len(), 64);
+        assert!(!subject.contains("ECHOECHO"));
+    }
+}
@@ -0,0 +1,200 @@
+//! Threema ID backups.
+//!
+//! An ID backup is a password protected export of a Threema identity and its
+//! private key, in the same format as used by the Threema apps. It looks like
+//! this:
+//!
+//! ```text
+//! XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX-XXXX
+//! ```
+//!
+//! The backup consists of a random 8 byte salt, followed by the identity, the
+//! private key and a 2 byte checksum, encrypted with XSalsa20. The encryption
+//! key is derived from the password using PBKDF2-HMAC-SHA256.
+
+use data_encoding::BASE32_NOPAD;
+use rand::{rngs::OsRng, RngCore};
+use salsa20::{
+    cipher::{KeyIvInit, StreamCipher},
+    XSalsa20,
+};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::{errors::IdBackupError, SecretKey};
+
+const SALT_SIZE: usize = 8;
+const ID_SIZE: usize = 8;
+const KEY_SIZE: usize = 32;
+const CHECKSUM_SIZE: usize = 2;
+const PLAINTEXT_SIZE: usize = ID_SIZE + KEY_SIZE + CHECKSUM_SIZE;
+const BACKUP_SIZE: usize = SALT_SIZE + PLAINTEXT_SIZE;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// A Threema identity together with its private key, as contained in an ID
+/// backup.
+#[derive(Debug, Clone)]
+pub struct IdBackup {
+    /// The Threema ID (8 characters).
+    pub id: String,
+    /// The private key of the identity.
+    pub private_key: SecretKey,
+}
+
+impl IdBackup {
+    /// Create a new `IdBackup` from an identity and a private key.
+    pub fn new<I: Into<String>>(id: I, private_key: SecretKey) -> Self {
+        IdBackup {
+            id: id.into(),
+            private_key,
+        }
+    }
+
+    /// Encrypt this identity with the specified password and return the
+    /// encoded backup string.
+    ///
+    /// The password must be at least 8 characters long.
+    pub fn encode(&self, password: &str) -> Result<String, IdBackupError> {
+        if self.id.len() != ID_SIZE || !self.id.is_ascii() {
+            return Err(IdBackupError::InvalidId(self.id.clone()));
+        }
+        if password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(IdBackupError::PasswordTooShort);
+        }
+
+        let mut backup = Zeroizing::new([0u8; BACKUP_SIZE]);
+        let (salt, data) = backup.split_at_mut(SALT_SIZE);
+        OsRng.fill_bytes(salt);
+
+        // Identity, private key and checksum
+        data[..ID_SIZE].copy_from_slice(self.id.as_bytes());
+        data[ID_SIZE..ID_SIZE + KEY_SIZE].copy_from_slice(&self.private_key.to_bytes());
+        let checksum = Sha256::digest(&data[..ID_SIZE + KEY_SIZE]);
+        data[ID_SIZE + KEY_SIZE..].copy_from_slice(&checksum[..CHECKSUM_SIZE]);
+
+        apply_keystream(password, salt, data);
+
+        Ok(group(&BASE32_NOPAD.encode(&backup[..])))
+    }
+
+    /// Decode and decrypt an ID backup string with the specified password.
+    ///
+    /// Dashes and whitespace in the backup string are ignored.
+    pub fn decode(backup: &str, password: &str) -> Result<Self, IdBackupError> {
+        let encoded: String = backup
+            .chars()
+            .filter(|c| *c != '-' && !c.is_whitespace())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        let mut decoded = Zeroizing::new(BASE32_NOPAD.decode(encoded.as_bytes()).map_err(|e| {
+            IdBackupError::InvalidFormat(format!("Could not decode base32 string: {}", e))
+        })?);
+        if decoded.len() != BACKUP_SIZE {
+            return Err(IdBackupError::InvalidFormat(format!(
+                "Expected {} bytes, but got {}",
+                BACKUP_SIZE,
+                decoded.len()
+            )));
+        }
+
+        let (salt, data) = decoded.split_at_mut(SALT_SIZE);
+        apply_keystream(password, salt, data);
+
+        // Verify checksum
+        let checksum = Sha256::digest(&data[..ID_SIZE + KEY_SIZE]);
+        if checksum[..CHECKSUM_SIZE] != data[ID_SIZE + KEY_SIZE..] {
+            return Err(IdBackupError::DecryptionFailed);
+        }
+
+        let id = std::str::from_utf8(&data[..ID_SIZE])
+            .map_err(|_| {
+                IdBackupError::InvalidId(String::from_utf8_lossy(&data[..ID_SIZE]).into())
+            })?
+            .to_string();
+        let mut key_bytes = Zeroizing::new([0u8; KEY_SIZE]);
+        key_bytes.copy_from_slice(&data[ID_SIZE..ID_SIZE + KEY_SIZE]);
+        let private_key = SecretKey::from(*key_bytes);
+
+        Ok(IdBackup { id, private_key })
+    }
+}
+
+/// Derive the backup key from the password and salt and en- or decrypt the
+/// data in place.
+fn apply_keystream(password: &str, salt: &[u8], data: &mut [u8]) {
+    let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PBKDF2_ITERATIONS, &mut key[..]);
+    let mut cipher = XSalsa20::new(key.as_ref().into(), &[0u8; 24].into());
+    cipher.apply_keystream(data);
+}
+
+/// Split the encoded backup into groups of four characters, separated by
+/// dashes.
+fn group(encoded: &str) -> String {
+    encoded
+        .as_bytes()
+        .chunks(4)
+        .map(|chunk| std::str::from_utf8(chunk).expect("base32 is ASCII"))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    fn private_key() -> SecretKey {
+        SecretKey::from([42u8; KEY_SIZE])
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let backup = IdBackup::new("*3MAGWID", private_key())
+            .encode(PASSWORD)
+            .unwrap();
+        assert_eq!(backup.len(), 80 + 19);
+        assert_eq!(backup.split('-').count(), 20);
+
+        let decoded = IdBackup::decode(&backup.to_lowercase(), PASSWORD).unwrap();
+        assert_eq!(decoded.id, "*3MAGWID");
+        assert_eq!(decoded.private_key, private_key());
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let backup = IdBackup::new("ECHOECHO", private_key())
+            .encode(PASSWORD)
+            .unwrap();
+        let err = IdBackup::decode(&backup, "wrong password").unwrap_err();
+        assert_eq!(err, IdBackupError::DecryptionFailed);
+    }
+
+    #[test]
+    fn test_encode_invalid() {
+        let backup = IdBackup::new("ECHO", private_key());
+        assert_eq!(
+            backup.encode(PASSWORD).unwrap_err(),
+            IdBackupError::InvalidId("ECHO".into())
+        );
+        let backup = IdBackup::new("ECHOECHO", private_key());
+        assert_eq!(
+            backup.encode("short").unwrap_err(),
+            IdBackupError::PasswordTooShort
+        );
+    }
+
+    #[test]
+    fn test_decode_invalid_format() {
+        assert!(matches!(
+            IdBackup::decode("ABCD-EFGH", PASSWORD),
+            Err(IdBackupError::InvalidFormat(_))
+        ));
+        assert!(matches!(
+            IdBackup::decode("1111-1111", PASSWORD),
+            Err(IdBackupError::InvalidFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,235 @@
+use std::{env, ffi::OsStr, fs, path::Path, process};
+
+use docopt::{ArgvMap, Docopt};
+use threema_gateway::{
+    ApiBuilder, BlobId, E2eApi, FileData, FileSendOptions, LookupCriterion, Recipient,
+    RenderingType, SimpleApi,
+};
+
+const USAGE: &str = "
+Command line client for the Threema Gateway.
+
+Usage:
+    threema-gateway [options] send-simple <to> <text>
+    threema-gateway [options] send-text <to> <text>
+    threema-gateway [options] send-file [--thumbnail=<path>] [--caption=<caption>] [--rendering-type=<type>] <to> <path>
+    threema-gateway [options] lookup-id (phone | phone-hash | email | email-hash) <value>
+    threema-gateway [options] lookup-pubkey <id>
+    threema-gateway [options] lookup-capabilities <id>
+    threema-gateway [options] credits
+    threema-gateway [options] blob-upload [--persist] <path>
+    threema-gateway [options] blob-download <blob-id> <path>
+    threema-gateway [options] decode <request-body>
+    threema-gateway (-h | --help)
+
+Credentials can also be passed in through the environment variables
+THREEMA_GATEWAY_ID, THREEMA_GATEWAY_SECRET and THREEMA_GATEWAY_PRIVATE_KEY.
+
+The recipient of send-simple can be a Threema ID, an e-mail address or a
+phone number.
+
+Options:
+    --from <id>               The gateway ID
+    --secret <secret>         The API secret
+    --private-key <key>       The hex encoded private key (E2E commands only)
+    --endpoint <url>          Use a custom API endpoint
+    --allow-insecure-http     Allow a plain HTTP endpoint
+    --thumbnail <path>        Path to a JPEG thumbnail
+    --caption <caption>       Caption of the file
+    --rendering-type <type>   Rendering type (file, media or sticker)
+    --persist                 Don't delete the blob after download
+    -h, --help                Show this help
+";
+
+/// Try or exit.
+macro_rules! etry {
+    ($result:expr, $msg:expr) => {{
+        $result.unwrap_or_else(|e| {
+            eprintln!("{}: {}", $msg, e);
+            process::exit(1);
+        })
+    }};
+}
+
+/// Return the value of the option, or of the environment variable if the
+/// option was not passed in.
+fn arg_or_env(args: &ArgvMap, arg: &str, var: &str) -> Option<String> {
+    match args.get_str(arg) {
+        "" => env::var(var).ok(),
+        value => Some(value.to_string()),
+    }
+}
+
+fn builder(args: &ArgvMap) -> ApiBuilder {
+    let from = arg_or_env(args, "--from", "THREEMA_GATEWAY_ID").unwrap_or_else(|| {
+        eprintln!("Missing gateway ID (--from)");
+        process::exit(1);
+    });
+    let secret = arg_or_env(args, "--secret", "THREEMA_GATEWAY_SECRET").unwrap_or_else(|| {
+        eprintln!("Missing API secret (--secret)");
+        process::exit(1);
+    });
+    let mut builder = ApiBuilder::new(from, secret);
+    if args.get_bool("--allow-insecure-http") {
+        builder = builder.allow_insecure_http();
+    }
+    match args.get_str("--endpoint") {
+        "" => builder,
+        endpoint => builder.with_custom_endpoint(endpoint.to_string()),
+    }
+}
+
+fn simple_api(args: &ArgvMap) -> SimpleApi {
+    etry!(builder(args).into_simple(), "Invalid configuration")
+}
+
+fn e2e_api(args: &ArgvMap) -> E2eApi {
+    let private_key = arg_or_env(args, "--private-key", "THREEMA_GATEWAY_PRIVATE_KEY")
+        .unwrap_or_else(|| {
+            eprintln!("Missing private key (--private-key)");
+            process::exit(1);
+        });
+    etry!(
+        builder(args)
+            .with_private_key_str(&private_key)
+            .and_then(|builder| builder.into_e2e()),
+        "Invalid configuration"
+    )
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args = Docopt::new(USAGE)
+        .and_then(|docopt| docopt.parse())
+        .unwrap_or_else(|e| e.exit());
+
+    if args.get_bool("send-simple") {
+        let api = simple_api(&args);
+        let to: Recipient = etry!(args.get_str("<to>").parse(), "Invalid recipient");
+        let result = etry!(
+            api.send(&to, args.get_str("<text>")).await,
+            "Could not send message"
+        );
+        println!("{}", result.message_id);
+    } else if args.get_bool("send-text") {
+        let api = e2e_api(&args);
+        let result = etry!(
+            api.send_text(args.get_str("<to>"), args.get_str("<text>"))
+                .await,
+            "Could not send message"
+        );
+        println!("{}", result.message_id);
+    } else if args.get_bool("send-file") {
+        let api = e2e_api(&args);
+        let to = args.get_str("<to>");
+        let path = Path::new(args.get_str("<path>"));
+        let rendering_type = match args.get_str("--rendering-type") {
+            "" | "file" => RenderingType::File,
+            "media" => RenderingType::Media,
+            "sticker" => RenderingType::Sticker,
+            other => {
+                eprintln!("Invalid rendering type: {}", other);
+                process::exit(1);
+            }
+        };
+        let thumbnail = match args.get_str("--thumbnail") {
+            "" => None,
+            p => Some(etry!(fs::read(p), "Could not read thumbnail")),
+        };
+        let data = FileData {
+            file: etry!(fs::read(path), "Could not read file"),
+            thumbnail,
+        };
+        let options = FileSendOptions {
+            media_type: Some(
+                mime_guess::from_path(path)
+                    .first_or_octet_stream()
+                    .to_string(),
+            ),
+            file_name: path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .map(ToOwned::to_owned),
+            description: match args.get_str("--caption") {
+                "" => None,
+                c => Some(c.to_string()),
+            },
+            rendering_type,
+            ..Default::default()
+        };
+        let recipient_key = etry!(api.lookup_pubkey(to).await, "Could not fetch public key");
+        let result = etry!(
+            api.send_file(to, &recipient_key, data, options).await,
+            "Could not send file"
+        );
+        println!("{}", result.message_id);
+    } else if args.get_bool("lookup-id") {
+        let api = simple_api(&args);
+        let value = args.get_str("<value>").to_string();
+        let criterion = if args.get_bool("phone") {
+            LookupCriterion::Phone(value)
+        } else if args.get_bool("phone-hash") {
+            LookupCriterion::PhoneHash(value)
+        } else if args.get_bool("email") {
+            LookupCriterion::Email(value)
+        } else {
+            LookupCriterion::EmailHash(value)
+        };
+        let id = etry!(api.lookup_id(&criterion).await, "Could not look up ID");
+        println!("{}", id);
+    } else if args.get_bool("lookup-pubkey") {
+        let api = simple_api(&args);
+        let key = etry!(
+            api.lookup_pubkey(args.get_str("<id>")).await,
+            "Could not fetch public key"
+        );
+        println!("{}", key.to_hex_string());
+    } else if args.get_bool("lookup-capabilities") {
+        let api = simple_api(&args);
+        let capabilities = etry!(
+            api.lookup_capabilities(args.get_str("<id>")).await,
+            "Could not fetch capabilities"
+        );
+        println!("{}", capabilities);
+    } else if args.get_bool("credits") {
+        let api = simple_api(&args);
+        let credits = etry!(api.lookup_credits().await, "Could not fetch credits");
+        println!("{}", credits);
+    } else if args.get_bool("blob-upload") {
+        let api = e2e_api(&args);
+        let data = etry!(fs::read(args.get_str("<path>")), "Could not read file");
+        let blob_id = etry!(
+            api.blob_upload_raw(&data, args.get_bool("--persist")).await,
+            "Could not upload blob"
+        );
+        println!("{}", blob_id);
+    } else if args.get_bool("blob-download") {
+        let api = e2e_api(&args);
+        let blob_id: BlobId = etry!(args.get_str("<blob-id>").parse(), "Invalid blob ID");
+        let data = etry!(api.blob_download(&blob_id).await, "Could not download blob");
+        etry!(
+            fs::write(args.get_str("<path>"), data),
+            "Could not write file"
+        );
+    } else if args.get_bool("decode") {
+        let api = e2e_api(&args);
+        let msg = etry!(
+            api.decode_incoming_message(args.get_str("<request-body>")),
+            "Could not decode incoming message"
+        );
+        println!("From: {}", msg.from);
+        println!("To: {}", msg.to);
+        println!("Message ID: {}", msg.message_id);
+        println!("Timestamp: {}", msg.date);
+        println!("Sender nickname: {:?}", msg.nickname);
+        let sender_key = etry!(
+            api.lookup_pubkey(&msg.from).await,
+            "Could not fetch public key"
+        );
+        let data = etry!(
+            api.decrypt_incoming_message(&msg, &sender_key),
+            "Could not decrypt box"
+        );
+        println!("Decrypted box: {:?}", data);
+    }
+}
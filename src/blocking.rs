@@ -0,0 +1,514 @@
+//! Blocking (synchronous) wrappers around the API objects.
+//!
+//! The types in this module wrap the async [`SimpleApi`](crate::SimpleApi)
+//! and [`E2eApi`](crate::E2eApi) and drive their methods to completion on an
+//! internal single-threaded tokio runtime. This is useful for scripts and
+//! synchronous applications that don't want to set up an async runtime
+//! themselves.
+//!
+//! Note: The blocking methods must not be called from within an async
+//! context, since this would block (and panic inside) the surrounding
+//! runtime.
+//!
+//! ```no_run
+//! use threema_gateway::{blocking, ApiBuilder, Recipient};
+//!
+//! let api = ApiBuilder::new("*3MAGWID", "hihghrg98h00ghrg").into_simple().unwrap();
+//! let api = blocking::SimpleApi::new(api).unwrap();
+//! let msg_id = api.send(&Recipient::new_id("ECHOECHO"), "Hello!").unwrap();
+//! ```
+
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use reqwest::{header::HeaderMap, StatusCode};
+use tokio::runtime::{Builder, Runtime};
+
+#[cfg(feature = "receive")]
+use crate::receive::IncomingMessage;
+use crate::{
+    audit::AuditSink,
+    cache::PublicKeyCache,
+    connection::Recipient,
+    credits::{CreditReconciliation, CreditUsage},
+    crypto::{EncryptedMessage, RecipientKey},
+    endpoint::EndpointStatus,
+    errors::{ApiError, ApiOrCacheError, CryptoError},
+    lookup::{Capabilities, LookupCriterion},
+    reporting::ErrorReporter,
+    types::{
+        BlobId, FileMessage, FileSendOptions, FileSource, MessageType, SendOptions, SendResult,
+    },
+    PublicKey,
+};
+
+/// Create the runtime used to drive the async methods.
+fn make_runtime() -> Result<Arc<Runtime>, ApiError> {
+    Ok(Arc::new(
+        Builder::new_current_thread().enable_all().build()?,
+    ))
+}
+
+/// Implement the blocking variants of the methods available on both the
+/// simple and the e2e API objects.
+macro_rules! impl_common_functionality {
+    () => {
+        /// Blocking variant of
+        /// [`SimpleApi::lookup_pubkey`](crate::SimpleApi::lookup_pubkey).
+        pub fn lookup_pubkey(&self, id: &str) -> Result<RecipientKey, ApiError> {
+            self.block_on(self.inner.lookup_pubkey(id))
+        }
+
+        /// Blocking variant of
+        /// [`SimpleApi::lookup_pubkey_with_cache`](crate::SimpleApi::lookup_pubkey_with_cache).
+        pub fn lookup_pubkey_with_cache<C>(
+            &self,
+            id: &str,
+            public_key_cache: &C,
+        ) -> Result<RecipientKey, ApiOrCacheError<C::Error>>
+        where
+            C: PublicKeyCache,
+        {
+            self.block_on(self.inner.lookup_pubkey_with_cache(id, public_key_cache))
+        }
+
+        /// Blocking variant of
+        /// [`SimpleApi::lookup_id`](crate::SimpleApi::lookup_id).
+        pub fn lookup_id(&self, criterion: &LookupCriterion) -> Result<String, ApiError> {
+            self.block_on(self.inner.lookup_id(criterion))
+        }
+
+        /// Blocking variant of
+        /// [`SimpleApi::lookup_capabilities`](crate::SimpleApi::lookup_capabilities).
+        pub fn lookup_capabilities(&self, id: &str) -> Result<Capabilities, ApiError> {
+            self.block_on(self.inner.lookup_capabilities(id))
+        }
+
+        /// Blocking variant of
+        /// [`SimpleApi::lookup_credits`](crate::SimpleApi::lookup_credits).
+        pub fn lookup_credits(&self) -> Result<i64, ApiError> {
+            self.block_on(self.inner.lookup_credits())
+        }
+
+        /// See
+        /// [`SimpleApi::with_deadline`](crate::SimpleApi::with_deadline).
+        pub fn with_deadline(&self, deadline: Instant) -> Self {
+            Self {
+                inner: self.inner.with_deadline(deadline),
+                runtime: self.runtime.clone(),
+            }
+        }
+
+        /// See
+        /// [`SimpleApi::with_timeout`](crate::SimpleApi::with_timeout).
+        pub fn with_timeout(&self, timeout: Duration) -> Self {
+            Self {
+                inner: self.inner.with_timeout(timeout),
+                runtime: self.runtime.clone(),
+            }
+        }
+
+        /// See
+        /// [`SimpleApi::endpoint_status`](crate::SimpleApi::endpoint_status).
+        pub fn endpoint_status(&self) -> Vec<EndpointStatus> {
+            self.inner.endpoint_status()
+        }
+
+        /// See [`SimpleApi::on_send`](crate::SimpleApi::on_send).
+        pub fn on_send<F>(self, callback: F) -> Self
+        where
+            F: Fn(&Recipient<'_>, &SendResult) + Send + Sync + 'static,
+        {
+            Self {
+                inner: self.inner.on_send(callback),
+                runtime: self.runtime,
+            }
+        }
+
+        /// See [`SimpleApi::on_error`](crate::SimpleApi::on_error).
+        pub fn on_error<F>(self, callback: F) -> Self
+        where
+            F: Fn(&Recipient<'_>, &ApiError) + Send + Sync + 'static,
+        {
+            Self {
+                inner: self.inner.on_error(callback),
+                runtime: self.runtime,
+            }
+        }
+
+        /// See [`SimpleApi::on_response`](crate::SimpleApi::on_response).
+        pub fn on_response<F>(self, callback: F) -> Self
+        where
+            F: Fn(StatusCode, &HeaderMap) + Send + Sync + 'static,
+        {
+            Self {
+                inner: self.inner.on_response(callback),
+                runtime: self.runtime,
+            }
+        }
+
+        /// See
+        /// [`SimpleApi::with_audit_sink`](crate::SimpleApi::with_audit_sink).
+        pub fn with_audit_sink<S: AuditSink + 'static>(self, sink: S) -> Self {
+            Self {
+                inner: self.inner.with_audit_sink(sink),
+                runtime: self.runtime,
+            }
+        }
+
+        /// See
+        /// [`SimpleApi::with_error_reporter`](crate::SimpleApi::with_error_reporter).
+        pub fn with_error_reporter<R: ErrorReporter + 'static>(self, reporter: R) -> Self {
+            Self {
+                inner: self.inner.with_error_reporter(reporter),
+                runtime: self.runtime,
+            }
+        }
+
+        /// See
+        /// [`SimpleApi::credit_usage`](crate::SimpleApi::credit_usage).
+        pub fn credit_usage(&self) -> CreditUsage {
+            self.inner.credit_usage()
+        }
+
+        /// See
+        /// [`SimpleApi::reset_credit_usage`](crate::SimpleApi::reset_credit_usage).
+        pub fn reset_credit_usage(&self) -> CreditUsage {
+            self.inner.reset_credit_usage()
+        }
+
+        /// Blocking variant of
+        /// [`SimpleApi::reconcile_credits`](crate::SimpleApi::reconcile_credits).
+        pub fn reconcile_credits(&self) -> Result<CreditReconciliation, ApiError> {
+            self.block_on(self.inner.reconcile_credits())
+        }
+
+        fn block_on<F: Future>(&self, future: F) -> F::Output {
+            self.runtime.block_on(future)
+        }
+    };
+}
+
+/// Blocking variant of [`SimpleApi`](crate::SimpleApi).
+#[derive(Debug, Clone)]
+pub struct SimpleApi {
+    inner: crate::SimpleApi,
+    runtime: Arc<Runtime>,
+}
+
+impl SimpleApi {
+    /// Wrap an async [`SimpleApi`](crate::SimpleApi).
+    ///
+    /// This fails if the internal runtime cannot be created.
+    pub fn new(api: crate::SimpleApi) -> Result<Self, ApiError> {
+        Ok(SimpleApi {
+            inner: api,
+            runtime: make_runtime()?,
+        })
+    }
+
+    /// Return a reference to the wrapped async API object.
+    pub fn inner(&self) -> &crate::SimpleApi {
+        &self.inner
+    }
+
+    /// Blocking variant of [`SimpleApi::send`](crate::SimpleApi::send).
+    pub fn send(&self, to: &Recipient<'_>, text: &str) -> Result<SendResult, ApiError> {
+        self.block_on(self.inner.send(to, text))
+    }
+
+    impl_common_functionality!();
+}
+
+/// Blocking variant of [`E2eApi`](crate::E2eApi).
+#[derive(Debug, Clone)]
+pub struct E2eApi {
+    inner: crate::E2eApi,
+    runtime: Arc<Runtime>,
+}
+
+impl E2eApi {
+    /// Wrap an async [`E2eApi`](crate::E2eApi).
+    ///
+    /// This fails if the internal runtime cannot be created.
+    pub fn new(api: crate::E2eApi) -> Result<Self, ApiError> {
+        Ok(E2eApi {
+            inner: api,
+            runtime: make_runtime()?,
+        })
+    }
+
+    /// Return a reference to the wrapped async API object.
+    pub fn inner(&self) -> &crate::E2eApi {
+        &self.inner
+    }
+
+    /// See [`E2eApi::public_key`](crate::E2eApi::public_key).
+    pub fn public_key(&self) -> PublicKey {
+        self.inner.public_key()
+    }
+
+    /// See [`E2eApi::public_key_hex`](crate::E2eApi::public_key_hex).
+    pub fn public_key_hex(&self) -> String {
+        self.inner.public_key_hex()
+    }
+
+    /// See [`E2eApi::qr_payload`](crate::E2eApi::qr_payload).
+    pub fn qr_payload(&self) -> String {
+        self.inner.qr_payload()
+    }
+
+    /// See [`E2eApi::encrypt_text_msg`](crate::E2eApi::encrypt_text_msg).
+    pub fn encrypt_text_msg(
+        &self,
+        text: &str,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        self.inner.encrypt_text_msg(text, recipient_key)
+    }
+
+    /// See [`E2eApi::encrypt_image_msg`](crate::E2eApi::encrypt_image_msg).
+    pub fn encrypt_image_msg(
+        &self,
+        blob_id: &BlobId,
+        img_size_bytes: u32,
+        image_data_nonce: &crate::Nonce,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        self.inner
+            .encrypt_image_msg(blob_id, img_size_bytes, image_data_nonce, recipient_key)
+    }
+
+    /// See [`E2eApi::encrypt_file_msg`](crate::E2eApi::encrypt_file_msg).
+    pub fn encrypt_file_msg(
+        &self,
+        msg: &FileMessage,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        self.inner.encrypt_file_msg(msg, recipient_key)
+    }
+
+    /// Blocking variant of
+    /// [`E2eApi::encrypt_bulk`](crate::E2eApi::encrypt_bulk).
+    #[cfg(feature = "parallel")]
+    pub fn encrypt_bulk(
+        &self,
+        raw_data: &[u8],
+        msgtype: crate::MessageType,
+        recipient_keys: &[RecipientKey],
+        max_parallelism: std::num::NonZeroUsize,
+    ) -> Result<Vec<EncryptedMessage>, CryptoError> {
+        self.block_on(
+            self.inner
+                .encrypt_bulk(raw_data, msgtype, recipient_keys, max_parallelism),
+        )
+    }
+
+    /// See [`E2eApi::encrypt_raw`](crate::E2eApi::encrypt_raw).
+    pub fn encrypt_raw(
+        &self,
+        raw_data: &[u8],
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        self.inner.encrypt_raw(raw_data, recipient_key)
+    }
+
+    /// Blocking variant of [`E2eApi::send`](crate::E2eApi::send).
+    pub fn send(
+        &self,
+        to: &str,
+        message: &EncryptedMessage,
+        delivery_receipts: bool,
+    ) -> Result<SendResult, ApiError> {
+        self.block_on(self.inner.send(to, message, delivery_receipts))
+    }
+
+    /// Blocking variant of [`E2eApi::send_with_options`](crate::E2eApi::send_with_options).
+    pub fn send_with_options(
+        &self,
+        to: &str,
+        message: &EncryptedMessage,
+        options: SendOptions,
+    ) -> Result<SendResult, ApiError> {
+        self.block_on(self.inner.send_with_options(to, message, options))
+    }
+
+    /// Blocking variant of [`E2eApi::send_once`](crate::E2eApi::send_once).
+    pub fn send_once(
+        &self,
+        key: &str,
+        to: &str,
+        message: &EncryptedMessage,
+        options: SendOptions,
+    ) -> Result<SendResult, ApiError> {
+        self.block_on(self.inner.send_once(key, to, message, options))
+    }
+
+    /// Blocking variant of [`E2eApi::send_text`](crate::E2eApi::send_text).
+    pub fn send_text(&self, to: &str, text: &str) -> Result<SendResult, ApiError> {
+        self.block_on(self.inner.send_text(to, text))
+    }
+
+    /// Blocking variant of [`E2eApi::blob_upload`](crate::E2eApi::blob_upload).
+    pub fn blob_upload(&self, data: &EncryptedMessage, persist: bool) -> Result<BlobId, ApiError> {
+        self.block_on(self.inner.blob_upload(data, persist))
+    }
+
+    /// Blocking variant of
+    /// [`E2eApi::blob_upload_raw`](crate::E2eApi::blob_upload_raw).
+    pub fn blob_upload_raw(&self, data: &[u8], persist: bool) -> Result<BlobId, ApiError> {
+        self.block_on(self.inner.blob_upload_raw(data, persist))
+    }
+
+    /// See [`E2eApi::on_blob_upload`](crate::E2eApi::on_blob_upload).
+    pub fn on_blob_upload<F>(self, callback: F) -> Self
+    where
+        F: Fn(&BlobId) + Send + Sync + 'static,
+    {
+        E2eApi {
+            inner: self.inner.on_blob_upload(callback),
+            runtime: self.runtime,
+        }
+    }
+
+    /// Blocking variant of
+    /// [`E2eApi::blob_upload_bytes`](crate::E2eApi::blob_upload_bytes).
+    pub fn blob_upload_bytes(&self, data: Bytes, persist: bool) -> Result<BlobId, ApiError> {
+        self.block_on(self.inner.blob_upload_bytes(data, persist))
+    }
+
+    /// Blocking variant of
+    /// [`E2eApi::blob_upload_many`](crate::E2eApi::blob_upload_many).
+    pub fn blob_upload_many<I>(
+        &self,
+        items: I,
+        persist: bool,
+        concurrency: NonZeroUsize,
+    ) -> Result<Vec<Result<BlobId, ApiError>>, ApiError>
+    where
+        I: IntoIterator<Item = Bytes>,
+    {
+        self.block_on(self.inner.blob_upload_many(items, persist, concurrency))
+    }
+
+    /// Blocking variant of
+    /// [`E2eApi::blob_download`](crate::E2eApi::blob_download).
+    pub fn blob_download(&self, blob_id: &BlobId) -> Result<Vec<u8>, ApiError> {
+        self.block_on(self.inner.blob_download(blob_id))
+    }
+
+    /// Blocking variant of [`E2eApi::send_file`](crate::E2eApi::send_file).
+    pub fn send_file(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        file: impl Into<FileSource>,
+        options: FileSendOptions,
+    ) -> Result<SendResult, ApiError> {
+        self.block_on(self.inner.send_file(to, recipient_key, file, options))
+    }
+
+    /// Blocking variant of [`E2eApi::send_image`](crate::E2eApi::send_image).
+    pub fn send_image(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        jpeg_data: Vec<u8>,
+    ) -> Result<SendResult, ApiError> {
+        self.block_on(self.inner.send_image(to, recipient_key, jpeg_data))
+    }
+
+    /// Blocking variant of
+    /// [`E2eApi::send_image_with_capabilities`](crate::E2eApi::send_image_with_capabilities).
+    pub fn send_image_with_capabilities(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        jpeg_data: Vec<u8>,
+        capabilities: &Capabilities,
+    ) -> Result<SendResult, ApiError> {
+        self.block_on(self.inner.send_image_with_capabilities(
+            to,
+            recipient_key,
+            jpeg_data,
+            capabilities,
+        ))
+    }
+
+    /// See
+    /// [`E2eApi::decode_incoming_message`](crate::E2eApi::decode_incoming_message).
+    #[cfg(feature = "receive")]
+    pub fn decode_incoming_message(
+        &self,
+        bytes: impl AsRef<[u8]>,
+    ) -> Result<IncomingMessage, ApiError> {
+        self.inner.decode_incoming_message(bytes)
+    }
+
+    /// See
+    /// [`E2eApi::decrypt_incoming_message`](crate::E2eApi::decrypt_incoming_message).
+    #[cfg(feature = "receive")]
+    pub fn decrypt_incoming_message(
+        &self,
+        message: &IncomingMessage,
+        recipient_key: &RecipientKey,
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.inner.decrypt_incoming_message(message, recipient_key)
+    }
+
+    /// See
+    /// [`E2eApi::decrypt_incoming_payload`](crate::E2eApi::decrypt_incoming_payload).
+    #[cfg(feature = "receive")]
+    pub fn decrypt_incoming_payload(
+        &self,
+        message: &IncomingMessage,
+        recipient_key: &RecipientKey,
+    ) -> Result<(MessageType, Vec<u8>), ApiError> {
+        self.inner.decrypt_incoming_payload(message, recipient_key)
+    }
+
+    impl_common_functionality!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ApiBuilder, SecretKey};
+
+    #[test]
+    fn test_simple_send_too_long() {
+        let api = ApiBuilder::new("*3MAGWID", "1234").into_simple().unwrap();
+        let api = SimpleApi::new(api).unwrap();
+        let text = "x".repeat(3501);
+        let result = api.send(&Recipient::new_id("ECHOECHO"), &text);
+        assert!(matches!(result, Err(ApiError::MessageTooLong)));
+    }
+
+    #[test]
+    fn test_e2e_encrypt() {
+        let own_key = SecretKey::from([1; 32]);
+        let other_key = SecretKey::from([2; 32]);
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key(own_key.clone())
+            .into_e2e()
+            .unwrap();
+        let api = E2eApi::new(api).unwrap();
+        assert_eq!(api.public_key(), own_key.public_key());
+
+        let recipient = RecipientKey::from(other_key.public_key());
+        let msg = api.encrypt_text_msg("hello", &recipient).unwrap();
+        let (_, data) = crate::decrypt(
+            &msg.ciphertext,
+            &msg.nonce,
+            &own_key.public_key(),
+            &other_key,
+        )
+        .unwrap();
+        assert_eq!(data, b"hello");
+    }
+}
@@ -0,0 +1,1886 @@
+//! A framework for bots that receive and answer messages.
+//!
+//! A [`Bot`] runs a webhook server for the Threema Gateway callback. Incoming
+//! requests are validated and decrypted, then dispatched to the handlers
+//! registered in a [`MessageRouter`]. The public keys of the senders are
+//! resolved through the [`PublicKeyCache`](crate::PublicKeyCache) of the API
+//! object, and incoming messages are recorded in its
+//! [`MessageStore`](crate::history::MessageStore), if configured.
+//!
+//! An echo bot:
+//!
+//! ```no_run
+//! use threema_gateway::{bot::Bot, ApiBuilder, MemoryPublicKeyCache};
+//!
+//! #[tokio::main(flavor = "current_thread")]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let api = ApiBuilder::new("*YOUR_ID", "your-gateway-secret")
+//!         .with_private_key_str("your-private-key")?
+//!         .with_public_key_cache(MemoryPublicKeyCache::default())
+//!         .into_e2e()?;
+//!
+//!     Bot::builder(api)
+//!         .on_text(|ctx, text| async move {
+//!             ctx.reply_text(&text).await?;
+//!             Ok(())
+//!         })
+//!         .build()
+//!         .run("0.0.0.0:8080")
+//!         .await?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::{
+    body::Incoming, header::CONTENT_TYPE, server::conn::http1, service::service_fn, Method,
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, ToSocketAddrs},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+};
+
+use crate::{
+    api::E2eApi,
+    bot_rate_limit::{Decision, RateLimiter},
+    bot_sessions::SharedSessionStore,
+    crypto::RecipientKey,
+    errors::ApiError,
+    history::{Direction, StoredMessage},
+    metrics::Metrics,
+    receive::IncomingMessage,
+    status::MessageStatus,
+    types::{MessageId, MessageType, SendResult},
+};
+
+#[cfg(feature = "bot-tls")]
+pub use crate::bot_tls::TlsConfig;
+pub use crate::{
+    bot_commands::{Command, CommandRouter, FromArgs},
+    bot_rate_limit::RateLimit,
+    bot_sessions::{MemorySessionStore, SessionStore},
+};
+
+/// The timeout of the gateway request made by [`HealthCheck::Credits`].
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The default maximum size of a callback request body, see
+/// [`IncomingMessage::DEFAULT_MAX_SIZE`].
+pub const DEFAULT_MAX_BODY_SIZE: usize = IncomingMessage::DEFAULT_MAX_SIZE;
+
+/// The default number of messages that may wait for a handler, see
+/// [`BotBuilder::with_max_queued_messages`].
+pub const DEFAULT_MAX_QUEUED_MESSAGES: usize = 100;
+
+/// A decrypted incoming message.
+#[derive(Debug, Clone)]
+pub struct BotMessage {
+    /// The Threema ID of the sender.
+    pub from: String,
+    /// The message ID assigned by the sender.
+    pub message_id: MessageId,
+    /// The message date set by the sender (UNIX timestamp).
+    pub date: u64,
+    /// The public nickname of the sender, if set.
+    pub nickname: Option<String>,
+    /// The message type.
+    pub msgtype: MessageType,
+    /// The decrypted message data (without the message type byte).
+    pub data: Vec<u8>,
+}
+
+impl BotMessage {
+    /// Return the text of a text message.
+    ///
+    /// Return `None` for other message types or if the text is not valid
+    /// UTF-8.
+    pub fn text(&self) -> Option<&str> {
+        match self.msgtype {
+            MessageType::Text => std::str::from_utf8(&self.data).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// The context passed to message handlers.
+///
+/// Gives access to the received message and allows replying to the sender.
+#[derive(Debug, Clone)]
+pub struct Context {
+    api: E2eApi,
+    sender_key: RecipientKey,
+    message: Arc<BotMessage>,
+    sessions: Option<SharedSessionStore>,
+}
+
+impl Context {
+    /// The API object of the bot.
+    pub fn api(&self) -> &E2eApi {
+        &self.api
+    }
+
+    /// The received message.
+    pub fn message(&self) -> &BotMessage {
+        &self.message
+    }
+
+    /// The received message, for modification by a middleware (see
+    /// [`MessageRouter::middleware`]).
+    pub fn message_mut(&mut self) -> &mut BotMessage {
+        Arc::make_mut(&mut self.message)
+    }
+
+    /// The public key of the sender.
+    pub fn sender_key(&self) -> &RecipientKey {
+        &self.sender_key
+    }
+
+    /// Send a text message to the sender.
+    ///
+    /// Cost: 1 credit.
+    pub async fn reply_text(&self, text: &str) -> Result<SendResult, ApiError> {
+        let text = self.api.hooks().transform_text(text);
+        let encrypted = self.api.encrypt_transformed_text(&text, &self.sender_key)?;
+        let result = self.api.send(&self.message.from, &encrypted, true).await?;
+        self.api
+            .record_message(&StoredMessage::text_message(
+                &self.message.from,
+                Direction::Outgoing,
+                Some(result.message_id),
+                &text,
+            ))
+            .await;
+        Ok(result)
+    }
+
+    /// Return the session state of the sender (see
+    /// [`BotBuilder::with_session_store`]).
+    ///
+    /// Returns `None` if there is no session or no session store is
+    /// configured.
+    pub async fn session<T: DeserializeOwned>(&self) -> Result<Option<T>, ApiError> {
+        let store = match self.sessions {
+            Some(ref store) => store,
+            None => return Ok(None),
+        };
+        let from = &self.message.from;
+        match store.load(from).await {
+            Ok(Some(session)) => serde_json::from_value(session)
+                .map(Some)
+                .map_err(|e| ApiError::Other(format!("Invalid session of {}: {}", from, e))),
+            Ok(None) => Ok(None),
+            Err(e) => Err(ApiError::Other(format!(
+                "Could not load session of {}: {}",
+                from, e
+            ))),
+        }
+    }
+
+    /// Replace the session state of the sender.
+    ///
+    /// Fails if no session store is configured.
+    pub async fn set_session<T: Serialize>(&self, session: &T) -> Result<(), ApiError> {
+        let from = &self.message.from;
+        let store = self
+            .sessions
+            .as_ref()
+            .ok_or_else(|| ApiError::Other("No session store configured".to_string()))?;
+        let session = serde_json::to_value(session)
+            .map_err(|e| ApiError::Other(format!("Invalid session of {}: {}", from, e)))?;
+        store
+            .store(from, &session)
+            .await
+            .map_err(|e| ApiError::Other(format!("Could not store session of {}: {}", from, e)))
+    }
+
+    /// Remove the session state of the sender, e.g. at the end of a
+    /// conversation.
+    pub async fn clear_session(&self) -> Result<(), ApiError> {
+        match self.sessions {
+            Some(ref store) => store.remove(&self.message.from).await.map_err(|e| {
+                ApiError::Other(format!(
+                    "Could not remove session of {}: {}",
+                    self.message.from, e
+                ))
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Send a delivery receipt for the received message to the sender.
+    ///
+    /// Cost: 1 credit.
+    pub async fn send_receipt(&self, status: MessageStatus) -> Result<SendResult, ApiError> {
+        self.send_receipt_for(status, &[self.message.message_id])
+            .await
+    }
+
+    /// Send a single delivery receipt for several messages of the sender
+    /// (e.g. to mark a conversation as read).
+    ///
+    /// Cost: 1 credit.
+    pub async fn send_receipt_for(
+        &self,
+        status: MessageStatus,
+        message_ids: &[MessageId],
+    ) -> Result<SendResult, ApiError> {
+        self.api
+            .send_delivery_receipt(&self.message.from, &self.sender_key, status, message_ids)
+            .await
+    }
+}
+
+pub(crate) type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type Handler = Arc<dyn Fn(Context) -> BoxFuture<Result<(), ApiError>> + Send + Sync>;
+type Middleware = Arc<dyn Fn(Context, Next) -> BoxFuture<Result<(), ApiError>> + Send + Sync>;
+
+/// The rest of the middleware chain, passed to every middleware (see
+/// [`MessageRouter::middleware`]).
+pub struct Next {
+    middleware: Arc<Vec<Middleware>>,
+    index: usize,
+    handler: Option<Handler>,
+}
+
+impl Next {
+    /// Run the remaining middleware and the handler.
+    pub async fn run(mut self, ctx: Context) -> Result<(), ApiError> {
+        match self.middleware.get(self.index).cloned() {
+            Some(middleware) => {
+                self.index += 1;
+                middleware(ctx, self).await
+            }
+            None => match self.handler {
+                Some(handler) => handler(ctx).await,
+                None => {
+                    debug!("Ignoring message of type {:?}", ctx.message().msgtype);
+                    Ok(())
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Next {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Next")
+            .field("remaining", &(self.middleware.len() - self.index))
+            .field("handler", &self.handler.is_some())
+            .finish()
+    }
+}
+
+/// Dispatches incoming messages to handlers by message type.
+#[derive(Clone, Default)]
+pub struct MessageRouter {
+    routes: HashMap<MessageType, Handler>,
+    fallback: Option<Handler>,
+    middleware: Arc<Vec<Middleware>>,
+}
+
+impl MessageRouter {
+    /// Create a router without handlers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle messages of type `msgtype`, replacing a previously registered
+    /// handler.
+    pub fn on<F, Fut>(mut self, msgtype: MessageType, handler: F) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ApiError>> + Send + 'static,
+    {
+        self.routes
+            .insert(msgtype, Arc::new(move |ctx| Box::pin(handler(ctx))));
+        self
+    }
+
+    /// Handle text messages. The handler receives the text, invalid UTF-8 is
+    /// replaced.
+    pub fn on_text<F, Fut>(self, handler: F) -> Self
+    where
+        F: Fn(Context, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ApiError>> + Send + 'static,
+    {
+        self.on(MessageType::Text, move |ctx| {
+            let text = String::from_utf8_lossy(&ctx.message().data).into_owned();
+            handler(ctx, text)
+        })
+    }
+
+    /// Handle text messages with a [`CommandRouter`], replacing a
+    /// previously registered text handler.
+    pub fn on_commands(self, commands: CommandRouter) -> Self {
+        let commands = Arc::new(commands);
+        self.on_text(move |ctx, text| {
+            let commands = commands.clone();
+            async move { commands.dispatch(ctx, text).await }
+        })
+    }
+
+    /// Handle messages for which no other handler is registered.
+    pub fn fallback<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ApiError>> + Send + 'static,
+    {
+        self.fallback = Some(Arc::new(move |ctx| Box::pin(handler(ctx))));
+        self
+    }
+
+    /// Forward messages for which no other handler is registered into a
+    /// channel, replacing a previously registered fallback handler.
+    ///
+    /// This bridges the incoming messages into an existing task or actor,
+    /// which receives the [`Context`] of every message and can reply
+    /// through it. A message counts as handled once it is in the channel.
+    /// If the channel is full, the handler waits for capacity. If the
+    /// receiver was dropped, the message is deferred with
+    /// [`ApiError::Deferred`], so that the gateway redelivers it.
+    ///
+    /// ```no_run
+    /// use threema_gateway::bot::MessageRouter;
+    /// use tokio::sync::mpsc;
+    ///
+    /// let (tx, mut rx) = mpsc::channel(100);
+    /// let router = MessageRouter::new().forward_to(tx);
+    /// tokio::spawn(async move {
+    ///     while let Some(ctx) = rx.recv().await {
+    ///         println!("Message from {}", ctx.message().from);
+    ///     }
+    /// });
+    /// ```
+    pub fn forward_to(self, sender: mpsc::Sender<Context>) -> Self {
+        self.fallback(move |ctx| {
+            let sender = sender.clone();
+            async move {
+                sender
+                    .send(ctx)
+                    .await
+                    .map_err(|_| ApiError::Deferred("Message channel is closed".to_string()))
+            }
+        })
+    }
+
+    /// Wrap all handlers in a middleware.
+    ///
+    /// The middleware receives the message and the rest of the chain, which
+    /// it can [run](Next::run) with the (possibly modified) context. It can
+    /// act before and after the handler (e.g. for logging or metrics), or
+    /// return early without running it (e.g. for authorization).
+    /// Middleware registered first runs outermost. The handler is selected
+    /// by the message type before the middleware runs.
+    ///
+    /// ```
+    /// use threema_gateway::bot::{MessageRouter, Next};
+    ///
+    /// const ALLOWED: &[&str] = &["ECHOECHO"];
+    ///
+    /// let router = MessageRouter::new()
+    ///     .middleware(|ctx, next: Next| async move {
+    ///         if !ALLOWED.contains(&ctx.message().from.as_str()) {
+    ///             return Ok(());
+    ///         }
+    ///         next.run(ctx).await
+    ///     })
+    ///     .on_text(|ctx, text| async move {
+    ///         ctx.reply_text(&text).await?;
+    ///         Ok(())
+    ///     });
+    /// ```
+    pub fn middleware<F, Fut>(mut self, middleware: F) -> Self
+    where
+        F: Fn(Context, Next) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ApiError>> + Send + 'static,
+    {
+        Arc::make_mut(&mut self.middleware)
+            .push(Arc::new(move |ctx, next| Box::pin(middleware(ctx, next))));
+        self
+    }
+
+    /// Pass the message through the middleware to the matching handler.
+    ///
+    /// Messages without a matching handler are ignored (after running the
+    /// middleware).
+    pub async fn dispatch(&self, ctx: Context) -> Result<(), ApiError> {
+        let msgtype = ctx.message().msgtype;
+        let next = Next {
+            middleware: self.middleware.clone(),
+            index: 0,
+            handler: self
+                .routes
+                .get(&msgtype)
+                .or(self.fallback.as_ref())
+                .cloned(),
+        };
+        next.run(ctx).await
+    }
+}
+
+impl fmt::Debug for MessageRouter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageRouter")
+            .field("routes", &self.routes.keys().collect::<Vec<_>>())
+            .field("fallback", &self.fallback.is_some())
+            .field("middleware", &self.middleware.len())
+            .finish()
+    }
+}
+
+/// How a callback request is answered, which determines whether the gateway
+/// redelivers the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Answer with status 200: the message was processed, or processing it
+    /// again would fail in the same way.
+    Ack,
+    /// Answer with status 500, so that the gateway redelivers the message
+    /// later.
+    Defer,
+    /// Answer with status 400: the request is invalid and must not be
+    /// redelivered.
+    Reject,
+}
+
+impl Outcome {
+    /// The HTTP status code of the response.
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            Outcome::Ack => StatusCode::OK,
+            Outcome::Defer => StatusCode::INTERNAL_SERVER_ERROR,
+            Outcome::Reject => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// Map the result of processing a message (e.g. of
+    /// [`Bot::handle_callback`]) to an outcome.
+    ///
+    /// Errors that may go away on redelivery (connection errors, timeouts,
+    /// server errors, missing credits and [`ApiError::Deferred`]) defer the
+    /// message, invalid requests are rejected and all other errors are
+    /// acknowledged.
+    pub fn from_result(result: &Result<(), ApiError>) -> Self {
+        match result {
+            Ok(()) => Outcome::Ack,
+            Err(e) => match e.inner() {
+                ApiError::Deferred(_)
+                | ApiError::RequestError(_)
+                | ApiError::IoError(_)
+                | ApiError::ServerError
+                | ApiError::DeadlineExceeded
+                | ApiError::NoCredits
+                | ApiError::InsufficientCredits { .. } => Outcome::Defer,
+                ApiError::InvalidMac
+                | ApiError::BodyTooLarge { .. }
+                | ApiError::ParseError(_)
+                | ApiError::BadMessageId
+                | ApiError::UnexpectedRecipient(_)
+                | ApiError::CryptoError(_) => Outcome::Reject,
+                _ => Outcome::Ack,
+            },
+        }
+    }
+}
+
+/// What the `GET /healthz` route of the webhook server reports, see
+/// [`BotBuilder::with_health_check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HealthCheck {
+    /// No health route.
+    #[default]
+    Disabled,
+    /// Report that the server is alive.
+    Liveness,
+    /// Look up the remaining credits to check that the gateway is reachable
+    /// and the credentials are valid, and report them. Every check costs a
+    /// request to the gateway, so orchestrators should not poll too often.
+    Credits,
+}
+
+/// The [`Metrics`] of a bot, see [`BotBuilder::with_metrics`].
+struct BotMetrics(Box<dyn Metrics>);
+
+impl fmt::Debug for BotMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BotMetrics")
+    }
+}
+
+/// The handler for messages over the rate limit, see
+/// [`BotBuilder::on_rate_limited`].
+struct RateLimitedHandler(Handler);
+
+impl fmt::Debug for RateLimitedHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RateLimitedHandler")
+    }
+}
+
+/// Bounds of the handler execution, see
+/// [`BotBuilder::with_max_concurrent_handlers`].
+#[derive(Debug)]
+struct HandlerQueue {
+    /// Permits for messages that are queued or being handled.
+    slots: Arc<Semaphore>,
+    /// Permits for running handlers.
+    workers: Semaphore,
+}
+
+/// Builder for a [`Bot`], see [`Bot::builder`].
+#[derive(Debug)]
+pub struct BotBuilder {
+    api: E2eApi,
+    router: MessageRouter,
+    auto_receipts: bool,
+    max_body_size: usize,
+    await_handlers: bool,
+    health_check: HealthCheck,
+    metrics: Option<BotMetrics>,
+    sessions: Option<SharedSessionStore>,
+    rate_limit: Option<RateLimit>,
+    rate_limited: Option<RateLimitedHandler>,
+    max_concurrent_handlers: Option<usize>,
+    max_queued_messages: usize,
+}
+
+impl BotBuilder {
+    /// Replace the router, including all handlers registered so far.
+    pub fn router(mut self, router: MessageRouter) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// See [`MessageRouter::on`].
+    pub fn on<F, Fut>(mut self, msgtype: MessageType, handler: F) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ApiError>> + Send + 'static,
+    {
+        self.router = self.router.on(msgtype, handler);
+        self
+    }
+
+    /// See [`MessageRouter::on_text`].
+    pub fn on_text<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Context, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ApiError>> + Send + 'static,
+    {
+        self.router = self.router.on_text(handler);
+        self
+    }
+
+    /// See [`MessageRouter::on_commands`].
+    pub fn on_commands(mut self, commands: CommandRouter) -> Self {
+        self.router = self.router.on_commands(commands);
+        self
+    }
+
+    /// See [`MessageRouter::forward_to`].
+    pub fn forward_to(mut self, sender: mpsc::Sender<Context>) -> Self {
+        self.router = self.router.forward_to(sender);
+        self
+    }
+
+    /// See [`MessageRouter::middleware`].
+    pub fn middleware<F, Fut>(mut self, middleware: F) -> Self
+    where
+        F: Fn(Context, Next) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ApiError>> + Send + 'static,
+    {
+        self.router = self.router.middleware(middleware);
+        self
+    }
+
+    /// See [`MessageRouter::fallback`].
+    pub fn fallback<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ApiError>> + Send + 'static,
+    {
+        self.router = self.router.fallback(handler);
+        self
+    }
+
+    /// Automatically send a delivery receipt for every received message
+    /// (except receipts, typing indicators and group messages) before it is
+    /// dispatched. Disabled by default, since every receipt costs 1 credit.
+    pub fn with_auto_receipts(mut self, auto_receipts: bool) -> Self {
+        self.auto_receipts = auto_receipts;
+        self
+    }
+
+    /// Set the maximum size of a callback request body. Larger requests are
+    /// rejected with status 413.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Answer callback requests only after the handler finished, with the
+    /// status of the [`Outcome`] of its result. Handlers can return
+    /// [`ApiError::Deferred`] to have the message redelivered later.
+    ///
+    /// Disabled by default: requests are acknowledged as soon as the message
+    /// is decrypted, and handler errors are only logged.
+    pub fn with_await_handlers(mut self, await_handlers: bool) -> Self {
+        self.await_handlers = await_handlers;
+        self
+    }
+
+    /// Run at most `max_concurrent_handlers` handlers at the same time.
+    ///
+    /// Further messages wait in a queue (see
+    /// [`with_max_queued_messages`](Self::with_max_queued_messages)). If
+    /// the queue is full, e.g. because handlers are stuck downloading
+    /// blobs, new messages are rejected with [`ApiError::Deferred`] and the
+    /// webhook server answers with the status of [`Outcome::Defer`], so
+    /// that the gateway retries the delivery later. By default, the number
+    /// of handlers is not limited.
+    pub fn with_max_concurrent_handlers(mut self, max_concurrent_handlers: usize) -> Self {
+        self.max_concurrent_handlers = Some(max_concurrent_handlers.max(1));
+        self
+    }
+
+    /// Set the number of messages that may wait for a handler if
+    /// [`with_max_concurrent_handlers`](Self::with_max_concurrent_handlers)
+    /// is set. Defaults to [`DEFAULT_MAX_QUEUED_MESSAGES`].
+    pub fn with_max_queued_messages(mut self, max_queued_messages: usize) -> Self {
+        self.max_queued_messages = max_queued_messages;
+        self
+    }
+
+    /// Enable the `GET /healthz` route of the webhook server.
+    ///
+    /// The route answers with status 200 and a JSON object like
+    /// `{"status": "ok", "credits": 100}` if the bot is healthy, and with
+    /// status 503 and `{"status": "unavailable", "error": "..."}` if the
+    /// gateway check failed. Disabled by default.
+    pub fn with_health_check(mut self, health_check: HealthCheck) -> Self {
+        self.health_check = health_check;
+        self
+    }
+
+    /// Report received messages, failed MAC checks, decryption errors and
+    /// handler latencies to `metrics`.
+    ///
+    /// If the implementation renders Prometheus metrics (like
+    /// [`PrometheusMetrics`](crate::metrics::PrometheusMetrics)), they are
+    /// served on the `GET /metrics` route of the webhook server. To access
+    /// the metrics elsewhere, keep a reference, e.g. by passing an `Arc`.
+    pub fn with_metrics<M: Metrics + 'static>(mut self, metrics: M) -> Self {
+        self.metrics = Some(BotMetrics(Box::new(metrics)));
+        self
+    }
+
+    /// Keep the session state of the senders in `store`, see
+    /// [`Context::session`].
+    pub fn with_session_store<S: SessionStore + 'static>(mut self, store: S) -> Self {
+        self.sessions = Some(SharedSessionStore::new(store));
+        self
+    }
+
+    /// Limit the messages per sender that are passed to the handlers, so
+    /// that a single user cannot make the bot spend credits on answers.
+    ///
+    /// The limit is enforced before the public key of the sender is looked
+    /// up and the message is decrypted, so messages over the limit do not
+    /// cost lookup credits. They are acknowledged without running the
+    /// middleware and handlers, and without automatic receipt. Because the
+    /// message type is not known at that point, delivery receipts and
+    /// typing indicators count towards the limit as well. Disabled by
+    /// default.
+    ///
+    /// ```
+    /// # let api = threema_gateway::ApiBuilder::new("*3MAGWID", "secret")
+    /// #     .with_private_key(threema_gateway::SecretKey::from([1; 32]))
+    /// #     .into_e2e()
+    /// #     .unwrap();
+    /// use threema_gateway::bot::{Bot, RateLimit};
+    ///
+    /// let bot = Bot::builder(api)
+    ///     .with_rate_limit(RateLimit::per_minute(10).with_burst(3))
+    ///     .on_rate_limited(|ctx| async move {
+    ///         ctx.reply_text("Slow down, please.").await?;
+    ///         Ok(())
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Handle the first message of a sender that exceeds the
+    /// [rate limit](Self::with_rate_limit), e.g. to reply with a warning.
+    ///
+    /// The handler is not called again for the same sender until a message
+    /// of that sender is accepted, so that replying does not spend credits
+    /// for every further message.
+    pub fn on_rate_limited<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ApiError>> + Send + 'static,
+    {
+        self.rate_limited = Some(RateLimitedHandler(Arc::new(move |ctx| {
+            Box::pin(handler(ctx))
+        })));
+        self
+    }
+
+    /// Return the [`Bot`].
+    pub fn build(self) -> Bot {
+        Bot(Arc::new(BotInner {
+            api: self.api,
+            router: self.router,
+            auto_receipts: self.auto_receipts,
+            max_body_size: self.max_body_size,
+            await_handlers: self.await_handlers,
+            health_check: self.health_check,
+            metrics: self.metrics,
+            sessions: self.sessions,
+            rate_limiter: self.rate_limit.map(RateLimiter::new),
+            rate_limited: self.rate_limited,
+            queue: self.max_concurrent_handlers.map(|workers| HandlerQueue {
+                slots: Arc::new(Semaphore::new(
+                    workers
+                        .saturating_add(self.max_queued_messages)
+                        .min(Semaphore::MAX_PERMITS),
+                )),
+                workers: Semaphore::new(workers.min(Semaphore::MAX_PERMITS)),
+            }),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct BotInner {
+    api: E2eApi,
+    router: MessageRouter,
+    auto_receipts: bool,
+    max_body_size: usize,
+    await_handlers: bool,
+    health_check: HealthCheck,
+    metrics: Option<BotMetrics>,
+    sessions: Option<SharedSessionStore>,
+    rate_limiter: Option<RateLimiter>,
+    rate_limited: Option<RateLimitedHandler>,
+    queue: Option<HandlerQueue>,
+}
+
+/// A bot receiving messages through the gateway callback.
+///
+/// See the [module documentation](self) for an example. Clones share the
+/// handlers.
+#[derive(Debug, Clone)]
+pub struct Bot(Arc<BotInner>);
+
+impl Bot {
+    /// Create a builder for a bot sending and receiving messages with `api`.
+    pub fn builder(api: E2eApi) -> BotBuilder {
+        BotBuilder {
+            api,
+            router: MessageRouter::new(),
+            auto_receipts: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            await_handlers: false,
+            health_check: HealthCheck::Disabled,
+            metrics: None,
+            sessions: None,
+            rate_limit: None,
+            rate_limited: None,
+            max_concurrent_handlers: None,
+            max_queued_messages: DEFAULT_MAX_QUEUED_MESSAGES,
+        }
+    }
+
+    /// Listen on `addr` and handle callback requests until the future is
+    /// dropped.
+    ///
+    /// Only returns if binding to `addr` fails.
+    pub async fn run(self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Bot listening on {}", listener.local_addr()?);
+        self.serve(listener).await;
+        Ok(())
+    }
+
+    /// Handle callback requests on connections accepted by `listener` until
+    /// the future is dropped.
+    ///
+    /// Requests are answered as soon as the message is decrypted, the
+    /// handlers run in the background (unless
+    /// [`with_await_handlers`](BotBuilder::with_await_handlers) is
+    /// enabled). Invalid requests are answered with status 400 or 401, if
+    /// the message cannot be processed for other reasons (e.g. a failed
+    /// public key lookup), status 500 is returned so that the gateway
+    /// retries the delivery. The same applies if the handler queue is full
+    /// (see [`BotBuilder::with_max_concurrent_handlers`]).
+    ///
+    /// Requests with the content type `application/json` are decoded as
+    /// re-encoded JSON callbacks (see
+    /// [`handle_json_callback`](Self::handle_json_callback)).
+    pub async fn serve(self, listener: TcpListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Could not accept connection: {}", e);
+                    continue;
+                }
+            };
+            self.serve_connection(stream);
+        }
+    }
+
+    /// Listen on the unix domain socket at `path` and handle callback
+    /// requests until the future is dropped, e.g. behind a reverse proxy on
+    /// the same host.
+    ///
+    /// A stale socket at `path` (e.g. left over from a previous run) is
+    /// removed. If another process is still listening on the socket, this
+    /// fails with [`io::ErrorKind::AddrInUse`]. Only returns if binding to
+    /// `path` fails.
+    #[cfg(unix)]
+    pub async fn run_unix(self, path: impl AsRef<std::path::Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let listener = bind_unix(path)?;
+        info!("Bot listening on {}", path.display());
+        self.serve_unix(listener).await;
+        Ok(())
+    }
+
+    /// Like [`serve`](Self::serve), but for connections accepted by a unix
+    /// domain socket listener.
+    #[cfg(unix)]
+    pub async fn serve_unix(self, listener: UnixListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => self.serve_connection(stream),
+                Err(e) => warn!("Could not accept connection: {}", e),
+            }
+        }
+    }
+
+    /// Handle callback requests on the sockets passed by systemd socket
+    /// activation (`LISTEN_FDS`) until the future is dropped.
+    ///
+    /// TCP and unix domain stream sockets are supported. This allows
+    /// starting the bot on demand and binding to privileged ports without
+    /// running it as root. Fails if the process was not socket activated or
+    /// if the sockets were already taken.
+    #[cfg(unix)]
+    pub async fn run_socket_activated(self) -> io::Result<()> {
+        use std::{
+            net,
+            os::unix::{
+                io::{FromRawFd, IntoRawFd},
+                net as unix_net,
+            },
+            sync::atomic::{AtomicBool, Ordering},
+        };
+
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+
+        let fds = activated_fds(
+            std::env::var("LISTEN_PID").ok().as_deref(),
+            std::env::var("LISTEN_FDS").ok().as_deref(),
+            std::process::id(),
+        )?;
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "The activated sockets were already taken",
+            ));
+        }
+
+        let mut servers = tokio::task::JoinSet::new();
+        for fd in fds {
+            // SAFETY: systemd passes ownership of the file descriptors to
+            // the process, and the guard above ensures that they are only
+            // taken once.
+            let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+            if let Ok(addr) = listener.local_addr() {
+                listener.set_nonblocking(true)?;
+                info!("Bot listening on {} (socket activated)", addr);
+                let listener = TcpListener::from_std(listener)?;
+                servers.spawn(self.clone().serve(listener));
+            } else {
+                // Not an internet socket, treat it as unix domain socket
+                let fd = listener.into_raw_fd();
+                // SAFETY: See above, the descriptor was released again.
+                let listener = unsafe { unix_net::UnixListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                info!("Bot listening on unix domain socket (socket activated)");
+                let listener = UnixListener::from_std(listener)?;
+                servers.spawn(self.clone().serve_unix(listener));
+            }
+        }
+        while servers.join_next().await.is_some() {}
+        Ok(())
+    }
+
+    /// Handle callback requests on an accepted connection in the
+    /// background.
+    pub(crate) fn serve_connection<S>(&self, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let bot = self.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| bot.clone().handle_request(req));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("Connection error: {}", e);
+            }
+        });
+    }
+
+    /// Process the body of a callback request, for use with an own HTTP
+    /// server: validate and decrypt the message, then dispatch it to the
+    /// handlers.
+    pub async fn handle_callback(&self, body: &[u8]) -> Result<(), ApiError> {
+        let slot = self.reserve_slot()?;
+        match self.receive(body, BodyEncoding::Form).await? {
+            Some(received) => self.dispatch_queued(received, slot).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Like [`handle_callback`](Self::handle_callback), but for callback
+    /// bodies that were re-encoded as JSON object (see
+    /// [`IncomingMessage::from_json_bytes`]).
+    pub async fn handle_json_callback(&self, body: &[u8]) -> Result<(), ApiError> {
+        let slot = self.reserve_slot()?;
+        match self.receive(body, BodyEncoding::Json).await? {
+            Some(received) => self.dispatch_queued(received, slot).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Reserve a place in the handler queue, if the handlers are bounded.
+    fn reserve_slot(&self) -> Result<Option<OwnedSemaphorePermit>, ApiError> {
+        match &self.0.queue {
+            Some(queue) => match queue.slots.clone().try_acquire_owned() {
+                Ok(slot) => Ok(Some(slot)),
+                Err(_) => Err(ApiError::Deferred("Handler queue is full".to_string())),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Wait for a free handler, then dispatch the message. The reserved
+    /// `slot` is released when the handler finished.
+    async fn dispatch_queued(
+        &self,
+        received: Received,
+        slot: Option<OwnedSemaphorePermit>,
+    ) -> Result<(), ApiError> {
+        // The semaphore is never closed, so acquiring only fails if the
+        // handlers are unbounded
+        let _worker = match &self.0.queue {
+            Some(queue) => queue.workers.acquire().await.ok(),
+            None => None,
+        };
+        let result = self.dispatch(received).await;
+        drop(slot);
+        result
+    }
+
+    /// Validate the message, enforce the rate limit and decrypt the message.
+    ///
+    /// The rate limit is enforced before the public key of the sender is
+    /// looked up, so that a flooding sender does not cause paid lookups.
+    /// Returns `None` if the message is dropped because of the rate limit.
+    async fn receive(
+        &self,
+        body: &[u8],
+        encoding: BodyEncoding,
+    ) -> Result<Option<Received>, ApiError> {
+        let api = &self.0.api;
+        let msg = match encoding {
+            BodyEncoding::Form => {
+                api.decode_incoming_message_with_limit(body, self.0.max_body_size)
+            }
+            BodyEncoding::Json => {
+                api.decode_incoming_json_message_with_limit(body, self.0.max_body_size)
+            }
+        }
+        .map_err(|e| {
+            if matches!(e.inner(), ApiError::InvalidMac) {
+                self.record(|metrics| metrics.mac_failure());
+            }
+            e
+        })?;
+        let message_id = msg.message_id.parse()?;
+        let decision = match &self.0.rate_limiter {
+            Some(limiter) => limiter.check(&msg.from),
+            None => Decision::Allow,
+        };
+        if decision == Decision::RejectAgain {
+            debug!("Dropping message of {} (rate limited)", msg.from);
+            return Ok(None);
+        }
+        let sender_key = api.resolve_public_key(&msg.from).await?;
+        let (msgtype, data) = api
+            .decrypt_incoming_payload(&msg, &sender_key)
+            .map_err(|e| {
+                if matches!(e, ApiError::CryptoError(_)) {
+                    self.record(|metrics| metrics.decrypt_error());
+                }
+                e
+            })?;
+        let message = BotMessage {
+            from: msg.from,
+            message_id,
+            date: msg.date as u64,
+            nickname: msg.nickname,
+            msgtype,
+            data,
+        };
+        self.record(|metrics| metrics.message_received(message.msgtype));
+        api.record_message(&StoredMessage {
+            contact: message.from.clone(),
+            direction: Direction::Incoming,
+            message_id: Some(message.message_id),
+            msgtype: message.msgtype,
+            data: message.data.clone(),
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(message.date),
+        })
+        .await;
+        Ok(Some(Received {
+            ctx: Context {
+                api: api.clone(),
+                sender_key,
+                message: Arc::new(message),
+                sessions: self.0.sessions.clone(),
+            },
+            rate_limited: decision == Decision::Reject,
+        }))
+    }
+
+    /// Notify about an exceeded rate limit, or send the automatic receipt
+    /// and pass the message to the router.
+    async fn dispatch(&self, received: Received) -> Result<(), ApiError> {
+        let Received { ctx, rate_limited } = received;
+        let msgtype = ctx.message().msgtype;
+        let is_status = matches!(
+            msgtype,
+            MessageType::DeliveryReceipt
+                | MessageType::GroupDeliveryReceipt
+                | MessageType::TypingIndicator
+        );
+        if rate_limited {
+            info!("Rate limit exceeded by {}", ctx.message().from);
+            return match (&self.0.rate_limited, is_status) {
+                (Some(handler), false) => (handler.0)(ctx).await,
+                _ => Ok(()),
+            };
+        }
+        if self.0.auto_receipts && !is_status && !msgtype.is_group() {
+            if let Err(e) = ctx.send_receipt(MessageStatus::Delivered).await {
+                warn!(
+                    "Could not send delivery receipt for message {}: {}",
+                    ctx.message().message_id,
+                    e
+                );
+            }
+        }
+        let start = Instant::now();
+        let result = self.0.router.dispatch(ctx).await;
+        self.record(|metrics| metrics.handler_finished(msgtype, start.elapsed(), result.is_ok()));
+        result
+    }
+
+    /// Report an event to the metrics, if configured.
+    fn record(&self, event: impl FnOnce(&dyn Metrics)) {
+        if let Some(metrics) = &self.0.metrics {
+            event(&*metrics.0);
+        }
+    }
+
+    /// Answer a request to the health route.
+    async fn health(&self) -> Response<Full<Bytes>> {
+        let (code, body) = match self.0.health_check {
+            HealthCheck::Disabled | HealthCheck::Liveness => {
+                (StatusCode::OK, serde_json::json!({"status": "ok"}))
+            }
+            HealthCheck::Credits => {
+                let api = self.0.api.with_timeout(HEALTH_CHECK_TIMEOUT);
+                match api.lookup_credits().await {
+                    Ok(credits) => (
+                        StatusCode::OK,
+                        serde_json::json!({"status": "ok", "credits": credits}),
+                    ),
+                    Err(e) => {
+                        warn!("Health check failed: {}", e);
+                        (
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            serde_json::json!({"status": "unavailable", "error": e.to_string()}),
+                        )
+                    }
+                }
+            }
+        };
+        let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+        *response.status_mut() = code;
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("application/json"),
+        );
+        response
+    }
+
+    async fn handle_request(
+        self,
+        req: Request<Incoming>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        #[cfg(feature = "otel")]
+        let cx =
+            crate::otel::start_server_span(req.method().as_str(), req.uri().path(), req.headers());
+        let response = self.process_request(req);
+        #[cfg(feature = "otel")]
+        let response = opentelemetry::context::FutureExt::with_context(response, cx.clone());
+        let Ok(response) = response.await;
+        #[cfg(feature = "otel")]
+        crate::otel::end_server_span(&cx, response.status());
+        Ok(response)
+    }
+
+    async fn process_request(
+        self,
+        req: Request<Incoming>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        if req.uri().path() == "/healthz" && self.0.health_check != HealthCheck::Disabled {
+            return Ok(self.health().await);
+        }
+        if req.uri().path() == "/metrics" {
+            if let Some(text) = self
+                .0
+                .metrics
+                .as_ref()
+                .and_then(|m| m.0.render_prometheus())
+            {
+                let mut response = Response::new(Full::new(Bytes::from(text)));
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                return Ok(response);
+            }
+        }
+        if req.method() != Method::POST {
+            return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+        }
+        let encoding = match req.headers().get(CONTENT_TYPE) {
+            Some(value) if value.as_bytes().starts_with(b"application/json") => BodyEncoding::Json,
+            _ => BodyEncoding::Form,
+        };
+        let slot = match self.reserve_slot() {
+            Ok(slot) => slot,
+            Err(e) => {
+                warn!("Could not accept incoming message: {}", e);
+                return Ok(status(Outcome::Defer.status_code()));
+            }
+        };
+        let body = match Limited::new(req.into_body(), self.0.max_body_size)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(e) if e.is::<LengthLimitError>() => {
+                return Ok(status(StatusCode::PAYLOAD_TOO_LARGE))
+            }
+            Err(e) => {
+                debug!("Could not read request body: {}", e);
+                return Ok(status(StatusCode::BAD_REQUEST));
+            }
+        };
+        let received = match self.receive(&body, encoding).await {
+            Ok(Some(received)) => received,
+            Ok(None) => return Ok(status(StatusCode::OK)),
+            Err(e) => {
+                warn!("Could not process incoming message: {}", e);
+                return Ok(status(error_status(&e)));
+            }
+        };
+        let message_id = received.ctx.message().message_id;
+        if self.0.await_handlers {
+            let result = self.dispatch_queued(received, slot).await;
+            if let Err(e) = &result {
+                warn!("Handler for message {} failed: {}", message_id, e);
+            }
+            return Ok(status(Outcome::from_result(&result).status_code()));
+        }
+        let dispatch = async move {
+            if let Err(e) = self.dispatch_queued(received, slot).await {
+                warn!("Handler for message {} failed: {}", message_id, e);
+            }
+        };
+        // Keep the handler in the trace of the request
+        #[cfg(feature = "otel")]
+        let dispatch = opentelemetry::context::FutureExt::with_current_context(dispatch);
+        tokio::spawn(dispatch);
+        Ok(status(StatusCode::OK))
+    }
+}
+
+/// The first file descriptor passed by systemd socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Return the file descriptors passed by systemd socket activation, given
+/// the values of the `LISTEN_PID` and `LISTEN_FDS` environment variables.
+#[cfg(unix)]
+fn activated_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> io::Result<std::ops::Range<i32>> {
+    let not_activated = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Not socket activated: {}", reason),
+        )
+    };
+    if listen_pid.and_then(|p| p.parse::<u32>().ok()) != Some(pid) {
+        return Err(not_activated("LISTEN_PID does not match"));
+    }
+    match listen_fds.and_then(|n| n.parse::<i32>().ok()) {
+        Some(n) if n > 0 => Ok(LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(n)),
+        _ => Err(not_activated("no sockets in LISTEN_FDS")),
+    }
+}
+
+/// Bind a unix domain socket listener to `path`, replacing a stale socket.
+///
+/// A socket is stale if connecting to it is refused, i.e. nobody listens on
+/// it any more.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> io::Result<UnixListener> {
+    use std::{fs, os::unix::fs::FileTypeExt, os::unix::net::UnixStream};
+
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        match UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+            Err(e) => return Err(e),
+        }
+    }
+    UnixListener::bind(path)
+}
+
+/// The encoding of a callback request body.
+#[derive(Debug, Clone, Copy)]
+enum BodyEncoding {
+    /// `application/x-www-form-urlencoded`, as sent by the gateway.
+    Form,
+    /// A JSON object, as re-encoded by an intermediary.
+    Json,
+}
+
+/// A decrypted incoming message.
+struct Received {
+    ctx: Context,
+    /// Whether this is the first message of the sender over the rate limit.
+    rate_limited: bool,
+}
+
+fn status(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    *response.status_mut() = status;
+    response
+}
+
+/// The status code returned for requests that could not be processed.
+fn error_status(err: &ApiError) -> StatusCode {
+    match err.inner() {
+        ApiError::InvalidMac => StatusCode::UNAUTHORIZED,
+        ApiError::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        ApiError::ParseError(_)
+        | ApiError::BadMessageId
+        | ApiError::UnexpectedRecipient(_)
+        | ApiError::CryptoError(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create the context of a text message.
+    fn text_context(
+        api: &E2eApi,
+        from: &str,
+        text: &str,
+        sessions: Option<SharedSessionStore>,
+    ) -> Context {
+        Context {
+            api: api.clone(),
+            sender_key: crate::test_support::recipient_key(),
+            message: Arc::new(BotMessage {
+                from: from.to_string(),
+                message_id: MessageId([1; 8]),
+                date: 0,
+                nickname: None,
+                msgtype: MessageType::Text,
+                data: text.as_bytes().to_vec(),
+            }),
+            sessions,
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_unix() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("threema-bot-{}.sock", std::process::id()));
+        let api = crate::test_support::api_builder().into_e2e().unwrap();
+        let bot = Bot::builder(api).build();
+        let listener = bind_unix(&path).unwrap();
+        tokio::spawn(bot.serve_unix(listener));
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 405"));
+
+        // A socket in use is not replaced
+        let err = bind_unix(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        std::fs::remove_file(&path).unwrap();
+
+        // A stale socket is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = bind_unix(&path).unwrap();
+        assert!(tokio::net::UnixStream::connect(&path).await.is_ok());
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_activated_fds() {
+        assert_eq!(activated_fds(Some("42"), Some("2"), 42).unwrap(), 3..5);
+        assert!(activated_fds(Some("41"), Some("2"), 42).is_err());
+        assert!(activated_fds(None, Some("2"), 42).is_err());
+        assert!(activated_fds(Some("42"), Some("0"), 42).is_err());
+        assert!(activated_fds(Some("42"), None, 42).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let api = crate::test_support::api_builder().into_e2e().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/healthz", listener.local_addr().unwrap());
+        let bot = Bot::builder(api)
+            .with_health_check(HealthCheck::Liveness)
+            .build();
+        tokio::spawn(bot.serve(listener));
+
+        let res = reqwest::get(&url).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), r#"{"status":"ok"}"#);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_reply_text_transform() {
+        use crate::{
+            history::{MemoryMessageStore, MessageStore},
+            mock_server::MockServer,
+            test_support,
+        };
+
+        let server = MockServer::start(test_support::GATEWAY_ID, test_support::API_SECRET)
+            .await
+            .unwrap();
+        server.set_public_key(test_support::RECIPIENT_ID, test_support::recipient_key());
+        let store = Arc::new(MemoryMessageStore::default());
+        let api = test_support::api_builder()
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_message_store(store.clone())
+            .into_e2e()
+            .unwrap()
+            .transform_text(|text| text.to_uppercase());
+        let ctx = text_context(&api, test_support::RECIPIENT_ID, "ping", None);
+
+        ctx.reply_text("pong").await.unwrap();
+        let history = store.history(test_support::RECIPIENT_ID, 10).await.unwrap();
+        assert_eq!(history[0].text(), Some("PONG"));
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_health_check_credits() {
+        use crate::{mock_server::MockServer, ApiBuilder, SecretKey};
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/healthz", listener.local_addr().unwrap());
+        let bot = Bot::builder(api)
+            .with_health_check(HealthCheck::Credits)
+            .build();
+        tokio::spawn(bot.serve(listener));
+
+        let res = reqwest::get(&url).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(body["credits"], server.credits());
+
+        server.fail_next(401);
+        let res = reqwest::get(&url).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        use crate::{
+            metrics::PrometheusMetrics, test_support, MemoryPublicKeyCache, PublicKeyCache,
+        };
+
+        let cache = MemoryPublicKeyCache::default();
+        cache
+            .store(test_support::RECIPIENT_ID, &test_support::recipient_key())
+            .await
+            .unwrap();
+        let api = test_support::api_builder()
+            .with_public_key_cache(cache)
+            .into_e2e()
+            .unwrap();
+        let metrics = Arc::new(PrometheusMetrics::default());
+        let bot = Bot::builder(api)
+            .on_text(|_, _| async { Err(ApiError::Deferred("busy".into())) })
+            .with_metrics(metrics.clone())
+            .build();
+
+        let body = test_support::text_callback_body("hi");
+        assert!(bot.handle_callback(body.as_bytes()).await.is_err());
+        let body = test_support::CALLBACK_BODY.replace("mac=b", "mac=c");
+        assert!(bot.handle_callback(body.as_bytes()).await.is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/metrics", listener.local_addr().unwrap());
+        tokio::spawn(bot.serve(listener));
+        let res = reqwest::get(&url).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let text = res.text().await.unwrap();
+        assert_eq!(text, metrics.render());
+        assert!(text.contains("threema_messages_received_total{type=\"text\"} 1\n"));
+        assert!(text.contains("threema_mac_failures_total 1\n"));
+        assert!(text.contains("threema_handler_errors_total{type=\"text\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_handler_queue() {
+        use tokio::sync::{mpsc, Notify};
+
+        use crate::{test_support, MemoryPublicKeyCache, PublicKeyCache};
+
+        let cache = MemoryPublicKeyCache::default();
+        cache
+            .store(test_support::RECIPIENT_ID, &test_support::recipient_key())
+            .await
+            .unwrap();
+        let api = test_support::api_builder()
+            .with_public_key_cache(cache)
+            .into_e2e()
+            .unwrap();
+        let (started_tx, mut started) = mpsc::unbounded_channel();
+        let release = Arc::new(Notify::new());
+        let bot = Bot::builder(api)
+            .on_text({
+                let release = release.clone();
+                move |_, text| {
+                    let (started_tx, release) = (started_tx.clone(), release.clone());
+                    async move {
+                        started_tx.send(text).unwrap();
+                        release.notified().await;
+                        Ok(())
+                    }
+                }
+            })
+            .with_max_concurrent_handlers(1)
+            .with_max_queued_messages(1)
+            .build();
+
+        // The first message is handled, the second one waits
+        let first = tokio::spawn({
+            let bot = bot.clone();
+            async move {
+                let body = test_support::text_callback_body("first");
+                bot.handle_callback(body.as_bytes()).await
+            }
+        });
+        assert_eq!(started.recv().await.unwrap(), "first");
+        let second = tokio::spawn({
+            let bot = bot.clone();
+            async move {
+                let body = test_support::text_callback_body("second");
+                bot.handle_callback(body.as_bytes()).await
+            }
+        });
+        tokio::task::yield_now().await;
+
+        // The queue is full, so the third one is deferred
+        let body = test_support::text_callback_body("third");
+        let result = bot.handle_callback(body.as_bytes()).await;
+        assert_eq!(Outcome::from_result(&result), Outcome::Defer);
+
+        release.notify_one();
+        first.await.unwrap().unwrap();
+        assert_eq!(started.recv().await.unwrap(), "second");
+        release.notify_one();
+        second.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        use std::sync::Mutex;
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let logger = |name: &'static str| {
+            let log = log.clone();
+            move |ctx: Context, next: Next| {
+                let log = log.clone();
+                async move {
+                    log.lock().unwrap().push(format!("{} before", name));
+                    let result = next.run(ctx).await;
+                    log.lock().unwrap().push(format!("{} after", name));
+                    result
+                }
+            }
+        };
+        let router = MessageRouter::new()
+            .middleware(logger("outer"))
+            .middleware(|mut ctx: Context, next: Next| async move {
+                if ctx.message().from == "BLOCKED1" {
+                    return Err(ApiError::Other("Not allowed".into()));
+                }
+                ctx.message_mut().data.make_ascii_uppercase();
+                next.run(ctx).await
+            })
+            .middleware(logger("inner"))
+            .on_text({
+                let log = log.clone();
+                move |_, text| {
+                    let log = log.clone();
+                    async move {
+                        log.lock().unwrap().push(text);
+                        Ok(())
+                    }
+                }
+            });
+
+        let api = crate::test_support::api_builder().into_e2e().unwrap();
+        let ctx = |from: &str| text_context(&api, from, "hello", None);
+        router.dispatch(ctx("ECHOECHO")).await.unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "outer before",
+                "inner before",
+                "HELLO",
+                "inner after",
+                "outer after"
+            ]
+        );
+
+        log.lock().unwrap().clear();
+        assert!(router.dispatch(ctx("BLOCKED1")).await.is_err());
+        assert_eq!(*log.lock().unwrap(), ["outer before", "outer after"]);
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        use std::sync::Mutex;
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let commands = CommandRouter::new()
+            .command("add", "Add two numbers", {
+                let log = log.clone();
+                move |_, (a, b): (i64, i64)| {
+                    let log = log.clone();
+                    async move {
+                        log.lock().unwrap().push(format!("sum {}", a + b));
+                        Ok(())
+                    }
+                }
+            })
+            .command("/Start", "Start the bot", |_, ()| async { Ok(()) })
+            .fallback({
+                let log = log.clone();
+                move |_, text| {
+                    let log = log.clone();
+                    async move {
+                        log.lock().unwrap().push(format!("text {}", text));
+                        Ok(())
+                    }
+                }
+            });
+        assert_eq!(
+            commands.help_text(),
+            "/add - Add two numbers\n/start - Start the bot"
+        );
+        let router = MessageRouter::new().on_commands(commands);
+
+        let api = crate::test_support::api_builder().into_e2e().unwrap();
+        for text in ["/ADD 2 -5", "/start", "hello", "/stop"] {
+            let ctx = text_context(&api, "ECHOECHO", text, None);
+            router.dispatch(ctx).await.unwrap();
+        }
+        assert_eq!(*log.lock().unwrap(), ["sum -3", "text hello", "text /stop"]);
+    }
+
+    #[tokio::test]
+    async fn test_forward_to() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let router = MessageRouter::new().forward_to(tx);
+
+        let api = crate::test_support::api_builder().into_e2e().unwrap();
+        router
+            .dispatch(text_context(&api, "ECHOECHO", "hello", None))
+            .await
+            .unwrap();
+        let ctx = rx.recv().await.unwrap();
+        assert_eq!(ctx.message().text(), Some("hello"));
+        assert_eq!(ctx.message().from, "ECHOECHO");
+
+        drop(rx);
+        let result = router
+            .dispatch(text_context(&api, "ECHOECHO", "again", None))
+            .await;
+        assert!(matches!(result, Err(ApiError::Deferred(_))));
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_rate_limit() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::{mock_server::MockServer, test_support};
+
+        let server = MockServer::start(test_support::GATEWAY_ID, test_support::API_SECRET)
+            .await
+            .unwrap();
+        server.set_public_key(test_support::RECIPIENT_ID, test_support::recipient_key());
+        let handled = Arc::new(AtomicUsize::new(0));
+        let rejected = Arc::new(AtomicUsize::new(0));
+        let counter = |count: &Arc<AtomicUsize>| {
+            let count = count.clone();
+            move |_| {
+                count.fetch_add(1, Ordering::SeqCst);
+                async { Ok(()) }
+            }
+        };
+        let api = test_support::api_builder()
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .into_e2e()
+            .unwrap();
+        let bot = Bot::builder(api)
+            .with_rate_limit(RateLimit::per_minute(1))
+            .on(MessageType::Text, counter(&handled))
+            .on_rate_limited(counter(&rejected))
+            .build();
+
+        for _ in 0..3 {
+            bot.handle_callback(test_support::text_callback_body("hi").as_bytes())
+                .await
+                .unwrap();
+        }
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+        assert_eq!(rejected.load(Ordering::SeqCst), 1);
+
+        // The third message is dropped before the key lookup
+        let lookups = server
+            .requests()
+            .iter()
+            .filter(|request| request.starts_with("GET /pubkeys/"))
+            .count();
+        assert_eq!(lookups, 2);
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        #[derive(Debug, PartialEq, Serialize, serde::Deserialize)]
+        struct Signup {
+            name: Option<String>,
+        }
+
+        let api = crate::test_support::api_builder().into_e2e().unwrap();
+        let sessions = Some(SharedSessionStore::new(MemorySessionStore::default()));
+        let alice = text_context(&api, "ALICE123", "hi", sessions.clone());
+        let bob = text_context(&api, "BOB12345", "hi", sessions.clone());
+
+        assert_eq!(alice.session::<Signup>().await.unwrap(), None);
+        alice.set_session(&Signup { name: None }).await.unwrap();
+        let alice = text_context(&api, "ALICE123", "Alice", sessions);
+        assert_eq!(
+            alice.session::<Signup>().await.unwrap(),
+            Some(Signup { name: None })
+        );
+        assert_eq!(bob.session::<Signup>().await.unwrap(), None);
+        assert!(alice.session::<u32>().await.is_err());
+        alice.clear_session().await.unwrap();
+        assert_eq!(alice.session::<Signup>().await.unwrap(), None);
+
+        let no_store = text_context(&api, "ALICE123", "hi", None);
+        assert_eq!(no_store.session::<Signup>().await.unwrap(), None);
+        assert!(no_store.set_session(&Signup { name: None }).await.is_err());
+    }
+
+    #[test]
+    fn test_outcome_from_result() {
+        assert_eq!(Outcome::from_result(&Ok(())), Outcome::Ack);
+        assert_eq!(
+            Outcome::from_result(&Err(ApiError::Deferred("busy".into()))),
+            Outcome::Defer
+        );
+        assert_eq!(
+            Outcome::from_result(&Err(ApiError::ServerError)),
+            Outcome::Defer
+        );
+        assert_eq!(
+            Outcome::from_result(&Err(ApiError::InvalidMac)),
+            Outcome::Reject
+        );
+        assert_eq!(
+            Outcome::from_result(&Err(ApiError::BadSenderOrRecipient)),
+            Outcome::Ack
+        );
+        assert_eq!(
+            Outcome::Defer.status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_bot() {
+        use crate::{
+            history::{Direction, MemoryMessageStore, MessageStore},
+            mock_server::{MockServer, ReceivedMessage},
+            test_support, MemoryPublicKeyCache,
+        };
+
+        let server = MockServer::start(test_support::GATEWAY_ID, test_support::API_SECRET)
+            .await
+            .unwrap();
+        server.set_public_key(test_support::RECIPIENT_ID, test_support::recipient_key());
+        let store = Arc::new(MemoryMessageStore::default());
+        let api = test_support::api_builder()
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_public_key_cache(MemoryPublicKeyCache::default())
+            .with_message_store(store.clone())
+            .into_e2e()
+            .unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let bot = Bot::builder(api)
+            .on_text(move |ctx, text| {
+                let tx = tx.clone();
+                async move {
+                    ctx.reply_text(&text).await?;
+                    tx.send(text).unwrap();
+                    Ok(())
+                }
+            })
+            .with_auto_receipts(true)
+            .build();
+
+        // Echo, after a delivery receipt
+        bot.handle_callback(test_support::text_callback_body("ping").as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), "ping");
+        let received = server.received_messages();
+        assert_eq!(received.len(), 2);
+        let recipient_private_key = test_support::recipient_private_key();
+        let decrypted: Vec<_> = received
+            .iter()
+            .map(|msg| match msg {
+                ReceivedMessage::E2e {
+                    nonce, ciphertext, ..
+                } => crate::decrypt(
+                    ciphertext,
+                    &crate::Nonce::from(<[u8; 24]>::try_from(&nonce[..]).unwrap()),
+                    &test_support::gateway_public_key(),
+                    &recipient_private_key,
+                )
+                .unwrap(),
+                other => panic!("Unexpected message: {:?}", other),
+            })
+            .collect();
+        assert_eq!(decrypted[0].0, MessageType::DeliveryReceipt);
+        assert_eq!(decrypted[1], (MessageType::Text, b"ping".to_vec()));
+        let history = store.history(test_support::RECIPIENT_ID, 10).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].direction, Direction::Incoming);
+        assert_eq!(history[1].direction, Direction::Outgoing);
+
+        // Over HTTP
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let url = format!("http://{}/callback", listener.local_addr().unwrap());
+        let bot_task = tokio::spawn(bot.serve(listener));
+        let client = reqwest::Client::new();
+        let res = client
+            .post(&url)
+            .body(test_support::text_callback_body("pong"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(rx.recv().await.unwrap(), "pong");
+
+        let res = client
+            .post(&url)
+            .body(test_support::CALLBACK_BODY.replace("mac=b", "mac=c"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 401);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), 405);
+        bot_task.abort();
+    }
+}
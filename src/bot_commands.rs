@@ -0,0 +1,325 @@
+//! Chat commands for the [`Bot`](crate::bot::Bot).
+
+use std::{collections::BTreeMap, fmt, future::Future, str::FromStr, sync::Arc};
+
+use crate::{
+    bot::{BoxFuture, Context},
+    errors::{ApiError, CommandError},
+};
+
+/// A command in a text message, e.g. `/help topic`.
+///
+/// The command name follows the leading `/` and is case insensitive. The
+/// arguments are separated by whitespace, arguments containing whitespace
+/// can be enclosed in double quotes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    name: String,
+    args: Vec<String>,
+}
+
+impl Command {
+    /// Parse a text message. Returns `None` if the text is not a command.
+    pub fn parse(text: &str) -> Option<Self> {
+        let rest = text.trim().strip_prefix('/')?;
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let name = &rest[..end];
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        {
+            return None;
+        }
+
+        let mut args = Vec::new();
+        let mut arg = None::<String>;
+        let mut quoted = false;
+        for c in rest[end..].chars() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    arg.get_or_insert_with(String::new);
+                }
+                c if c.is_whitespace() && !quoted => args.extend(arg.take()),
+                c => arg.get_or_insert_with(String::new).push(c),
+            }
+        }
+        args.extend(arg);
+
+        Some(Command {
+            name: name.to_lowercase(),
+            args,
+        })
+    }
+
+    /// The command name (lowercase, without `/`).
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The arguments.
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Convert the arguments, see [`FromArgs`].
+    pub fn parse_args<A: FromArgs>(&self) -> Result<A, CommandError> {
+        A::from_args(&self.args)
+    }
+}
+
+/// Conversion of command arguments into the parameters of a command handler.
+///
+/// Implemented for `()` (no arguments), for tuples of up to four
+/// [`FromStr`] types (exactly that many arguments) and for `Vec<T>` (any
+/// number of arguments).
+pub trait FromArgs: Sized {
+    /// Convert the arguments.
+    fn from_args(args: &[String]) -> Result<Self, CommandError>;
+}
+
+/// Parse the argument at `index`.
+fn parse_arg<T: FromStr>(args: &[String], index: usize) -> Result<T, CommandError> {
+    args[index]
+        .parse()
+        .map_err(|_| CommandError::InvalidArgument {
+            position: index + 1,
+            value: args[index].clone(),
+        })
+}
+
+/// Check that exactly `expected` arguments were passed.
+fn check_count(args: &[String], expected: usize) -> Result<(), CommandError> {
+    if args.len() == expected {
+        Ok(())
+    } else {
+        Err(CommandError::WrongArgumentCount {
+            expected,
+            actual: args.len(),
+        })
+    }
+}
+
+impl FromArgs for () {
+    fn from_args(args: &[String]) -> Result<Self, CommandError> {
+        check_count(args, 0)
+    }
+}
+
+impl<T: FromStr> FromArgs for Vec<T> {
+    fn from_args(args: &[String]) -> Result<Self, CommandError> {
+        (0..args.len())
+            .map(|index| parse_arg(args, index))
+            .collect()
+    }
+}
+
+macro_rules! impl_from_args {
+    ($count:expr; $($ty:ident: $index:tt),+) => {
+        impl<$($ty: FromStr),+> FromArgs for ($($ty,)+) {
+            fn from_args(args: &[String]) -> Result<Self, CommandError> {
+                check_count(args, $count)?;
+                Ok(($(parse_arg::<$ty>(args, $index)?,)+))
+            }
+        }
+    };
+}
+
+impl_from_args!(1; A: 0);
+impl_from_args!(2; A: 0, B: 1);
+impl_from_args!(3; A: 0, B: 1, C: 2);
+impl_from_args!(4; A: 0, B: 1, C: 2, D: 3);
+
+type CommandHandler = Arc<
+    dyn Fn(Context, &Command) -> Result<BoxFuture<Result<(), ApiError>>, CommandError>
+        + Send
+        + Sync,
+>;
+type TextHandler = Arc<dyn Fn(Context, String) -> BoxFuture<Result<(), ApiError>> + Send + Sync>;
+
+/// A registered command.
+#[derive(Clone)]
+struct Route {
+    description: String,
+    handler: CommandHandler,
+}
+
+/// Dispatches commands in text messages to handlers by name.
+///
+/// The arguments of a command are converted to the parameters of its
+/// handler (see [`FromArgs`]). If that fails, the error is sent to the
+/// sender as reply. Texts that are not a registered command are passed to
+/// the [`fallback`](Self::fallback) handler.
+///
+/// ```
+/// use threema_gateway::bot::{CommandRouter, MessageRouter};
+///
+/// let commands = CommandRouter::new()
+///     .command("start", "Start the bot", |ctx, ()| async move {
+///         ctx.reply_text("Welcome!").await?;
+///         Ok(())
+///     })
+///     .command("add", "Add two numbers", |ctx, (a, b): (i64, i64)| async move {
+///         ctx.reply_text(&(a + b).to_string()).await?;
+///         Ok(())
+///     })
+///     .with_help();
+/// let router = MessageRouter::new().on_commands(commands);
+/// ```
+#[derive(Clone, Default)]
+pub struct CommandRouter {
+    commands: BTreeMap<String, Route>,
+    fallback: Option<TextHandler>,
+    help: bool,
+}
+
+impl CommandRouter {
+    /// Create a router without commands.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle the command `/name`, replacing a previously registered
+    /// handler. The description is shown by the [help](Self::with_help)
+    /// command.
+    pub fn command<A, F, Fut>(mut self, name: &str, description: &str, handler: F) -> Self
+    where
+        A: FromArgs,
+        F: Fn(Context, A) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ApiError>> + Send + 'static,
+    {
+        let handler: CommandHandler = Arc::new(move |ctx, command| {
+            let args = command.parse_args()?;
+            Ok(Box::pin(handler(ctx, args)))
+        });
+        self.commands.insert(
+            name.trim_start_matches('/').to_lowercase(),
+            Route {
+                description: description.to_string(),
+                handler,
+            },
+        );
+        self
+    }
+
+    /// Handle texts that are not a registered command.
+    pub fn fallback<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Context, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ApiError>> + Send + 'static,
+    {
+        self.fallback = Some(Arc::new(move |ctx, text| Box::pin(handler(ctx, text))));
+        self
+    }
+
+    /// Answer `/help` with the [help text](Self::help_text), unless a `help`
+    /// command is registered.
+    pub fn with_help(mut self) -> Self {
+        self.help = true;
+        self
+    }
+
+    /// Return a list of the registered commands with their descriptions.
+    pub fn help_text(&self) -> String {
+        self.commands
+            .iter()
+            .map(|(name, route)| format!("/{} - {}", name, route.description))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Run the handler for the command in `text`.
+    pub async fn dispatch(&self, ctx: Context, text: String) -> Result<(), ApiError> {
+        let command = Command::parse(&text);
+        let route = command
+            .as_ref()
+            .and_then(|command| Some((command, self.commands.get(command.name())?)));
+        match route {
+            Some((command, route)) => match (route.handler)(ctx.clone(), command) {
+                Ok(future) => future.await,
+                Err(e) => {
+                    debug!("Invalid arguments for /{}: {}", command.name(), e);
+                    ctx.reply_text(&format!("/{}: {}", command.name(), e))
+                        .await
+                        .map(|_| ())
+                }
+            },
+            None if self.help
+                && command
+                    .as_ref()
+                    .is_some_and(|command| command.name() == "help") =>
+            {
+                ctx.reply_text(&self.help_text()).await.map(|_| ())
+            }
+            None => match self.fallback {
+                Some(ref fallback) => fallback(ctx, text).await,
+                None => {
+                    debug!("Ignoring text that is not a command");
+                    Ok(())
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Debug for CommandRouter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandRouter")
+            .field("commands", &self.commands.keys().collect::<Vec<_>>())
+            .field("fallback", &self.fallback.is_some())
+            .field("help", &self.help)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(text: &str) -> Option<(String, Vec<String>)> {
+        Command::parse(text).map(|command| (command.name, command.args))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(command("/start"), Some(("start".into(), vec![])));
+        assert_eq!(
+            command(" /Help  arg1\targ2 "),
+            Some(("help".into(), vec!["arg1".into(), "arg2".into()]))
+        );
+        assert_eq!(
+            command(r#"/say "hello world" "" x"#),
+            Some((
+                "say".into(),
+                vec!["hello world".into(), "".into(), "x".into()]
+            ))
+        );
+        assert_eq!(command("hello /start"), None);
+        assert_eq!(command("/"), None);
+        assert_eq!(command("/ start"), None);
+        assert_eq!(command("/path/to/file"), None);
+    }
+
+    #[test]
+    fn test_from_args() {
+        let args = |text: &str| Command::parse(text).unwrap();
+        assert_eq!(args("/a 1 x").parse_args(), Ok((1u8, 'x')));
+        assert_eq!(args("/a").parse_args(), Ok(()));
+        assert_eq!(args("/a 1 2 3").parse_args(), Ok(vec![1, 2, 3]));
+        assert_eq!(
+            args("/a 1").parse_args::<(u8, u8)>(),
+            Err(CommandError::WrongArgumentCount {
+                expected: 2,
+                actual: 1
+            })
+        );
+        assert_eq!(
+            args("/a 1 300").parse_args::<(u8, u8)>(),
+            Err(CommandError::InvalidArgument {
+                position: 2,
+                value: "300".into()
+            })
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! Per-sender rate limits for the [`Bot`](crate::bot::Bot).
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
+
+/// The number of tracked senders above which the state of senders that are
+/// back at their full burst is discarded.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// A limit of the messages per sender that are passed to the handlers, see
+/// [`BotBuilder::with_rate_limit`](crate::bot::BotBuilder::with_rate_limit).
+///
+/// A sender may send `burst` messages at once, after that the messages are
+/// limited to the configured rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    per_minute: u32,
+    burst: u32,
+}
+
+impl RateLimit {
+    /// Allow `messages` messages per minute, with a burst of the same size.
+    pub fn per_minute(messages: u32) -> Self {
+        RateLimit {
+            per_minute: messages,
+            burst: messages,
+        }
+    }
+
+    /// Set the number of messages that may be sent at once.
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+}
+
+/// The token bucket of a sender.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether a message was rejected since the last accepted message.
+    rejected: bool,
+}
+
+/// The decision for a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Decision {
+    Allow,
+    /// The first message over the limit.
+    Reject,
+    /// Further messages over the limit.
+    RejectAgain,
+}
+
+/// Enforces a [`RateLimit`] per sender.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::default(),
+        }
+    }
+
+    fn buckets(&self) -> MutexGuard<'_, HashMap<String, Bucket>> {
+        self.buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Count a message of `sender`.
+    pub(crate) fn check(&self, sender: &str) -> Decision {
+        self.check_at(sender, Instant::now())
+    }
+
+    fn check_at(&self, sender: &str, now: Instant) -> Decision {
+        let burst = f64::from(self.limit.burst);
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated);
+            let tokens = elapsed.as_secs_f64() * f64::from(self.limit.per_minute) / 60.0;
+            (bucket.tokens + tokens).min(burst)
+        };
+
+        let mut buckets = self.buckets();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| refill(bucket) < burst);
+        }
+        let bucket = buckets.entry(sender.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            rejected: false,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.rejected = false;
+            Decision::Allow
+        } else if bucket.rejected {
+            Decision::RejectAgain
+        } else {
+            bucket.rejected = true;
+            Decision::Reject
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_burst_and_refill() {
+        let limiter = RateLimiter::new(RateLimit::per_minute(6).with_burst(2));
+        let start = Instant::now();
+        assert_eq!(limiter.check_at("ECHOECHO", start), Decision::Allow);
+        assert_eq!(limiter.check_at("ECHOECHO", start), Decision::Allow);
+        assert_eq!(limiter.check_at("ECHOECHO", start), Decision::Reject);
+        assert_eq!(limiter.check_at("ECHOECHO", start), Decision::RejectAgain);
+        assert_eq!(limiter.check_at("*3MAGWID", start), Decision::Allow);
+
+        // One message every 10 seconds
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.check_at("ECHOECHO", later), Decision::Allow);
+        assert_eq!(limiter.check_at("ECHOECHO", later), Decision::Reject);
+
+        // The burst is not exceeded after a long pause
+        let much_later = later + Duration::from_secs(3600);
+        for _ in 0..2 {
+            assert_eq!(limiter.check_at("ECHOECHO", much_later), Decision::Allow);
+        }
+        assert_eq!(limiter.check_at("ECHOECHO", much_later), Decision::Reject);
+    }
+}
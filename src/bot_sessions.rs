@@ -0,0 +1,168 @@
+//! Per-sender session state for the [`Bot`](crate::bot::Bot).
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::store::shared_store;
+
+/// Storage for the session state of the bot users.
+///
+/// The state of a session is a JSON value per sender Threema ID. Handlers
+/// access the session of the sender through
+/// [`Context::session`](crate::bot::Context::session) and
+/// [`Context::set_session`](crate::bot::Context::set_session), which convert
+/// the state from and to their own types. This allows multi-step
+/// conversations, where a handler asks a question and the handler for the
+/// next message finds out from the session what the answer belongs to.
+/// The store is configured with
+/// [`BotBuilder::with_session_store`](crate::bot::BotBuilder::with_session_store).
+///
+/// ```
+/// use serde::{Deserialize, Serialize};
+/// use threema_gateway::bot::MessageRouter;
+///
+/// #[derive(Serialize, Deserialize)]
+/// enum Signup {
+///     AskedForName,
+/// }
+///
+/// let router = MessageRouter::new().on_text(|ctx, text| async move {
+///     match ctx.session::<Signup>().await? {
+///         None => {
+///             ctx.reply_text("What's your name?").await?;
+///             ctx.set_session(&Signup::AskedForName).await?;
+///         }
+///         Some(Signup::AskedForName) => {
+///             ctx.reply_text(&format!("Welcome, {}!", text)).await?;
+///             ctx.clear_session().await?;
+///         }
+///     }
+///     Ok(())
+/// });
+/// ```
+pub trait SessionStore: Send + Sync {
+    /// Error returned if store operations fail
+    type Error: Error + Send + Sync + 'static;
+
+    /// Return the session state of the Threema ID `id`.
+    fn load(&self, id: &str) -> impl Future<Output = Result<Option<Value>, Self::Error>> + Send;
+
+    /// Insert or replace the session state of the Threema ID `id`.
+    fn store(
+        &self,
+        id: &str,
+        session: &Value,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Remove the session state of the Threema ID `id`, if it exists.
+    fn remove(&self, id: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+impl<S: SessionStore + ?Sized> SessionStore for Arc<S> {
+    type Error = S::Error;
+
+    fn load(&self, id: &str) -> impl Future<Output = Result<Option<Value>, Self::Error>> + Send {
+        (**self).load(id)
+    }
+
+    fn store(
+        &self,
+        id: &str,
+        session: &Value,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        (**self).store(id, session)
+    }
+
+    fn remove(&self, id: &str) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        (**self).remove(id)
+    }
+}
+
+/// A [`SessionStore`] that keeps the sessions in memory.
+///
+/// Sessions are kept until they are removed, or, if a timeout is set (see
+/// [`with_timeout`](Self::with_timeout)), until they have not been stored for
+/// that long. The timeout ends conversations that users abandoned midway.
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, (Instant, Value)>>,
+    timeout: Option<Duration>,
+}
+
+impl MemorySessionStore {
+    /// Create a store that discards sessions which have not been stored for
+    /// `timeout`.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        MemorySessionStore {
+            sessions: Mutex::default(),
+            timeout: Some(timeout),
+        }
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, HashMap<String, (Instant, Value)>> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    type Error = Infallible;
+
+    async fn load(&self, id: &str) -> Result<Option<Value>, Self::Error> {
+        let mut sessions = self.sessions();
+        if let Some(timeout) = self.timeout {
+            sessions.retain(|_, (stored, _)| stored.elapsed() < timeout);
+        }
+        Ok(sessions.get(id).map(|(_, session)| session.clone()))
+    }
+
+    async fn store(&self, id: &str, session: &Value) -> Result<(), Self::Error> {
+        self.sessions()
+            .insert(id.to_string(), (Instant::now(), session.clone()));
+        Ok(())
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), Self::Error> {
+        self.sessions().remove(id);
+        Ok(())
+    }
+}
+
+shared_store! {
+    /// A type erased [`SessionStore`] that is shared between the handler
+    /// contexts.
+    SharedSessionStore(DynSessionStore): SessionStore {
+        fn load(id: &str) -> Option<Value>;
+        fn store(id: &str, session: &Value) -> ();
+        fn remove(id: &str) -> ();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let store = MemorySessionStore::with_timeout(Duration::from_millis(50));
+        SessionStore::store(&store, "ECHOECHO", &Value::from(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            SessionStore::load(&store, "ECHOECHO").await.unwrap(),
+            Some(Value::from(1))
+        );
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(SessionStore::load(&store, "ECHOECHO").await.unwrap(), None);
+        assert!(store.sessions().is_empty());
+    }
+}
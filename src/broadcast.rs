@@ -0,0 +1,325 @@
+//! Client for the Threema Broadcast API.
+//!
+//! Threema Broadcast manages feeds, bots and distribution lists of Broadcast
+//! identities. The [`BroadcastApi`] can be used to list these and to send
+//! messages to distribution lists. Requests are authenticated with a
+//! Broadcast API key (which is different from the gateway secret).
+//!
+//! The Broadcast API object can share the HTTP client with a gateway API
+//! object:
+//!
+//! ```no_run
+//! # tokio_test::block_on(async {
+//! use threema_gateway::ApiBuilder;
+//!
+//! let api = ApiBuilder::new("*3MAGWID", "secret").into_simple().unwrap();
+//! let broadcast = api.broadcast_api("broadcast-api-key");
+//! for identity in broadcast.identities().await.unwrap() {
+//!     for list in broadcast.distribution_lists(&identity.id).await.unwrap() {
+//!         broadcast
+//!             .send_text(&identity.id, &list.id, "Hello")
+//!             .await
+//!             .unwrap();
+//!     }
+//! }
+//! # })
+//! ```
+
+use std::{
+    borrow::Cow,
+    time::{Duration, Instant},
+};
+
+use reqwest::{Client, RequestBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    connection::{map_response_code, send_request, Timeouts},
+    errors::ApiError,
+    hooks::Hooks,
+};
+
+/// The URL of the Threema Broadcast API.
+pub const BROADCAST_API_URL: &str = "https://broadcast.threema.ch/api/v1";
+
+/// The header carrying the API key.
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// The kind of a Broadcast identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityKind {
+    /// A feed that sends messages to its subscribers.
+    Feed,
+    /// A bot that responds to incoming messages.
+    Bot,
+    /// A kind not known to this library.
+    #[serde(other)]
+    Other,
+}
+
+/// A Broadcast identity (feed or bot).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BroadcastIdentity {
+    /// The ID of the identity in the Broadcast API.
+    pub id: String,
+    /// The Threema ID of the identity.
+    pub threema_id: String,
+    /// The name of the identity.
+    pub name: Option<String>,
+    /// Whether the identity is a feed or a bot.
+    #[serde(rename = "type")]
+    pub kind: IdentityKind,
+}
+
+/// A contact of a Broadcast identity.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BroadcastContact {
+    /// The ID of the contact in the Broadcast API.
+    pub id: String,
+    /// The Threema ID of the contact.
+    pub threema_id: String,
+    /// The first name of the contact.
+    pub first_name: Option<String>,
+    /// The last name of the contact.
+    pub last_name: Option<String>,
+}
+
+/// A distribution list of a Broadcast identity.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DistributionList {
+    /// The ID of the distribution list in the Broadcast API.
+    pub id: String,
+    /// The name of the distribution list.
+    pub name: String,
+    /// The number of members of the distribution list.
+    pub member_count: Option<u32>,
+}
+
+/// A link to a related resource, used for pagination.
+#[derive(Debug, Deserialize)]
+struct Link {
+    #[serde(rename = "ref")]
+    rel: String,
+    link: String,
+}
+
+/// A page of a list resource. The items are stored under a resource specific
+/// key, which is flattened into `items`.
+#[derive(Debug, Deserialize)]
+struct Page<T> {
+    #[serde(alias = "identities", alias = "contacts", alias = "distributionLists")]
+    items: Vec<T>,
+    #[serde(rename = "_links", default)]
+    links: Vec<Link>,
+}
+
+/// The body of a message sent to a distribution list.
+#[derive(Debug, Serialize)]
+struct TextMessage<'a> {
+    text: &'a str,
+}
+
+/// Return the URL of the next page, if any.
+fn next_link(links: Vec<Link>) -> Option<String> {
+    links
+        .into_iter()
+        .find(|link| link.rel == "next")
+        .map(|link| link.link)
+}
+
+/// Struct to talk to the Threema Broadcast API.
+#[derive(Debug, Clone)]
+pub struct BroadcastApi {
+    api_key: String,
+    endpoint: Cow<'static, str>,
+    client: Client,
+    deadline: Option<Instant>,
+}
+
+impl BroadcastApi {
+    /// Initialize the Broadcast API with a Broadcast API key.
+    pub fn new<K: Into<String>>(api_key: K) -> Self {
+        let client = Timeouts::default()
+            .client_builder()
+            .build()
+            .expect("Could not build client");
+        Self::with_client(api_key, client)
+    }
+
+    /// Initialize the Broadcast API with the API key and a custom HTTP
+    /// client.
+    pub fn with_client<K: Into<String>>(api_key: K, client: Client) -> Self {
+        BroadcastApi {
+            api_key: api_key.into(),
+            endpoint: Cow::Borrowed(BROADCAST_API_URL),
+            client,
+            deadline: None,
+        }
+    }
+
+    /// Set a custom API endpoint (without trailing slash).
+    pub fn with_custom_endpoint<E: Into<Cow<'static, str>>>(mut self, endpoint: E) -> Self {
+        self.endpoint = endpoint.into();
+        self
+    }
+
+    /// Return a copy of the API object whose calls fail with
+    /// [`ApiError::DeadlineExceeded`] if they don't complete before
+    /// `deadline`.
+    pub fn with_deadline(&self, deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self.clone()
+        }
+    }
+
+    /// Return a copy of the API object whose calls fail with
+    /// [`ApiError::DeadlineExceeded`] if they don't complete within
+    /// `timeout`, starting now.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Send an authenticated request and check the response status.
+    async fn send<T, F, Fut>(&self, request: RequestBuilder, handle: F) -> Result<T, ApiError>
+    where
+        F: FnOnce(reqwest::Response) -> Fut,
+        Fut: std::future::Future<Output = Result<T, ApiError>>,
+    {
+        let request = request.header(API_KEY_HEADER, &self.api_key);
+        send_request(
+            request,
+            self.deadline,
+            &Hooks::default(),
+            |res| async move {
+                if !res.status().is_success() {
+                    map_response_code(res.status(), None)?;
+                }
+                handle(res).await
+            },
+        )
+        .await
+    }
+
+    /// Fetch all pages of the list resource at `url`.
+    async fn get_all<T: DeserializeOwned>(&self, url: String) -> Result<Vec<T>, ApiError> {
+        let mut items = Vec::new();
+        let mut url = Some(url);
+        while let Some(page_url) = url {
+            let page: Page<T> = self
+                .send(self.client.get(&page_url), |res| async move {
+                    let body = res.bytes().await?;
+                    serde_json::from_slice(&body).map_err(|e| {
+                        ApiError::ParseError(format!(
+                            "Could not parse Broadcast API response: {}",
+                            e
+                        ))
+                    })
+                })
+                .await?;
+            items.extend(page.items);
+            url = next_link(page.links);
+        }
+        Ok(items)
+    }
+
+    /// List the Broadcast identities (feeds and bots) accessible with the
+    /// API key.
+    pub async fn identities(&self) -> Result<Vec<BroadcastIdentity>, ApiError> {
+        self.get_all(format!("{}/identities", self.endpoint)).await
+    }
+
+    /// List the contacts of a Broadcast identity.
+    pub async fn contacts(&self, identity_id: &str) -> Result<Vec<BroadcastContact>, ApiError> {
+        self.get_all(format!(
+            "{}/identities/{}/contacts",
+            self.endpoint, identity_id
+        ))
+        .await
+    }
+
+    /// List the distribution lists of a Broadcast identity.
+    pub async fn distribution_lists(
+        &self,
+        identity_id: &str,
+    ) -> Result<Vec<DistributionList>, ApiError> {
+        self.get_all(format!(
+            "{}/identities/{}/distribution-lists",
+            self.endpoint, identity_id
+        ))
+        .await
+    }
+
+    /// Send a text message to all members of a distribution list.
+    pub async fn send_text(
+        &self,
+        identity_id: &str,
+        distribution_list_id: &str,
+        text: &str,
+    ) -> Result<(), ApiError> {
+        let url = format!(
+            "{}/identities/{}/distribution-lists/{}/messages",
+            self.endpoint, identity_id, distribution_list_id
+        );
+        let body = serde_json::to_vec(&TextMessage { text })
+            .map_err(|e| ApiError::Other(format!("Could not serialize message: {}", e)))?;
+        let request = self
+            .client
+            .post(url)
+            .header("content-type", "application/json")
+            .body(body);
+        self.send(request, |_| async { Ok(()) }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_identities_page() {
+        let json = r#"{
+            "identities": [
+                {"id": "i1", "threemaId": "*FEED001", "name": "News", "type": "feed"},
+                {"id": "i2", "threemaId": "*BOT0001", "type": "bot"},
+                {"id": "i3", "threemaId": "*OTHER01", "type": "relay"}
+            ],
+            "_links": [
+                {"ref": "next", "link": "https://broadcast.threema.ch/api/v1/identities?page=1"}
+            ]
+        }"#;
+        let page: Page<BroadcastIdentity> = serde_json::from_str(json).unwrap();
+        let kinds: Vec<_> = page.items.iter().map(|i| i.kind).collect();
+        assert_eq!(
+            kinds,
+            [IdentityKind::Feed, IdentityKind::Bot, IdentityKind::Other]
+        );
+        assert_eq!(page.items[0].name.as_deref(), Some("News"));
+        assert_eq!(page.items[1].name, None);
+        assert_eq!(
+            next_link(page.links).as_deref(),
+            Some("https://broadcast.threema.ch/api/v1/identities?page=1")
+        );
+    }
+
+    #[test]
+    fn test_parse_distribution_lists_page() {
+        let json = r#"{
+            "distributionLists": [{"id": "d1", "name": "Everyone", "memberCount": 42}]
+        }"#;
+        let page: Page<DistributionList> = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            page.items,
+            vec![DistributionList {
+                id: "d1".into(),
+                name: "Everyone".into(),
+                member_count: Some(42),
+            }]
+        );
+        assert_eq!(next_link(page.links), None);
+    }
+}
@@ -1,22 +1,348 @@
-use std::future::Future;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    error::Error,
+    future::Future,
+    io,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
 
-use crate::crypto::RecipientKey;
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto::RecipientKey, errors::KeyExportError, store::shared_store, types::BlobId};
 
 /// A cache for Threema public keys
-pub trait PublicKeyCache {
+///
+/// A cache can be passed to
+/// [`ApiBuilder::with_public_key_cache`](crate::ApiBuilder::with_public_key_cache),
+/// it is then used by [`E2eApi::send_text`](crate::E2eApi::send_text).
+pub trait PublicKeyCache: Send + Sync {
     /// Error returned if cache operations fail
-    type Error: std::error::Error;
+    type Error: Error + Send + Sync + 'static;
 
     /// Store a public key for `identity` in the cache
     fn store(
         &self,
         identity: &str,
         key: &RecipientKey,
-    ) -> impl Future<Output = Result<(), Self::Error>>;
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
     /// Retrieve a public key for `identity` from the cache
     fn load(
         &self,
         identity: &str,
-    ) -> impl Future<Output = Result<Option<RecipientKey>, Self::Error>>;
+    ) -> impl Future<Output = Result<Option<RecipientKey>, Self::Error>> + Send;
+}
+
+/// A [`PublicKeyCache`] that can enumerate its entries
+///
+/// Required by [`export_public_keys`].
+pub trait ListablePublicKeyCache: PublicKeyCache {
+    /// Return all identities in the cache with their public keys
+    fn list(&self)
+        -> impl Future<Output = Result<Vec<(String, RecipientKey)>, Self::Error>> + Send;
+}
+
+/// A [`PublicKeyCache`] that keeps the keys in memory.
+#[derive(Debug, Default)]
+pub struct MemoryPublicKeyCache {
+    keys: Mutex<HashMap<String, RecipientKey>>,
+}
+
+impl MemoryPublicKeyCache {
+    fn keys(&self) -> MutexGuard<'_, HashMap<String, RecipientKey>> {
+        self.keys
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl PublicKeyCache for MemoryPublicKeyCache {
+    type Error = Infallible;
+
+    async fn store(&self, identity: &str, key: &RecipientKey) -> Result<(), Self::Error> {
+        self.keys().insert(identity.to_string(), key.clone());
+        Ok(())
+    }
+
+    async fn load(&self, identity: &str) -> Result<Option<RecipientKey>, Self::Error> {
+        Ok(self.keys().get(identity).cloned())
+    }
+}
+
+impl ListablePublicKeyCache for MemoryPublicKeyCache {
+    async fn list(&self) -> Result<Vec<(String, RecipientKey)>, Self::Error> {
+        Ok(self
+            .keys()
+            .iter()
+            .map(|(identity, key)| (identity.clone(), key.clone()))
+            .collect())
+    }
+}
+
+/// The version of the format written by [`export_public_keys`].
+const EXPORT_VERSION: u32 = 1;
+
+/// The JSON document written by [`export_public_keys`].
+#[derive(Debug, Serialize, Deserialize)]
+struct PublicKeyExport {
+    version: u32,
+    /// Hex encoded public keys by identity
+    keys: BTreeMap<String, String>,
+}
+
+/// Export the contents of a [`ListablePublicKeyCache`] as JSON.
+///
+/// The document contains a format version and an object mapping the
+/// identities to their hex encoded public keys, sorted by identity:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "keys": {
+///     "ECHOECHO": "4a6a1b34dcef15d43cb74de2fd36091be99fbbaf126d099d47d83d919712c72b"
+///   }
+/// }
+/// ```
+///
+/// The document can be loaded into another cache with
+/// [`import_public_keys`], e.g. to seed a cache in a test environment
+/// without querying the directory.
+pub async fn export_public_keys<C: ListablePublicKeyCache>(
+    cache: &C,
+) -> Result<String, KeyExportError<C::Error>> {
+    let export = PublicKeyExport {
+        version: EXPORT_VERSION,
+        keys: cache
+            .list()
+            .await
+            .map_err(KeyExportError::CacheError)?
+            .into_iter()
+            .map(|(identity, key)| (identity, key.to_hex_string()))
+            .collect(),
+    };
+    serde_json::to_string_pretty(&export).map_err(|e| KeyExportError::InvalidExport(e.to_string()))
+}
+
+/// Store the public keys of a document written by [`export_public_keys`] in
+/// a [`PublicKeyCache`].
+///
+/// The whole document is validated before any key is stored. Returns the
+/// number of imported keys.
+pub async fn import_public_keys<C: PublicKeyCache>(
+    cache: &C,
+    json: &str,
+) -> Result<usize, KeyExportError<C::Error>> {
+    let export: PublicKeyExport =
+        serde_json::from_str(json).map_err(|e| KeyExportError::InvalidExport(e.to_string()))?;
+    if export.version != EXPORT_VERSION {
+        return Err(KeyExportError::InvalidExport(format!(
+            "Unsupported version {}",
+            export.version
+        )));
+    }
+    let keys = export
+        .keys
+        .into_iter()
+        .map(|(identity, key)| match key.parse::<RecipientKey>() {
+            Ok(key) if identity.len() == 8 => Ok((identity, key)),
+            Ok(_) => Err(KeyExportError::InvalidExport(format!(
+                "Invalid identity {}",
+                identity
+            ))),
+            Err(e) => Err(KeyExportError::InvalidExport(format!(
+                "Invalid key for {}: {}",
+                identity, e
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (identity, key) in &keys {
+        cache
+            .store(identity, key)
+            .await
+            .map_err(KeyExportError::CacheError)?;
+    }
+    Ok(keys.len())
+}
+
+shared_store! {
+    /// A type erased [`PublicKeyCache`] that is shared between clones of the
+    /// API object.
+    SharedPublicKeyCache(DynPublicKeyCache): PublicKeyCache {
+        fn store(identity: &str, key: &RecipientKey) -> ();
+        fn load(identity: &str) -> Option<RecipientKey>;
+    }
+}
+
+/// A cache for downloaded blobs
+///
+/// A cache can be passed to
+/// [`ApiBuilder::with_blob_cache`](crate::ApiBuilder::with_blob_cache), it
+/// is then consulted by [`E2eApi::blob_download`](crate::E2eApi::blob_download)
+/// and the [`BlobDownloader`](crate::download::BlobDownloader). This avoids
+/// fetching persistent blobs that are referenced in many messages (e.g.
+/// stickers) over and over. The blobs are cached in encrypted form.
+pub trait BlobCache: Send + Sync {
+    /// Error returned if cache operations fail
+    type Error: Error + Send + Sync + 'static;
+
+    /// Store the encrypted data of the blob `blob_id` in the cache
+    fn store(
+        &self,
+        blob_id: &BlobId,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Retrieve the encrypted data of the blob `blob_id` from the cache
+    fn load(
+        &self,
+        blob_id: &BlobId,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, Self::Error>> + Send;
+}
+
+/// A [`BlobCache`] that stores every blob in a file in a directory.
+///
+/// The directory is created if it doesn't exist. Blobs are never evicted,
+/// old files can be removed by an external job at any time.
+#[derive(Debug, Clone)]
+pub struct DiskBlobCache {
+    dir: PathBuf,
+}
+
+impl DiskBlobCache {
+    /// Create a cache storing the blobs in `dir`.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        DiskBlobCache { dir: dir.into() }
+    }
+
+    fn path(&self, blob_id: &BlobId) -> PathBuf {
+        self.dir.join(blob_id.to_string())
+    }
+}
+
+impl BlobCache for DiskBlobCache {
+    type Error = io::Error;
+
+    async fn store(&self, blob_id: &BlobId, data: &[u8]) -> Result<(), Self::Error> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        // Write to a temporary file first, so that concurrent readers never
+        // see a partially written blob
+        let path = self.path(blob_id);
+        let tmp_path = path.with_extension(format!("{}.tmp", rand::random::<u32>()));
+        tokio::fs::write(&tmp_path, data).await?;
+        if let Err(e) = tokio::fs::rename(&tmp_path, &path).await {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn load(&self, blob_id: &BlobId) -> Result<Option<Vec<u8>>, Self::Error> {
+        match tokio::fs::read(self.path(blob_id)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+shared_store! {
+    /// A type erased [`BlobCache`] that is shared between clones of the API
+    /// object.
+    SharedBlobCache(DynBlobCache): BlobCache {
+        fn store(blob_id: &BlobId, data: &[u8]) -> ();
+        fn load(blob_id: &BlobId) -> Option<Vec<u8>>;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        export_public_keys, import_public_keys, ListablePublicKeyCache, MemoryPublicKeyCache,
+        PublicKeyCache,
+    };
+    use crate::{crypto::RecipientKey, errors::KeyExportError};
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let cache = MemoryPublicKeyCache::default();
+        cache
+            .store("ECHOECHO", &RecipientKey::from([1; 32]))
+            .await
+            .unwrap();
+        cache
+            .store("*3MAGWID", &RecipientKey::from([2; 32]))
+            .await
+            .unwrap();
+        let json = export_public_keys(&cache).await.unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["version"], 1);
+        assert_eq!(value["keys"]["ECHOECHO"], "01".repeat(32));
+
+        let other = MemoryPublicKeyCache::default();
+        assert_eq!(import_public_keys(&other, &json).await.unwrap(), 2);
+        let mut keys = other.list().await.unwrap();
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            keys,
+            [
+                ("*3MAGWID".to_string(), RecipientKey::from([2; 32])),
+                ("ECHOECHO".to_string(), RecipientKey::from([1; 32])),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_invalid() {
+        let cache = MemoryPublicKeyCache::default();
+        for json in [
+            "[]",
+            r#"{"version": 2, "keys": {}}"#,
+            r#"{"version": 1, "keys": {"ECHOECHO": "abcd"}}"#,
+            r#"{"version": 1, "keys": {"ECHO": "0101010101010101010101010101010101010101010101010101010101010101"}}"#,
+        ] {
+            assert!(matches!(
+                import_public_keys(&cache, json).await,
+                Err(KeyExportError::InvalidExport(_))
+            ));
+        }
+        assert!(cache.list().await.unwrap().is_empty());
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_blob_cache() {
+        use super::{BlobCache, DiskBlobCache};
+        use crate::{
+            errors::ApiError, mock_server::MockServer, types::BlobId, ApiBuilder, SecretKey,
+        };
+
+        let dir = std::env::temp_dir().join(format!("threema-blob-cache-{}", std::process::id()));
+        let cache = DiskBlobCache::new(&dir);
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .with_blob_cache(cache.clone())
+            .into_e2e()
+            .unwrap();
+
+        // Downloaded blobs are stored in the cache
+        let blob_id = api.blob_upload_raw(&[1, 2, 3], true).await.unwrap();
+        assert_eq!(api.blob_download(&blob_id).await.unwrap(), vec![1, 2, 3]);
+        assert_eq!(cache.load(&blob_id).await.unwrap(), Some(vec![1, 2, 3]));
+
+        // Cached blobs are not fetched from the server
+        let cached_id = BlobId::random();
+        cache.store(&cached_id, &[4; 8]).await.unwrap();
+        assert_eq!(api.blob_download(&cached_id).await.unwrap(), vec![4; 8]);
+        let result = api.blob_download_limited(&cached_id, 4).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            result,
+            Err(ApiError::BlobTooLarge { size: 8, limit: 4 })
+        ));
+    }
 }
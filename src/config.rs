@@ -0,0 +1,313 @@
+//! Loading of the API configuration from a file.
+//!
+//! The configuration can be written in TOML or JSON. Files with a `.json`
+//! extension are parsed as JSON, all other files as TOML. Key material and
+//! the API secret can be read from separate files, so that the configuration
+//! itself does not need to contain secrets. Relative paths are resolved
+//! relative to the directory containing the configuration file.
+//!
+//! Example (TOML):
+//!
+//! ```toml
+//! id = "*3MAGWID"
+//! secret_file = "secret.txt"
+//! private_key_file = "private.key"
+//! timeout_secs = 300
+//! connect_timeout_secs = 5
+//! read_timeout_secs = 30
+//!
+//! [retry]
+//! max_retries = 3
+//! initial_backoff_ms = 500
+//! max_backoff_ms = 5000
+//! ```
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{api::ApiBuilder, errors::ConfigError, redact::Redacted, retry::RetryPolicy};
+
+/// Configuration for an [`ApiBuilder`], as loaded from a file.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The gateway ID.
+    pub id: String,
+    /// The API secret. Either this or `secret_file` must be set.
+    pub secret: Option<String>,
+    /// Path to a file containing the API secret.
+    pub secret_file: Option<PathBuf>,
+    /// The hex encoded private key, optionally prefixed with `private:`.
+    pub private_key: Option<String>,
+    /// Path to a file containing the hex encoded private key, optionally
+    /// prefixed with `private:` (as generated by the Threema Gateway tools).
+    pub private_key_file: Option<PathBuf>,
+    /// Custom API endpoint.
+    pub endpoint: Option<String>,
+    /// Fallback API endpoints, used if the API endpoint is unreachable.
+    #[serde(default)]
+    pub fallback_endpoints: Vec<String>,
+    /// Custom blob server endpoint.
+    pub blob_endpoint: Option<String>,
+    /// Allow plain HTTP endpoints, see
+    /// [`ApiBuilder::allow_insecure_http`].
+    #[serde(default)]
+    pub allow_insecure_http: bool,
+    /// Timeout for whole requests in seconds.
+    pub timeout_secs: Option<u64>,
+    /// Timeout for establishing a connection in seconds.
+    pub connect_timeout_secs: Option<u64>,
+    /// Timeout for every read from the connection in seconds.
+    pub read_timeout_secs: Option<u64>,
+    /// Retry settings for blob transfers.
+    pub retry: Option<RetryConfig>,
+}
+
+/// Retry settings, see [`RetryPolicy`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Maximum number of retries after the initial attempt.
+    pub max_retries: Option<u32>,
+    /// Delay before the first retry in milliseconds.
+    pub initial_backoff_ms: Option<u64>,
+    /// Upper bound for the delay between two attempts in milliseconds.
+    pub max_backoff_ms: Option<u64>,
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("id", &self.id)
+            .field("secret", &self.secret.as_ref().map(Redacted))
+            .field("secret_file", &self.secret_file)
+            .field("private_key", &self.private_key.as_ref().map(Redacted))
+            .field("private_key_file", &self.private_key_file)
+            .field("endpoint", &self.endpoint)
+            .field("fallback_endpoints", &self.fallback_endpoints)
+            .field("blob_endpoint", &self.blob_endpoint)
+            .field("allow_insecure_http", &self.allow_insecure_http)
+            .field("timeout_secs", &self.timeout_secs)
+            .field("connect_timeout_secs", &self.connect_timeout_secs)
+            .field("read_timeout_secs", &self.read_timeout_secs)
+            .field("retry", &self.retry)
+            .finish()
+    }
+}
+
+impl Config {
+    /// Load the configuration from a TOML or JSON file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        let mut config = if path.extension().is_some_and(|ext| ext == "json") {
+            Self::from_json(&contents)?
+        } else {
+            Self::from_toml(&contents)?
+        };
+
+        // Resolve paths relative to the config file
+        if let Some(dir) = path.parent() {
+            for file in [&mut config.secret_file, &mut config.private_key_file]
+                .into_iter()
+                .flatten()
+            {
+                if file.is_relative() {
+                    *file = dir.join(&*file);
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Parse the configuration from a TOML string.
+    pub fn from_toml(contents: &str) -> Result<Self, ConfigError> {
+        toml::from_str(contents).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+
+    /// Parse the configuration from a JSON string.
+    pub fn from_json(contents: &str) -> Result<Self, ConfigError> {
+        serde_json::from_str(contents).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+
+    /// Create an [`ApiBuilder`] from this configuration.
+    ///
+    /// Referenced secret and key files are read in this step.
+    pub fn into_builder(self) -> Result<ApiBuilder, ConfigError> {
+        let secret = match (self.secret, self.secret_file) {
+            (Some(_), Some(_)) => {
+                return Err(ConfigError::Invalid(
+                    "Only one of secret and secret_file may be set".into(),
+                ))
+            }
+            (Some(secret), None) => secret,
+            (None, Some(path)) => fs::read_to_string(path)?.trim().to_string(),
+            (None, None) => {
+                return Err(ConfigError::Invalid(
+                    "One of secret and secret_file must be set".into(),
+                ))
+            }
+        };
+        let mut builder = ApiBuilder::new(self.id, secret);
+
+        let private_key = match (self.private_key, self.private_key_file) {
+            (Some(_), Some(_)) => {
+                return Err(ConfigError::Invalid(
+                    "Only one of private_key and private_key_file may be set".into(),
+                ))
+            }
+            (Some(key), None) => Some(key),
+            (None, Some(path)) => Some(fs::read_to_string(path)?),
+            (None, None) => None,
+        };
+        if let Some(private_key) = private_key {
+            builder = builder.with_private_key_str(&private_key)?;
+        }
+
+        if let Some(endpoint) = self.endpoint {
+            builder = builder.with_custom_endpoint(endpoint);
+        }
+        if self.allow_insecure_http {
+            builder = builder.allow_insecure_http();
+        }
+        for endpoint in self.fallback_endpoints {
+            builder = builder.with_fallback_endpoint(endpoint);
+        }
+        if let Some(blob_endpoint) = self.blob_endpoint {
+            builder = builder.with_blob_endpoint(blob_endpoint);
+        }
+        if let Some(timeout) = self.timeout_secs {
+            builder = builder.with_total_timeout(Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = self.connect_timeout_secs {
+            builder = builder.with_connect_timeout(Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = self.read_timeout_secs {
+            builder = builder.with_read_timeout(Duration::from_secs(timeout));
+        }
+        if let Some(retry) = self.retry {
+            let default = RetryPolicy::default();
+            builder = builder.with_retry_policy(RetryPolicy {
+                max_retries: retry.max_retries.unwrap_or(default.max_retries),
+                initial_backoff: retry
+                    .initial_backoff_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(default.initial_backoff),
+                max_backoff: retry
+                    .max_backoff_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(default.max_backoff),
+            });
+        }
+
+        Ok(builder)
+    }
+}
+
+impl ApiBuilder {
+    /// Create an `ApiBuilder` from a TOML or JSON configuration file.
+    ///
+    /// See the [`config`](crate::config) module for the file format.
+    pub fn from_config_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        Config::from_file(path)?.into_builder()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Timeouts;
+
+    const PRIVATE_KEY: &str = "998730fbcac1c57dbb181139de41d12835b3fae6af6acdf6ce91670262e88453";
+
+    #[test]
+    fn test_from_toml() {
+        let config = Config::from_toml(
+            r#"
+            id = "*3MAGWID"
+            secret = "1234"
+            endpoint = "https://example.com"
+            fallback_endpoints = ["https://fallback.example.com"]
+            connect_timeout_secs = 5
+
+            [retry]
+            max_retries = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.id, "*3MAGWID");
+        assert_eq!(config.endpoint.as_deref(), Some("https://example.com"));
+        assert_eq!(config.retry.as_ref().unwrap().max_retries, Some(5));
+
+        let builder = config.into_builder().unwrap();
+        assert_eq!(builder.secret, "1234");
+        assert_eq!(builder.endpoint, "https://example.com");
+        assert_eq!(
+            builder.fallback_endpoints,
+            vec!["https://fallback.example.com"]
+        );
+        assert_eq!(
+            builder.timeouts,
+            Timeouts {
+                connect: Some(Duration::from_secs(5)),
+                ..Default::default()
+            }
+        );
+        assert_eq!(builder.retry_policy.max_retries, 5);
+        assert_eq!(
+            builder.retry_policy.initial_backoff,
+            RetryPolicy::default().initial_backoff
+        );
+        assert!(builder.private_key.is_none());
+    }
+
+    #[test]
+    fn test_from_file_with_key_files() {
+        let dir = std::env::temp_dir().join(format!("threema-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("secret.txt"), "1234\n").unwrap();
+        fs::write(
+            dir.join("private.key"),
+            format!("private:{}\n", PRIVATE_KEY),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("config.json"),
+            r#"{"id": "*3MAGWID", "secret_file": "secret.txt", "private_key_file": "private.key", "timeout_secs": 5}"#,
+        )
+        .unwrap();
+
+        let builder = ApiBuilder::from_config_file(dir.join("config.json"));
+        fs::remove_dir_all(&dir).unwrap();
+        let builder = builder.unwrap();
+        assert_eq!(builder.secret, "1234");
+        assert!(builder.private_key.is_some());
+        assert_eq!(builder.timeouts.total, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(
+            Config::from_toml("id = \"*3MAGWID\"\nfoo = 1"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            Config::from_toml("id = \"*3MAGWID\"")
+                .unwrap()
+                .into_builder(),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            Config::from_toml("id = \"*3MAGWID\"\nsecret = \"1234\"\nprivate_key = \"xyz\"")
+                .unwrap()
+                .into_builder(),
+            Err(ConfigError::InvalidKey(_))
+        ));
+    }
+}
@@ -1,11 +1,73 @@
 //! Send and receive messages.
 
-use std::{borrow::Cow, collections::HashMap, str::FromStr};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    future::Future,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
+use bytes::Bytes;
 use data_encoding::HEXLOWER;
-use reqwest::{multipart, Client, StatusCode};
+use reqwest::{multipart, Client, ClientBuilder, RequestBuilder, Response, StatusCode};
 
-use crate::{errors::ApiError, types::BlobId};
+use crate::{
+    errors::{ApiError, RecipientParseError},
+    hooks::Hooks,
+    redact::Redacted,
+    retry::{with_retry, RetryPolicy},
+    types::{BlobId, MessageId, RequestId, SendOptions, SendResult},
+};
+
+/// Timeouts of the HTTP client created by the [`ApiBuilder`](crate::ApiBuilder).
+///
+/// Lookups usually complete within milliseconds, while blob transfers of
+/// large files can take minutes. To detect unreachable or stalled servers
+/// without aborting slow but progressing transfers, set a short `connect`
+/// and `read` timeout and a long (or no) `total` timeout.
+///
+/// The timeouts are not applied to a custom client set with
+/// [`ApiBuilder::with_client`](crate::ApiBuilder::with_client).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Timeout for establishing a connection, including the TLS handshake.
+    pub connect: Option<Duration>,
+    /// Timeout for every read from the connection. It is reset after each
+    /// successful read, so it limits stalls rather than the duration of a
+    /// transfer.
+    pub read: Option<Duration>,
+    /// Timeout for the whole request, from connecting until the response
+    /// body has been read.
+    pub total: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Return a HTTP client builder with these timeouts.
+    pub(crate) fn client_builder(&self) -> ClientBuilder {
+        let mut builder = Client::builder();
+        if let Some(connect) = self.connect {
+            builder = builder.connect_timeout(connect);
+        }
+        if let Some(read) = self.read {
+            builder = builder.read_timeout(read);
+        }
+        if let Some(total) = self.total {
+            builder = builder.timeout(total);
+        }
+        builder
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: None,
+            read: None,
+            total: Some(Duration::from_secs(10)),
+        }
+    }
+}
 
 /// Map HTTP response status code to an ApiError if it isn't "200".
 ///
@@ -39,8 +101,73 @@ pub(crate) fn map_response_code(
     }
 }
 
+/// The header carrying the correlation ID of a request.
+pub(crate) const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Send a request with a new correlation ID and process the response with
+/// `handle`.
+///
+/// The correlation ID is sent as header and included in the log output.
+/// Errors are annotated with it.
+///
+/// If a `deadline` is set, it overrides the timeout of the client.
+pub(crate) async fn send_request<T, F, Fut>(
+    request: RequestBuilder,
+    deadline: Option<Instant>,
+    hooks: &Hooks,
+    handle: F,
+) -> Result<T, ApiError>
+where
+    F: FnOnce(Response) -> Fut,
+    Fut: Future<Output = Result<T, ApiError>>,
+{
+    let request_id = RequestId::generate();
+    #[cfg(feature = "otel")]
+    let (request, otel_cx) = match crate::otel::start_client_span(request, &request_id) {
+        Ok(traced) => traced,
+        Err(e) => return Err(e.with_request_id(request_id)),
+    };
+    let result = async {
+        let request = match deadline {
+            Some(deadline) => {
+                let remaining = deadline
+                    .checked_duration_since(Instant::now())
+                    .filter(|remaining| !remaining.is_zero())
+                    .ok_or(ApiError::DeadlineExceeded)?;
+                request.timeout(remaining)
+            }
+            None => request,
+        };
+        log::trace!("Sending HTTP request {}", request_id);
+        let res = request
+            .header(REQUEST_ID_HEADER, request_id.to_string())
+            .send()
+            .await?;
+        log::trace!("Received HTTP response for request {}", request_id);
+        #[cfg(feature = "otel")]
+        crate::otel::record_status(&otel_cx, res.status());
+        hooks.response_received(res.status(), res.headers());
+        handle(res).await
+    }
+    .await
+    .map_err(|e| match e {
+        ApiError::RequestError(ref inner)
+            if inner.is_timeout() && deadline.is_some_and(|d| d <= Instant::now()) =>
+        {
+            ApiError::DeadlineExceeded
+        }
+        e => e,
+    });
+    #[cfg(feature = "otel")]
+    crate::otel::end_client_span(&otel_cx, result.as_ref().err());
+    result.map_err(|e| {
+        log::debug!("Request {} failed: {}", request_id, e);
+        e.with_request_id(request_id)
+    })
+}
+
 /// Different ways to specify a message recipient in basic mode.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Recipient<'a> {
     /// Recipient identity (8 characters)
     Id(Cow<'a, str>),
@@ -62,22 +189,140 @@ impl<'a> Recipient<'a> {
     pub fn new_email<T: Into<Cow<'a, str>>>(email: T) -> Self {
         Recipient::Email(email.into())
     }
+
+    /// Return the kind of this recipient.
+    pub fn kind(&self) -> RecipientKind {
+        match self {
+            Recipient::Id(_) => RecipientKind::Id,
+            Recipient::Phone(_) => RecipientKind::Phone,
+            Recipient::Email(_) => RecipientKind::Email,
+        }
+    }
+}
+
+impl Recipient<'static> {
+    /// Parse and normalize `value` as a recipient of the given kind, skipping
+    /// the auto-detection done by [`FromStr`].
+    ///
+    /// Use this if the kind is known in advance, e.g. for phone numbers with
+    /// 8 digits, which would otherwise be detected as Threema ID.
+    pub fn parse_as(value: &str, kind: RecipientKind) -> Result<Self, RecipientParseError> {
+        let value = value.trim();
+        match kind {
+            RecipientKind::Id => {
+                let id = value.to_ascii_uppercase();
+                if id.len() == 8 && id.chars().all(|c| c == '*' || c.is_ascii_alphanumeric()) {
+                    Ok(Recipient::Id(id.into()))
+                } else {
+                    Err(RecipientParseError::InvalidId(value.to_string()))
+                }
+            }
+            RecipientKind::Phone => {
+                // Strip the leading + and common separators
+                let phone: String = value
+                    .strip_prefix('+')
+                    .unwrap_or(value)
+                    .chars()
+                    .filter(|c| !matches!(c, ' ' | '-' | '.' | '/' | '(' | ')'))
+                    .collect();
+                if !phone.is_empty() && phone.chars().all(|c| c.is_ascii_digit()) {
+                    Ok(Recipient::Phone(phone.into()))
+                } else {
+                    Err(RecipientParseError::InvalidPhone(value.to_string()))
+                }
+            }
+            RecipientKind::Email => match value.split_once('@') {
+                Some((local, domain))
+                    if !local.is_empty()
+                        && !domain.is_empty()
+                        && !domain.contains('@')
+                        && !value.contains(char::is_whitespace) =>
+                {
+                    Ok(Recipient::Email(value.to_string().into()))
+                }
+                _ => Err(RecipientParseError::InvalidEmail(value.to_string())),
+            },
+        }
+    }
+}
+
+/// Parse a recipient, detecting its kind:
+///
+/// - Values containing `@` are e-mail addresses
+/// - Values with 8 alphanumeric characters (or `*`) are Threema IDs
+/// - Everything else is treated as phone number
+///
+/// Use [`Recipient::parse_as`] to override the detection.
+impl FromStr for Recipient<'static> {
+    type Err = RecipientParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim();
+        let kind = if value.contains('@') {
+            RecipientKind::Email
+        } else if value.len() == 8 && value.chars().all(|c| c == '*' || c.is_ascii_alphanumeric()) {
+            RecipientKind::Id
+        } else {
+            RecipientKind::Phone
+        };
+        Recipient::parse_as(value, kind)
+    }
+}
+
+/// The kind of a [`Recipient`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecipientKind {
+    /// Threema ID
+    Id,
+    /// Phone number
+    Phone,
+    /// E-mail address
+    Email,
+}
+
+/// Return a random message ID for a message that was not actually sent.
+fn dry_run_message_id() -> MessageId {
+    log::debug!("Dry run, not sending message");
+    MessageId::new(rand::random())
+}
+
+/// Parse the message ID returned by the send endpoints.
+async fn parse_send_response(res: Response) -> Result<SendResult, ApiError> {
+    let body = res.text().await?;
+    let message_id = body
+        .trim()
+        .parse()
+        .map_err(|_| ApiError::ParseError(format!("Invalid message ID in response: {:?}", body)))?;
+    Ok(SendResult { message_id })
 }
 
 /// Send a message to the specified recipient in basic mode.
 pub(crate) async fn send_simple(
     client: &Client,
     endpoint: &str,
+    deadline: Option<Instant>,
+    hooks: &Hooks,
     from: &str,
     to: &Recipient<'_>,
     secret: &str,
     text: &str,
-) -> Result<String, ApiError> {
-    log::debug!(
-        "Sending transport encrypted message from {} to {:?}",
-        from,
-        to
-    );
+    dry_run: bool,
+) -> Result<SendResult, ApiError> {
+    // Phone numbers and email addresses are personal data
+    match to {
+        Recipient::Id(id) => {
+            log::debug!(
+                "Sending transport encrypted message from {} to {}",
+                from,
+                id
+            )
+        }
+        Recipient::Phone(_) | Recipient::Email(_) => log::debug!(
+            "Sending transport encrypted message from {} to {:?}",
+            from,
+            Redacted(to)
+        ),
+    }
 
     // Check text length (max 3500 bytes)
     // Note: Strings in Rust are UTF8, so len() returns the byte count.
@@ -96,111 +341,190 @@ pub(crate) async fn send_simple(
         Recipient::Email(ref email) => params.insert("email", email),
     };
 
+    if dry_run {
+        return Ok(SendResult {
+            message_id: dry_run_message_id(),
+        });
+    }
+
     // Send request
-    log::trace!("Sending HTTP request");
-    let res = client
+    let request = client
         .post(format!("{}/send_simple", endpoint))
         .form(&params)
-        .header("accept", "application/json")
-        .send()
-        .await?;
-    log::trace!("Received HTTP response");
-    map_response_code(res.status(), Some(ApiError::BadSenderOrRecipient))?;
+        .header("accept", "application/json");
+    send_request(request, deadline, hooks, |res| async move {
+        map_response_code(res.status(), Some(ApiError::BadSenderOrRecipient))?;
+
+        // Read and return the message ID
+        parse_send_response(res).await
+    })
+    .await
+}
 
-    // Read and return response body
-    Ok(res.text().await?)
+/// Encode the form body of an E2E message.
+///
+/// The nonce and the box are hex encoded directly into a buffer of the final
+/// size, since near the size limit, the box is by far the largest field.
+fn e2e_form_body(params: &HashMap<String, String>, nonce: &[u8], ciphertext: &[u8]) -> String {
+    let capacity = params
+        .iter()
+        .map(|(k, v)| k.len() + v.len() * 3 + 2)
+        .sum::<usize>()
+        + "nonce=&box=".len()
+        + HEXLOWER.encode_len(nonce.len())
+        + HEXLOWER.encode_len(ciphertext.len());
+    let mut body = String::with_capacity(capacity);
+    form_urlencoded::Serializer::new(&mut body).extend_pairs(params);
+    if !body.is_empty() {
+        body.push('&');
+    }
+    body.push_str("nonce=");
+    HEXLOWER.encode_append(nonce, &mut body);
+    body.push_str("&box=");
+    HEXLOWER.encode_append(ciphertext, &mut body);
+    body
 }
 
 /// Send an encrypted E2E message to the specified recipient.
 pub(crate) async fn send_e2e(
     client: &Client,
     endpoint: &str,
+    deadline: Option<Instant>,
+    hooks: &Hooks,
     from: &str,
     to: &str,
     secret: &str,
     nonce: &[u8],
     ciphertext: &[u8],
-    delivery_receipts: bool,
+    options: &SendOptions,
     additional_params: Option<HashMap<String, String>>,
-) -> Result<String, ApiError> {
+    dry_run: bool,
+) -> Result<SendResult, ApiError> {
     log::debug!("Sending e2e encrypted message from {} to {}", from, to);
 
+    if dry_run {
+        return Ok(SendResult {
+            message_id: dry_run_message_id(),
+        });
+    }
+
     // Prepare POST data
     let mut params = additional_params.unwrap_or_default();
     params.insert("from".into(), from.into());
     params.insert("to".into(), to.into());
     params.insert("secret".into(), secret.into());
-    params.insert("nonce".into(), HEXLOWER.encode(nonce));
-    params.insert("box".into(), HEXLOWER.encode(ciphertext));
-    if !delivery_receipts {
+    if !options.delivery_receipts {
         params.insert("noDeliveryReceipts".into(), "1".into());
     }
+    if options.no_push {
+        params.insert("noPush".into(), "1".into());
+    }
+    if options.group {
+        params.insert("group".into(), "1".into());
+    }
+    let body = e2e_form_body(&params, nonce, ciphertext);
 
     // Send request
-    log::trace!("Sending HTTP request");
-    let res = client
+    let request = client
         .post(format!("{}/send_e2e", endpoint))
-        .form(&params)
+        .header("content-type", "application/x-www-form-urlencoded")
         .header("accept", "application/json")
-        .send()
-        .await?;
-    log::trace!("Received HTTP response");
-    map_response_code(res.status(), Some(ApiError::BadSenderOrRecipient))?;
+        .body(body);
+    send_request(request, deadline, hooks, |res| async move {
+        map_response_code(res.status(), Some(ApiError::BadSenderOrRecipient))?;
+
+        // Read and return the message ID
+        parse_send_response(res).await
+    })
+    .await
+}
+
+/// Whether a failed blob download may be retried.
+///
+/// Downloads are idempotent, so all transient errors are retryable.
+fn is_download_retryable(err: &ApiError) -> bool {
+    match err.inner() {
+        ApiError::RequestError(e) => e.is_connect() || e.is_timeout() || e.is_body(),
+        ApiError::ServerError => true,
+        _ => false,
+    }
+}
 
-    // Read and return response body
-    Ok(res.text().await?)
+/// Whether a failed blob upload may be retried.
+///
+/// Uploads are only retried if the connection could not be established, since
+/// otherwise the body might already have been accepted by the server.
+fn is_upload_retryable(err: &ApiError) -> bool {
+    matches!(err.inner(), ApiError::RequestError(e) if e.is_connect())
 }
 
 /// Upload a blob to the blob server.
 pub(crate) async fn blob_upload(
     client: &Client,
     endpoint: &str,
+    deadline: Option<Instant>,
+    hooks: &Hooks,
     from: &str,
     secret: &str,
-    data: &[u8],
+    data: &Bytes,
     persist: bool,
     additional_params: Option<HashMap<String, String>>,
+    retry_policy: &RetryPolicy,
+    dry_run: bool,
 ) -> Result<BlobId, ApiError> {
+    if dry_run {
+        log::debug!("Dry run, not uploading blob ({} bytes)", data.len());
+        return Ok(BlobId::random());
+    }
+
     // Build URL
     let mut url = format!("{}/upload_blob?from={}&secret={}", endpoint, from, secret);
     if persist {
         url.push_str("&persist=1");
     }
 
-    // Build multipart/form-data request body
-    let mut form = multipart::Form::new();
-    form = form.part(
-        "blob",
-        multipart::Part::bytes(data.to_vec())
-            .mime_str("application/octet-stream")
-            .expect("Could not parse MIME string"),
-    );
-    if let Some(params) = additional_params {
-        for (k, v) in params {
-            form = form.text(k, v);
+    with_retry(retry_policy, is_upload_retryable, || async {
+        // Build multipart/form-data request body
+        let mut form = multipart::Form::new();
+        form = form.part(
+            "blob",
+            multipart::Part::stream_with_length(data.clone(), data.len() as u64)
+                .mime_str("application/octet-stream")
+                .expect("Could not parse MIME string"),
+        );
+        if let Some(ref params) = additional_params {
+            for (k, v) in params {
+                form = form.text(k.clone(), v.clone());
+            }
         }
-    }
 
-    // Send request
-    let res = client
-        .post(&url)
-        .multipart(form)
-        .header("accept", "text/plain")
-        .send()
-        .await?;
-    map_response_code(res.status(), Some(ApiError::BadBlob))?;
-
-    // Read response body containing blob ID
-    BlobId::from_str(res.text().await?.trim())
+        // Send request
+        let request = client
+            .post(&url)
+            .multipart(form)
+            .header("accept", "text/plain");
+        send_request(request, deadline, hooks, |res| async move {
+            map_response_code(res.status(), Some(ApiError::BadBlob))?;
+
+            // Read response body containing blob ID
+            BlobId::from_str(res.text().await?.trim())
+        })
+        .await
+    })
+    .await
 }
 
 /// Download a blob from the blob server.
 pub(crate) async fn blob_download(
     client: &Client,
     endpoint: &str,
+    deadline: Option<Instant>,
+    hooks: &Hooks,
     from: &str,
     secret: &str,
     blob_id: &BlobId,
+    max_size: Option<u64>,
+    retry_policy: &RetryPolicy,
 ) -> Result<Vec<u8>, ApiError> {
     // Build URL
     let url = format!(
@@ -208,12 +532,34 @@ pub(crate) async fn blob_download(
         endpoint, blob_id, from, secret
     );
 
-    // Send request
-    let res = client.get(&url).send().await?;
-    map_response_code(res.status(), Some(ApiError::BadBlob))?;
+    with_retry(retry_policy, is_download_retryable, || async {
+        // Send request
+        send_request(client.get(&url), deadline, hooks, |mut res| async move {
+            map_response_code(res.status(), Some(ApiError::BadBlob))?;
 
-    // Read response bytes
-    Ok(res.bytes().await?.to_vec())
+            // Read response bytes, aborting as soon as the limit is exceeded
+            let limit = match max_size {
+                Some(limit) => limit,
+                None => return Ok(res.bytes().await?.to_vec()),
+            };
+            if let Some(size) = res.content_length().filter(|size| *size > limit) {
+                return Err(ApiError::BlobTooLarge { size, limit });
+            }
+            let mut data = Vec::new();
+            while let Some(chunk) = res.chunk().await? {
+                data.extend_from_slice(&chunk);
+                if data.len() as u64 > limit {
+                    return Err(ApiError::BlobTooLarge {
+                        size: data.len() as u64,
+                        limit,
+                    });
+                }
+            }
+            Ok(data)
+        })
+        .await
+    })
+    .await
 }
 
 #[cfg(test)]
@@ -222,6 +568,23 @@ mod tests {
 
     use crate::{errors::ApiError, MSGAPI_URL};
 
+    #[test]
+    fn test_e2e_form_body() {
+        let mut params = HashMap::new();
+        params.insert("from".to_string(), "*3MAGWID".to_string());
+        params.insert("secret".to_string(), "a&b=c".to_string());
+        let body = e2e_form_body(&params, &[0xab; 24], &[0x01, 0xff]);
+
+        let parsed: HashMap<String, String> = form_urlencoded::parse(body.as_bytes())
+            .into_owned()
+            .collect();
+        assert_eq!(parsed.len(), 4);
+        assert_eq!(parsed["from"], "*3MAGWID");
+        assert_eq!(parsed["secret"], "a&b=c");
+        assert_eq!(parsed["nonce"], "ab".repeat(24));
+        assert_eq!(parsed["box"], "01ff");
+    }
+
     #[tokio::test]
     async fn test_simple_max_length_ok() {
         let text: String = "à".repeat(3500 / 2);
@@ -229,10 +592,13 @@ mod tests {
         let result = send_simple(
             &client,
             MSGAPI_URL,
+            None,
+            &Hooks::default(),
             "TESTTEST",
             &Recipient::new_id("ECHOECHO"),
             "secret",
             &text,
+            false,
         )
         .await;
         if let Err(ApiError::MessageTooLong) = result {
@@ -248,10 +614,13 @@ mod tests {
         let result = send_simple(
             &client,
             MSGAPI_URL,
+            None,
+            &Hooks::default(),
             "TESTTEST",
             &Recipient::new_id("ECHOECHO"),
             "secret",
             &text,
+            true,
         )
         .await;
         match result {
@@ -259,4 +628,121 @@ mod tests {
             _ => panic!(),
         }
     }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        // The endpoint is unreachable, so any actual request would fail
+        let client = Client::new();
+        let endpoint = "http://127.0.0.1:1";
+        let id = send_simple(
+            &client,
+            endpoint,
+            None,
+            &Hooks::default(),
+            "TESTTEST",
+            &Recipient::new_id("ECHOECHO"),
+            "secret",
+            "hi",
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(id.message_id.to_string().len(), 16);
+        let id = send_e2e(
+            &client,
+            endpoint,
+            None,
+            &Hooks::default(),
+            "TESTTEST",
+            "ECHOECHO",
+            "secret",
+            &[0; 24],
+            &[1, 2, 3],
+            &SendOptions::default(),
+            None,
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(id.message_id.to_string().len(), 16);
+        blob_upload(
+            &client,
+            endpoint,
+            None,
+            &Hooks::default(),
+            "TESTTEST",
+            "secret",
+            &Bytes::from_static(&[1, 2, 3]),
+            false,
+            None,
+            &RetryPolicy::no_retries(),
+            true,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn test_recipient_from_str() {
+        assert_eq!(
+            "echoecho".parse::<Recipient>().unwrap(),
+            Recipient::new_id("ECHOECHO")
+        );
+        assert_eq!(
+            "*3MAGWID".parse::<Recipient>().unwrap(),
+            Recipient::new_id("*3MAGWID")
+        );
+        assert_eq!(
+            " user@example.com ".parse::<Recipient>().unwrap(),
+            Recipient::new_email("user@example.com")
+        );
+        assert_eq!(
+            "+41 79 123 45 67".parse::<Recipient>().unwrap(),
+            Recipient::new_phone("41791234567")
+        );
+        assert_eq!(
+            "+(41) 791-234/567".parse::<Recipient>().unwrap(),
+            Recipient::new_phone("41791234567")
+        );
+    }
+
+    #[test]
+    fn test_recipient_from_str_invalid() {
+        assert_eq!(
+            "".parse::<Recipient>(),
+            Err(RecipientParseError::InvalidPhone("".into()))
+        );
+        assert_eq!(
+            "ECHO-ECHO".parse::<Recipient>(),
+            Err(RecipientParseError::InvalidPhone("ECHO-ECHO".into()))
+        );
+        assert_eq!(
+            "user@".parse::<Recipient>(),
+            Err(RecipientParseError::InvalidEmail("user@".into()))
+        );
+        assert_eq!(
+            "a@b@c".parse::<Recipient>(),
+            Err(RecipientParseError::InvalidEmail("a@b@c".into()))
+        );
+    }
+
+    #[test]
+    fn test_recipient_parse_as() {
+        assert_eq!(
+            Recipient::parse_as("41791234", RecipientKind::Phone).unwrap(),
+            Recipient::new_phone("41791234")
+        );
+        assert_eq!(
+            "41791234".parse::<Recipient>().unwrap().kind(),
+            RecipientKind::Id
+        );
+        assert_eq!(
+            Recipient::parse_as("ECHO", RecipientKind::Id),
+            Err(RecipientParseError::InvalidId("ECHO".into()))
+        );
+        assert_eq!(
+            Recipient::parse_as("ECHOECHO", RecipientKind::Email),
+            Err(RecipientParseError::InvalidEmail("ECHOECHO".into()))
+        );
+    }
 }
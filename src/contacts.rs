@@ -0,0 +1,497 @@
+//! Known contacts.
+//!
+//! A [`ContactStore`] keeps the Threema IDs the gateway communicates with,
+//! together with their public key, a nickname, their capabilities and the
+//! [`VerificationLevel`] of the public key. If a store is configured with
+//! [`ApiBuilder::with_contact_store`](crate::ApiBuilder::with_contact_store),
+//! contacts can be added with [`E2eApi::add_contact`](crate::E2eApi::add_contact)
+//! and [`E2eApi::send_text`](crate::E2eApi::send_text) accepts the nickname
+//! of a contact instead of the Threema ID. The public keys of stored contacts
+//! are used without looking them up again.
+//!
+//! ```no_run
+//! # tokio_test::block_on(async {
+//! use std::sync::Arc;
+//!
+//! use threema_gateway::{contacts::MemoryContactStore, ApiBuilder, SecretKey};
+//!
+//! let store = Arc::new(MemoryContactStore::default());
+//! let api = ApiBuilder::new("*3MAGWID", "hihghrg98h00ghrg")
+//!     .with_private_key(SecretKey::from([1; 32]))
+//!     .with_contact_store(store.clone())
+//!     .into_e2e()
+//!     .unwrap();
+//! api.add_contact("ECHOECHO", Some("echo")).await.unwrap();
+//! api.send_text("echo", "Hello!").await.unwrap();
+//! # })
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    error::Error,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{
+    crypto::RecipientKey,
+    lookup::{Capabilities, LookupCriterion},
+    store::{shared_store, BoxError},
+};
+
+/// How much the public key of a contact is trusted.
+///
+/// This corresponds to the verification dots shown in the Threema apps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum VerificationLevel {
+    /// The public key was fetched from the directory server by Threema ID
+    /// (one red dot).
+    #[default]
+    Unverified,
+    /// The Threema ID was found by a phone number or e-mail address linked
+    /// to it (two orange dots).
+    ServerVerified,
+    /// The public key was verified in person, e.g. by scanning the QR code
+    /// (three green dots).
+    FullyVerified,
+}
+
+/// A known contact.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contact {
+    /// The Threema ID of the contact.
+    pub id: String,
+    /// The public key of the contact.
+    pub public_key: RecipientKey,
+    /// A name that can be used instead of the Threema ID.
+    pub nickname: Option<String>,
+    /// The capabilities of the contact, if known.
+    pub capabilities: Option<Capabilities>,
+    /// How much the public key is trusted.
+    pub verification_level: VerificationLevel,
+}
+
+impl Contact {
+    /// Create an unverified contact without nickname and capabilities.
+    pub fn new(id: impl Into<String>, public_key: RecipientKey) -> Self {
+        Contact {
+            id: id.into(),
+            public_key,
+            nickname: None,
+            capabilities: None,
+            verification_level: VerificationLevel::default(),
+        }
+    }
+
+    /// Return whether `name` is the Threema ID or (case insensitively) the
+    /// nickname of the contact.
+    pub fn matches(&self, name: &str) -> bool {
+        self.id.eq_ignore_ascii_case(name)
+            || matches!(&self.nickname, Some(nickname) if nickname.to_lowercase() == name.to_lowercase())
+    }
+}
+
+/// Storage for the known contacts.
+pub trait ContactStore: Send + Sync {
+    /// Error returned if store operations fail
+    type Error: Error + Send + Sync + 'static;
+
+    /// Insert or replace a contact.
+    fn store(&self, contact: &Contact) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Return the contact with the Threema ID `id`.
+    fn load(&self, id: &str) -> impl Future<Output = Result<Option<Contact>, Self::Error>> + Send;
+
+    /// Remove the contact with the Threema ID `id`, if it exists.
+    fn remove(&self, id: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Return all contacts.
+    fn list(&self) -> impl Future<Output = Result<Vec<Contact>, Self::Error>> + Send;
+}
+
+impl<S: ContactStore + ?Sized> ContactStore for Arc<S> {
+    type Error = S::Error;
+
+    fn store(&self, contact: &Contact) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        (**self).store(contact)
+    }
+
+    fn load(&self, id: &str) -> impl Future<Output = Result<Option<Contact>, Self::Error>> + Send {
+        (**self).load(id)
+    }
+
+    fn remove(&self, id: &str) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        (**self).remove(id)
+    }
+
+    fn list(&self) -> impl Future<Output = Result<Vec<Contact>, Self::Error>> + Send {
+        (**self).list()
+    }
+}
+
+/// A [`ContactStore`] that keeps the contacts in memory.
+#[derive(Debug, Default)]
+pub struct MemoryContactStore {
+    contacts: Mutex<BTreeMap<String, Contact>>,
+}
+
+impl MemoryContactStore {
+    fn contacts(&self) -> MutexGuard<'_, BTreeMap<String, Contact>> {
+        self.contacts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl ContactStore for MemoryContactStore {
+    type Error = Infallible;
+
+    async fn store(&self, contact: &Contact) -> Result<(), Self::Error> {
+        self.contacts()
+            .insert(contact.id.to_uppercase(), contact.clone());
+        Ok(())
+    }
+
+    async fn load(&self, id: &str) -> Result<Option<Contact>, Self::Error> {
+        Ok(self.contacts().get(&id.to_uppercase()).cloned())
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), Self::Error> {
+        self.contacts().remove(&id.to_uppercase());
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Contact>, Self::Error> {
+        Ok(self.contacts().values().cloned().collect())
+    }
+}
+
+/// A phone number or e-mail address read by
+/// [`E2eApi::import_contacts_csv`](crate::E2eApi::import_contacts_csv).
+#[derive(Debug, PartialEq)]
+pub struct ImportEntry {
+    /// The line of the value in the file (starting at 1).
+    pub line: usize,
+    /// The value as found in the file.
+    pub value: String,
+    /// The normalized phone number (E.164 format without `+`) or e-mail
+    /// address.
+    pub criterion: LookupCriterion,
+}
+
+/// The result of [`E2eApi::import_contacts_csv`](crate::E2eApi::import_contacts_csv).
+#[derive(Debug, Default)]
+pub struct ContactImport {
+    /// The entries linked to a Threema ID, with the resulting contacts.
+    pub matched: Vec<(ImportEntry, Contact)>,
+    /// The entries not linked to any Threema ID.
+    pub unmatched: Vec<ImportEntry>,
+    /// Values that look like a phone number or an e-mail address (i.e.
+    /// contain a digit or an `@`), but are neither a phone number in
+    /// international format nor a valid e-mail address, with their line
+    /// number. Other values, like names, are ignored.
+    pub invalid: Vec<(usize, String)>,
+}
+
+/// Normalize a phone number in international format (with `+` or `00`
+/// prefix) or an e-mail address.
+fn normalize(value: &str) -> Option<LookupCriterion> {
+    if value.contains('@') {
+        let email = value.trim().to_lowercase();
+        let (local, domain) = email.split_once('@')?;
+        let valid = !local.is_empty()
+            && domain.contains('.')
+            && !domain.starts_with('.')
+            && !domain.ends_with('.')
+            && !email.contains(char::is_whitespace)
+            && !domain.contains('@');
+        return valid.then_some(LookupCriterion::Email(email));
+    }
+    let phone: String = value
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '(' | ')' | '.' | '/'))
+        .collect();
+    let digits = phone
+        .strip_prefix('+')
+        .or_else(|| phone.strip_prefix("00"))?;
+    let valid = (8..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit());
+    valid.then(|| LookupCriterion::Phone(digits.to_string()))
+}
+
+/// Split a CSV line into fields. Both `,` and `;` are accepted as separator,
+/// fields may be quoted (with `""` as escaped quote).
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.next_if_eq(&'"').is_some() => field.push('"'),
+            '"' => quoted = !quoted,
+            ',' | ';' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Read the phone numbers and e-mail addresses from a CSV file.
+///
+/// Empty fields and fields that don't look like a phone number or an e-mail
+/// address (e.g. names) are ignored. The first line is skipped as header if
+/// it doesn't contain any valid value. Returns the entries and the invalid
+/// values with their line number.
+pub(crate) fn parse_csv(csv: &str) -> (Vec<ImportEntry>, Vec<(usize, String)>) {
+    let mut entries = Vec::new();
+    let mut invalid = Vec::new();
+    for (index, line) in csv.lines().enumerate() {
+        let mut line_entries = Vec::new();
+        let mut line_invalid = Vec::new();
+        for value in csv_fields(line) {
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            match normalize(value) {
+                Some(criterion) => line_entries.push(ImportEntry {
+                    line: index + 1,
+                    value: value.to_string(),
+                    criterion,
+                }),
+                None if value.contains(|c: char| c == '@' || c.is_ascii_digit()) => {
+                    line_invalid.push((index + 1, value.to_string()))
+                }
+                None => {}
+            }
+        }
+        if index == 0 && line_entries.is_empty() {
+            continue;
+        }
+        entries.extend(line_entries);
+        invalid.extend(line_invalid);
+    }
+    (entries, invalid)
+}
+
+shared_store! {
+    /// A type erased [`ContactStore`] that is shared between clones of the API
+    /// object.
+    SharedContactStore(DynContactStore): ContactStore {
+        fn store(contact: &Contact) -> ();
+        fn load(id: &str) -> Option<Contact>;
+        fn list() -> Vec<Contact>;
+    }
+}
+
+impl SharedContactStore {
+    /// Find a contact by Threema ID or nickname. The Threema ID takes
+    /// precedence.
+    pub(crate) async fn find(&self, name: &str) -> Result<Option<Contact>, BoxError> {
+        if let Some(contact) = self.load(name).await? {
+            return Ok(Some(contact));
+        }
+        Ok(self
+            .list()
+            .await?
+            .into_iter()
+            .find(|contact| contact.matches(name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(id: &str, nickname: Option<&str>) -> Contact {
+        Contact {
+            nickname: nickname.map(Into::into),
+            ..Contact::new(id, RecipientKey::from([1; 32]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        let store = MemoryContactStore::default();
+        store.store(&contact("ECHOECHO", None)).await.unwrap();
+        store
+            .store(&contact("ABCD1234", Some("Alice")))
+            .await
+            .unwrap();
+        assert_eq!(store.list().await.unwrap().len(), 2);
+        assert_eq!(
+            store.load("echoecho").await.unwrap().map(|c| c.id),
+            Some("ECHOECHO".to_string())
+        );
+        store.remove("ECHOECHO").await.unwrap();
+        assert_eq!(store.load("ECHOECHO").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_find() {
+        let store = SharedContactStore::new(MemoryContactStore::default());
+        store
+            .store(&contact("ABCD1234", Some("Alice")))
+            .await
+            .unwrap();
+        // The nickname of another contact may look like a Threema ID
+        store
+            .store(&contact("EFGH5678", Some("ABCD1234")))
+            .await
+            .unwrap();
+
+        let find = |name: &'static str| {
+            let store = store.clone();
+            async move { store.find(name).await.unwrap().map(|c| c.id) }
+        };
+        assert_eq!(find("alice").await.as_deref(), Some("ABCD1234"));
+        assert_eq!(find("ABCD1234").await.as_deref(), Some("ABCD1234"));
+        assert_eq!(find("efgh5678").await.as_deref(), Some("EFGH5678"));
+        assert_eq!(find("Bob").await, None);
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let csv = "Name;Phone;E-Mail\n\
+                   Alice;+41 79 123 45 67;Alice@Example.com\n\
+                   Bob;0041-79-765-43-21;\n\
+                   \"Carol, Jr.\";079 111 22 33;carol@\n";
+        let (entries, invalid) = parse_csv(csv);
+        let criteria: Vec<_> = entries.iter().map(|e| (e.line, &e.criterion)).collect();
+        assert_eq!(
+            criteria,
+            [
+                (2, &LookupCriterion::Phone("41791234567".into())),
+                (2, &LookupCriterion::Email("alice@example.com".into())),
+                (3, &LookupCriterion::Phone("41797654321".into())),
+            ]
+        );
+        assert_eq!(entries[1].value, "Alice@Example.com");
+        assert_eq!(
+            invalid,
+            [(4, "079 111 22 33".to_string()), (4, "carol@".to_string())]
+        );
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_contacts() {
+        use crate::{
+            mock_server::{MockServer, ReceivedMessage},
+            ApiBuilder, SecretKey,
+        };
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let echo_private_key = SecretKey::from([2; 32]);
+        let echo_key = RecipientKey::from(echo_private_key.public_key());
+        server.set_public_key("ECHOECHO", echo_key.clone());
+        server.set_public_key("ABCD1234", RecipientKey::from([3; 32]));
+        server.set_capabilities("ECHOECHO", "text,file".parse().unwrap());
+        server.set_id(LookupCriterion::Email("a@example.com".into()), "ABCD1234");
+        let store = Arc::new(MemoryContactStore::default());
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .with_contact_store(store.clone())
+            .into_e2e()
+            .unwrap();
+
+        let echo = api.add_contact("echoecho", Some("Echo")).await.unwrap();
+        assert_eq!(echo.id, "ECHOECHO");
+        assert_eq!(echo.public_key, echo_key);
+        assert!(echo.capabilities.as_ref().unwrap().file);
+        assert_eq!(echo.verification_level, VerificationLevel::Unverified);
+        let alice = api
+            .add_contact_by(&LookupCriterion::Email("a@example.com".into()), None)
+            .await
+            .unwrap();
+        assert_eq!(alice.verification_level, VerificationLevel::ServerVerified);
+        assert_eq!(store.list().await.unwrap().len(), 2);
+        assert_eq!(api.contact("echo").await.unwrap(), Some(echo));
+
+        // Send by nickname, with the stored key
+        server.set_public_key("ECHOECHO", RecipientKey::from([4; 32]));
+        api.send_text("echo", "hi").await.unwrap();
+        match server.received_messages().as_slice() {
+            [ReceivedMessage::E2e {
+                to,
+                nonce,
+                ciphertext,
+                ..
+            }] => {
+                assert_eq!(to, "ECHOECHO");
+                let nonce = crate::Nonce::from(<[u8; 24]>::try_from(&nonce[..]).unwrap());
+                let (_, data) = crate::decrypt(
+                    ciphertext,
+                    &nonce,
+                    &SecretKey::from([1; 32]).public_key(),
+                    &echo_private_key,
+                )
+                .unwrap();
+                assert_eq!(data, b"hi");
+            }
+            other => panic!("Unexpected messages: {:?}", other),
+        }
+
+        let no_store = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap();
+        assert_eq!(no_store.contact("echo").await.unwrap(), None);
+        assert!(no_store.add_contact("ECHOECHO", None).await.is_err());
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_contacts_csv_import() {
+        use crate::{mock_server::MockServer, ApiBuilder, SecretKey};
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_public_key("ALICE123", RecipientKey::from([3; 32]));
+        server.set_public_key("BOB12345", RecipientKey::from([4; 32]));
+        server.set_id(LookupCriterion::Phone("41791234567".into()), "ALICE123");
+        server.set_id(
+            LookupCriterion::Email("alice@example.com".into()),
+            "ALICE123",
+        );
+        server.set_id(LookupCriterion::Email("bob@example.com".into()), "BOB12345");
+        let store = Arc::new(MemoryContactStore::default());
+        let bob = Contact {
+            nickname: Some("Bob".into()),
+            ..Contact::new("BOB12345", RecipientKey::from([4; 32]))
+        };
+        store.store(&bob).await.unwrap();
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .with_contact_store(store.clone())
+            .into_e2e()
+            .unwrap();
+
+        let csv = "phone,email\n\
+                   +41 79 123 45 67,ALICE@example.com\n\
+                   +41 79 000 00 00,bob@example.com\n\
+                   Carol,+41 79\n";
+        let import = api.import_contacts_csv(csv).await.unwrap();
+        let matched: Vec<_> = import
+            .matched
+            .iter()
+            .map(|(entry, contact)| (entry.line, contact.id.as_str()))
+            .collect();
+        assert_eq!(matched, [(2, "ALICE123"), (2, "ALICE123"), (3, "BOB12345")]);
+        assert_eq!(import.unmatched.len(), 1);
+        assert_eq!(import.unmatched[0].value, "+41 79 000 00 00");
+        assert_eq!(import.invalid, [(4, "+41 79".to_string())]);
+
+        // New contacts are server verified, existing contacts are kept
+        let alice = store.load("ALICE123").await.unwrap().unwrap();
+        assert_eq!(alice.verification_level, VerificationLevel::ServerVerified);
+        assert_eq!(store.load("BOB12345").await.unwrap(), Some(bob));
+    }
+}
@@ -0,0 +1,198 @@
+//! Local accounting of consumed credits.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+/// The credits consumed by an API object (and its clones), counted locally.
+///
+/// See [`E2eApi::credit_usage`](crate::E2eApi::credit_usage). Only
+/// successful calls are counted, calls in dry run mode are free.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CreditUsage {
+    /// Credits consumed by sent messages (1 per message).
+    pub messages: u64,
+    /// Credits consumed by blob uploads (1 per upload).
+    pub blob_uploads: u64,
+    /// Credits consumed by ID, public key and capabilities lookups (1 per
+    /// lookup).
+    pub lookups: u64,
+}
+
+impl CreditUsage {
+    /// The total number of consumed credits.
+    pub fn total(&self) -> u64 {
+        self.messages + self.blob_uploads + self.lookups
+    }
+}
+
+/// The result of comparing the credit balance on the server with the local
+/// accounting, see
+/// [`E2eApi::reconcile_credits`](crate::E2eApi::reconcile_credits).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CreditReconciliation {
+    /// The remaining credits, as reported by the server.
+    pub balance: i64,
+    /// The credits consumed according to the server since the previous
+    /// reconciliation. `None` for the first reconciliation.
+    pub consumed: Option<i64>,
+    /// The credits counted locally since the previous reconciliation.
+    pub counted: u64,
+}
+
+impl CreditReconciliation {
+    /// The number of credits that were consumed, but not counted locally,
+    /// e.g. by other applications using the same gateway ID. A negative
+    /// value means that credits were added in the meantime.
+    ///
+    /// `None` for the first reconciliation.
+    pub fn discrepancy(&self) -> Option<i64> {
+        self.consumed
+            .map(|consumed| consumed - i64::try_from(self.counted).unwrap_or(i64::MAX))
+    }
+}
+
+/// The operations that consume credits.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Cost {
+    Message,
+    BlobUpload,
+    Lookup,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    messages: AtomicU64,
+    blob_uploads: AtomicU64,
+    lookups: AtomicU64,
+    /// All credits counted since the API object was created, not affected
+    /// by resets.
+    total: AtomicU64,
+    /// The balance and the total at the previous reconciliation.
+    last_reconciliation: Mutex<Option<(i64, u64)>>,
+}
+
+/// Credit counters shared between clones of the API object.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CreditCounter(Arc<Counters>);
+
+impl CreditCounter {
+    /// Count one credit for `cost`.
+    pub(crate) fn charge(&self, cost: Cost) {
+        let counter = match cost {
+            Cost::Message => &self.0.messages,
+            Cost::BlobUpload => &self.0.blob_uploads,
+            Cost::Lookup => &self.0.lookups,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.0.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn usage(&self) -> CreditUsage {
+        CreditUsage {
+            messages: self.0.messages.load(Ordering::Relaxed),
+            blob_uploads: self.0.blob_uploads.load(Ordering::Relaxed),
+            lookups: self.0.lookups.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset the counters and return their previous values.
+    pub(crate) fn reset(&self) -> CreditUsage {
+        CreditUsage {
+            messages: self.0.messages.swap(0, Ordering::Relaxed),
+            blob_uploads: self.0.blob_uploads.swap(0, Ordering::Relaxed),
+            lookups: self.0.lookups.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    /// Compare `balance` with the balance at the previous reconciliation.
+    pub(crate) fn reconcile(&self, balance: i64) -> CreditReconciliation {
+        let total = self.0.total.load(Ordering::Relaxed);
+        let mut last = self
+            .0
+            .last_reconciliation
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (consumed, counted) = match *last {
+            Some((last_balance, last_total)) => (Some(last_balance - balance), total - last_total),
+            None => (None, 0),
+        };
+        *last = Some((balance, total));
+        CreditReconciliation {
+            balance,
+            consumed,
+            counted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconcile() {
+        let counter = CreditCounter::default();
+        counter.charge(Cost::Lookup);
+        let first = counter.reconcile(100);
+        assert_eq!(first.consumed, None);
+        assert_eq!(first.discrepancy(), None);
+
+        counter.charge(Cost::Message);
+        counter.charge(Cost::BlobUpload);
+        assert_eq!(counter.reset().total(), 3);
+        counter.charge(Cost::Message);
+        let second = counter.reconcile(95);
+        assert_eq!(second.consumed, Some(5));
+        assert_eq!(second.counted, 3);
+        assert_eq!(second.discrepancy(), Some(2));
+        assert_eq!(counter.usage().messages, 1);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_credit_usage() {
+        use std::time::Duration;
+
+        use crate::{mock_server::MockServer, ApiBuilder, Recipient, RecipientKey, SecretKey};
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_public_key("ECHOECHO", RecipientKey::from([2; 32]));
+        server.set_capabilities("ECHOECHO", "text".parse().unwrap());
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .into_simple()
+            .unwrap();
+        api.send(&Recipient::new_id("ECHOECHO"), "hi")
+            .await
+            .unwrap();
+        let api = api.into_e2e(SecretKey::from([1; 32]));
+        let key = api.lookup_pubkey("ECHOECHO").await.unwrap();
+        api.lookup_capabilities("ECHOECHO").await.unwrap();
+        let msg = api.encrypt_text_msg("hello", &key).unwrap();
+        api.send("ECHOECHO", &msg, false).await.unwrap();
+        api.blob_upload_raw(&[1, 2, 3], false).await.unwrap();
+        server.fail_next(500);
+        assert!(api.send("ECHOECHO", &msg, false).await.is_err());
+
+        let usage = api.with_timeout(Duration::from_secs(5)).credit_usage();
+        assert_eq!(usage.messages, 2);
+        assert_eq!(usage.blob_uploads, 1);
+        assert_eq!(usage.lookups, 2);
+        assert_eq!(api.reset_credit_usage().total(), 5);
+        assert_eq!(api.credit_usage().total(), 0);
+
+        // The mock server doesn't charge for lookups
+        assert_eq!(api.reconcile_credits().await.unwrap().consumed, None);
+        api.send("ECHOECHO", &msg, false).await.unwrap();
+        api.lookup_pubkey("ECHOECHO").await.unwrap();
+        server.set_credits(server.credits() - 3);
+        let reconciliation = api.reconcile_credits().await.unwrap();
+        assert_eq!(reconciliation.balance, server.credits());
+        assert_eq!(reconciliation.consumed, Some(4));
+        assert_eq!(reconciliation.counted, 2);
+        assert_eq!(reconciliation.discrepancy(), Some(2));
+    }
+}
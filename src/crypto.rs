@@ -1,8 +1,16 @@
 //! Encrypt and decrypt messages.
 
-use std::{convert::Into, fmt::Debug, io::Write, iter::repeat, str::FromStr, sync::OnceLock};
+use std::{
+    convert::Into,
+    fmt::Debug,
+    io::{self, Read, Seek, SeekFrom, Write},
+    num::NonZeroU8,
+    str::FromStr,
+    sync::OnceLock,
+};
 
 use byteorder::{LittleEndian, WriteBytesExt};
+use bytes::Bytes;
 use crypto_box::{aead::Aead, SalsaBox};
 use crypto_secretbox::{
     aead::{OsRng, Payload},
@@ -10,24 +18,53 @@ use crypto_secretbox::{
     AeadCore, Key as SecretboxKey, KeyInit, Nonce, XSalsa20Poly1305,
 };
 use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
-use rand::Rng;
-use serde::{Serialize, Serializer};
-use serde_json as json;
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use poly1305::{universal_hash::UniversalHash, Poly1305};
+use rand::{CryptoRng, Rng, RngCore};
+use salsa20::{
+    cipher::{KeyIvInit, StreamCipher},
+    XSalsa20,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 use crate::{
     errors::{self, CryptoError},
-    types::{BlobId, FileMessage, MessageType},
-    PublicKey, SecretKey,
+    key_provider::{KeyProvider, TAG_SIZE},
+    redact::{Redacted, RedactedBytes},
+    types::{BlobId, MessageType},
+    PublicKey,
 };
 
 pub const NONCE_SIZE: usize = 24;
 const KEY_SIZE: usize = 32;
 
+/// Size of the chunks processed by [`encrypt_file_stream`] and
+/// [`decrypt_file_stream`]. Must be a multiple of the Poly1305 block size.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
 /// Key type used for nacl secretbox cryptography
-#[derive(PartialEq, Zeroize, ZeroizeOnDrop)]
+///
+/// Keys are compared in constant time.
+#[derive(Zeroize, ZeroizeOnDrop)]
 pub struct Key(SecretboxKey);
 
+impl Key {
+    /// Generate a new random key.
+    pub fn generate() -> Self {
+        XSalsa20Poly1305::generate_key(&mut OsRng).into()
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_slice().ct_eq(other.0.as_slice()).into()
+    }
+}
+
+impl Eq for Key {}
+
 impl AsRef<SecretboxKey> for Key {
     fn as_ref(&self) -> &SecretboxKey {
         &self.0
@@ -69,12 +106,40 @@ impl TryFrom<Vec<u8>> for Key {
     }
 }
 
+impl FromStr for Key {
+    type Err = CryptoError;
+
+    /// Create a `Key` from a hex encoded string slice.
+    ///
+    /// The key material is decoded in constant time.
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; KEY_SIZE];
+        let result = decode_hex_ct(val.as_bytes(), &mut bytes).map(|_| Key::from(bytes));
+        bytes.zeroize();
+        result
+    }
+}
+
 impl Serialize for Key {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         serializer.serialize_str(&HEXLOWER.encode(&self.0))
     }
 }
 
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex: Zeroizing<String> = Zeroizing::new(Deserialize::deserialize(deserializer)?);
+        hex.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Key {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Key::from(<[u8; KEY_SIZE]>::arbitrary(u)?))
+    }
+}
+
 fn get_file_nonce() -> &'static Nonce {
     static FILE_NONCE: OnceLock<Nonce> = OnceLock::new();
     FILE_NONCE.get_or_init(|| {
@@ -93,18 +158,199 @@ fn get_thumb_nonce() -> &'static Nonce {
     })
 }
 
+/// Decode a single hex character in constant time.
+///
+/// Return the value of the nibble and a mask that is `0xff` if the character
+/// is a valid (upper- or lowercase) hex digit and `0x00` otherwise.
+fn decode_nibble_ct(c: u8) -> (u8, u8) {
+    // Digits: '0'..='9' xor 0x30 results in 0..=9
+    let num = c ^ 0x30;
+    let num_mask = ((num as u16).wrapping_sub(10) >> 8) as u8;
+    // Letters: 'a'..='f' and 'A'..='F' result in 10..=15
+    let alpha = (c & !0x20).wrapping_sub(55);
+    let alpha_mask =
+        (((alpha as u16).wrapping_sub(10) ^ (alpha as u16).wrapping_sub(16)) >> 8) as u8;
+    (
+        (num_mask & num) | (alpha_mask & alpha),
+        num_mask | alpha_mask,
+    )
+}
+
+/// Decode the hex string `input` into `output` without data dependent
+/// branches or table lookups, so that the timing does not leak the decoded
+/// key material.
+///
+/// The length of `input` must be exactly twice the length of `output`. Only
+/// the length (but not the position of an invalid character) is revealed
+/// through timing.
+pub(crate) fn decode_hex_ct(input: &[u8], output: &mut [u8]) -> Result<(), CryptoError> {
+    if input.len() != output.len() * 2 {
+        return Err(CryptoError::BadKey(format!(
+            "Expected {} hex characters, but got {}",
+            output.len() * 2,
+            input.len()
+        )));
+    }
+    let mut valid = 0xff;
+    for (byte, pair) in output.iter_mut().zip(input.chunks_exact(2)) {
+        let (high, high_valid) = decode_nibble_ct(pair[0]);
+        let (low, low_valid) = decode_nibble_ct(pair[1]);
+        *byte = (high << 4) | low;
+        valid &= high_valid & low_valid;
+    }
+    if valid != 0xff {
+        output.zeroize();
+        return Err(CryptoError::BadKey("Invalid hex character".into()));
+    }
+    Ok(())
+}
+
+/// Return `0xff` if `low <= c <= high` and `0x00` otherwise, in constant
+/// time.
+fn range_mask_ct(c: u8, low: u8, high: u8) -> u8 {
+    let c = i16::from(c);
+    // Both differences are negative if and only if `c` is in the range
+    (((i16::from(low) - 1 - c) & (c - i16::from(high) - 1)) >> 15) as u8
+}
+
+/// Decode a single (standard alphabet) base64 character in constant time.
+///
+/// Return the value of the sextet and a mask that is `0xff` if the character
+/// is valid and `0x00` otherwise.
+fn decode_sextet_ct(c: u8) -> (u8, u8) {
+    let upper = range_mask_ct(c, b'A', b'Z');
+    let lower = range_mask_ct(c, b'a', b'z');
+    let digit = range_mask_ct(c, b'0', b'9');
+    let plus = range_mask_ct(c, b'+', b'+');
+    let slash = range_mask_ct(c, b'/', b'/');
+    (
+        (upper & c.wrapping_sub(b'A'))
+            | (lower & c.wrapping_sub(b'a' - 26))
+            | (digit & c.wrapping_add(52 - b'0'))
+            | (plus & 62)
+            | (slash & 63),
+        upper | lower | digit | plus | slash,
+    )
+}
+
+/// Decode the padded base64 string `input` into `output`, like
+/// [`decode_hex_ct`].
+///
+/// The length of `input` must be exactly the padded base64 length of
+/// `output`, and unused trailing bits must be zero.
+pub(crate) fn decode_base64_ct(input: &[u8], output: &mut [u8]) -> Result<(), CryptoError> {
+    let encoded_len = output.len().div_ceil(3) * 4;
+    let data_len = (output.len() * 4).div_ceil(3);
+    if input.len() != encoded_len {
+        return Err(CryptoError::BadKey(format!(
+            "Expected {} base64 characters, but got {}",
+            encoded_len,
+            input.len()
+        )));
+    }
+    if input[data_len..].iter().any(|&c| c != b'=') {
+        return Err(CryptoError::BadKey("Invalid base64 padding".into()));
+    }
+    let mut valid = 0xff;
+    let mut acc: u16 = 0;
+    let mut bits = 0;
+    let mut bytes = output.iter_mut();
+    for &c in &input[..data_len] {
+        let (sextet, sextet_valid) = decode_sextet_ct(c);
+        valid &= sextet_valid;
+        acc = ((acc << 6) | u16::from(sextet)) & 0x3fff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            if let Some(byte) = bytes.next() {
+                *byte = (acc >> bits) as u8;
+            }
+        }
+    }
+    // The remaining bits must be zero for the encoding to be canonical
+    let rest = acc & ((1 << bits) - 1);
+    valid &= (rest.wrapping_sub(1) >> 8) as u8;
+    if valid != 0xff {
+        output.zeroize();
+        return Err(CryptoError::BadKey("Invalid base64 character".into()));
+    }
+    Ok(())
+}
+
 /// Return a random number in the range `[1, 255]`.
-fn random_padding_amount() -> u8 {
-    let mut rng = rand::thread_rng();
+fn random_padding_amount(rng: &mut impl RngCore) -> u8 {
     rng.gen_range(1..=255)
 }
 
+/// Policy for the amount of PKCS#7 style padding added to encrypted messages.
+///
+/// The padding hides the exact length of a message. Note that the amount of
+/// padding is limited to 255 bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum PaddingPolicy {
+    /// Add a random amount of 1–255 bytes of padding.
+    #[default]
+    Random,
+    /// Add a random amount of padding, but at least as much as needed to make
+    /// the padded plaintext (including the message type byte) `n` bytes long.
+    /// The Threema apps use a minimum length of 32 bytes.
+    RandomWithMinLength(u8),
+    /// Pad the plaintext (including the message type byte) to the next
+    /// multiple of the bucket size, so that all messages within one bucket
+    /// have the same length.
+    Bucket(NonZeroU8),
+}
+
+impl PaddingPolicy {
+    /// Return the amount of padding for a plaintext of length `len`
+    /// (including the message type byte).
+    fn padding_amount(&self, len: usize, rng: &mut impl RngCore) -> u8 {
+        match *self {
+            PaddingPolicy::Random => random_padding_amount(rng),
+            PaddingPolicy::RandomWithMinLength(min_length) => {
+                let min_amount = (min_length as usize).saturating_sub(len).max(1) as u8;
+                rng.gen_range(min_amount..=255)
+            }
+            PaddingPolicy::Bucket(size) => {
+                let size = size.get() as usize;
+                (size - len % size) as u8
+            }
+        }
+    }
+}
+
 /// An encrypted message. Contains both the ciphertext and the nonce.
+///
+/// The ciphertext is reference counted, so cloning the message (e.g. to pass
+/// it to a queue or to send it to multiple endpoints) doesn't copy it.
+///
+/// The `Debug` output only contains the length of the ciphertext.
+#[derive(Clone)]
 pub struct EncryptedMessage {
-    pub ciphertext: Vec<u8>,
+    pub ciphertext: Bytes,
     pub nonce: Nonce,
 }
 
+impl Debug for EncryptedMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedMessage")
+            .field("ciphertext", &RedactedBytes(&self.ciphertext))
+            .field("nonce", &Redacted(&self.nonce))
+            .finish()
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for EncryptedMessage {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(EncryptedMessage {
+            ciphertext: Bytes::from(Vec::<u8>::arbitrary(u)?),
+            nonce: Nonce::from(<[u8; NONCE_SIZE]>::arbitrary(u)?),
+        })
+    }
+}
+
 /// The public key of a recipient.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct RecipientKey(pub PublicKey);
@@ -123,6 +369,13 @@ impl From<[u8; 32]> for RecipientKey {
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for RecipientKey {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(RecipientKey::from(<[u8; 32]>::arbitrary(u)?))
+    }
+}
+
 impl RecipientKey {
     /// Create a `RecipientKey` from a byte slice. It must contain 32 bytes.
     pub fn from_bytes(val: &[u8]) -> Result<Self, CryptoError> {
@@ -140,6 +393,26 @@ impl RecipientKey {
     pub fn to_hex_string(&self) -> String {
         HEXLOWER.encode(self.as_bytes())
     }
+
+    /// Return the key fingerprint, the first 16 bytes of the SHA-256 hash of
+    /// the key.
+    pub fn fingerprint(&self) -> [u8; 16] {
+        let hash = Sha256::digest(self.as_bytes());
+        let mut fingerprint = [0; 16];
+        fingerprint.copy_from_slice(&hash[..16]);
+        fingerprint
+    }
+
+    /// Return the key fingerprint as lowercase hex string.
+    pub fn fingerprint_hex(&self) -> String {
+        HEXLOWER.encode(&self.fingerprint())
+    }
+
+    /// Return the payload of the QR code used by the Threema apps to verify
+    /// the public key of the specified identity (`3mid:<ID>,<KEY>`).
+    pub fn qr_payload(&self, id: &str) -> String {
+        format!("3mid:{},{}", id, self.to_hex_string())
+    }
 }
 
 impl FromStr for RecipientKey {
@@ -158,14 +431,58 @@ impl FromStr for RecipientKey {
 pub fn encrypt_raw(
     data: &[u8],
     public_key: &PublicKey,
-    private_key: &SecretKey,
+    private_key: &(impl KeyProvider + ?Sized),
 ) -> Result<EncryptedMessage, CryptoError> {
-    let crypto_box: SalsaBox = SalsaBox::new(public_key, private_key);
-    let nonce: Nonce = SalsaBox::generate_nonce(&mut OsRng);
-    let ciphertext = crypto_box
-        .encrypt(&nonce, data)
-        .map_err(|_| CryptoError::EncryptionFailed)?;
-    Ok(EncryptedMessage { ciphertext, nonce })
+    encrypt_raw_with_rng(data, public_key, private_key, &mut OsRng)
+}
+
+/// Encrypt raw data for the recipient, using `rng` to generate the nonce.
+///
+/// Note: In almost all cases you should use [`encrypt_raw`] instead. Using a
+/// deterministic RNG is only useful for testing purposes!
+pub fn encrypt_raw_with_rng(
+    data: &[u8],
+    public_key: &PublicKey,
+    private_key: &(impl KeyProvider + ?Sized),
+    rng: &mut (impl CryptoRng + RngCore),
+) -> Result<EncryptedMessage, CryptoError> {
+    let mut ciphertext = data.to_vec();
+    let nonce = encrypt_raw_in_place_with_rng(&mut ciphertext, public_key, private_key, rng)?;
+    Ok(EncryptedMessage {
+        ciphertext: ciphertext.into(),
+        nonce,
+    })
+}
+
+/// Encrypt raw data for the recipient in place.
+///
+/// The plaintext in `buffer` is replaced with the ciphertext. If the capacity
+/// of the buffer suffices for the additional authentication tag, no memory is
+/// allocated. Return the randomly generated nonce.
+pub fn encrypt_raw_in_place(
+    buffer: &mut Vec<u8>,
+    public_key: &PublicKey,
+    private_key: &(impl KeyProvider + ?Sized),
+) -> Result<Nonce, CryptoError> {
+    encrypt_raw_in_place_with_rng(buffer, public_key, private_key, &mut OsRng)
+}
+
+/// Encrypt raw data for the recipient in place, using `rng` to generate the
+/// nonce.
+///
+/// Note: In almost all cases you should use [`encrypt_raw_in_place`]
+/// instead. Using a deterministic RNG is only useful for testing purposes!
+pub fn encrypt_raw_in_place_with_rng(
+    buffer: &mut Vec<u8>,
+    public_key: &PublicKey,
+    private_key: &(impl KeyProvider + ?Sized),
+    rng: &mut (impl CryptoRng + RngCore),
+) -> Result<Nonce, CryptoError> {
+    let nonce: Nonce = SalsaBox::generate_nonce(rng);
+    buffer.splice(0..0, [0; TAG_SIZE]);
+    let tag = private_key.encrypt_in_place_detached(public_key, &nonce, &mut buffer[TAG_SIZE..])?;
+    buffer[..TAG_SIZE].copy_from_slice(&tag);
+    Ok(nonce)
 }
 
 /// Encrypt a message with the specified `msgtype` for the recipient.
@@ -175,19 +492,172 @@ pub fn encrypt(
     data: &[u8],
     msgtype: MessageType,
     public_key: &PublicKey,
-    private_key: &SecretKey,
+    private_key: &(impl KeyProvider + ?Sized),
+) -> Result<EncryptedMessage, CryptoError> {
+    encrypt_with_rng(data, msgtype, public_key, private_key, &mut OsRng)
+}
+
+/// Encrypt a message with the specified `msgtype` for the recipient, using
+/// `rng` to generate the nonce and the padding.
+///
+/// Note: In almost all cases you should use [`encrypt`] instead. Using a
+/// deterministic RNG is only useful for testing purposes!
+pub fn encrypt_with_rng(
+    data: &[u8],
+    msgtype: MessageType,
+    public_key: &PublicKey,
+    private_key: &(impl KeyProvider + ?Sized),
+    rng: &mut (impl CryptoRng + RngCore),
+) -> Result<EncryptedMessage, CryptoError> {
+    let mut ciphertext = Vec::new();
+    let nonce =
+        encrypt_in_place_with_rng(data, msgtype, public_key, private_key, &mut ciphertext, rng)?;
+    Ok(EncryptedMessage {
+        ciphertext: ciphertext.into(),
+        nonce,
+    })
+}
+
+/// Encrypt a message with the specified `msgtype` for the recipient into a
+/// caller-provided buffer.
+///
+/// This works like [`encrypt`], but the ciphertext is written to `buffer`
+/// (replacing its previous contents) instead of a newly allocated vector.
+/// When re-using the same buffer for many messages, this avoids allocations
+/// once the buffer has grown large enough. Return the randomly generated
+/// nonce.
+pub fn encrypt_in_place(
+    data: &[u8],
+    msgtype: MessageType,
+    public_key: &PublicKey,
+    private_key: &(impl KeyProvider + ?Sized),
+    buffer: &mut Vec<u8>,
+) -> Result<Nonce, CryptoError> {
+    encrypt_in_place_with_rng(data, msgtype, public_key, private_key, buffer, &mut OsRng)
+}
+
+/// Encrypt a message with the specified `msgtype` for the recipient into a
+/// caller-provided buffer, using `rng` to generate the nonce and the padding.
+///
+/// Note: In almost all cases you should use [`encrypt_in_place`] instead.
+/// Using a deterministic RNG is only useful for testing purposes!
+pub fn encrypt_in_place_with_rng(
+    data: &[u8],
+    msgtype: MessageType,
+    public_key: &PublicKey,
+    private_key: &(impl KeyProvider + ?Sized),
+    buffer: &mut Vec<u8>,
+    rng: &mut (impl CryptoRng + RngCore),
+) -> Result<Nonce, CryptoError> {
+    encrypt_in_place_with_padding(
+        data,
+        msgtype,
+        PaddingPolicy::default(),
+        public_key,
+        private_key,
+        buffer,
+        rng,
+    )
+}
+
+/// Encrypt a message with the specified `msgtype` for the recipient, using
+/// the specified padding policy.
+///
+/// See [`PaddingPolicy`] for the available options.
+pub fn encrypt_with_padding(
+    data: &[u8],
+    msgtype: MessageType,
+    padding: PaddingPolicy,
+    public_key: &PublicKey,
+    private_key: &(impl KeyProvider + ?Sized),
 ) -> Result<EncryptedMessage, CryptoError> {
-    // Add random amount of PKCS#7 style padding
-    let padding_amount = random_padding_amount();
-    let padding = repeat(padding_amount).take(padding_amount as usize);
-    let msgtype_byte = repeat(msgtype.into()).take(1);
-    let padded_plaintext: Vec<u8> = msgtype_byte
-        .chain(data.iter().cloned())
-        .chain(padding)
-        .collect();
+    let mut ciphertext = Vec::new();
+    let nonce = encrypt_in_place_with_padding(
+        data,
+        msgtype,
+        padding,
+        public_key,
+        private_key,
+        &mut ciphertext,
+        &mut OsRng,
+    )?;
+    Ok(EncryptedMessage {
+        ciphertext: ciphertext.into(),
+        nonce,
+    })
+}
+
+fn encrypt_in_place_with_padding(
+    data: &[u8],
+    msgtype: MessageType,
+    padding: PaddingPolicy,
+    public_key: &PublicKey,
+    private_key: &(impl KeyProvider + ?Sized),
+    buffer: &mut Vec<u8>,
+    rng: &mut (impl CryptoRng + RngCore),
+) -> Result<Nonce, CryptoError> {
+    // Assemble the plaintext behind the space reserved for the
+    // authentication tag: Message type, data and PKCS#7 style padding.
+    let padding_amount = padding.padding_amount(1 + data.len(), rng);
+    buffer.clear();
+    buffer.reserve(TAG_SIZE + 1 + data.len() + padding_amount as usize);
+    buffer.resize(TAG_SIZE, 0);
+    buffer.push(msgtype.into());
+    buffer.extend_from_slice(data);
+    buffer.resize(buffer.len() + padding_amount as usize, padding_amount);
 
     // Encrypt
-    encrypt_raw(&padded_plaintext, public_key, private_key)
+    let nonce: Nonce = SalsaBox::generate_nonce(rng);
+    let tag = private_key.encrypt_in_place_detached(public_key, &nonce, &mut buffer[TAG_SIZE..])?;
+    buffer[..TAG_SIZE].copy_from_slice(&tag);
+    Ok(nonce)
+}
+
+/// Decrypt raw data from the sender.
+///
+/// This is the counterpart to [`encrypt_raw`].
+pub fn decrypt_raw(
+    data: &[u8],
+    nonce: &Nonce,
+    public_key: &PublicKey,
+    private_key: &(impl KeyProvider + ?Sized),
+) -> Result<Vec<u8>, CryptoError> {
+    if data.len() < TAG_SIZE {
+        return Err(CryptoError::DecryptionFailed);
+    }
+    let (tag, ciphertext) = data.split_at(TAG_SIZE);
+    let tag: &[u8; TAG_SIZE] = tag.try_into().expect("Tag has the correct size");
+    let mut plaintext = ciphertext.to_vec();
+    private_key.decrypt_in_place_detached(public_key, nonce, &mut plaintext, tag)?;
+    Ok(plaintext)
+}
+
+/// Remove PKCS#7 style padding from decrypted data.
+///
+/// At least one byte of data must remain after removing the padding.
+pub(crate) fn remove_padding(mut data: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+    let padding_amount = data.last().cloned().ok_or(CryptoError::BadPadding)? as usize;
+    if padding_amount >= data.len() {
+        return Err(CryptoError::BadPadding);
+    }
+    data.truncate(data.len() - padding_amount);
+    Ok(data)
+}
+
+/// Decrypt a message from the sender.
+///
+/// This is the counterpart to [`encrypt`]: The PKCS#7 padding is removed and
+/// the message type byte is split off the data. If the padding is missing or
+/// invalid, a [`CryptoError::BadPadding`] will be returned.
+pub fn decrypt(
+    data: &[u8],
+    nonce: &Nonce,
+    public_key: &PublicKey,
+    private_key: &(impl KeyProvider + ?Sized),
+) -> Result<(MessageType, Vec<u8>), CryptoError> {
+    let mut decrypted = remove_padding(decrypt_raw(data, nonce, public_key, private_key)?)?;
+    let msgtype = MessageType::from(decrypted.remove(0));
+    Ok((msgtype, decrypted))
 }
 
 /// Encrypt an image message for the recipient.
@@ -195,8 +665,9 @@ pub fn encrypt_image_msg(
     blob_id: &BlobId,
     img_size_bytes: u32,
     image_data_nonce: &Nonce,
+    padding: PaddingPolicy,
     public_key: &PublicKey,
-    private_key: &SecretKey,
+    private_key: &(impl KeyProvider + ?Sized),
 ) -> Result<EncryptedMessage, CryptoError> {
     let mut data = [0; 44];
     // Since we're writing to an array and not to a file or socket, these
@@ -211,18 +682,7 @@ pub fn encrypt_image_msg(
         .write_all(image_data_nonce)
         .expect("Writing to buffer failed");
     let msgtype = MessageType::Image;
-    encrypt(&data, msgtype, public_key, private_key)
-}
-
-/// Encrypt a file message for the recipient.
-pub fn encrypt_file_msg(
-    msg: &FileMessage,
-    public_key: &PublicKey,
-    private_key: &SecretKey,
-) -> Result<EncryptedMessage, CryptoError> {
-    let data = json::to_string(msg).unwrap();
-    let msgtype = MessageType::File;
-    encrypt(data.as_bytes(), msgtype, public_key, private_key)
+    encrypt_with_padding(&data, msgtype, padding, public_key, private_key)
 }
 
 /// Raw unencrypted bytes of a file and optionally a thumbnail.
@@ -250,8 +710,20 @@ pub struct EncryptedFileData {
 ///
 /// Return the encrypted bytes and the key.
 pub fn encrypt_file_data(data: &FileData) -> Result<(EncryptedFileData, Key), CryptoError> {
+    encrypt_file_data_with_rng(data, &mut OsRng)
+}
+
+/// Encrypt file data and an optional thumbnail using a symmetric key
+/// generated with `rng`.
+///
+/// Note: In almost all cases you should use [`encrypt_file_data`] instead.
+/// Using a deterministic RNG is only useful for testing purposes!
+pub fn encrypt_file_data_with_rng(
+    data: &FileData,
+    rng: &mut (impl CryptoRng + RngCore),
+) -> Result<(EncryptedFileData, Key), CryptoError> {
     // Generate a random encryption key
-    let key: Key = XSalsa20Poly1305::generate_key(&mut OsRng).into();
+    let key: Key = XSalsa20Poly1305::generate_key(rng).into();
     let secretbox = XSalsa20Poly1305::new(key.as_ref());
 
     // Encrypt data
@@ -293,6 +765,134 @@ pub fn decrypt_file_data(
     Ok(FileData { file, thumbnail })
 }
 
+/// Read from `reader` until `buf` is full or the end of the input is reached.
+///
+/// Return the number of bytes read.
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Initialize the XSalsa20 cipher and the Poly1305 MAC the same way as the
+/// NaCl secretbox construction does.
+fn init_secretbox_stream(key: &Key, nonce: &Nonce) -> (XSalsa20, Poly1305) {
+    let mut cipher = XSalsa20::new(key.as_ref(), nonce);
+    let mut mac_key = poly1305::Key::default();
+    cipher.apply_keystream(&mut mac_key);
+    let mac = Poly1305::new(&mac_key);
+    mac_key.zeroize();
+    (cipher, mac)
+}
+
+/// Encrypt file data read from `reader` with the provided symmetric key and
+/// write the ciphertext to `writer`.
+///
+/// In contrast to [`encrypt_file_data`], the data is processed in chunks, so
+/// neither the plaintext nor the ciphertext need to be held in memory
+/// completely. The output is identical to the encrypted file produced by
+/// [`encrypt_file_data`] and can be uploaded to the blob server as-is.
+///
+/// Since the authentication tag precedes the ciphertext, the writer must be
+/// seekable. The tag is written once all data has been encrypted.
+///
+/// Return the number of plaintext bytes that were encrypted.
+pub fn encrypt_file_stream<R: Read, W: Write + Seek>(
+    mut reader: R,
+    mut writer: W,
+    key: &Key,
+) -> io::Result<u64> {
+    let (mut cipher, mut mac) = init_secretbox_stream(key, get_file_nonce());
+
+    // Reserve space for the authentication tag
+    let tag_position = writer.stream_position()?;
+    writer.write_all(&[0; TAG_SIZE])?;
+
+    let mut buf = vec![0; STREAM_CHUNK_SIZE];
+    let mut total = 0;
+    let tag = loop {
+        let len = read_chunk(&mut reader, &mut buf)?;
+        let chunk = &mut buf[..len];
+        cipher.apply_keystream(chunk);
+        writer.write_all(chunk)?;
+        total += len as u64;
+        if len < STREAM_CHUNK_SIZE {
+            break mac.compute_unpadded(chunk);
+        }
+        mac.update_padded(chunk);
+    };
+
+    // Write authentication tag
+    let end_position = writer.stream_position()?;
+    writer.seek(SeekFrom::Start(tag_position))?;
+    writer.write_all(&tag)?;
+    writer.seek(SeekFrom::Start(end_position))?;
+
+    Ok(total)
+}
+
+/// Decrypt file data read from `reader` with the provided symmetric key and
+/// write the plaintext to `writer`.
+///
+/// This is the streaming counterpart to [`decrypt_file_data`]. The reader
+/// must be seekable, since the data is read twice: First to verify the
+/// authentication tag, then to decrypt it. No plaintext is written unless
+/// the authentication tag is valid. If it is invalid, an error of kind
+/// [`InvalidData`](io::ErrorKind::InvalidData) is returned.
+///
+/// Return the number of plaintext bytes that were written.
+pub fn decrypt_file_stream<R: Read + Seek, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    key: &Key,
+) -> io::Result<u64> {
+    let (mut cipher, mut mac) = init_secretbox_stream(key, get_file_nonce());
+
+    // Read authentication tag
+    let mut tag = [0; TAG_SIZE];
+    reader.read_exact(&mut tag)?;
+    let data_position = reader.stream_position()?;
+
+    // Verify authentication tag
+    let mut buf = vec![0; STREAM_CHUNK_SIZE];
+    let expected_tag = loop {
+        let len = read_chunk(&mut reader, &mut buf)?;
+        if len < STREAM_CHUNK_SIZE {
+            break mac.compute_unpadded(&buf[..len]);
+        }
+        mac.update_padded(&buf);
+    };
+    if !bool::from(expected_tag.as_slice().ct_eq(&tag)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            CryptoError::DecryptionFailed,
+        ));
+    }
+
+    // Decrypt
+    reader.seek(SeekFrom::Start(data_position))?;
+    let mut total = 0;
+    loop {
+        let len = read_chunk(&mut reader, &mut buf)?;
+        let chunk = &mut buf[..len];
+        cipher.apply_keystream(chunk);
+        writer.write_all(chunk)?;
+        total += len as u64;
+        if len < STREAM_CHUNK_SIZE {
+            break;
+        }
+    }
+
+    Ok(total)
+}
+
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
@@ -303,12 +903,15 @@ mod test {
     };
     use crypto_box::{Nonce, PublicKey, SalsaBox, SecretKey};
 
+    use data_encoding::{BASE64, HEXUPPER};
+
     use super::*;
 
     #[test]
     fn test_randombytes_uniform() {
+        let mut rng = rand::thread_rng();
         for _ in 0..500 {
-            let random = random_padding_amount();
+            let random = random_padding_amount(&mut rng);
             assert!(random >= 1);
         }
     }
@@ -316,8 +919,9 @@ mod test {
     #[test]
     /// Make sure that not all random numbers are the same.
     fn test_randombytes_uniform_not_stuck() {
+        let mut rng = rand::thread_rng();
         let random_numbers = (1..100)
-            .map(|_| random_padding_amount())
+            .map(|_| random_padding_amount(&mut rng))
             .collect::<Vec<u8>>();
         let first = random_numbers[0];
         assert!(!random_numbers.iter().all(|n| *n == first));
@@ -377,6 +981,17 @@ mod test {
         assert_eq!(&data[21..45], &blob_nonce[..]);
     }
 
+    #[test]
+    fn test_encrypted_message_debug() {
+        let message = EncryptedMessage {
+            ciphertext: Bytes::from_static(&[0xab; 40]),
+            nonce: [0xcd; 24].into(),
+        };
+        let debug = format!("{:?}", message);
+        assert!(debug.contains("[redacted, 40 bytes]"));
+        assert!(!debug.contains("171") && !debug.contains("205"));
+    }
+
     #[test]
     fn test_recipient_key_from_publickey() {
         let bytes = [0; 32];
@@ -442,6 +1057,112 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_decode_hex_ct() {
+        for byte in 0..=255u8 {
+            let mut out = [0; 1];
+            decode_hex_ct(HEXLOWER.encode(&[byte]).as_bytes(), &mut out).unwrap();
+            assert_eq!(out[0], byte);
+            decode_hex_ct(HEXUPPER.encode(&[byte]).as_bytes(), &mut out).unwrap();
+            assert_eq!(out[0], byte);
+        }
+        for c in 0..=255u8 {
+            let mut out = [0; 1];
+            let expected = (c as char).is_ascii_hexdigit();
+            assert_eq!(decode_hex_ct(&[b'0', c], &mut out).is_ok(), expected);
+            assert_eq!(decode_hex_ct(&[c, b'0'], &mut out).is_ok(), expected);
+        }
+        let mut out = [0; 2];
+        assert_eq!(
+            decode_hex_ct(b"abc", &mut out),
+            Err(CryptoError::BadKey(
+                "Expected 4 hex characters, but got 3".into()
+            ))
+        );
+        assert_eq!(
+            decode_hex_ct(b"ab0g", &mut out),
+            Err(CryptoError::BadKey("Invalid hex character".into()))
+        );
+        assert_eq!(out, [0, 0]);
+    }
+
+    #[test]
+    fn test_decode_base64_ct() {
+        let mut rng = rand::thread_rng();
+        for len in 0..40 {
+            let mut bytes = vec![0; len];
+            rng.fill_bytes(&mut bytes);
+            let mut out = vec![0; len];
+            decode_base64_ct(BASE64.encode(&bytes).as_bytes(), &mut out).unwrap();
+            assert_eq!(out, bytes);
+        }
+        let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        for c in 0..=255u8 {
+            let mut out = [0; 3];
+            let expected = alphabet.contains(&c);
+            assert_eq!(
+                decode_base64_ct(&[b'A', b'A', b'A', c], &mut out).is_ok(),
+                expected
+            );
+            assert_eq!(
+                decode_base64_ct(&[c, b'A', b'A', b'A'], &mut out).is_ok(),
+                expected
+            );
+        }
+        let mut out = [0; 2];
+        assert_eq!(
+            decode_base64_ct(b"AAA", &mut out),
+            Err(CryptoError::BadKey(
+                "Expected 4 base64 characters, but got 3".into()
+            ))
+        );
+        assert_eq!(
+            decode_base64_ct(b"AAAA", &mut out),
+            Err(CryptoError::BadKey("Invalid base64 padding".into()))
+        );
+        // Non-zero trailing bits
+        assert_eq!(
+            decode_base64_ct(b"AAB=", &mut out),
+            Err(CryptoError::BadKey("Invalid base64 character".into()))
+        );
+        assert_eq!(
+            decode_base64_ct(b"/w!=", &mut out),
+            Err(CryptoError::BadKey("Invalid base64 character".into()))
+        );
+        assert_eq!(out, [0, 0]);
+    }
+
+    #[test]
+    fn test_key_from_str() {
+        let key: Key = "0101010101010101010101010101010101010101010101010101010101010101"
+            .parse()
+            .unwrap();
+        assert_eq!(key, Key::from([1; 32]));
+        assert_ne!(key, Key::from([2; 32]));
+        assert!("0101".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn test_recipient_key_fingerprint() {
+        let recipient = RecipientKey::from([0; 32]);
+        assert_eq!(
+            recipient.fingerprint_hex(),
+            "66687aadf862bd776c8fc18b8e9f8e20"
+        );
+        assert_eq!(recipient.fingerprint()[0], 0x66);
+    }
+
+    #[test]
+    fn test_recipient_key_qr_payload() {
+        let mut bytes = [0; 32];
+        bytes[0] = 0xff;
+        let recipient = RecipientKey::from(bytes);
+        assert_eq!(
+            recipient.qr_payload("ECHOECHO"),
+            "3mid:ECHOECHO,ff00000000000000000000000000000000000000000000000000000000000000"
+        );
+    }
+
     #[test]
     fn test_encrypt_file_data() {
         let file_data = [1, 2, 3, 4];
@@ -515,4 +1236,219 @@ mod test {
         assert_eq!(decrypted.file, &file_data);
         assert_eq!(decrypted.thumbnail.unwrap(), &thumb_data);
     }
+
+    #[test]
+    fn test_encrypt_file_stream() {
+        // Test lengths around the chunk and block boundaries
+        for len in [0, 1, 15, 16, 17, STREAM_CHUNK_SIZE, STREAM_CHUNK_SIZE + 5] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let key = Key::generate();
+
+            // Encrypt streaming
+            let mut ciphertext = io::Cursor::new(vec![0xff; 3]);
+            ciphertext.seek(SeekFrom::End(0)).unwrap();
+            let written = encrypt_file_stream(plaintext.as_slice(), &mut ciphertext, &key).unwrap();
+            assert_eq!(written, len as u64);
+            let ciphertext = ciphertext.into_inner();
+            assert_eq!(&ciphertext[..3], &[0xff; 3]);
+
+            // Compare to non-streaming encryption
+            let secretbox = XSalsa20Poly1305::new(key.as_ref());
+            let expected = secretbox
+                .encrypt(get_file_nonce(), Payload::from(plaintext.as_ref()))
+                .unwrap();
+            assert_eq!(&ciphertext[3..], expected);
+
+            // Decrypt streaming
+            let mut decrypted = vec![];
+            let reader = io::Cursor::new(&ciphertext[3..]);
+            let read = decrypt_file_stream(reader, &mut decrypted, &key).unwrap();
+            assert_eq!(read, len as u64);
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_file_stream_invalid() {
+        let key = Key::generate();
+        let mut ciphertext = io::Cursor::new(vec![]);
+        encrypt_file_stream(&[1, 2, 3][..], &mut ciphertext, &key).unwrap();
+        let mut ciphertext = ciphertext.into_inner();
+        ciphertext[17] ^= 1;
+
+        let mut decrypted = vec![];
+        let err =
+            decrypt_file_stream(io::Cursor::new(ciphertext), &mut decrypted, &key).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(decrypted.is_empty());
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let a_sk = SecretKey::generate(&mut OsRng);
+        let b_sk = SecretKey::generate(&mut OsRng);
+
+        // Roundtrip
+        let encrypted = encrypt(&[1, 2, 3], MessageType::Text, &b_sk.public_key(), &a_sk).unwrap();
+        let (msgtype, data) = decrypt(
+            &encrypted.ciphertext,
+            &encrypted.nonce,
+            &a_sk.public_key(),
+            &b_sk,
+        )
+        .unwrap();
+        assert_eq!(msgtype, MessageType::Text);
+        assert_eq!(data, vec![1, 2, 3]);
+
+        // Wrong key
+        let err = decrypt(
+            &encrypted.ciphertext,
+            &encrypted.nonce,
+            &b_sk.public_key(),
+            &b_sk,
+        )
+        .unwrap_err();
+        assert_eq!(err, CryptoError::DecryptionFailed);
+
+        // Missing padding
+        let encrypted = encrypt_raw(&[1, 2, 3], &b_sk.public_key(), &a_sk).unwrap();
+        let err = decrypt(
+            &encrypted.ciphertext,
+            &encrypted.nonce,
+            &a_sk.public_key(),
+            &b_sk,
+        )
+        .unwrap_err();
+        assert_eq!(err, CryptoError::BadPadding);
+    }
+
+    #[test]
+    fn test_encrypt_in_place() {
+        let a_sk = SecretKey::generate(&mut OsRng);
+        let b_sk = SecretKey::generate(&mut OsRng);
+
+        // Re-use the same buffer for multiple messages
+        let mut buffer = Vec::with_capacity(1024);
+        for data in [&b"hello"[..], &b"hi"[..], &[0; 300][..]] {
+            let nonce = encrypt_in_place(
+                data,
+                MessageType::Text,
+                &b_sk.public_key(),
+                &a_sk,
+                &mut buffer,
+            )
+            .unwrap();
+            assert_eq!(buffer.capacity(), 1024);
+            let (msgtype, decrypted) = decrypt(&buffer, &nonce, &a_sk.public_key(), &b_sk).unwrap();
+            assert_eq!(msgtype, MessageType::Text);
+            assert_eq!(decrypted, data);
+        }
+    }
+
+    #[test]
+    fn test_encrypt_raw_in_place() {
+        let a_sk = SecretKey::generate(&mut OsRng);
+        let b_sk = SecretKey::generate(&mut OsRng);
+
+        let mut buffer = Vec::with_capacity(64);
+        buffer.extend_from_slice(&[1, 2, 3]);
+        let nonce = encrypt_raw_in_place(&mut buffer, &b_sk.public_key(), &a_sk).unwrap();
+        assert_eq!(buffer.len(), 3 + 16);
+        assert_eq!(buffer.capacity(), 64);
+        let decrypted = decrypt_raw(&buffer, &nonce, &a_sk.public_key(), &b_sk).unwrap();
+        assert_eq!(decrypted, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_encrypted_message_clone_shares_ciphertext() {
+        let a_sk = SecretKey::generate(&mut OsRng);
+        let b_pk = SecretKey::generate(&mut OsRng).public_key();
+        let encrypted = encrypt_raw(&[0; 1024], &b_pk, &a_sk).unwrap();
+        let cloned = encrypted.clone();
+        assert_eq!(cloned.ciphertext.as_ptr(), encrypted.ciphertext.as_ptr());
+    }
+
+    #[test]
+    fn test_encrypt_with_rng_deterministic() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let a_sk = SecretKey::from([1; 32]);
+        let b_pk = SecretKey::from([2; 32]).public_key();
+
+        let encrypt_seeded = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            encrypt_with_rng(b"hello", MessageType::Text, &b_pk, &a_sk, &mut rng).unwrap()
+        };
+        let first = encrypt_seeded(42);
+        let second = encrypt_seeded(42);
+        let third = encrypt_seeded(43);
+        assert_eq!(first.nonce, second.nonce);
+        assert_eq!(first.ciphertext, second.ciphertext);
+        assert_ne!(first.nonce, third.nonce);
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let first_raw = encrypt_raw_with_rng(b"hello", &b_pk, &a_sk, &mut rng).unwrap();
+        let mut rng = StdRng::seed_from_u64(42);
+        let second_raw = encrypt_raw_with_rng(b"hello", &b_pk, &a_sk, &mut rng).unwrap();
+        assert_eq!(first_raw.nonce, second_raw.nonce);
+        assert_eq!(first_raw.ciphertext, second_raw.ciphertext);
+
+        let data = FileData {
+            file: vec![1, 2, 3],
+            thumbnail: None,
+        };
+        let (first_file, first_key) =
+            encrypt_file_data_with_rng(&data, &mut StdRng::seed_from_u64(42)).unwrap();
+        let (second_file, second_key) =
+            encrypt_file_data_with_rng(&data, &mut StdRng::seed_from_u64(42)).unwrap();
+        assert_eq!(first_key, second_key);
+        assert_eq!(first_file.file, second_file.file);
+    }
+
+    #[test]
+    fn test_padding_policy() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let amount = PaddingPolicy::Random.padding_amount(10, &mut rng);
+            assert!(amount >= 1);
+
+            let policy = PaddingPolicy::RandomWithMinLength(32);
+            assert!(policy.padding_amount(10, &mut rng) >= 22);
+            assert!(policy.padding_amount(40, &mut rng) >= 1);
+        }
+
+        let policy = PaddingPolicy::Bucket(NonZeroU8::new(64).unwrap());
+        assert_eq!(policy.padding_amount(1, &mut rng), 63);
+        assert_eq!(policy.padding_amount(63, &mut rng), 1);
+        assert_eq!(policy.padding_amount(64, &mut rng), 64);
+        assert_eq!(policy.padding_amount(100, &mut rng), 28);
+
+        let policy = PaddingPolicy::Bucket(NonZeroU8::new(255).unwrap());
+        assert_eq!(policy.padding_amount(255, &mut rng), 255);
+    }
+
+    #[test]
+    fn test_encrypt_with_padding() {
+        let a_sk = SecretKey::generate(&mut OsRng);
+        let b_sk = SecretKey::generate(&mut OsRng);
+        let policy = PaddingPolicy::Bucket(NonZeroU8::new(128).unwrap());
+
+        // All short messages result in the same ciphertext length
+        let short =
+            encrypt_with_padding(b"hi", MessageType::Text, policy, &b_sk.public_key(), &a_sk)
+                .unwrap();
+        let long = encrypt_with_padding(
+            &[b'a'; 100],
+            MessageType::Text,
+            policy,
+            &b_sk.public_key(),
+            &a_sk,
+        )
+        .unwrap();
+        assert_eq!(short.ciphertext.len(), 16 + 128);
+        assert_eq!(long.ciphertext.len(), 16 + 128);
+
+        let (_, data) = decrypt(&long.ciphertext, &long.nonce, &a_sk.public_key(), &b_sk).unwrap();
+        assert_eq!(data, vec![b'a'; 100]);
+    }
 }
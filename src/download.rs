@@ -0,0 +1,219 @@
+//! Automatic download of the files referenced by incoming file messages.
+//!
+//! The [`BlobDownloader`] parses an incoming file message, downloads the
+//! file (and the thumbnail) from the blob server and decrypts it with the key
+//! embedded in the message. Size limits are enforced while downloading and
+//! the number of concurrent downloads is capped. Blobs are taken from the
+//! [`BlobCache`](crate::BlobCache) of the API object, if configured.
+//!
+//! ```no_run
+//! use threema_gateway::{download::BlobDownloader, E2eApi, MessageType};
+//!
+//! # async fn f(api: E2eApi, msgtype: MessageType, payload: &[u8]) -> Result<(), threema_gateway::errors::ApiError> {
+//! let downloader = BlobDownloader::new(api)
+//!     .with_max_file_size(10 * 1024 * 1024)
+//!     .with_max_concurrent_downloads(2);
+//!
+//! if msgtype == MessageType::File {
+//!     let file = downloader.download(payload).await?;
+//!     println!(
+//!         "Received {} ({} bytes)",
+//!         file.message.file_name().unwrap_or("file"),
+//!         file.data.file.len()
+//!     );
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+use crate::{
+    api::E2eApi,
+    crypto::{decrypt_file_data, EncryptedFileData, FileData},
+    errors::ApiError,
+    types::FileMessage,
+};
+
+/// The default maximum file size (50 MiB).
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 50 * 1024 * 1024;
+
+/// The default maximum thumbnail size (1 MiB).
+pub const DEFAULT_MAX_THUMBNAIL_SIZE: u64 = 1024 * 1024;
+
+/// The default maximum number of concurrent downloads.
+pub const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 4;
+
+/// Size of the authentication tag added by the blob encryption.
+const TAG_SIZE: u64 = 16;
+
+/// A downloaded and decrypted file.
+pub struct DownloadedFile {
+    /// The file message referencing the file.
+    pub message: FileMessage,
+    /// The decrypted file data and thumbnail.
+    pub data: FileData,
+}
+
+/// Downloads and decrypts the files referenced by incoming file messages.
+///
+/// Clones share the concurrency limit.
+#[derive(Debug, Clone)]
+pub struct BlobDownloader {
+    api: E2eApi,
+    max_file_size: u64,
+    max_thumbnail_size: u64,
+    semaphore: Arc<Semaphore>,
+}
+
+impl BlobDownloader {
+    /// Create a downloader with the default limits.
+    pub fn new(api: E2eApi) -> Self {
+        BlobDownloader {
+            api,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_thumbnail_size: DEFAULT_MAX_THUMBNAIL_SIZE,
+            semaphore: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_DOWNLOADS)),
+        }
+    }
+
+    /// Set the maximum size of a file in bytes.
+    ///
+    /// Files that are larger (according to the file message or the blob
+    /// server) are rejected with [`ApiError::BlobTooLarge`].
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Set the maximum size of a thumbnail in bytes.
+    pub fn with_max_thumbnail_size(mut self, max_thumbnail_size: u64) -> Self {
+        self.max_thumbnail_size = max_thumbnail_size;
+        self
+    }
+
+    /// Set the maximum number of files that are downloaded concurrently.
+    /// Further downloads wait until a download has completed.
+    ///
+    /// Panics if `max_concurrent_downloads` is 0.
+    pub fn with_max_concurrent_downloads(mut self, max_concurrent_downloads: usize) -> Self {
+        assert!(
+            max_concurrent_downloads > 0,
+            "Concurrency limit must not be 0"
+        );
+        self.semaphore = Arc::new(Semaphore::new(max_concurrent_downloads));
+        self
+    }
+
+    /// Parse a file message from the decrypted message data (without the
+    /// message type byte), then download and decrypt the file.
+    pub async fn download(&self, payload: &[u8]) -> Result<DownloadedFile, ApiError> {
+        let message = FileMessage::from_json(payload)?;
+        let data = self.download_message(&message).await?;
+        Ok(DownloadedFile { message, data })
+    }
+
+    /// Download and decrypt the file (and the thumbnail) referenced by a file
+    /// message.
+    pub async fn download_message(&self, message: &FileMessage) -> Result<FileData, ApiError> {
+        let declared_size = u64::from(message.file_size_bytes());
+        if declared_size > self.max_file_size {
+            return Err(ApiError::BlobTooLarge {
+                size: declared_size,
+                limit: self.max_file_size,
+            });
+        }
+
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("Semaphore is never closed");
+        let file = self
+            .api
+            .blob_download_limited(message.file_blob_id(), self.max_file_size + TAG_SIZE)
+            .await?;
+        let thumbnail = match message.thumbnail_blob_id() {
+            Some(blob_id) => Some(
+                self.api
+                    .blob_download_limited(blob_id, self.max_thumbnail_size + TAG_SIZE)
+                    .await?,
+            ),
+            None => None,
+        };
+
+        let encrypted = EncryptedFileData { file, thumbnail };
+        Ok(decrypt_file_data(
+            &encrypted,
+            message.blob_encryption_key(),
+        )?)
+    }
+}
+
+#[cfg(all(test, feature = "mock-server"))]
+mod tests {
+    use super::*;
+    use crate::{encrypt_file_data, mock_server::MockServer, types::BlobId, ApiBuilder, SecretKey};
+
+    #[tokio::test]
+    async fn test_blob_downloader() {
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap();
+
+        let (encrypted, key) = encrypt_file_data(&FileData {
+            file: vec![7; 2048],
+            thumbnail: Some(vec![8; 64]),
+        })
+        .unwrap();
+        let file_blob_id = BlobId::random();
+        let thumbnail_blob_id = BlobId::random();
+        server.insert_blob(file_blob_id.clone(), encrypted.file);
+        server.insert_blob(thumbnail_blob_id.clone(), encrypted.thumbnail.unwrap());
+        let msg = FileMessage::builder(file_blob_id, key, "application/octet-stream", 2048)
+            .thumbnail(thumbnail_blob_id, "image/jpeg")
+            .build()
+            .unwrap();
+        let payload = serde_json::to_vec(&msg).unwrap();
+
+        let downloader = BlobDownloader::new(api).with_max_concurrent_downloads(1);
+        let file = downloader.download(&payload).await.unwrap();
+        assert_eq!(file.data.file, vec![7; 2048]);
+        assert_eq!(file.data.thumbnail, Some(vec![8; 64]));
+        assert_eq!(file.message.file_size_bytes(), 2048);
+
+        // Rejected because of the declared size
+        let err = downloader
+            .clone()
+            .with_max_file_size(1024)
+            .download(&payload)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            ApiError::BlobTooLarge {
+                size: 2048,
+                limit: 1024
+            }
+        ));
+
+        // Rejected while downloading the thumbnail
+        let err = downloader
+            .with_max_thumbnail_size(32)
+            .download(&payload)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.inner(),
+            ApiError::BlobTooLarge { limit: 48, .. }
+        ));
+    }
+}
@@ -0,0 +1,254 @@
+//! Failover between multiple API endpoints.
+
+use std::{
+    borrow::Cow,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::errors::ApiError;
+
+/// How long an unreachable endpoint is skipped before it is tried again.
+pub(crate) const DEFAULT_ENDPOINT_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// The health of an API endpoint, see
+/// [`E2eApi::endpoint_status`](crate::E2eApi::endpoint_status).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointStatus {
+    /// The endpoint URL.
+    pub url: String,
+    /// Whether the endpoint is considered healthy. Unhealthy endpoints are
+    /// only used if all healthy endpoints failed.
+    pub healthy: bool,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    url: Cow<'static, str>,
+    /// If set, the endpoint was unreachable and is skipped until then.
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl Endpoint {
+    fn unhealthy_until(&self) -> MutexGuard<'_, Option<Instant>> {
+        self.unhealthy_until
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn is_healthy(&self, now: Instant) -> bool {
+        self.unhealthy_until().map_or(true, |until| until <= now)
+    }
+}
+
+/// A prioritized list of API endpoints with health tracking.
+///
+/// Clones share the health state.
+#[derive(Debug, Clone)]
+pub(crate) struct Endpoints {
+    endpoints: Arc<Vec<Endpoint>>,
+    cooldown: Duration,
+}
+
+impl Endpoints {
+    /// Create the endpoint list from the primary endpoint and the fallback
+    /// endpoints (in order of priority).
+    pub(crate) fn new(
+        primary: Cow<'static, str>,
+        fallbacks: Vec<Cow<'static, str>>,
+        cooldown: Duration,
+    ) -> Self {
+        let endpoints = std::iter::once(primary)
+            .chain(fallbacks)
+            .map(|url| Endpoint {
+                url,
+                unhealthy_until: Mutex::new(None),
+            })
+            .collect();
+        Endpoints {
+            endpoints: Arc::new(endpoints),
+            cooldown,
+        }
+    }
+
+    /// Return the current health of all endpoints, in order of priority.
+    pub(crate) fn status(&self) -> Vec<EndpointStatus> {
+        let now = Instant::now();
+        self.endpoints
+            .iter()
+            .map(|endpoint| EndpointStatus {
+                url: endpoint.url.to_string(),
+                healthy: endpoint.is_healthy(now),
+            })
+            .collect()
+    }
+
+    /// Run `operation` against the endpoints in order of priority, until it
+    /// succeeds or fails with an error other than an unreachable endpoint.
+    ///
+    /// Endpoints that are unreachable are marked as unhealthy for the
+    /// cooldown period. Unhealthy endpoints are tried last. Only connection
+    /// errors cause a failover, so a request is never processed twice.
+    ///
+    /// If the `deadline` passes before the operation completes, it is
+    /// aborted with [`ApiError::DeadlineExceeded`].
+    pub(crate) async fn run<'a, T, F, Fut>(
+        &'a self,
+        deadline: Option<Instant>,
+        operation: F,
+    ) -> Result<T, ApiError>
+    where
+        F: FnMut(&'a str) -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), self.failover(operation))
+                .await
+                .unwrap_or(Err(ApiError::DeadlineExceeded)),
+            None => self.failover(operation).await,
+        }
+    }
+
+    async fn failover<'a, T, F, Fut>(&'a self, mut operation: F) -> Result<T, ApiError>
+    where
+        F: FnMut(&'a str) -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        let now = Instant::now();
+        let (healthy, unhealthy): (Vec<&'a Endpoint>, Vec<&'a Endpoint>) = self
+            .endpoints
+            .iter()
+            .partition(|endpoint| endpoint.is_healthy(now));
+
+        let mut last_error = None;
+        for endpoint in healthy.into_iter().chain(unhealthy) {
+            match operation(&endpoint.url).await {
+                Err(e) if is_unreachable(&e) && self.endpoints.len() > 1 => {
+                    warn!("Endpoint {} is unreachable: {}", endpoint.url, e);
+                    *endpoint.unhealthy_until() = Some(Instant::now() + self.cooldown);
+                    last_error = Some(e);
+                }
+                result => {
+                    if result.is_ok() {
+                        *endpoint.unhealthy_until() = None;
+                    }
+                    return result;
+                }
+            }
+        }
+        Err(last_error.expect("Endpoint list is empty"))
+    }
+}
+
+/// Whether the error indicates that the endpoint could not be reached.
+fn is_unreachable(err: &ApiError) -> bool {
+    matches!(err.inner(), ApiError::RequestError(e) if e.is_connect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn connect_error() -> ApiError {
+        reqwest::get("http://127.0.0.1:1").await.unwrap_err().into()
+    }
+
+    fn endpoints() -> Endpoints {
+        Endpoints::new(
+            "https://primary.example.com".into(),
+            vec!["https://fallback.example.com".into()],
+            DEFAULT_ENDPOINT_COOLDOWN,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let endpoints = endpoints();
+        let result = endpoints
+            .run(None, |url| {
+                let url = url.to_string();
+                async move {
+                    if url.contains("primary") {
+                        Err(connect_error().await)
+                    } else {
+                        Ok(url)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), "https://fallback.example.com");
+        assert_eq!(
+            endpoints.status(),
+            vec![
+                EndpointStatus {
+                    url: "https://primary.example.com".into(),
+                    healthy: false,
+                },
+                EndpointStatus {
+                    url: "https://fallback.example.com".into(),
+                    healthy: true,
+                },
+            ]
+        );
+
+        // The unhealthy primary endpoint is tried last
+        let mut tried = Vec::new();
+        endpoints
+            .run(None, |url| {
+                tried.push(url.to_string());
+                async { Ok(()) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(tried, vec!["https://fallback.example.com"]);
+    }
+
+    #[tokio::test]
+    async fn test_no_failover_on_other_errors() {
+        let endpoints = endpoints();
+        let mut tried = 0;
+        let result: Result<(), _> = endpoints
+            .run(None, |_| {
+                tried += 1;
+                async { Err(ApiError::ServerError) }
+            })
+            .await;
+        assert!(matches!(result, Err(ApiError::ServerError)));
+        assert_eq!(tried, 1);
+        assert!(endpoints.status().iter().all(|status| status.healthy));
+    }
+
+    #[tokio::test]
+    async fn test_all_unreachable() {
+        let endpoints = endpoints();
+        let result: Result<(), _> = endpoints
+            .run(None, |_| async { Err(connect_error().await) })
+            .await;
+        assert!(matches!(result, Err(ApiError::RequestError(_))));
+        assert!(endpoints.status().iter().all(|status| !status.healthy));
+
+        // Unhealthy endpoints are still tried as last resort
+        let result = endpoints.run(None, |_| async { Ok(()) }).await;
+        assert!(result.is_ok());
+        assert!(endpoints.status()[0].healthy);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_api_failover() {
+        use crate::{mock_server::MockServer, ApiBuilder};
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint("http://127.0.0.1:1")
+            .allow_insecure_http()
+            .with_fallback_endpoint(server.url())
+            .into_simple()
+            .unwrap();
+        assert_eq!(api.lookup_credits().await.unwrap(), server.credits());
+        let status = api.endpoint_status();
+        assert!(!status[0].healthy);
+        assert!(status[1].healthy);
+    }
+}
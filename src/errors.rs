@@ -5,8 +5,11 @@ use std::io::Error as IoError;
 use reqwest::Error as ReqwestError;
 use thiserror::Error;
 
+use crate::{format::Style, types::RequestId};
+
 /// Errors when interacting with the API.
 #[derive(Debug, Error)]
+#[cfg_attr(feature = "uniffi", derive(uniffi::Error), uniffi(flat_error))]
 pub enum ApiError {
     /// The recipient identity is invalid or the account is not set up for basic mode
     #[error("bad sender or recipient")]
@@ -20,6 +23,11 @@ pub enum ApiError {
     #[error("no credits")]
     NoCredits,
 
+    /// Not enough credits remain for the operation (see
+    /// [`ApiBuilder::with_credits_check`](crate::ApiBuilder::with_credits_check))
+    #[error("insufficient credits: {required} required, {available} available")]
+    InsufficientCredits { required: i64, available: i64 },
+
     /// Target ID not found
     #[error("target ID not found")]
     IdNotFound,
@@ -44,10 +52,44 @@ pub enum ApiError {
     #[error("bad blob ID")]
     BadBlobId,
 
+    /// Invalid message ID
+    #[error("bad message ID")]
+    BadMessageId,
+
+    /// A blob exceeds the configured size limit
+    #[error("blob is too large: {size} bytes, limit is {limit} bytes")]
+    BlobTooLarge { size: u64, limit: u64 },
+
+    /// An incoming message is addressed to another gateway ID (see
+    /// [`ApiBuilder::with_strict_recipient_check`](crate::ApiBuilder::with_strict_recipient_check))
+    #[error("message is addressed to {0}")]
+    UnexpectedRecipient(String),
+
+    /// An incoming callback request body exceeds the size limit
+    #[error("request body is too large: {size} bytes, limit is {limit} bytes")]
+    BodyTooLarge { size: usize, limit: usize },
+
     /// Invalid MAC
     #[error("invalid MAC")]
     InvalidMac,
 
+    /// Encryption or decryption failed
+    #[error("crypto error: {0}")]
+    CryptoError(#[from] CryptoError),
+
+    /// Invalid file message
+    #[error("invalid file message: {0}")]
+    InvalidFileMessage(#[from] FileMessageBuilderError),
+
+    /// A template could not be rendered
+    #[error("invalid template: {0}")]
+    InvalidTemplate(#[from] TemplateError),
+
+    /// The deadline of the call has passed (see
+    /// [`E2eApi::with_deadline`](crate::E2eApi::with_deadline))
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+
     /// Error when sending request (via reqwest)
     #[error("request error: {0}")]
     RequestError(#[source] ReqwestError),
@@ -60,9 +102,84 @@ pub enum ApiError {
     #[error("parse error: {0}")]
     ParseError(String),
 
+    /// A message handler asked for the message to be redelivered later (see
+    /// [`bot::Outcome::Defer`](crate::bot::Outcome::Defer))
+    #[error("processing deferred: {0}")]
+    Deferred(String),
+
+    /// A message with this deduplication key is being sent, or the send was
+    /// interrupted (see [`E2eApi::send_once`](crate::E2eApi::send_once))
+    #[error("send with deduplication key {0} is pending")]
+    SendPending(String),
+
     /// Other
     #[error("other: {0}")]
     Other(String),
+
+    /// An error that occurred while processing the HTTP request with the
+    /// given correlation ID. Use [`ApiError::inner`] to inspect the
+    /// underlying error.
+    ///
+    /// All errors returned by requests to the gateway are wrapped in this
+    /// variant, so match on the inner error to handle specific errors:
+    ///
+    /// ```
+    /// use threema_gateway::errors::ApiError;
+    ///
+    /// fn is_out_of_credits(error: &ApiError) -> bool {
+    ///     matches!(error.inner(), ApiError::NoCredits)
+    /// }
+    /// ```
+    #[error("{error} (request ID {request_id})")]
+    Request {
+        request_id: RequestId,
+        error: Box<ApiError>,
+    },
+}
+
+impl ApiError {
+    /// Return the correlation ID of the failed request, if the error occurred
+    /// while processing an HTTP request.
+    pub fn request_id(&self) -> Option<&RequestId> {
+        match self {
+            ApiError::Request { request_id, .. } => Some(request_id),
+            _ => None,
+        }
+    }
+
+    /// Return the underlying error, without the request context.
+    pub fn inner(&self) -> &ApiError {
+        match self {
+            ApiError::Request { error, .. } => error.inner(),
+            other => other,
+        }
+    }
+
+    /// Convert into the underlying error, without the request context.
+    pub fn into_inner(self) -> ApiError {
+        match self {
+            ApiError::Request { error, .. } => error.into_inner(),
+            other => other,
+        }
+    }
+
+    /// Whether the request may have been processed by the server despite
+    /// the error, e.g. because the connection broke before the response was
+    /// received.
+    pub(crate) fn is_ambiguous(&self) -> bool {
+        matches!(
+            self.inner(),
+            ApiError::RequestError(_) | ApiError::IoError(_) | ApiError::DeadlineExceeded
+        )
+    }
+
+    /// Annotate the error with the correlation ID of the request.
+    pub(crate) fn with_request_id(self, request_id: RequestId) -> ApiError {
+        ApiError::Request {
+            request_id,
+            error: Box::new(self.into_inner()),
+        }
+    }
 }
 
 impl From<ReqwestError> for ApiError {
@@ -80,6 +197,19 @@ pub enum ApiOrCacheError<C: std::error::Error> {
     CacheError(C),
 }
 
+/// Errors when exporting or importing the contents of a
+/// [`PublicKeyCache`](crate::PublicKeyCache).
+#[derive(Debug, Error)]
+pub enum KeyExportError<C: std::error::Error> {
+    /// The cache operation failed
+    #[error("cache error: {0}")]
+    CacheError(C),
+
+    /// The document is not a valid export
+    #[error("invalid export: {0}")]
+    InvalidExport(String),
+}
+
 /// Crypto related errors.
 #[derive(Debug, PartialEq, Clone, Error)]
 pub enum CryptoError {
@@ -106,6 +236,7 @@ pub enum CryptoError {
 
 /// Errors when interacting with the [`ApiBuilder`](../struct.ApiBuilder.html).
 #[derive(Debug, PartialEq, Clone, Error)]
+#[cfg_attr(feature = "uniffi", derive(uniffi::Error), uniffi(flat_error))]
 pub enum ApiBuilderError {
     /// No private key has been set.
     #[error("missing private key")]
@@ -114,6 +245,21 @@ pub enum ApiBuilderError {
     /// Invalid libsodium private key.
     #[error("invalid libsodium private key: {0}")]
     InvalidKey(String),
+
+    /// A plain HTTP endpoint was configured without calling
+    /// [`ApiBuilder::allow_insecure_http`](crate::ApiBuilder::allow_insecure_http).
+    #[error("insecure endpoint (use HTTPS or allow insecure HTTP): {0}")]
+    InsecureEndpoint(String),
+
+    /// The API secret or the private key could not be read from the OS
+    /// keyring.
+    #[error("keyring error: {0}")]
+    Keyring(String),
+
+    /// The HTTP client could not be built (e.g. because the TLS backend
+    /// could not be initialized).
+    #[error("could not build HTTP client: {0}")]
+    Client(String),
 }
 
 /// Errors when interacting with the [`FileMessageBuilder`](../struct.FileMessageBuilder.html).
@@ -122,4 +268,121 @@ pub enum FileMessageBuilderError {
     /// Illegal combination of fields (e.g. setting the `animated` flag on a PDF file message).
     #[error("illegal combination: {0}")]
     IllegalCombination(&'static str),
+    /// The correlation ID is empty or longer than 32 characters.
+    #[error("invalid correlation ID")]
+    InvalidCorrelationId,
+    /// The media type of a sticker does not support transparency (only PNG
+    /// and WebP are allowed).
+    #[error("invalid sticker media type: {0}")]
+    InvalidStickerMediaType(String),
+}
+
+/// Errors when building a text with markup using
+/// [`FormattedText`](crate::format::FormattedText).
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum FormatError {
+    /// A span was opened inside a span of the same style.
+    #[error("nested {0:?} span")]
+    NestedStyle(Style),
+    /// A span was closed that is not the innermost open span.
+    #[error("unexpected end of {0:?} span")]
+    UnexpectedEnd(Style),
+    /// A span is empty or starts or ends with whitespace (the markup would
+    /// not be rendered).
+    #[error("empty or whitespace padded {0:?} span")]
+    InvalidSpan(Style),
+    /// A span was not closed.
+    #[error("unclosed {0:?} span")]
+    UnclosedStyle(Style),
+}
+
+/// Errors when parsing or rendering a [`Template`](crate::template::Template).
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum TemplateError {
+    /// A placeholder starting at the specified byte position is not closed.
+    #[error("unclosed placeholder at position {0}")]
+    UnclosedPlaceholder(usize),
+    /// A closing brace at the specified byte position has no opening brace
+    /// (literal braces must be written as `}}`).
+    #[error("unmatched brace at position {0}")]
+    UnmatchedBrace(usize),
+    /// A variable name is empty or contains invalid characters.
+    #[error("invalid variable name: {0:?}")]
+    InvalidVariableName(String),
+    /// No value was provided for a variable.
+    #[error("missing variable: {0}")]
+    MissingVariable(String),
+    /// The rendered text exceeds the maximum length of a text message.
+    #[error("rendered text is too long: {length} bytes, limit is {limit} bytes")]
+    TooLong { length: usize, limit: usize },
+}
+
+/// Errors when converting the arguments of a bot command (see
+/// [`FromArgs`](crate::bot::FromArgs)).
+#[cfg(feature = "bot")]
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum CommandError {
+    /// The command has too few or too many arguments.
+    #[error("expected {expected} arguments, got {actual}")]
+    WrongArgumentCount { expected: usize, actual: usize },
+    /// An argument could not be converted (the position starts at 1).
+    #[error("invalid argument {position}: {value}")]
+    InvalidArgument { position: usize, value: String },
+}
+
+/// Errors when parsing a [`Recipient`](../enum.Recipient.html).
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum RecipientParseError {
+    /// The Threema ID is invalid.
+    #[error("invalid Threema ID: {0}")]
+    InvalidId(String),
+
+    /// The phone number is invalid.
+    #[error("invalid phone number: {0}")]
+    InvalidPhone(String),
+
+    /// The e-mail address is invalid.
+    #[error("invalid e-mail address: {0}")]
+    InvalidEmail(String),
+}
+
+/// Errors when encoding or decoding a Threema ID backup.
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum IdBackupError {
+    /// The backup string is malformed.
+    #[error("invalid backup format: {0}")]
+    InvalidFormat(String),
+
+    /// The Threema ID is invalid.
+    #[error("invalid Threema ID: {0}")]
+    InvalidId(String),
+
+    /// The password is too short (must be at least 8 characters).
+    #[error("password is too short")]
+    PasswordTooShort,
+
+    /// Decryption failed, most likely because of a wrong password.
+    #[error("decryption failed (wrong password?)")]
+    DecryptionFailed,
+}
+
+/// Errors when loading the configuration from a file.
+#[cfg(feature = "config")]
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// Error when reading the configuration or a referenced file
+    #[error("I/O error: {0}")]
+    IoError(#[from] IoError),
+
+    /// The configuration could not be parsed
+    #[error("parse error: {0}")]
+    Parse(String),
+
+    /// The configuration is invalid
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
+
+    /// The private key is invalid
+    #[error("invalid key: {0}")]
+    InvalidKey(#[from] ApiBuilderError),
 }
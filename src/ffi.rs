@@ -0,0 +1,675 @@
+//! C bindings for the core operations of the E2E API.
+//!
+//! The functions in this module wrap the [blocking](crate::blocking) E2E API
+//! and can be called from C or C++. The corresponding header file is
+//! `include/threema_gateway.h`, it is generated with
+//! [cbindgen](https://github.com/mozilla/cbindgen):
+//!
+//! ```text
+//! cbindgen --config cbindgen.toml --output include/threema_gateway.h
+//! ```
+//!
+//! To build a static or dynamic library, run
+//! `cargo rustc --release --features ffi --crate-type staticlib` (or
+//! `cdylib`).
+//!
+//! All fallible functions return a [`ThreemaStatus`]. If the status is not
+//! [`ThreemaStatus::Ok`], a description of the error can be retrieved with
+//! [`threema_last_error`]. Objects returned through out parameters are owned
+//! by the caller and must be released with the matching `*_free` function.
+//!
+//! Note: The functions must not be called from within a thread that drives a
+//! tokio runtime.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr, slice,
+};
+
+use crate::{
+    api::ApiBuilder,
+    blocking,
+    crypto::{EncryptedMessage, RecipientKey},
+    errors::{ApiBuilderError, ApiError, CryptoError},
+    receive::IncomingMessage,
+    Nonce,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Status codes returned by the C API.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreemaStatus {
+    /// The call succeeded.
+    Ok = 0,
+    /// A null pointer, an invalid UTF-8 string or an invalid key was passed.
+    InvalidArgument = 1,
+    /// The API object could not be built.
+    BuilderError = 2,
+    /// Encryption or decryption failed.
+    CryptoError = 3,
+    /// The request to the gateway failed.
+    ApiError = 4,
+    /// The MAC of an incoming message is invalid.
+    InvalidMac = 5,
+    /// The call panicked.
+    Panic = 6,
+}
+
+/// An error of a C API call.
+struct FfiError(ThreemaStatus, String);
+
+impl FfiError {
+    fn invalid_argument(msg: &str) -> Self {
+        FfiError(ThreemaStatus::InvalidArgument, msg.to_string())
+    }
+}
+
+impl From<ApiBuilderError> for FfiError {
+    fn from(e: ApiBuilderError) -> Self {
+        FfiError(ThreemaStatus::BuilderError, e.to_string())
+    }
+}
+
+impl From<CryptoError> for FfiError {
+    fn from(e: CryptoError) -> Self {
+        FfiError(ThreemaStatus::CryptoError, e.to_string())
+    }
+}
+
+impl From<ApiError> for FfiError {
+    fn from(e: ApiError) -> Self {
+        let status = match e.inner() {
+            ApiError::InvalidMac => ThreemaStatus::InvalidMac,
+            ApiError::CryptoError(_) => ThreemaStatus::CryptoError,
+            _ => ThreemaStatus::ApiError,
+        };
+        FfiError(status, e.to_string())
+    }
+}
+
+/// Run `f`, store the error message (if any) and convert the result into a
+/// status code.
+fn ffi_call(f: impl FnOnce() -> Result<(), FfiError>) -> ThreemaStatus {
+    let (status, msg) = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => (ThreemaStatus::Ok, None),
+        Ok(Err(FfiError(status, msg))) => (status, Some(msg)),
+        Err(_) => (
+            ThreemaStatus::Panic,
+            Some("panic in threema-gateway".to_string()),
+        ),
+    };
+    LAST_ERROR.with(|last_error| {
+        *last_error.borrow_mut() = msg.map(|msg| CString::new(msg.replace('\0', "")).unwrap());
+    });
+    status
+}
+
+/// Convert a C string into a `&str`.
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, FfiError> {
+    if s.is_null() {
+        return Err(FfiError::invalid_argument(&format!("{} is null", name)));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| FfiError::invalid_argument(&format!("{} is not valid UTF-8", name)))
+}
+
+/// Convert a pointer to a buffer into a `&[u8]`.
+unsafe fn bytes_arg<'a>(data: *const u8, len: usize, name: &str) -> Result<&'a [u8], FfiError> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if data.is_null() {
+        return Err(FfiError::invalid_argument(&format!("{} is null", name)));
+    }
+    Ok(slice::from_raw_parts(data, len))
+}
+
+/// Convert a reference to an object into a `&T`.
+unsafe fn ref_arg<'a, T>(obj: *const T, name: &str) -> Result<&'a T, FfiError> {
+    obj.as_ref()
+        .ok_or_else(|| FfiError::invalid_argument(&format!("{} is null", name)))
+}
+
+/// Check that an out parameter is not null.
+///
+/// The memory behind an out parameter may be uninitialized, so no reference
+/// must be created to it. Write the result with [`ptr::write`] instead.
+fn out_arg<T>(out: *mut T, name: &str) -> Result<*mut T, FfiError> {
+    if out.is_null() {
+        return Err(FfiError::invalid_argument(&format!("{} is null", name)));
+    }
+    Ok(out)
+}
+
+/// Read a 32 byte public key.
+unsafe fn key_arg(key: *const u8, name: &str) -> Result<RecipientKey, FfiError> {
+    let bytes = bytes_arg(key, 32, name)?;
+    Ok(RecipientKey::from_bytes(bytes)?)
+}
+
+/// Move a byte vector to the heap and return pointer and length. The buffer
+/// must be released with [`threema_bytes_free`].
+fn into_raw_bytes(data: Vec<u8>) -> (*mut u8, usize) {
+    let len = data.len();
+    (Box::into_raw(data.into_boxed_slice()) as *mut u8, len)
+}
+
+/// Copy a string into a newly allocated C string. The string must be
+/// released with `CString::from_raw`.
+fn into_raw_string(s: &str) -> *mut c_char {
+    CString::new(s.replace('\0', "")).unwrap().into_raw()
+}
+
+/// Encode a message ID as null terminated hex string of 17 bytes.
+fn message_id_buffer(message_id: &str) -> [c_char; 17] {
+    let mut buffer = [0; 17];
+    for (dst, src) in buffer.iter_mut().zip(message_id.bytes().take(16)) {
+        *dst = src as c_char;
+    }
+    buffer
+}
+
+/// Free a C string allocated by this module, if it isn't null.
+unsafe fn free_raw_string(s: &mut *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(*s));
+        *s = ptr::null_mut();
+    }
+}
+
+/// Free a byte buffer allocated by this module, if it isn't null.
+unsafe fn free_raw_bytes(data: &mut *mut u8, len: &mut usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(*data, *len)));
+        *data = ptr::null_mut();
+        *len = 0;
+    }
+}
+
+/// An E2E API object. Create it with [`threema_e2e_api_new`] and free it
+/// with [`threema_e2e_api_free`].
+pub struct ThreemaE2eApi(blocking::E2eApi);
+
+/// An encrypted message.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ThreemaEncryptedMessage {
+    /// The ciphertext.
+    pub ciphertext: *mut u8,
+    /// The length of the ciphertext in bytes.
+    pub ciphertext_len: usize,
+    /// The nonce used for encryption.
+    pub nonce: [u8; 24],
+}
+
+/// An incoming message with a valid MAC. The box is not decrypted yet, see
+/// [`threema_decrypt_incoming`].
+#[repr(C)]
+#[derive(Debug)]
+pub struct ThreemaIncomingMessage {
+    /// Sender identity.
+    pub from: *mut c_char,
+    /// The gateway ID the message is addressed to.
+    pub to: *mut c_char,
+    /// The hex encoded message ID.
+    pub message_id: *mut c_char,
+    /// Message date set by the sender (UNIX timestamp).
+    pub date: u64,
+    /// Public nickname of the sender, or null if not set.
+    pub nickname: *mut c_char,
+    /// The nonce used for encryption.
+    pub nonce: *mut u8,
+    /// The length of the nonce in bytes.
+    pub nonce_len: usize,
+    /// The encrypted message data.
+    pub box_data: *mut u8,
+    /// The length of the encrypted message data in bytes.
+    pub box_len: usize,
+}
+
+/// Return the description of the last error that occurred on the calling
+/// thread, or null if the last call succeeded.
+///
+/// The string is valid until the next call to this library on the same
+/// thread.
+#[no_mangle]
+pub extern "C" fn threema_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |msg| msg.as_ptr())
+    })
+}
+
+/// Create an E2E API object.
+///
+/// `endpoint` may be null to use the default API endpoint. `private_key` is
+/// the hex encoded private key, optionally prefixed with `private:`.
+///
+/// # Safety
+///
+/// All strings must be null terminated. `out` must point to writable memory
+/// for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn threema_e2e_api_new(
+    id: *const c_char,
+    secret: *const c_char,
+    private_key: *const c_char,
+    endpoint: *const c_char,
+    out: *mut *mut ThreemaE2eApi,
+) -> ThreemaStatus {
+    ffi_call(|| {
+        let out = out_arg(out, "out")?;
+        let mut builder = ApiBuilder::new(str_arg(id, "id")?, str_arg(secret, "secret")?)
+            .with_private_key_str(str_arg(private_key, "private_key")?)?;
+        if !endpoint.is_null() {
+            builder = builder.with_custom_endpoint(str_arg(endpoint, "endpoint")?.to_string());
+        }
+        let api = blocking::E2eApi::new(builder.into_e2e()?)?;
+        ptr::write(out, Box::into_raw(Box::new(ThreemaE2eApi(api))));
+        Ok(())
+    })
+}
+
+/// Free an E2E API object. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `api` must have been created with [`threema_e2e_api_new`] and must not
+/// be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn threema_e2e_api_free(api: *mut ThreemaE2eApi) {
+    if !api.is_null() {
+        drop(Box::from_raw(api));
+    }
+}
+
+/// Look up the public key of a Threema ID and write it to `out` (32 bytes).
+///
+/// # Safety
+///
+/// `api` must be a valid API object, `id` a null terminated string and `out`
+/// must point to 32 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn threema_lookup_pubkey(
+    api: *const ThreemaE2eApi,
+    id: *const c_char,
+    out: *mut u8,
+) -> ThreemaStatus {
+    ffi_call(|| {
+        let api = ref_arg(api, "api")?;
+        let id = str_arg(id, "id")?;
+        if out.is_null() {
+            return Err(FfiError::invalid_argument("out is null"));
+        }
+        let key = api.0.lookup_pubkey(id)?;
+        ptr::copy_nonoverlapping(key.0.as_bytes().as_ptr(), out, 32);
+        Ok(())
+    })
+}
+
+/// Encrypt a text message for the recipient with the specified public key
+/// (32 bytes).
+///
+/// The message must be released with [`threema_encrypted_message_free`].
+///
+/// # Safety
+///
+/// `api` must be a valid API object, `text` a null terminated string,
+/// `recipient_key` must point to 32 readable bytes and `out` to a writable
+/// [`ThreemaEncryptedMessage`].
+#[no_mangle]
+pub unsafe extern "C" fn threema_encrypt_text(
+    api: *const ThreemaE2eApi,
+    text: *const c_char,
+    recipient_key: *const u8,
+    out: *mut ThreemaEncryptedMessage,
+) -> ThreemaStatus {
+    ffi_call(|| {
+        let api = ref_arg(api, "api")?;
+        let text = str_arg(text, "text")?;
+        let recipient_key = key_arg(recipient_key, "recipient_key")?;
+        let out = out_arg(out, "out")?;
+        let encrypted = api.0.encrypt_text_msg(text, &recipient_key)?;
+        let (ciphertext, ciphertext_len) = into_raw_bytes(encrypted.ciphertext.to_vec());
+        ptr::write(
+            out,
+            ThreemaEncryptedMessage {
+                ciphertext,
+                ciphertext_len,
+                nonce: encrypted.nonce.into(),
+            },
+        );
+        Ok(())
+    })
+}
+
+/// Free the ciphertext of an encrypted message. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `msg` must have been filled by [`threema_encrypt_text`].
+#[no_mangle]
+pub unsafe extern "C" fn threema_encrypted_message_free(msg: *mut ThreemaEncryptedMessage) {
+    if let Some(msg) = msg.as_mut() {
+        free_raw_bytes(&mut msg.ciphertext, &mut msg.ciphertext_len);
+    }
+}
+
+/// Send an encrypted message and write the hex encoded message ID to
+/// `out_message_id`.
+///
+/// # Safety
+///
+/// `api` must be a valid API object, `to` a null terminated string, `msg` a
+/// valid encrypted message and `out_message_id` must point to 17 writable
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn threema_send(
+    api: *const ThreemaE2eApi,
+    to: *const c_char,
+    msg: *const ThreemaEncryptedMessage,
+    delivery_receipts: bool,
+    out_message_id: *mut [c_char; 17],
+) -> ThreemaStatus {
+    ffi_call(|| {
+        let api = ref_arg(api, "api")?;
+        let to = str_arg(to, "to")?;
+        let msg = ref_arg(msg, "msg")?;
+        let out_message_id = out_arg(out_message_id, "out_message_id")?;
+        let encrypted = EncryptedMessage {
+            ciphertext: bytes_arg(msg.ciphertext, msg.ciphertext_len, "ciphertext")?
+                .to_vec()
+                .into(),
+            nonce: Nonce::from(msg.nonce),
+        };
+        let result = api.0.send(to, &encrypted, delivery_receipts)?;
+        ptr::write(
+            out_message_id,
+            message_id_buffer(&result.message_id.to_string()),
+        );
+        Ok(())
+    })
+}
+
+/// Look up the public key of the recipient, encrypt and send a text message
+/// and write the hex encoded message ID to `out_message_id`.
+///
+/// # Safety
+///
+/// `api` must be a valid API object, `to` and `text` null terminated strings
+/// and `out_message_id` must point to 17 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn threema_send_text(
+    api: *const ThreemaE2eApi,
+    to: *const c_char,
+    text: *const c_char,
+    out_message_id: *mut [c_char; 17],
+) -> ThreemaStatus {
+    ffi_call(|| {
+        let api = ref_arg(api, "api")?;
+        let to = str_arg(to, "to")?;
+        let text = str_arg(text, "text")?;
+        let out_message_id = out_arg(out_message_id, "out_message_id")?;
+        let result = api.0.send_text(to, text)?;
+        ptr::write(
+            out_message_id,
+            message_id_buffer(&result.message_id.to_string()),
+        );
+        Ok(())
+    })
+}
+
+/// Validate the MAC of an incoming callback request body and parse it.
+///
+/// The message must be released with [`threema_incoming_message_free`].
+///
+/// # Safety
+///
+/// `api` must be a valid API object, `body` must point to `body_len`
+/// readable bytes and `out` to a writable [`ThreemaIncomingMessage`].
+#[no_mangle]
+pub unsafe extern "C" fn threema_decode_incoming(
+    api: *const ThreemaE2eApi,
+    body: *const u8,
+    body_len: usize,
+    out: *mut ThreemaIncomingMessage,
+) -> ThreemaStatus {
+    ffi_call(|| {
+        let api = ref_arg(api, "api")?;
+        let body = bytes_arg(body, body_len, "body")?;
+        let out = out_arg(out, "out")?;
+        let msg = api.0.decode_incoming_message(body)?;
+        let (nonce, nonce_len) = into_raw_bytes(msg.nonce);
+        let (box_data, box_len) = into_raw_bytes(msg.box_data);
+        ptr::write(
+            out,
+            ThreemaIncomingMessage {
+                from: into_raw_string(&msg.from),
+                to: into_raw_string(&msg.to),
+                message_id: into_raw_string(&msg.message_id),
+                date: msg.date as u64,
+                nickname: msg
+                    .nickname
+                    .as_deref()
+                    .map_or(ptr::null_mut(), into_raw_string),
+                nonce,
+                nonce_len,
+                box_data,
+                box_len,
+            },
+        );
+        Ok(())
+    })
+}
+
+/// Decrypt the box of an incoming message using the public key of the
+/// sender (32 bytes) and remove the padding.
+///
+/// The decrypted data (starting with the message type byte) must be released
+/// with [`threema_bytes_free`].
+///
+/// # Safety
+///
+/// `api` must be a valid API object, `msg` a message filled by
+/// [`threema_decode_incoming`], `sender_key` must point to 32 readable bytes
+/// and `out_data` and `out_len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn threema_decrypt_incoming(
+    api: *const ThreemaE2eApi,
+    msg: *const ThreemaIncomingMessage,
+    sender_key: *const u8,
+    out_data: *mut *mut u8,
+    out_len: *mut usize,
+) -> ThreemaStatus {
+    ffi_call(|| {
+        let api = ref_arg(api, "api")?;
+        let msg = ref_arg(msg, "msg")?;
+        let sender_key = key_arg(sender_key, "sender_key")?;
+        let out_data = out_arg(out_data, "out_data")?;
+        let out_len = out_arg(out_len, "out_len")?;
+        let incoming = IncomingMessage {
+            from: String::new(),
+            to: String::new(),
+            message_id: String::new(),
+            date: 0,
+            nonce: bytes_arg(msg.nonce, msg.nonce_len, "nonce")?.to_vec(),
+            box_data: bytes_arg(msg.box_data, msg.box_len, "box_data")?.to_vec(),
+            nickname: None,
+        };
+        let data = api.0.decrypt_incoming_message(&incoming, &sender_key)?;
+        let (data, len) = into_raw_bytes(data);
+        ptr::write(out_data, data);
+        ptr::write(out_len, len);
+        Ok(())
+    })
+}
+
+/// Free the fields of an incoming message. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `msg` must have been filled by [`threema_decode_incoming`].
+#[no_mangle]
+pub unsafe extern "C" fn threema_incoming_message_free(msg: *mut ThreemaIncomingMessage) {
+    if let Some(msg) = msg.as_mut() {
+        free_raw_string(&mut msg.from);
+        free_raw_string(&mut msg.to);
+        free_raw_string(&mut msg.message_id);
+        free_raw_string(&mut msg.nickname);
+        free_raw_bytes(&mut msg.nonce, &mut msg.nonce_len);
+        free_raw_bytes(&mut msg.box_data, &mut msg.box_len);
+    }
+}
+
+/// Free a byte buffer returned by this library. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `data` and `len` must have been returned by this library.
+#[no_mangle]
+pub unsafe extern "C" fn threema_bytes_free(data: *mut u8, len: usize) {
+    let (mut data, mut len) = (data, len);
+    free_raw_bytes(&mut data, &mut len);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::MaybeUninit;
+
+    use super::*;
+    use crate::test_support;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(threema_last_error()) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn new_api() -> *mut ThreemaE2eApi {
+        let mut api = ptr::null_mut();
+        let status = unsafe {
+            threema_e2e_api_new(
+                c(test_support::GATEWAY_ID).as_ptr(),
+                c(test_support::API_SECRET).as_ptr(),
+                c(test_support::GATEWAY_PRIVATE_KEY).as_ptr(),
+                ptr::null(),
+                &mut api,
+            )
+        };
+        assert_eq!(status, ThreemaStatus::Ok);
+        assert!(threema_last_error().is_null());
+        api
+    }
+
+    #[test]
+    fn test_new_invalid() {
+        let mut api = ptr::null_mut();
+        let status = unsafe {
+            threema_e2e_api_new(
+                c("*3MAGWID").as_ptr(),
+                ptr::null(),
+                c("abc").as_ptr(),
+                ptr::null(),
+                &mut api,
+            )
+        };
+        assert_eq!(status, ThreemaStatus::InvalidArgument);
+        assert_eq!(last_error(), "secret is null");
+        assert!(api.is_null());
+
+        let status = unsafe {
+            threema_e2e_api_new(
+                c("*3MAGWID").as_ptr(),
+                c("secret").as_ptr(),
+                c("abc").as_ptr(),
+                ptr::null(),
+                &mut api,
+            )
+        };
+        assert_eq!(status, ThreemaStatus::BuilderError);
+        assert!(api.is_null());
+    }
+
+    #[test]
+    fn test_encrypt_decode_decrypt() {
+        let api = new_api();
+        let recipient_key = test_support::recipient_public_key();
+
+        // Encrypt
+        let mut encrypted = MaybeUninit::<ThreemaEncryptedMessage>::uninit();
+        let status = unsafe {
+            threema_encrypt_text(
+                api,
+                c("Hello").as_ptr(),
+                recipient_key.as_bytes().as_ptr(),
+                encrypted.as_mut_ptr(),
+            )
+        };
+        assert_eq!(status, ThreemaStatus::Ok);
+        let mut encrypted = unsafe { encrypted.assume_init() };
+        assert!(encrypted.ciphertext_len > 16);
+        unsafe { threema_encrypted_message_free(&mut encrypted) };
+        assert!(encrypted.ciphertext.is_null());
+
+        // Decode
+        let body = test_support::CALLBACK_BODY;
+        let mut msg = MaybeUninit::<ThreemaIncomingMessage>::uninit();
+        let status =
+            unsafe { threema_decode_incoming(api, body.as_ptr(), body.len(), msg.as_mut_ptr()) };
+        assert_eq!(status, ThreemaStatus::Ok);
+        let mut msg = unsafe { msg.assume_init() };
+        assert_eq!(
+            unsafe { CStr::from_ptr(msg.from) }.to_str().unwrap(),
+            test_support::RECIPIENT_ID
+        );
+        assert_eq!(msg.date, test_support::CALLBACK_DATE);
+        assert!(msg.nickname.is_null());
+
+        // Decrypt
+        let (mut data, mut len) = (ptr::null_mut(), 0);
+        let status = unsafe {
+            threema_decrypt_incoming(
+                api,
+                &msg,
+                recipient_key.as_bytes().as_ptr(),
+                &mut data,
+                &mut len,
+            )
+        };
+        assert_eq!(status, ThreemaStatus::Ok);
+        let decrypted = unsafe { slice::from_raw_parts(data, len) };
+        assert_eq!(&decrypted[1..], test_support::TEXT_MESSAGE.as_bytes());
+
+        unsafe {
+            threema_bytes_free(data, len);
+            threema_incoming_message_free(&mut msg);
+            threema_e2e_api_free(api);
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid_mac() {
+        let api = new_api();
+        let body = test_support::CALLBACK_BODY.replace("mac=b", "mac=c");
+        let mut msg = MaybeUninit::<ThreemaIncomingMessage>::uninit();
+        let status =
+            unsafe { threema_decode_incoming(api, body.as_ptr(), body.len(), msg.as_mut_ptr()) };
+        assert_eq!(status, ThreemaStatus::InvalidMac);
+        assert_eq!(last_error(), "invalid MAC");
+        unsafe { threema_e2e_api_free(api) };
+    }
+}
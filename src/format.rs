@@ -0,0 +1,221 @@
+//! Construction of text messages with Threema markup.
+//!
+//! The Threema apps render text between `*` as bold, between `_` as italic
+//! and between `~` as strikethrough. [`FormattedText`] builds such texts from
+//! user-provided content, which is [escaped](escape) so that it cannot
+//! introduce markup of its own, and checks that the styles are properly
+//! nested.
+//!
+//! ```
+//! use threema_gateway::format::{FormattedText, Style};
+//!
+//! let text = FormattedText::new()
+//!     .text("Order ")
+//!     .bold("#1234")?
+//!     .text(" for ")
+//!     .start(Style::Italic)?
+//!     .text("*Bob*")
+//!     .end(Style::Italic)?
+//!     .build()?;
+//! assert_eq!(
+//!     text,
+//!     "Order *#1234* for _\u{200b}*\u{200b}Bob\u{200b}*\u{200b}_"
+//! );
+//! # Ok::<(), threema_gateway::errors::FormatError>(())
+//! ```
+//!
+//! Note that the apps only render markup if the markers are separated from
+//! the surrounding words, e.g. by whitespace or punctuation.
+
+use crate::errors::FormatError;
+
+/// The markup has no escape syntax. Instead, markers in user-provided text
+/// are surrounded by this invisible character, which prevents them from
+/// being recognized as start or end of a styled span.
+const ZERO_WIDTH_SPACE: char = '\u{200b}';
+
+/// A text style of the Threema markup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// Bold text (`*bold*`).
+    Bold,
+    /// Italic text (`_italic_`).
+    Italic,
+    /// Strikethrough text (`~strikethrough~`).
+    Strikethrough,
+}
+
+impl Style {
+    /// Return the marker character of this style.
+    pub fn marker(self) -> char {
+        match self {
+            Style::Bold => '*',
+            Style::Italic => '_',
+            Style::Strikethrough => '~',
+        }
+    }
+}
+
+/// Escape the markup characters in `text`, so that it is displayed
+/// literally.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '_' | '~') {
+            escaped.push(ZERO_WIDTH_SPACE);
+            escaped.push(c);
+            escaped.push(ZERO_WIDTH_SPACE);
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Builder for a text with Threema markup.
+///
+/// Text added with [`text`](Self::text) is escaped. Styled spans are opened
+/// with [`start`](Self::start) and closed with [`end`](Self::end) (or added
+/// in one go with [`styled`](Self::styled)). A span may not be empty, may not
+/// start or end with whitespace and may not contain another span of the same
+/// style. Spans must be closed in reverse order of opening.
+#[derive(Debug, Clone, Default)]
+pub struct FormattedText {
+    text: String,
+    /// The open spans and the position of their content in `text`.
+    open: Vec<(Style, usize)>,
+}
+
+impl FormattedText {
+    /// Create an empty text.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append plain text. Markup characters are escaped.
+    pub fn text(mut self, text: &str) -> Self {
+        self.text.push_str(&escape(text));
+        self
+    }
+
+    /// Open a span with the specified style.
+    pub fn start(mut self, style: Style) -> Result<Self, FormatError> {
+        if self.open.iter().any(|(open, _)| *open == style) {
+            return Err(FormatError::NestedStyle(style));
+        }
+        self.text.push(style.marker());
+        self.open.push((style, self.text.len()));
+        Ok(self)
+    }
+
+    /// Close the innermost span, which must have the specified style.
+    pub fn end(mut self, style: Style) -> Result<Self, FormatError> {
+        let content_start = match self.open.last() {
+            Some((open, content_start)) if *open == style => *content_start,
+            _ => return Err(FormatError::UnexpectedEnd(style)),
+        };
+        let content = &self.text[content_start..];
+        if content.is_empty()
+            || content.starts_with(char::is_whitespace)
+            || content.ends_with(char::is_whitespace)
+        {
+            return Err(FormatError::InvalidSpan(style));
+        }
+        self.open.pop();
+        self.text.push(style.marker());
+        Ok(self)
+    }
+
+    /// Append a span with the specified style containing `text`.
+    pub fn styled(self, style: Style, text: &str) -> Result<Self, FormatError> {
+        self.start(style)?.text(text).end(style)
+    }
+
+    /// Append bold text.
+    pub fn bold(self, text: &str) -> Result<Self, FormatError> {
+        self.styled(Style::Bold, text)
+    }
+
+    /// Append italic text.
+    pub fn italic(self, text: &str) -> Result<Self, FormatError> {
+        self.styled(Style::Italic, text)
+    }
+
+    /// Append strikethrough text.
+    pub fn strikethrough(self, text: &str) -> Result<Self, FormatError> {
+        self.styled(Style::Strikethrough, text)
+    }
+
+    /// Return the text with markup. Fails if a span is still open.
+    pub fn build(self) -> Result<String, FormatError> {
+        match self.open.last() {
+            Some((style, _)) => Err(FormatError::UnclosedStyle(*style)),
+            None => Ok(self.text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested() {
+        let text = FormattedText::new()
+            .text("Hello ")
+            .start(Style::Bold)
+            .and_then(|t| t.text("dear ").italic("world"))
+            .and_then(|t| t.end(Style::Bold))
+            .and_then(|t| t.text("! ").strikethrough("Bye"))
+            .and_then(FormattedText::build);
+        assert_eq!(text.unwrap(), "Hello *dear _world_*! ~Bye~");
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("no markup"), "no markup");
+        assert_eq!(escape("a_b"), "a\u{200b}_\u{200b}b");
+        let text = FormattedText::new()
+            .bold("*")
+            .and_then(FormattedText::build);
+        assert_eq!(text.unwrap(), "*\u{200b}*\u{200b}*");
+    }
+
+    #[test]
+    fn test_invalid_nesting() {
+        let same_style = FormattedText::new()
+            .start(Style::Bold)
+            .and_then(|t| t.bold("x"));
+        assert_eq!(
+            same_style.unwrap_err(),
+            FormatError::NestedStyle(Style::Bold)
+        );
+
+        let crossed = FormattedText::new()
+            .start(Style::Bold)
+            .and_then(|t| t.start(Style::Italic))
+            .and_then(|t| t.text("x").end(Style::Bold));
+        assert_eq!(
+            crossed.unwrap_err(),
+            FormatError::UnexpectedEnd(Style::Bold)
+        );
+
+        let unclosed = FormattedText::new()
+            .start(Style::Strikethrough)
+            .and_then(FormattedText::build);
+        assert_eq!(
+            unclosed.unwrap_err(),
+            FormatError::UnclosedStyle(Style::Strikethrough)
+        );
+    }
+
+    #[test]
+    fn test_invalid_span() {
+        for content in ["", " x", "x "] {
+            assert_eq!(
+                FormattedText::new().italic(content).unwrap_err(),
+                FormatError::InvalidSpan(Style::Italic)
+            );
+        }
+    }
+}
@@ -0,0 +1,500 @@
+//! Traits abstracting over the API objects.
+//!
+//! Application code can depend on these traits instead of the concrete
+//! [`SimpleApi`] and [`E2eApi`] types, which makes it possible to substitute
+//! a mock implementation in tests that don't have network access.
+//!
+//! ```
+//! use threema_gateway::{errors::ApiError, E2eGateway, RecipientKey, SendResult};
+//!
+//! async fn notify(api: &impl E2eGateway, to: &str, key: &RecipientKey) -> Result<SendResult, ApiError> {
+//!     let msg = api.encrypt_text_msg("Backup finished", key)?;
+//!     api.send(to, &msg, false).await
+//! }
+//! ```
+
+use std::future::Future;
+
+use bytes::Bytes;
+
+use crate::{
+    api::{E2eApi, SimpleApi},
+    connection::Recipient,
+    crypto::{encrypt_file_data, EncryptedMessage, FileData, RecipientKey},
+    errors::{ApiError, CryptoError},
+    lookup::{Capabilities, LookupCriterion},
+    types::{
+        BlobId, FileMessage, FileSendOptions, FileSource, MessageType, RenderingType, SendOptions,
+        SendResult,
+    },
+    Nonce,
+};
+
+/// Lookup operations available in both the simple and the e2e mode.
+pub trait LookupGateway {
+    /// See [`E2eApi::lookup_pubkey`].
+    fn lookup_pubkey(
+        &self,
+        id: &str,
+    ) -> impl Future<Output = Result<RecipientKey, ApiError>> + Send;
+
+    /// See [`E2eApi::lookup_id`].
+    fn lookup_id(
+        &self,
+        criterion: &LookupCriterion,
+    ) -> impl Future<Output = Result<String, ApiError>> + Send;
+
+    /// See [`E2eApi::lookup_capabilities`].
+    fn lookup_capabilities(
+        &self,
+        id: &str,
+    ) -> impl Future<Output = Result<Capabilities, ApiError>> + Send;
+
+    /// See [`E2eApi::lookup_credits`].
+    fn lookup_credits(&self) -> impl Future<Output = Result<i64, ApiError>> + Send;
+}
+
+/// Operations of the simple (basic mode) API, implemented by [`SimpleApi`].
+pub trait SimpleGateway: LookupGateway {
+    /// See [`SimpleApi::send`].
+    fn send(
+        &self,
+        to: &Recipient<'_>,
+        text: &str,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send;
+}
+
+/// Operations of the end-to-end encrypted API, implemented by [`E2eApi`].
+pub trait E2eGateway: LookupGateway {
+    /// See [`E2eApi::encrypt_text_msg`].
+    fn encrypt_text_msg(
+        &self,
+        text: &str,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError>;
+
+    /// See [`E2eApi::encrypt_image_msg`].
+    fn encrypt_image_msg(
+        &self,
+        blob_id: &BlobId,
+        img_size_bytes: u32,
+        image_data_nonce: &Nonce,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError>;
+
+    /// See [`E2eApi::encrypt_file_msg`].
+    fn encrypt_file_msg(
+        &self,
+        msg: &FileMessage,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError>;
+
+    /// See [`E2eApi::encrypt`].
+    fn encrypt(
+        &self,
+        raw_data: &[u8],
+        msgtype: MessageType,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError>;
+
+    /// See [`E2eApi::encrypt_raw`].
+    fn encrypt_raw(
+        &self,
+        raw_data: &[u8],
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError>;
+
+    /// See [`E2eApi::send`].
+    fn send(
+        &self,
+        to: &str,
+        message: &EncryptedMessage,
+        delivery_receipts: bool,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send;
+
+    /// See [`E2eApi::send_with_options`].
+    fn send_with_options(
+        &self,
+        to: &str,
+        message: &EncryptedMessage,
+        options: SendOptions,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send;
+
+    /// See [`E2eApi::send_text`].
+    fn send_text(
+        &self,
+        to: &str,
+        text: &str,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send;
+
+    /// See [`E2eApi::blob_upload`].
+    fn blob_upload(
+        &self,
+        data: &EncryptedMessage,
+        persist: bool,
+    ) -> impl Future<Output = Result<BlobId, ApiError>> + Send;
+
+    /// See [`E2eApi::blob_upload_raw`].
+    fn blob_upload_raw(
+        &self,
+        data: &[u8],
+        persist: bool,
+    ) -> impl Future<Output = Result<BlobId, ApiError>> + Send;
+
+    /// See [`E2eApi::blob_upload_bytes`].
+    fn blob_upload_bytes(
+        &self,
+        data: Bytes,
+        persist: bool,
+    ) -> impl Future<Output = Result<BlobId, ApiError>> + Send;
+
+    /// See [`E2eApi::blob_download`].
+    fn blob_download(
+        &self,
+        blob_id: &BlobId,
+    ) -> impl Future<Output = Result<Vec<u8>, ApiError>> + Send;
+
+    /// See [`E2eApi::send_file`].
+    fn send_file(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        file: FileSource,
+        options: FileSendOptions,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send;
+
+    /// See [`E2eApi::send_image`].
+    fn send_image(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        jpeg_data: Vec<u8>,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send;
+}
+
+/// Implement [`LookupGateway`] by delegating to the inherent methods.
+macro_rules! impl_lookup_gateway {
+    ($api:ty) => {
+        impl LookupGateway for $api {
+            fn lookup_pubkey(
+                &self,
+                id: &str,
+            ) -> impl Future<Output = Result<RecipientKey, ApiError>> + Send {
+                <$api>::lookup_pubkey(self, id)
+            }
+
+            fn lookup_id(
+                &self,
+                criterion: &LookupCriterion,
+            ) -> impl Future<Output = Result<String, ApiError>> + Send {
+                <$api>::lookup_id(self, criterion)
+            }
+
+            fn lookup_capabilities(
+                &self,
+                id: &str,
+            ) -> impl Future<Output = Result<Capabilities, ApiError>> + Send {
+                <$api>::lookup_capabilities(self, id)
+            }
+
+            fn lookup_credits(&self) -> impl Future<Output = Result<i64, ApiError>> + Send {
+                <$api>::lookup_credits(self)
+            }
+        }
+    };
+}
+
+impl_lookup_gateway!(SimpleApi);
+impl_lookup_gateway!(E2eApi);
+
+impl SimpleGateway for SimpleApi {
+    fn send(
+        &self,
+        to: &Recipient<'_>,
+        text: &str,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send {
+        SimpleApi::send(self, to, text)
+    }
+}
+
+impl E2eGateway for E2eApi {
+    fn encrypt_text_msg(
+        &self,
+        text: &str,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        E2eApi::encrypt_text_msg(self, text, recipient_key)
+    }
+
+    fn encrypt_image_msg(
+        &self,
+        blob_id: &BlobId,
+        img_size_bytes: u32,
+        image_data_nonce: &Nonce,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        E2eApi::encrypt_image_msg(
+            self,
+            blob_id,
+            img_size_bytes,
+            image_data_nonce,
+            recipient_key,
+        )
+    }
+
+    fn encrypt_file_msg(
+        &self,
+        msg: &FileMessage,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        E2eApi::encrypt_file_msg(self, msg, recipient_key)
+    }
+
+    fn encrypt(
+        &self,
+        raw_data: &[u8],
+        msgtype: MessageType,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        E2eApi::encrypt(self, raw_data, msgtype, recipient_key)
+    }
+
+    fn encrypt_raw(
+        &self,
+        raw_data: &[u8],
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        E2eApi::encrypt_raw(self, raw_data, recipient_key)
+    }
+
+    fn send(
+        &self,
+        to: &str,
+        message: &EncryptedMessage,
+        delivery_receipts: bool,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send {
+        E2eApi::send(self, to, message, delivery_receipts)
+    }
+
+    fn send_with_options(
+        &self,
+        to: &str,
+        message: &EncryptedMessage,
+        options: SendOptions,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send {
+        E2eApi::send_with_options(self, to, message, options)
+    }
+
+    fn send_text(
+        &self,
+        to: &str,
+        text: &str,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send {
+        E2eApi::send_text(self, to, text)
+    }
+
+    fn blob_upload(
+        &self,
+        data: &EncryptedMessage,
+        persist: bool,
+    ) -> impl Future<Output = Result<BlobId, ApiError>> + Send {
+        E2eApi::blob_upload(self, data, persist)
+    }
+
+    fn blob_upload_raw(
+        &self,
+        data: &[u8],
+        persist: bool,
+    ) -> impl Future<Output = Result<BlobId, ApiError>> + Send {
+        E2eApi::blob_upload_raw(self, data, persist)
+    }
+
+    fn blob_upload_bytes(
+        &self,
+        data: Bytes,
+        persist: bool,
+    ) -> impl Future<Output = Result<BlobId, ApiError>> + Send {
+        E2eApi::blob_upload_bytes(self, data, persist)
+    }
+
+    fn blob_download(
+        &self,
+        blob_id: &BlobId,
+    ) -> impl Future<Output = Result<Vec<u8>, ApiError>> + Send {
+        E2eApi::blob_download(self, blob_id)
+    }
+
+    fn send_file(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        file: FileSource,
+        options: FileSendOptions,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send {
+        E2eApi::send_file(self, to, recipient_key, file, options)
+    }
+
+    fn send_image(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        jpeg_data: Vec<u8>,
+    ) -> impl Future<Output = Result<SendResult, ApiError>> + Send {
+        E2eApi::send_image(self, to, recipient_key, jpeg_data)
+    }
+}
+
+/// Blob uploads for [`send_file`] and [`send_image`].
+///
+/// Unlike [`E2eGateway::blob_upload_bytes`], this must not do a credits
+/// pre-check, which the callers already did for the whole operation.
+pub(crate) trait BlobUploader {
+    fn upload_blob(
+        &self,
+        data: Bytes,
+        persist: bool,
+    ) -> impl Future<Output = Result<BlobId, ApiError>> + Send;
+}
+
+impl BlobUploader for E2eApi {
+    async fn upload_blob(&self, data: Bytes, persist: bool) -> Result<BlobId, ApiError> {
+        E2eApi::upload_blob(self, &data, persist, None).await
+    }
+}
+
+/// Encrypt, upload and send a file. See [`E2eApi::send_file`].
+pub(crate) async fn send_file<G: E2eGateway + BlobUploader + ?Sized>(
+    api: &G,
+    to: &str,
+    recipient_key: &RecipientKey,
+    file: FileSource,
+    mut options: FileSendOptions,
+) -> Result<SendResult, ApiError> {
+    let data = file.resolve(&mut options)?;
+    let file_size_bytes = u32::try_from(data.file.len()).map_err(|_| ApiError::MessageTooLong)?;
+
+    // Encrypt and upload file data
+    let (encrypted, key) = encrypt_file_data(&data)?;
+    let file_blob_id = api
+        .upload_blob(encrypted.file.into(), options.persist)
+        .await?;
+    let thumbnail = match encrypted.thumbnail {
+        Some(thumbnail) => {
+            let blob_id = api.upload_blob(thumbnail.into(), options.persist).await?;
+            let media_type = options
+                .thumbnail_media_type
+                .unwrap_or_else(|| "image/jpeg".to_string());
+            Some((blob_id, media_type))
+        }
+        None => None,
+    };
+
+    // Build and encrypt file message
+    let media_type = options
+        .media_type
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    let msg = FileMessage::builder(file_blob_id, key, media_type, file_size_bytes)
+        .thumbnail_opt(thumbnail)
+        .file_name_opt(options.file_name)
+        .description_opt(options.description)
+        .correlation_id_opt(options.correlation_id)
+        .rendering_type(options.rendering_type);
+    #[cfg(feature = "media-duration")]
+    let msg = match options.rendering_type {
+        RenderingType::Media => msg.duration_from_data(&data.file),
+        _ => msg,
+    };
+    #[cfg(feature = "image")]
+    let msg = match options.rendering_type {
+        RenderingType::Media | RenderingType::Sticker => msg.animated_from_data(&data.file),
+        RenderingType::File => msg,
+    };
+    let msg = msg.build()?;
+    let encrypted = api.encrypt_file_msg(&msg, recipient_key)?;
+
+    // Send
+    api.send(to, &encrypted, options.delivery_receipts).await
+}
+
+/// Encrypt, upload and send a JPEG image. See [`E2eApi::send_image`].
+///
+/// The capabilities of the recipient are looked up unless passed in.
+pub(crate) async fn send_image<G: E2eGateway + BlobUploader + ?Sized>(
+    api: &G,
+    to: &str,
+    recipient_key: &RecipientKey,
+    jpeg_data: Vec<u8>,
+    capabilities: Option<&Capabilities>,
+) -> Result<SendResult, ApiError> {
+    let supports_files = match capabilities {
+        Some(capabilities) => capabilities.file,
+        None => api.lookup_capabilities(to).await?.file,
+    };
+    if supports_files {
+        let data = FileData {
+            file: jpeg_data,
+            thumbnail: None,
+        };
+        let options = FileSendOptions {
+            media_type: Some("image/jpeg".to_string()),
+            rendering_type: RenderingType::Media,
+            ..Default::default()
+        };
+        return send_file(api, to, recipient_key, data.into(), options).await;
+    }
+
+    // Fall back to a legacy image message
+    let img_size_bytes = u32::try_from(jpeg_data.len()).map_err(|_| ApiError::MessageTooLong)?;
+    let encrypted_image = api.encrypt_raw(&jpeg_data, recipient_key)?;
+    let blob_id = api
+        .upload_blob(encrypted_image.ciphertext.clone(), false)
+        .await?;
+    let msg = api.encrypt_image_msg(
+        &blob_id,
+        img_size_bytes,
+        &encrypted_image.nonce,
+        recipient_key,
+    )?;
+    api.send(to, &msg, false).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ApiBuilder, SecretKey};
+
+    async fn send_text(
+        api: &impl SimpleGateway,
+        to: &str,
+        text: &str,
+    ) -> Result<SendResult, ApiError> {
+        api.send(&Recipient::new_id(to), text).await
+    }
+
+    #[tokio::test]
+    async fn test_simple_gateway() {
+        let api = ApiBuilder::new("*3MAGWID", "1234").into_simple().unwrap();
+        let result = send_text(&api, "ECHOECHO", &"x".repeat(3501)).await;
+        assert!(matches!(result, Err(ApiError::MessageTooLong)));
+    }
+
+    #[test]
+    fn test_e2e_gateway() {
+        fn assert_send<T: Send>(_: T) {}
+
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap();
+        let key = RecipientKey::from([2; 32]);
+        let msg = E2eGateway::encrypt_text_msg(&api, "hello", &key).unwrap();
+        assert_send(E2eGateway::send(&api, "ECHOECHO", &msg, false));
+        assert_send(E2eGateway::send_file(
+            &api,
+            "ECHOECHO",
+            &key,
+            FileSource::Path("/nonexistent".into()),
+            FileSendOptions::default(),
+        ));
+    }
+}
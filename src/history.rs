@@ -0,0 +1,251 @@
+//! Per-contact conversation history.
+//!
+//! A [`MessageStore`] records incoming and outgoing messages, so that bots
+//! can query the recent conversation with a contact. If a store is configured
+//! with [`ApiBuilder::with_message_store`](crate::ApiBuilder::with_message_store),
+//! messages sent with [`E2eApi::send_text`](crate::E2eApi::send_text) are
+//! recorded automatically.
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use threema_gateway::{history::MemoryMessageStore, ApiBuilder, SecretKey};
+//!
+//! let store = Arc::new(MemoryMessageStore::default());
+//! let api = ApiBuilder::new("*3MAGWID", "hihghrg98h00ghrg")
+//!     .with_private_key(SecretKey::from([1; 32]))
+//!     .with_message_store(store.clone())
+//!     .into_e2e()
+//!     .unwrap();
+//! ```
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use crate::{
+    store::shared_store,
+    types::{MessageId, MessageType},
+};
+
+/// Whether a message was received or sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// The message was received from the contact.
+    Incoming,
+    /// The message was sent to the contact.
+    Outgoing,
+}
+
+/// A message in the conversation history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredMessage {
+    /// The Threema ID of the contact.
+    pub contact: String,
+    /// Whether the message was received or sent.
+    pub direction: Direction,
+    /// The message ID, if known.
+    pub message_id: Option<MessageId>,
+    /// The message type.
+    pub msgtype: MessageType,
+    /// The decrypted message data (without the message type byte).
+    pub data: Vec<u8>,
+    /// When the message was received or sent.
+    pub timestamp: SystemTime,
+}
+
+impl StoredMessage {
+    /// Create a text message with the current time as timestamp.
+    pub fn text_message(
+        contact: impl Into<String>,
+        direction: Direction,
+        message_id: Option<MessageId>,
+        text: &str,
+    ) -> Self {
+        StoredMessage {
+            contact: contact.into(),
+            direction,
+            message_id,
+            msgtype: MessageType::Text,
+            data: text.as_bytes().to_vec(),
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    /// Return the text of a text message.
+    ///
+    /// Return `None` for other message types or if the text is not valid
+    /// UTF-8.
+    pub fn text(&self) -> Option<&str> {
+        match self.msgtype {
+            MessageType::Text | MessageType::GroupText => std::str::from_utf8(&self.data).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Storage for the conversation history.
+pub trait MessageStore: Send + Sync {
+    /// Error returned if store operations fail
+    type Error: Error + Send + Sync + 'static;
+
+    /// Append a message to the history of its contact.
+    fn append(
+        &self,
+        message: &StoredMessage,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Return the last `limit` messages exchanged with `contact`, oldest
+    /// first.
+    fn history(
+        &self,
+        contact: &str,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<StoredMessage>, Self::Error>> + Send;
+}
+
+impl<S: MessageStore + ?Sized> MessageStore for Arc<S> {
+    type Error = S::Error;
+
+    fn append(
+        &self,
+        message: &StoredMessage,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        (**self).append(message)
+    }
+
+    fn history(
+        &self,
+        contact: &str,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<StoredMessage>, Self::Error>> + Send {
+        (**self).history(contact, limit)
+    }
+}
+
+/// A [`MessageStore`] that keeps the history in memory.
+#[derive(Debug, Default)]
+pub struct MemoryMessageStore {
+    conversations: Mutex<HashMap<String, Vec<StoredMessage>>>,
+}
+
+impl MemoryMessageStore {
+    fn conversations(&self) -> MutexGuard<'_, HashMap<String, Vec<StoredMessage>>> {
+        self.conversations
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MessageStore for MemoryMessageStore {
+    type Error = Infallible;
+
+    async fn append(&self, message: &StoredMessage) -> Result<(), Self::Error> {
+        self.conversations()
+            .entry(message.contact.clone())
+            .or_default()
+            .push(message.clone());
+        Ok(())
+    }
+
+    async fn history(
+        &self,
+        contact: &str,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, Self::Error> {
+        Ok(self
+            .conversations()
+            .get(contact)
+            .map(|messages| messages[messages.len().saturating_sub(limit)..].to_vec())
+            .unwrap_or_default())
+    }
+}
+
+shared_store! {
+    /// A type erased [`MessageStore`] that is shared between clones of the API
+    /// object.
+    SharedMessageStore(DynMessageStore): MessageStore {
+        fn append(message: &StoredMessage) -> ();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_store_history() {
+        let store = MemoryMessageStore::default();
+        for (i, text) in ["one", "two", "three"].iter().enumerate() {
+            let direction = if i % 2 == 0 {
+                Direction::Incoming
+            } else {
+                Direction::Outgoing
+            };
+            store
+                .append(&StoredMessage::text_message(
+                    "ECHOECHO", direction, None, text,
+                ))
+                .await
+                .unwrap();
+        }
+        store
+            .append(&StoredMessage::text_message(
+                "OTHER123",
+                Direction::Incoming,
+                None,
+                "other",
+            ))
+            .await
+            .unwrap();
+
+        let history = store.history("ECHOECHO", 2).await.unwrap();
+        let texts: Vec<_> = history.iter().filter_map(StoredMessage::text).collect();
+        assert_eq!(texts, vec!["two", "three"]);
+        assert_eq!(history[0].direction, Direction::Outgoing);
+        assert_eq!(store.history("ECHOECHO", 10).await.unwrap().len(), 3);
+        assert!(store.history("UNKNOWN1", 10).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_text() {
+        let mut message = StoredMessage::text_message("ECHOECHO", Direction::Incoming, None, "hi");
+        assert_eq!(message.text(), Some("hi"));
+        message.msgtype = MessageType::File;
+        assert_eq!(message.text(), None);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_message_store() {
+        use crate::{mock_server::MockServer, ApiBuilder, RecipientKey, SecretKey};
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_public_key("ECHOECHO", RecipientKey::from([2; 32]));
+        let store = Arc::new(MemoryMessageStore::default());
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .with_message_store(store.clone())
+            .into_e2e()
+            .unwrap();
+
+        let result = api.send_text("ECHOECHO", "hello").await.unwrap();
+        let history = store.history("ECHOECHO", 10).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].direction, Direction::Outgoing);
+        assert_eq!(history[0].message_id, Some(result.message_id));
+        assert_eq!(history[0].text(), Some("hello"));
+
+        // Failed messages are not recorded
+        server.fail_next(500);
+        assert!(api.send_text("ECHOECHO", "again").await.is_err());
+        assert_eq!(store.history("ECHOECHO", 10).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,240 @@
+//! Callbacks for lifecycle events of the API objects.
+
+use std::{borrow::Cow, fmt, sync::Arc};
+
+use reqwest::{header::HeaderMap, StatusCode};
+
+use crate::{
+    audit::{AuditOperation, Auditor},
+    connection::Recipient,
+    errors::ApiError,
+    reporting::{ErrorContext, ErrorReporter},
+    types::{BlobId, MessageType, SendResult},
+};
+
+type SendHook = Arc<dyn Fn(&Recipient<'_>, &SendResult) + Send + Sync>;
+type ErrorHook = Arc<dyn Fn(&Recipient<'_>, &ApiError) + Send + Sync>;
+type BlobUploadHook = Arc<dyn Fn(&BlobId) + Send + Sync>;
+type ResponseHook = Arc<dyn Fn(StatusCode, &HeaderMap) + Send + Sync>;
+type TextTransform = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// The callbacks registered on an API object.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pub(crate) on_send: Vec<SendHook>,
+    pub(crate) on_error: Vec<ErrorHook>,
+    pub(crate) on_blob_upload: Vec<BlobUploadHook>,
+    pub(crate) on_response: Vec<ResponseHook>,
+    pub(crate) text_transforms: Vec<TextTransform>,
+    pub(crate) audit: Option<Auditor>,
+    pub(crate) error_reporter: Option<Arc<dyn ErrorReporter>>,
+}
+
+impl Hooks {
+    /// Notify the callbacks about the result of sending a message to `to`.
+    pub(crate) fn send_finished(&self, to: &Recipient<'_>, result: &Result<SendResult, ApiError>) {
+        let subject: &str = match to {
+            Recipient::Id(id) => id,
+            Recipient::Phone(phone) => phone,
+            Recipient::Email(email) => email,
+        };
+        match result {
+            Ok(result) => {
+                self.operation_finished(
+                    AuditOperation::Send,
+                    Some(subject),
+                    Some(result.message_id.to_string()),
+                    None,
+                );
+                self.on_send.iter().for_each(|hook| hook(to, result));
+            }
+            Err(e) => {
+                self.operation_finished(AuditOperation::Send, Some(subject), None, Some(e));
+                self.on_error.iter().for_each(|hook| hook(to, e));
+            }
+        }
+    }
+
+    /// Record an operation in the audit sink and report its error, if
+    /// configured.
+    pub(crate) fn operation_finished(
+        &self,
+        operation: AuditOperation,
+        subject: Option<&str>,
+        message_id: Option<String>,
+        error: Option<&ApiError>,
+    ) {
+        if let Some(ref auditor) = self.audit {
+            auditor.record(operation, subject, message_id, error);
+        }
+        if let Some(e) = error {
+            self.report_error(operation, subject, e);
+        }
+    }
+
+    /// Report an error to the error reporter, if one is configured.
+    pub(crate) fn report_error(
+        &self,
+        operation: AuditOperation,
+        subject: Option<&str>,
+        error: &ApiError,
+    ) {
+        if let Some(ref reporter) = self.error_reporter {
+            reporter.report(
+                error,
+                &ErrorContext {
+                    operation,
+                    subject,
+                    request_id: error.request_id(),
+                },
+            );
+        }
+    }
+
+    /// Notify the callbacks about an uploaded blob.
+    pub(crate) fn blob_uploaded(&self, blob_id: &BlobId) {
+        self.on_blob_upload.iter().for_each(|hook| hook(blob_id));
+    }
+
+    /// Notify the callbacks about a received HTTP response.
+    pub(crate) fn response_received(&self, status: StatusCode, headers: &HeaderMap) {
+        self.on_response
+            .iter()
+            .for_each(|hook| hook(status, headers));
+    }
+
+    /// Apply the transformations to the text of an outgoing message.
+    pub(crate) fn transform_text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        self.text_transforms
+            .iter()
+            .fold(Cow::Borrowed(text), |text, transform| {
+                Cow::Owned(transform(&text))
+            })
+    }
+
+    /// Apply the transformations to the data of an outgoing message: the
+    /// text of a text message and the caption of a file message.
+    pub(crate) fn transform_message<'a>(
+        &self,
+        data: &'a [u8],
+        msgtype: MessageType,
+    ) -> Cow<'a, [u8]> {
+        if self.text_transforms.is_empty() {
+            return Cow::Borrowed(data);
+        }
+        let transformed = if msgtype == MessageType::Text {
+            std::str::from_utf8(data)
+                .ok()
+                .map(|text| self.transform_text(text).into_owned().into_bytes())
+        } else if msgtype == MessageType::File {
+            self.transform_caption(data)
+        } else {
+            None
+        };
+        transformed.map_or(Cow::Borrowed(data), Cow::Owned)
+    }
+
+    /// Apply the transformations to the caption of a JSON encoded file
+    /// message. Returns `None` if the message has no caption.
+    fn transform_caption(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut msg: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(data).ok()?;
+        let caption = msg.get_mut("d")?;
+        let transformed = self.transform_text(caption.as_str()?).into_owned();
+        *caption = serde_json::Value::String(transformed);
+        serde_json::to_vec(&msg).ok()
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_send", &self.on_send.len())
+            .field("on_error", &self.on_error.len())
+            .field("on_blob_upload", &self.on_blob_upload.len())
+            .field("on_response", &self.on_response.len())
+            .field("text_transforms", &self.text_transforms.len())
+            .field("audit", &self.audit.is_some())
+            .field("error_reporter", &self.error_reporter.is_some())
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "mock-server"))]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use super::*;
+    use crate::{mock_server::MockServer, ApiBuilder, RecipientKey, SecretKey};
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_public_key("ECHOECHO", RecipientKey::from([2; 32]));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (sent, failed, uploaded) = (events.clone(), events.clone(), events.clone());
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap()
+            .on_send(move |to, result| {
+                sent.lock()
+                    .unwrap()
+                    .push(format!("sent {:?} {}", to, result.message_id))
+            })
+            .on_error(move |to, e| {
+                failed
+                    .lock()
+                    .unwrap()
+                    .push(format!("failed {:?} {}", to, e.inner()))
+            })
+            .on_blob_upload(move |blob_id| {
+                uploaded.lock().unwrap().push(format!("blob {}", blob_id))
+            });
+
+        let result = api.send_text("ECHOECHO", "hi").await.unwrap();
+        let msg = api
+            .encrypt_text_msg("again", &RecipientKey::from([2; 32]))
+            .unwrap();
+        server.fail_next(500);
+        assert!(api
+            .with_timeout(Duration::from_secs(5))
+            .send("ECHOECHO", &msg, false)
+            .await
+            .is_err());
+        let blob_id = api.blob_upload_raw(&[1, 2, 3], false).await.unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                format!("sent Id(\"ECHOECHO\") {}", result.message_id),
+                "failed Id(\"ECHOECHO\") internal server error".to_string(),
+                format!("blob {}", blob_id),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_hook() {
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let responses = Arc::new(Mutex::new(Vec::new()));
+        let captured = responses.clone();
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .into_simple()
+            .unwrap()
+            .on_response(move |status, headers| {
+                captured
+                    .lock()
+                    .unwrap()
+                    .push((status.as_u16(), headers.contains_key("content-length")))
+            });
+
+        api.lookup_credits().await.unwrap();
+        server.fail_next(500);
+        assert!(api.lookup_credits().await.is_err());
+        assert_eq!(*responses.lock().unwrap(), vec![(200, true), (500, true)]);
+    }
+}
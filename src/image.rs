@@ -0,0 +1,169 @@
+//! Inspection of image files.
+//!
+//! The headers of GIF, PNG and WebP images are parsed to find out whether an
+//! image is animated, which is used to set the `animated` flag of media and
+//! sticker file messages automatically.
+
+/// The PNG file signature.
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Skip a sequence of GIF data sub-blocks starting at `pos` and return the
+/// position after the block terminator.
+fn skip_gif_sub_blocks(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let size = *data.get(pos)? as usize;
+        pos += 1 + size;
+        if size == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+/// Return the size of a GIF color table, if the flags byte declares one.
+fn gif_color_table_size(flags: u8) -> usize {
+    if flags & 0x80 == 0 {
+        0
+    } else {
+        3 << ((flags & 0x07) + 1)
+    }
+}
+
+/// A GIF image is animated if it contains more than one image.
+fn gif_animated(data: &[u8]) -> Option<bool> {
+    let flags = *data.get(10)?;
+    let mut pos = 13 + gif_color_table_size(flags);
+    let mut images = 0;
+    loop {
+        match *data.get(pos)? {
+            // Extension: label followed by sub-blocks
+            0x21 => pos = skip_gif_sub_blocks(data, pos + 2)?,
+            // Image descriptor: local color table, LZW code size and
+            // sub-blocks with the image data
+            0x2c => {
+                images += 1;
+                if images > 1 {
+                    return Some(true);
+                }
+                let flags = *data.get(pos + 9)?;
+                pos = skip_gif_sub_blocks(data, pos + 11 + gif_color_table_size(flags))?;
+            }
+            // Trailer
+            0x3b => return Some(false),
+            _ => return None,
+        }
+    }
+}
+
+/// A PNG image is animated (APNG) if an `acTL` chunk precedes the image data.
+fn png_animated(data: &[u8]) -> Option<bool> {
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        let length = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        match data.get(pos + 4..pos + 8)? {
+            b"acTL" => return Some(true),
+            b"IDAT" | b"IEND" => return Some(false),
+            _ => {}
+        }
+        // Length, chunk type, chunk data and CRC
+        pos = pos.checked_add(12 + length)?;
+    }
+}
+
+/// A WebP image is animated if the animation flag of the extended header
+/// (`VP8X` chunk) is set. Simple (lossy and lossless) images cannot be
+/// animated.
+fn webp_animated(data: &[u8]) -> Option<bool> {
+    match data.get(12..16)? {
+        b"VP8X" => Some(*data.get(20)? & 0x02 != 0),
+        b"VP8 " | b"VP8L" => Some(false),
+        _ => None,
+    }
+}
+
+/// Determine whether an image is animated.
+///
+/// GIF, PNG (APNG) and WebP images are recognized by their signature. For
+/// other or malformed data, `None` is returned.
+pub fn detect_animation(data: &[u8]) -> Option<bool> {
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        gif_animated(data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        png_animated(data)
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        webp_animated(data)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a 1x1 GIF with the specified number of images.
+    fn gif(images: usize) -> Vec<u8> {
+        let mut gif = b"GIF89a\x01\x00\x01\x00\x80\x00\x00".to_vec();
+        gif.extend_from_slice(&[0, 0, 0, 0xff, 0xff, 0xff]); // Global color table
+        gif.extend_from_slice(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00");
+        for _ in 0..images {
+            gif.extend_from_slice(b"\x21\xf9\x04\x00\x0a\x00\x00\x00"); // Graphic control
+            gif.extend_from_slice(b"\x2c\x00\x00\x00\x00\x01\x00\x01\x00\x00");
+            gif.extend_from_slice(b"\x02\x02\x44\x01\x00");
+        }
+        gif.push(0x3b);
+        gif
+    }
+
+    /// Create a PNG with the specified chunks (the CRCs are not checked).
+    fn png(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut png = PNG_SIGNATURE.to_vec();
+        for (chunk_type, chunk_data) in chunks {
+            png.extend_from_slice(&(chunk_data.len() as u32).to_be_bytes());
+            png.extend_from_slice(*chunk_type);
+            png.extend_from_slice(chunk_data);
+            png.extend_from_slice(&[0; 4]);
+        }
+        png
+    }
+
+    #[test]
+    fn test_gif() {
+        assert_eq!(detect_animation(&gif(1)), Some(false));
+        assert_eq!(detect_animation(&gif(3)), Some(true));
+        let truncated = gif(1);
+        assert_eq!(detect_animation(&truncated[..truncated.len() - 4]), None);
+    }
+
+    #[test]
+    fn test_png() {
+        let ihdr: &[u8] = &[0; 13];
+        let static_png = png(&[(b"IHDR", ihdr), (b"IDAT", &[0; 8]), (b"IEND", &[])]);
+        assert_eq!(detect_animation(&static_png), Some(false));
+        let apng = png(&[
+            (b"IHDR", ihdr),
+            (b"acTL", &[0, 0, 0, 2, 0, 0, 0, 0]),
+            (b"IDAT", &[0; 8]),
+        ]);
+        assert_eq!(detect_animation(&apng), Some(true));
+        assert_eq!(detect_animation(PNG_SIGNATURE), None);
+    }
+
+    #[test]
+    fn test_webp() {
+        let mut webp = b"RIFF\x00\x00\x00\x00WEBPVP8X\x0a\x00\x00\x00".to_vec();
+        webp.extend_from_slice(&[0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(detect_animation(&webp), Some(true));
+        webp[20] = 0x10; // Alpha only
+        assert_eq!(detect_animation(&webp), Some(false));
+        assert_eq!(
+            detect_animation(b"RIFF\x00\x00\x00\x00WEBPVP8L\x00\x00\x00\x00"),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_other_data() {
+        assert_eq!(detect_animation(b"\xff\xd8\xff\xe0JFIF"), None);
+        assert_eq!(detect_animation(&[]), None);
+    }
+}
@@ -0,0 +1,125 @@
+//! Abstraction over the private key operations.
+
+use std::fmt::Debug;
+
+use crypto_box::{aead::AeadInPlace, SalsaBox};
+use crypto_secretbox::{cipher::generic_array::GenericArray, Nonce};
+
+use crate::{errors::CryptoError, PublicKey, SecretKey};
+
+/// Size of the Poly1305 authentication tag.
+pub const TAG_SIZE: usize = 16;
+
+/// Provider for the operations that require access to the private key of the
+/// gateway identity.
+///
+/// By default, the private key is kept in process memory as a [`SecretKey`],
+/// which implements this trait. By implementing it yourself, the key can live
+/// in an HSM, a TPM or a remote key management service instead. Pass your
+/// implementation to
+/// [`ApiBuilder::with_key_provider`](crate::ApiBuilder::with_key_provider).
+///
+/// Both operations correspond to the NaCl `crypto_box` construction
+/// (X25519, XSalsa20 and Poly1305) in detached mode, i.e. the authentication
+/// tag is not part of the buffer.
+pub trait KeyProvider: Debug + Send + Sync {
+    /// Return the public key belonging to the private key.
+    fn public_key(&self) -> PublicKey;
+
+    /// Encrypt `buffer` in place for the owner of `public_key` and return the
+    /// authentication tag.
+    fn encrypt_in_place_detached(
+        &self,
+        public_key: &PublicKey,
+        nonce: &Nonce,
+        buffer: &mut [u8],
+    ) -> Result<[u8; TAG_SIZE], CryptoError>;
+
+    /// Verify the authentication `tag` and decrypt `buffer` in place, which
+    /// was encrypted by the owner of `public_key`.
+    fn decrypt_in_place_detached(
+        &self,
+        public_key: &PublicKey,
+        nonce: &Nonce,
+        buffer: &mut [u8],
+        tag: &[u8; TAG_SIZE],
+    ) -> Result<(), CryptoError>;
+}
+
+impl KeyProvider for SecretKey {
+    fn public_key(&self) -> PublicKey {
+        SecretKey::public_key(self)
+    }
+
+    fn encrypt_in_place_detached(
+        &self,
+        public_key: &PublicKey,
+        nonce: &Nonce,
+        buffer: &mut [u8],
+    ) -> Result<[u8; TAG_SIZE], CryptoError> {
+        SalsaBox::new(public_key, self)
+            .encrypt_in_place_detached(nonce, b"", buffer)
+            .map(Into::into)
+            .map_err(|_| CryptoError::EncryptionFailed)
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        public_key: &PublicKey,
+        nonce: &Nonce,
+        buffer: &mut [u8],
+        tag: &[u8; TAG_SIZE],
+    ) -> Result<(), CryptoError> {
+        SalsaBox::new(public_key, self)
+            .decrypt_in_place_detached(nonce, b"", buffer, GenericArray::from_slice(tag))
+            .map_err(|_| CryptoError::DecryptionFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crypto_box::aead::{Aead, AeadCore, OsRng};
+
+    use super::*;
+
+    #[test]
+    fn test_secret_key_compatible_with_salsa_box() {
+        let a_sk = SecretKey::generate(&mut OsRng);
+        let b_sk = SecretKey::generate(&mut OsRng);
+        let nonce = SalsaBox::generate_nonce(&mut OsRng);
+
+        let mut buffer = b"hello".to_vec();
+        let tag =
+            KeyProvider::encrypt_in_place_detached(&a_sk, &b_sk.public_key(), &nonce, &mut buffer)
+                .unwrap();
+        let mut ciphertext = tag.to_vec();
+        ciphertext.extend_from_slice(&buffer);
+        let expected = SalsaBox::new(&b_sk.public_key(), &a_sk)
+            .encrypt(&nonce, &b"hello"[..])
+            .unwrap();
+        assert_eq!(ciphertext, expected);
+
+        KeyProvider::decrypt_in_place_detached(
+            &b_sk,
+            &a_sk.public_key(),
+            &nonce,
+            &mut buffer,
+            &tag,
+        )
+        .unwrap();
+        assert_eq!(buffer, b"hello");
+
+        let mut bad_tag = tag;
+        bad_tag[0] ^= 1;
+        assert_eq!(
+            KeyProvider::decrypt_in_place_detached(
+                &b_sk,
+                &a_sk.public_key(),
+                &nonce,
+                &mut buffer,
+                &bad_tag
+            ),
+            Err(CryptoError::DecryptionFailed)
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Loading of the API secret and the private key from the OS keyring.
+//!
+//! This uses the [keyring](https://docs.rs/keyring) crate, which stores
+//! secrets in the macOS keychain, the Windows credential manager or the
+//! Linux kernel keyring. To use the Secret Service on Linux instead, enable
+//! the corresponding feature of the keyring crate in your own `Cargo.toml`.
+//!
+//! The entries can be created with any tool that talks to the OS keyring (or
+//! with the keyring crate itself). Example:
+//!
+//! ```no_run
+//! use threema_gateway::ApiBuilder;
+//!
+//! let api = ApiBuilder::from_keyring("threema-gateway", "*3MAGWID")
+//!     .unwrap()
+//!     .into_e2e()
+//!     .unwrap();
+//! ```
+
+use ::keyring::Entry;
+use zeroize::Zeroizing;
+
+use crate::{api::ApiBuilder, errors::ApiBuilderError};
+
+/// Read the password of a keyring entry.
+fn read_entry(entry: &Entry) -> Result<Zeroizing<String>, ApiBuilderError> {
+    entry
+        .get_password()
+        .map(Zeroizing::new)
+        .map_err(|e| ApiBuilderError::Keyring(e.to_string()))
+}
+
+/// Open the keyring entry for `service` and `user`.
+fn open_entry(service: &str, user: &str) -> Result<Entry, ApiBuilderError> {
+    Entry::new(service, user).map_err(|e| ApiBuilderError::Keyring(e.to_string()))
+}
+
+impl ApiBuilder {
+    /// Initialize the ApiBuilder with the Gateway ID and load the API secret
+    /// and the private key from the OS keyring.
+    ///
+    /// The secret is read from the entry with the user `<id>/secret`, the
+    /// hex encoded private key (optionally prefixed with `private:`) from the
+    /// entry with the user `<id>/private-key` of the specified `service`.
+    pub fn from_keyring(service: &str, id: &str) -> Result<Self, ApiBuilderError> {
+        ApiBuilder::new(id, "")
+            .with_secret_from_keyring(service, &format!("{}/secret", id))?
+            .with_private_key_from_keyring(service, &format!("{}/private-key", id))
+    }
+
+    /// Load the API secret from the OS keyring entry for `service` and
+    /// `user`.
+    pub fn with_secret_from_keyring(
+        self,
+        service: &str,
+        user: &str,
+    ) -> Result<Self, ApiBuilderError> {
+        self.with_secret_from_keyring_entry(&open_entry(service, user)?)
+    }
+
+    /// Load the hex encoded private key (optionally prefixed with
+    /// `private:`) from the OS keyring entry for `service` and `user`. Only
+    /// needed for E2e mode.
+    pub fn with_private_key_from_keyring(
+        self,
+        service: &str,
+        user: &str,
+    ) -> Result<Self, ApiBuilderError> {
+        self.with_private_key_from_keyring_entry(&open_entry(service, user)?)
+    }
+
+    /// Load the API secret from a keyring entry.
+    pub fn with_secret_from_keyring_entry(
+        mut self,
+        entry: &Entry,
+    ) -> Result<Self, ApiBuilderError> {
+        self.secret = read_entry(entry)?.trim().to_string();
+        Ok(self)
+    }
+
+    /// Load the hex encoded private key (optionally prefixed with
+    /// `private:`) from a keyring entry. Only needed for E2e mode.
+    pub fn with_private_key_from_keyring_entry(
+        self,
+        entry: &Entry,
+    ) -> Result<Self, ApiBuilderError> {
+        self.with_private_key_str(&read_entry(entry)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::keyring::mock::MockCredential;
+
+    use super::*;
+    use crate::test_support;
+
+    fn mock_entry(password: Option<&str>) -> Entry {
+        let entry = Entry::new_with_credential(Box::<MockCredential>::default());
+        if let Some(password) = password {
+            entry.set_password(password).unwrap();
+        }
+        entry
+    }
+
+    #[test]
+    fn test_load_from_entries() {
+        let secret = mock_entry(Some(test_support::API_SECRET));
+        let private_key = mock_entry(Some(&format!(
+            "private:{}\n",
+            test_support::GATEWAY_PRIVATE_KEY
+        )));
+        let builder = ApiBuilder::new(test_support::GATEWAY_ID, "")
+            .with_secret_from_keyring_entry(&secret)
+            .unwrap()
+            .with_private_key_from_keyring_entry(&private_key)
+            .unwrap();
+        assert_eq!(builder.secret, test_support::API_SECRET);
+        let api = builder.into_e2e().unwrap();
+        assert_eq!(api.public_key(), test_support::gateway_public_key());
+    }
+
+    #[test]
+    fn test_missing_entry() {
+        let entry = mock_entry(None);
+        assert!(matches!(
+            ApiBuilder::new(test_support::GATEWAY_ID, "").with_secret_from_keyring_entry(&entry),
+            Err(ApiBuilderError::Keyring(_))
+        ));
+    }
+}
@@ -0,0 +1,215 @@
+//! Deduplication of sent messages.
+//!
+//! A [`SendLedger`] records the sends made with
+//! [`E2eApi::send_once`](crate::E2eApi::send_once) by a caller-chosen
+//! deduplication key, e.g. the ID of the order a notification is about. A
+//! send with a key that is already in the ledger is not repeated, so that
+//! retries of a job or a restarted process don't send the same message
+//! twice. The ledger is configured with
+//! [`ApiBuilder::with_send_ledger`](crate::ApiBuilder::with_send_ledger).
+//!
+//! [`MemorySendLedger`] only deduplicates within a process. To deduplicate
+//! across restarts, implement the trait on top of a persistent store.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{store::shared_store, types::MessageId};
+
+/// The state of a deduplication key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LedgerEntry {
+    /// The message is being sent, or the send was interrupted before its
+    /// result was recorded.
+    Pending,
+    /// The message was sent with the given message ID.
+    Sent(MessageId),
+}
+
+/// Storage for the deduplication keys of sent messages.
+pub trait SendLedger: Send + Sync {
+    /// Error returned if ledger operations fail
+    type Error: Error + Send + Sync + 'static;
+
+    /// Reserve `key` for a send.
+    ///
+    /// If the key is unknown, it must be recorded as [`LedgerEntry::Pending`]
+    /// and `None` is returned. Otherwise the existing entry is returned. The
+    /// check and the insertion must be atomic, so that only one of
+    /// concurrent sends with the same key gets the reservation.
+    fn reserve(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<LedgerEntry>, Self::Error>> + Send;
+
+    /// Record that the message with the reserved `key` was sent.
+    fn complete(
+        &self,
+        key: &str,
+        message_id: &MessageId,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Remove the reservation of `key` after a failed send, so that the send
+    /// can be retried.
+    fn release(&self, key: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+impl<L: SendLedger + ?Sized> SendLedger for Arc<L> {
+    type Error = L::Error;
+
+    fn reserve(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<LedgerEntry>, Self::Error>> + Send {
+        (**self).reserve(key)
+    }
+
+    fn complete(
+        &self,
+        key: &str,
+        message_id: &MessageId,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        (**self).complete(key, message_id)
+    }
+
+    fn release(&self, key: &str) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        (**self).release(key)
+    }
+}
+
+/// A [`SendLedger`] that keeps the keys in memory.
+#[derive(Debug, Default)]
+pub struct MemorySendLedger {
+    entries: Mutex<HashMap<String, LedgerEntry>>,
+}
+
+impl MemorySendLedger {
+    fn entries(&self) -> MutexGuard<'_, HashMap<String, LedgerEntry>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl SendLedger for MemorySendLedger {
+    type Error = Infallible;
+
+    async fn reserve(&self, key: &str) -> Result<Option<LedgerEntry>, Self::Error> {
+        let mut entries = self.entries();
+        match entries.get(key) {
+            Some(entry) => Ok(Some(*entry)),
+            None => {
+                entries.insert(key.to_string(), LedgerEntry::Pending);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn complete(&self, key: &str, message_id: &MessageId) -> Result<(), Self::Error> {
+        self.entries()
+            .insert(key.to_string(), LedgerEntry::Sent(*message_id));
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> Result<(), Self::Error> {
+        self.entries().remove(key);
+        Ok(())
+    }
+}
+
+shared_store! {
+    /// A type erased [`SendLedger`] that is shared between clones of the API
+    /// object.
+    SharedSendLedger(DynSendLedger): SendLedger {
+        fn reserve(key: &str) -> Option<LedgerEntry>;
+        fn complete(key: &str, message_id: &MessageId) -> ();
+        fn release(key: &str) -> ();
+    }
+}
+
+#[cfg(all(test, feature = "mock-server"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_once() {
+        use crate::{
+            errors::ApiError, mock_server::MockServer, ApiBuilder, RecipientKey, SecretKey,
+            SendOptions,
+        };
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_public_key("ECHOECHO", RecipientKey::from([2; 32]));
+        let ledger = Arc::new(MemorySendLedger::default());
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .with_send_ledger(ledger.clone())
+            .into_e2e()
+            .unwrap();
+        let msg = api
+            .encrypt_text_msg("once", &RecipientKey::from([2; 32]))
+            .unwrap();
+
+        // A rejected send releases the key
+        server.fail_next(400);
+        assert!(api
+            .send_once("order-1", "ECHOECHO", &msg, SendOptions::default())
+            .await
+            .is_err());
+        let first = api
+            .send_once("order-1", "ECHOECHO", &msg, SendOptions::default())
+            .await
+            .unwrap();
+        let second = api
+            .send_once("order-1", "ECHOECHO", &msg, SendOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(server.received_messages().len(), 1);
+
+        // A reserved key whose send was interrupted is not sent again
+        ledger.reserve("order-2").await.unwrap();
+        assert!(matches!(
+            api.send_once("order-2", "ECHOECHO", &msg, SendOptions::default())
+                .await,
+            Err(ApiError::SendPending(key)) if key == "order-2"
+        ));
+        assert_eq!(server.received_messages().len(), 1);
+
+        // Dry runs are not recorded
+        let dry_run = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .with_send_ledger(ledger.clone())
+            .with_dry_run(true)
+            .into_e2e()
+            .unwrap();
+        dry_run
+            .send_once("order-3", "ECHOECHO", &msg, SendOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(ledger.reserve("order-3").await.unwrap(), None);
+        assert_eq!(server.received_messages().len(), 1);
+
+        // Without a ledger, nothing is sent
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap();
+        assert!(api
+            .send_once("order-4", "ECHOECHO", &msg, SendOptions::default())
+            .await
+            .is_err());
+        assert_eq!(server.received_messages().len(), 1);
+    }
+}
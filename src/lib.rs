@@ -23,9 +23,9 @@
 //! let text = "Very secret message!";
 //!
 //! // Send
-//! let api = ApiBuilder::new(from, secret).into_simple();
+//! let api = ApiBuilder::new(from, secret).into_simple().unwrap();
 //! match api.send(&to, &text).await {
-//!     Ok(msg_id) => println!("Sent. Message id is {}.", msg_id),
+//!     Ok(result) => println!("Sent. Message id is {}.", result.message_id),
 //!     Err(e) => println!("Could not send message: {:?}", e),
 //! }
 //! # })
@@ -59,7 +59,7 @@
 //!
 //! // Send
 //! match api.send(&to, &encrypted, false).await {
-//!     Ok(msg_id) => println!("Sent. Message id is {}.", msg_id),
+//!     Ok(result) => println!("Sent. Message id is {}.", result.message_id),
 //!     Err(e) => println!("Could not send message: {:?}", e),
 //! }
 //! # })
@@ -75,32 +75,117 @@
 extern crate log;
 
 mod api;
+pub mod audit;
+mod backup;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "bot")]
+pub mod bot;
+#[cfg(feature = "bot")]
+mod bot_commands;
+#[cfg(feature = "bot")]
+mod bot_rate_limit;
+#[cfg(feature = "bot")]
+mod bot_sessions;
+#[cfg(feature = "bot-tls")]
+mod bot_tls;
+#[cfg(feature = "broadcast")]
+pub mod broadcast;
 mod cache;
+#[cfg(feature = "config")]
+pub mod config;
 mod connection;
+pub mod contacts;
+mod credits;
 mod crypto;
+pub mod download;
+mod endpoint;
 pub mod errors;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod format;
+mod gateway;
+pub mod history;
+mod hooks;
+#[cfg(feature = "image")]
+pub mod image;
+mod key_provider;
+#[cfg(feature = "keyring")]
+mod keyring;
+pub mod ledger;
 mod lookup;
+#[cfg(feature = "media-duration")]
+pub mod media;
+pub mod metrics;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+#[cfg(feature = "mock-server")]
+pub mod mock_server;
+#[cfg(feature = "otel")]
+mod otel;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "qr")]
+mod qr;
 #[cfg(feature = "receive")]
 mod receive;
+mod redact;
+pub mod reporting;
+mod retry;
+pub mod sender;
+pub mod status;
+mod store;
+#[cfg(feature = "proptest")]
+pub mod strategies;
+pub mod template;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 mod types;
+#[cfg(feature = "uniffi")]
+pub mod uniffi_bindings;
+#[cfg(feature = "work")]
+pub mod work;
 
+pub use bytes::Bytes;
 pub use crypto_box::{PublicKey, SecretKey};
 pub use crypto_secretbox::Nonce;
 
 pub use crate::{
     api::{ApiBuilder, E2eApi, SimpleApi},
-    cache::PublicKeyCache,
-    connection::Recipient,
+    backup::IdBackup,
+    cache::{
+        export_public_keys, import_public_keys, BlobCache, DiskBlobCache, ListablePublicKeyCache,
+        MemoryPublicKeyCache, PublicKeyCache,
+    },
+    connection::{Recipient, RecipientKind, Timeouts},
+    credits::{CreditReconciliation, CreditUsage},
     crypto::{
-        decrypt_file_data, encrypt, encrypt_file_data, encrypt_raw, EncryptedFileData,
-        EncryptedMessage, FileData, Key, RecipientKey,
+        decrypt, decrypt_file_data, decrypt_file_stream, decrypt_raw, encrypt, encrypt_file_data,
+        encrypt_file_data_with_rng, encrypt_file_stream, encrypt_in_place,
+        encrypt_in_place_with_rng, encrypt_raw, encrypt_raw_in_place,
+        encrypt_raw_in_place_with_rng, encrypt_raw_with_rng, encrypt_with_padding,
+        encrypt_with_rng, EncryptedFileData, EncryptedMessage, FileData, Key, PaddingPolicy,
+        RecipientKey,
+    },
+    endpoint::EndpointStatus,
+    gateway::{E2eGateway, LookupGateway, SimpleGateway},
+    key_provider::KeyProvider,
+    lookup::{
+        hash_email, hash_phone, BulkLookupMatch, Capabilities, LookupCriterion,
+        BULK_LOOKUP_MAX_HASHES,
+    },
+    retry::RetryPolicy,
+    types::{
+        BlobId, FileMessage, FileMessageBuilder, FileSendOptions, FileSource, MessageId,
+        MessageType, RenderingType, RequestId, SendOptions, SendResult,
     },
-    lookup::{Capabilities, LookupCriterion},
-    types::{BlobId, FileMessage, FileMessageBuilder, MessageType, RenderingType},
 };
 
 #[cfg(feature = "receive")]
-pub use crate::receive::IncomingMessage;
+pub use crate::receive::{IncomingMessage, NicknamePolicy};
+
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
 
 const MSGAPI_URL: &str = "https://msgapi.threema.ch";
 
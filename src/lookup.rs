@@ -1,15 +1,25 @@
 //! ID and public key lookups.
 
-use std::{fmt, str};
+use std::{fmt, str, time::Instant};
 
 use crypto_box::KEY_SIZE;
-use data_encoding::HEXLOWER_PERMISSIVE;
+use data_encoding::{BASE64, HEXLOWER, HEXLOWER_PERMISSIVE};
+use hmac::{Hmac, Mac};
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
-use crate::{connection::map_response_code, errors::ApiError, RecipientKey};
+use crate::{
+    connection::{map_response_code, send_request},
+    errors::ApiError,
+    hooks::Hooks,
+    RecipientKey,
+};
 
 /// Different ways to look up a Threema ID in the directory.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "uniffi", derive(uniffi::Enum))]
 pub enum LookupCriterion {
     /// The phone number must be passed in E.164 format, without the leading `+`.
     Phone(String),
@@ -27,6 +37,59 @@ pub enum LookupCriterion {
     EmailHash(String),
 }
 
+/// The HMAC key for phone number hashes (see [`LookupCriterion::PhoneHash`]).
+const PHONE_HASH_KEY: [u8; 32] = [
+    0x85, 0xad, 0xf8, 0x22, 0x69, 0x53, 0xf3, 0xd9, 0x6c, 0xfd, 0x5d, 0x09, 0xbf, 0x29, 0x55, 0x5e,
+    0xb9, 0x55, 0xfc, 0xd8, 0xaa, 0x5e, 0xc4, 0xf9, 0xfc, 0xd8, 0x69, 0xe2, 0x58, 0x37, 0x07, 0x23,
+];
+
+/// The HMAC key for e-mail address hashes (see [`LookupCriterion::EmailHash`]).
+const EMAIL_HASH_KEY: [u8; 32] = [
+    0x30, 0xa5, 0x50, 0x0f, 0xed, 0x97, 0x01, 0xfa, 0x6d, 0xef, 0xdb, 0x61, 0x08, 0x41, 0x90, 0x0f,
+    0xeb, 0xb8, 0xe4, 0x30, 0x88, 0x1f, 0x7a, 0xd8, 0x16, 0x82, 0x62, 0x64, 0xec, 0x09, 0xba, 0xd7,
+];
+
+/// The maximum number of hashes per bulk lookup request.
+pub const BULK_LOOKUP_MAX_HASHES: usize = 1000;
+
+pub(crate) fn hmac_hex(key: &[u8], value: &str) -> String {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    hmac.update(value.as_bytes());
+    HEXLOWER.encode(&hmac.finalize().into_bytes())
+}
+
+/// Hash a phone number in E.164 format for a lookup (see
+/// [`LookupCriterion::PhoneHash`]).
+///
+/// All characters except digits (e.g. a leading `+`) are removed before
+/// hashing.
+pub fn hash_phone(phone: &str) -> String {
+    let digits: String = phone.chars().filter(char::is_ascii_digit).collect();
+    hmac_hex(&PHONE_HASH_KEY, &digits)
+}
+
+/// Hash an e-mail address for a lookup (see [`LookupCriterion::EmailHash`]).
+///
+/// The address is lowercased and trimmed before hashing.
+pub fn hash_email(email: &str) -> String {
+    hmac_hex(&EMAIL_HASH_KEY, &email.trim().to_lowercase())
+}
+
+impl LookupCriterion {
+    /// Return the hashed form of the criterion, so that the phone number or
+    /// e-mail address is not sent to the server in plaintext.
+    ///
+    /// Hashed criteria are returned unchanged.
+    pub fn hashed(&self) -> LookupCriterion {
+        match self {
+            LookupCriterion::Phone(phone) => LookupCriterion::PhoneHash(hash_phone(phone)),
+            LookupCriterion::Email(email) => LookupCriterion::EmailHash(hash_email(email)),
+            LookupCriterion::PhoneHash(hash) => LookupCriterion::PhoneHash(hash.clone()),
+            LookupCriterion::EmailHash(hash) => LookupCriterion::EmailHash(hash.clone()),
+        }
+    }
+}
+
 impl fmt::Display for LookupCriterion {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -39,7 +102,14 @@ impl fmt::Display for LookupCriterion {
 }
 
 /// A struct containing flags according to the capabilities of a Threema ID.
-#[derive(Debug, PartialEq)]
+///
+/// The struct can be serialized with serde (e.g. to persist the result of a
+/// capabilities lookup). For a compact representation of the known
+/// capabilities, see [`bits`](Self::bits).
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "uniffi", derive(uniffi::Record))]
+#[serde(default)]
 pub struct Capabilities {
     /// Whether the ID can receive text messages.
     pub text: bool,
@@ -56,13 +126,48 @@ pub struct Capabilities {
 }
 
 impl Capabilities {
+    /// Bit of the `text` capability, see [`bits`](Self::bits).
+    pub const TEXT: u8 = 1 << 0;
+    /// Bit of the `image` capability, see [`bits`](Self::bits).
+    pub const IMAGE: u8 = 1 << 1;
+    /// Bit of the `video` capability, see [`bits`](Self::bits).
+    pub const VIDEO: u8 = 1 << 2;
+    /// Bit of the `audio` capability, see [`bits`](Self::bits).
+    pub const AUDIO: u8 = 1 << 3;
+    /// Bit of the `file` capability, see [`bits`](Self::bits).
+    pub const FILE: u8 = 1 << 4;
+
     fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the known capabilities as bit flags (see the associated
+    /// constants, e.g. [`Capabilities::TEXT`]).
+    ///
+    /// Note: The [`other`](Self::other) capabilities are not included.
+    pub fn bits(&self) -> u8 {
+        [
+            (self.text, Self::TEXT),
+            (self.image, Self::IMAGE),
+            (self.video, Self::VIDEO),
+            (self.audio, Self::AUDIO),
+            (self.file, Self::FILE),
+        ]
+        .into_iter()
+        .filter(|(present, _)| *present)
+        .fold(0, |bits, (_, bit)| bits | bit)
+    }
+
+    /// Create capabilities from bit flags returned by [`bits`](Self::bits).
+    ///
+    /// Unknown bits are ignored.
+    pub fn from_bits(bits: u8) -> Self {
         Capabilities {
-            text: false,
-            image: false,
-            video: false,
-            audio: false,
-            file: false,
+            text: bits & Self::TEXT != 0,
+            image: bits & Self::IMAGE != 0,
+            video: bits & Self::VIDEO != 0,
+            audio: bits & Self::AUDIO != 0,
+            file: bits & Self::FILE != 0,
             other: Vec::new(),
         }
     }
@@ -121,6 +226,8 @@ impl Capabilities {
 pub(crate) async fn lookup_pubkey(
     client: &Client,
     endpoint: &str,
+    deadline: Option<Instant>,
+    hooks: &Hooks,
     our_id: &str,
     their_id: &str,
     secret: &str,
@@ -134,11 +241,13 @@ pub(crate) async fn lookup_pubkey(
     debug!("Looking up public key for {}", their_id);
 
     // Send request
-    let res = client.get(&url).send().await?;
-    map_response_code(res.status(), None)?;
+    let pubkey_hex_bytes = send_request(client.get(&url), deadline, hooks, |res| async move {
+        map_response_code(res.status(), None)?;
 
-    // Read response body
-    let pubkey_hex_bytes = res.bytes().await?;
+        // Read response body
+        Ok(res.bytes().await?)
+    })
+    .await?;
 
     // Decode key
     let mut pubkey = [0u8; KEY_SIZE];
@@ -161,6 +270,8 @@ pub(crate) async fn lookup_pubkey(
 pub(crate) async fn lookup_id(
     client: &Client,
     endpoint: &str,
+    deadline: Option<Instant>,
+    hooks: &Hooks,
     criterion: &LookupCriterion,
     our_id: &str,
     secret: &str,
@@ -177,17 +288,125 @@ pub(crate) async fn lookup_id(
     debug!("Looking up id key for {}", criterion);
 
     // Send request
-    let res = client.get(&url).send().await?;
-    map_response_code(res.status(), Some(ApiError::BadHashLength))?;
+    send_request(client.get(&url), deadline, hooks, |res| async move {
+        map_response_code(res.status(), Some(ApiError::BadHashLength))?;
+
+        // Read and return response body
+        Ok(res.text().await?)
+    })
+    .await
+}
+
+/// A Threema ID found by a bulk lookup.
+#[derive(Debug, PartialEq)]
+pub struct BulkLookupMatch {
+    /// The hashed criterion that matched.
+    pub criterion: LookupCriterion,
+    /// The Threema ID.
+    pub id: String,
+    /// The public key of the Threema ID.
+    pub public_key: RecipientKey,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BulkLookupRequest<'a> {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    phone_hashes: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    email_hashes: Vec<&'a str>,
+}
 
-    // Read and return response body
-    Ok(res.text().await?)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BulkLookupEntry {
+    identity: String,
+    public_key: String,
+    phone_hash: Option<String>,
+    email_hash: Option<String>,
+}
+
+/// Look up the Threema IDs and public keys for many hashed criteria at once.
+///
+/// Plaintext criteria are ignored, they must be hashed by the caller.
+pub(crate) async fn lookup_bulk(
+    client: &Client,
+    endpoint: &str,
+    deadline: Option<Instant>,
+    hooks: &Hooks,
+    criteria: &[LookupCriterion],
+    our_id: &str,
+    secret: &str,
+) -> Result<Vec<BulkLookupMatch>, ApiError> {
+    let mut request = BulkLookupRequest {
+        phone_hashes: Vec::new(),
+        email_hashes: Vec::new(),
+    };
+    for criterion in criteria {
+        match criterion {
+            LookupCriterion::PhoneHash(hash) => request.phone_hashes.push(hash),
+            LookupCriterion::EmailHash(hash) => request.email_hashes.push(hash),
+            LookupCriterion::Phone(_) | LookupCriterion::Email(_) => {}
+        }
+    }
+    let body = serde_json::to_vec(&request)
+        .map_err(|e| ApiError::Other(format!("Could not serialize lookup request: {}", e)))?;
+    let url = format!("{}/lookup/bulk?from={}&secret={}", endpoint, our_id, secret);
+
+    debug!("Looking up {} hashes in bulk", criteria.len());
+
+    // Send request
+    let entries: Vec<BulkLookupEntry> = send_request(
+        client
+            .post(&url)
+            .header("content-type", "application/json")
+            .body(body),
+        deadline,
+        hooks,
+        |res| async move {
+            map_response_code(res.status(), Some(ApiError::BadHashLength))?;
+            let body = res.bytes().await?;
+            serde_json::from_slice(&body).map_err(|e| {
+                ApiError::ParseError(format!("Could not parse bulk lookup response: {}", e))
+            })
+        },
+    )
+    .await?;
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            let criterion = match (entry.phone_hash, entry.email_hash) {
+                (Some(hash), _) => LookupCriterion::PhoneHash(hash),
+                (None, Some(hash)) => LookupCriterion::EmailHash(hash),
+                (None, None) => {
+                    return Err(ApiError::ParseError(
+                        "Bulk lookup result without hash".to_string(),
+                    ))
+                }
+            };
+            let public_key: [u8; KEY_SIZE] = BASE64
+                .decode(entry.public_key.as_bytes())
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(|| {
+                    ApiError::ParseError(format!("Invalid public key for {}", entry.identity))
+                })?;
+            Ok(BulkLookupMatch {
+                criterion,
+                id: entry.identity,
+                public_key: public_key.into(),
+            })
+        })
+        .collect()
 }
 
 /// Look up remaining gateway credits.
 pub(crate) async fn lookup_credits(
     client: &Client,
     endpoint: &str,
+    deadline: Option<Instant>,
+    hooks: &Hooks,
     our_id: &str,
     secret: &str,
 ) -> Result<i64, ApiError> {
@@ -196,11 +415,15 @@ pub(crate) async fn lookup_credits(
     debug!("Looking up remaining credits");
 
     // Send request
-    let res = client.get(&url).send().await?;
-    map_response_code(res.status(), None)?;
+    let body = send_request(client.get(&url), deadline, hooks, |res| async move {
+        map_response_code(res.status(), None)?;
 
-    // Read, parse and return response body
-    let body = res.text().await?;
+        // Read response body
+        Ok(res.text().await?)
+    })
+    .await?;
+
+    // Parse and return response body
     body.trim().parse::<i64>().map_err(|_| {
         ApiError::ParseError(format!(
             "Could not parse response body as i64: \"{}\"",
@@ -213,6 +436,8 @@ pub(crate) async fn lookup_credits(
 pub(crate) async fn lookup_capabilities(
     client: &Client,
     endpoint: &str,
+    deadline: Option<Instant>,
+    hooks: &Hooks,
     our_id: &str,
     their_id: &str,
     secret: &str,
@@ -226,11 +451,13 @@ pub(crate) async fn lookup_capabilities(
     debug!("Looking up capabilities for {}", their_id);
 
     // Send request
-    let res = client.get(&url).send().await?;
-    map_response_code(res.status(), Some(ApiError::BadHashLength))?;
+    let body = send_request(client.get(&url), deadline, hooks, |res| async move {
+        map_response_code(res.status(), Some(ApiError::BadHashLength))?;
 
-    // Read response body
-    let body = res.text().await?;
+        // Read response body
+        Ok(res.text().await?)
+    })
+    .await?;
 
     // Parse response body
     body.parse()
@@ -238,7 +465,29 @@ pub(crate) async fn lookup_capabilities(
 
 #[cfg(test)]
 mod tests {
-    use super::{Capabilities, LookupCriterion};
+    use super::{hash_email, hash_phone, Capabilities, LookupCriterion};
+
+    #[test]
+    fn test_hashes() {
+        // Test vectors from the gateway API documentation
+        assert_eq!(
+            hash_email("test@threema.ch"),
+            "1ea093239cc5f0e1b6ec81b866265b921f26dc4033025410063309f4d1a8ee2c"
+        );
+        assert_eq!(
+            hash_phone("41791234567"),
+            "ad398f4d7ebe63c6550a486cc6e07f9baa09bd9d8b3d8cb9d9be106d35a7fdbc"
+        );
+        assert_eq!(
+            hash_email(" Test@Threema.ch\n"),
+            hash_email("test@threema.ch")
+        );
+        assert_eq!(hash_phone("+41 79 123 45 67"), hash_phone("41791234567"));
+        assert_eq!(
+            LookupCriterion::Email("test@threema.ch".into()).hashed(),
+            LookupCriterion::EmailHash(hash_email("test@threema.ch"))
+        );
+    }
 
     #[test]
     fn test_lookup_criterion_display() {
@@ -252,6 +501,39 @@ mod tests {
         assert_eq!(&email_hash.to_string(), "email hash 1234567890abcdef");
     }
 
+    #[test]
+    fn test_capabilities_serde() {
+        let capabilities: Capabilities = "text,file,ballot".parse().unwrap();
+        let json = serde_json::to_string(&capabilities).unwrap();
+        assert_eq!(
+            json,
+            r#"{"text":true,"image":false,"video":false,"audio":false,"file":true,"other":["ballot"]}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Capabilities>(&json).unwrap(),
+            capabilities
+        );
+
+        // Missing fields default to false
+        let parsed: Capabilities = serde_json::from_str(r#"{"text":true}"#).unwrap();
+        assert_eq!(parsed, "text".parse().unwrap());
+    }
+
+    #[test]
+    fn test_capabilities_bits() {
+        let capabilities: Capabilities = "text,audio,file,ballot".parse().unwrap();
+        let bits = capabilities.bits();
+        assert_eq!(
+            bits,
+            Capabilities::TEXT | Capabilities::AUDIO | Capabilities::FILE
+        );
+        assert_eq!(
+            Capabilities::from_bits(bits),
+            "text,audio,file".parse().unwrap()
+        );
+        assert_eq!(Capabilities::from_bits(0xff).bits(), 0b1_1111);
+    }
+
     #[test]
     fn test_parse_capabilities_empty() {
         assert_eq!(
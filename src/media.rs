@@ -0,0 +1,97 @@
+//! Extraction of metadata from audio and video files.
+
+use std::io::Cursor;
+
+use symphonia::core::{
+    formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+
+/// Return whether the data looks like an ISO base media file (MP4, M4A, MOV).
+fn is_iso_bmff(data: &[u8]) -> bool {
+    data.get(4..8) == Some(b"ftyp")
+}
+
+/// Extract the duration from an ISO base media file.
+///
+/// The duration of the longest track is returned.
+fn mp4_duration(data: &[u8]) -> Option<f32> {
+    let context = mp4parse::read_mp4(&mut Cursor::new(data)).ok()?;
+    context
+        .tracks
+        .iter()
+        .filter_map(|track| {
+            let duration = track.duration?.0;
+            let timescale = track.timescale?.0;
+            (timescale > 0).then(|| duration as f64 / timescale as f64)
+        })
+        .reduce(f64::max)
+        .map(|seconds| seconds as f32)
+}
+
+/// Extract the duration from an audio file supported by symphonia.
+fn audio_duration(data: &[u8], media_type: &str) -> Option<f32> {
+    let source = MediaSourceStream::new(Box::new(Cursor::new(data.to_vec())), Default::default());
+    let mut hint = Hint::new();
+    hint.mime_type(media_type);
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?;
+    let params = &probed.format.default_track()?.codec_params;
+    let time = params.time_base?.calc_time(params.n_frames?);
+    Some((time.seconds as f64 + time.frac) as f32)
+}
+
+/// Extract the duration (in seconds) of an audio or video file.
+///
+/// MP4 based containers (including M4A voice messages) as well as MP3, Ogg,
+/// FLAC, WAV and AAC (ADTS) files are supported. If the duration cannot be
+/// determined, `None` is returned.
+pub fn extract_duration(data: &[u8], media_type: &str) -> Option<f32> {
+    if is_iso_bmff(data) {
+        mp4_duration(data)
+    } else {
+        audio_duration(data, media_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a mono 16-bit PCM WAV file with the specified number of samples.
+    fn wav_file(sample_rate: u32, samples: u32) -> Vec<u8> {
+        let data_len = samples * 2;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes()); // Mono
+        wav.extend_from_slice(&sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        wav.resize(wav.len() + data_len as usize, 0);
+        wav
+    }
+
+    #[test]
+    fn test_wav_duration() {
+        let wav = wav_file(8000, 20000);
+        assert_eq!(extract_duration(&wav, "audio/wav"), Some(2.5));
+    }
+
+    #[test]
+    fn test_invalid_data() {
+        assert_eq!(extract_duration(&[1, 2, 3, 4], "audio/mpeg"), None);
+        assert_eq!(extract_duration(b"\0\0\0\x10ftypisom", "video/mp4"), None);
+    }
+}
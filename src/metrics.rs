@@ -0,0 +1,317 @@
+//! Instrumentation of message processing.
+//!
+//! A [`Metrics`] implementation is notified about received messages, failed
+//! MAC checks, decryption errors and handler runs. [`PrometheusMetrics`]
+//! counts these events and renders them in the Prometheus text format, e.g.
+//! for the `/metrics` route of the bot webhook server (see
+//! `BotBuilder::with_metrics`). Other monitoring systems can be connected by
+//! implementing the trait.
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use threema_gateway::{
+//!     metrics::{Metrics, PrometheusMetrics},
+//!     MessageType,
+//! };
+//!
+//! let metrics = PrometheusMetrics::default();
+//! metrics.message_received(MessageType::Text);
+//! metrics.handler_finished(MessageType::Text, Duration::from_millis(20), true);
+//! assert!(metrics
+//!     .render()
+//!     .contains("threema_messages_received_total{type=\"text\"} 1"));
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use crate::types::MessageType;
+
+/// Receiver of events of the message processing.
+///
+/// All methods have empty default implementations, so implementations only
+/// need to override the events they are interested in. The methods are
+/// called on the hot path and should not block.
+pub trait Metrics: Send + Sync {
+    /// A message of type `msgtype` was received and decrypted.
+    fn message_received(&self, msgtype: MessageType) {
+        let _ = msgtype;
+    }
+
+    /// The MAC of an incoming message was invalid.
+    fn mac_failure(&self) {}
+
+    /// An incoming message could not be decrypted.
+    fn decrypt_error(&self) {}
+
+    /// The handler for a message of type `msgtype` finished after `elapsed`.
+    fn handler_finished(&self, msgtype: MessageType, elapsed: Duration, success: bool) {
+        let _ = (msgtype, elapsed, success);
+    }
+
+    /// Render the collected metrics in the Prometheus text format.
+    ///
+    /// Returns `None` by default, which disables the `/metrics` route of the
+    /// bot webhook server.
+    fn render_prometheus(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The upper bounds (in seconds) of the handler latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Histogram of handler latencies.
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Cumulative counts per bucket of [`LATENCY_BUCKETS`].
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// A [`Metrics`] implementation that counts the events in memory and renders
+/// them in the Prometheus text format.
+///
+/// The following metrics are exported:
+///
+/// - `threema_messages_received_total` (counter, by message type)
+/// - `threema_mac_failures_total` (counter)
+/// - `threema_decrypt_errors_total` (counter)
+/// - `threema_handler_errors_total` (counter, by message type)
+/// - `threema_handler_duration_seconds` (histogram, by message type)
+#[derive(Debug, Default)]
+pub struct PrometheusMetrics {
+    received: Mutex<BTreeMap<String, u64>>,
+    mac_failures: AtomicU64,
+    decrypt_errors: AtomicU64,
+    handler_errors: Mutex<BTreeMap<String, u64>>,
+    handler_durations: Mutex<BTreeMap<String, Histogram>>,
+}
+
+/// Return the label value for a message type, e.g. `ballot_create`, or the
+/// hex encoded type byte for unknown types.
+fn type_label(msgtype: MessageType) -> String {
+    if let MessageType::Other(msgtype_byte) = msgtype {
+        return format!("0x{:02x}", msgtype_byte);
+    }
+    let mut label = String::new();
+    for c in format!("{:?}", msgtype).chars() {
+        if c.is_ascii_uppercase() && !label.is_empty() {
+            label.push('_');
+        }
+        label.push(c.to_ascii_lowercase());
+    }
+    label
+}
+
+/// Lock a mutex, ignoring poisoning (the counters stay consistent).
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl PrometheusMetrics {
+    /// Render the collected metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        // Writing to a `String` cannot fail
+        self.write(&mut out).expect("Could not render metrics");
+        out
+    }
+
+    fn write(&self, out: &mut String) -> fmt::Result {
+        fn counter(out: &mut String, name: &str, help: &str) -> fmt::Result {
+            writeln!(out, "# HELP {} {}", name, help)?;
+            writeln!(out, "# TYPE {} counter", name)
+        }
+
+        counter(
+            out,
+            "threema_messages_received_total",
+            "Messages received and decrypted.",
+        )?;
+        for (msgtype, count) in lock(&self.received).iter() {
+            writeln!(
+                out,
+                "threema_messages_received_total{{type=\"{}\"}} {}",
+                msgtype, count
+            )?;
+        }
+
+        counter(
+            out,
+            "threema_mac_failures_total",
+            "Incoming messages with an invalid MAC.",
+        )?;
+        writeln!(
+            out,
+            "threema_mac_failures_total {}",
+            self.mac_failures.load(Ordering::Relaxed)
+        )?;
+
+        counter(
+            out,
+            "threema_decrypt_errors_total",
+            "Incoming messages that could not be decrypted.",
+        )?;
+        writeln!(
+            out,
+            "threema_decrypt_errors_total {}",
+            self.decrypt_errors.load(Ordering::Relaxed)
+        )?;
+
+        counter(
+            out,
+            "threema_handler_errors_total",
+            "Message handlers that returned an error.",
+        )?;
+        for (msgtype, count) in lock(&self.handler_errors).iter() {
+            writeln!(
+                out,
+                "threema_handler_errors_total{{type=\"{}\"}} {}",
+                msgtype, count
+            )?;
+        }
+
+        let name = "threema_handler_duration_seconds";
+        writeln!(out, "# HELP {} Duration of message handlers.", name)?;
+        writeln!(out, "# TYPE {} histogram", name)?;
+        for (msgtype, histogram) in lock(&self.handler_durations).iter() {
+            for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                writeln!(
+                    out,
+                    "{}_bucket{{type=\"{}\",le=\"{}\"}} {}",
+                    name, msgtype, bound, count
+                )?;
+            }
+            writeln!(
+                out,
+                "{}_bucket{{type=\"{}\",le=\"+Inf\"}} {}",
+                name, msgtype, histogram.count
+            )?;
+            writeln!(
+                out,
+                "{}_sum{{type=\"{}\"}} {}",
+                name, msgtype, histogram.sum
+            )?;
+            writeln!(
+                out,
+                "{}_count{{type=\"{}\"}} {}",
+                name, msgtype, histogram.count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Metrics for PrometheusMetrics {
+    fn message_received(&self, msgtype: MessageType) {
+        *lock(&self.received).entry(type_label(msgtype)).or_default() += 1;
+    }
+
+    fn mac_failure(&self) {
+        self.mac_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn decrypt_error(&self) {
+        self.decrypt_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handler_finished(&self, msgtype: MessageType, elapsed: Duration, success: bool) {
+        let label = type_label(msgtype);
+        if !success {
+            *lock(&self.handler_errors).entry(label.clone()).or_default() += 1;
+        }
+        lock(&self.handler_durations)
+            .entry(label)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    fn render_prometheus(&self) -> Option<String> {
+        Some(self.render())
+    }
+}
+
+impl<M: Metrics + ?Sized> Metrics for std::sync::Arc<M> {
+    fn message_received(&self, msgtype: MessageType) {
+        (**self).message_received(msgtype)
+    }
+
+    fn mac_failure(&self) {
+        (**self).mac_failure()
+    }
+
+    fn decrypt_error(&self) {
+        (**self).decrypt_error()
+    }
+
+    fn handler_finished(&self, msgtype: MessageType, elapsed: Duration, success: bool) {
+        (**self).handler_finished(msgtype, elapsed, success)
+    }
+
+    fn render_prometheus(&self) -> Option<String> {
+        (**self).render_prometheus()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = PrometheusMetrics::default();
+        metrics.message_received(MessageType::Text);
+        metrics.message_received(MessageType::Text);
+        metrics.message_received(MessageType::Image);
+        metrics.mac_failure();
+        metrics.handler_finished(MessageType::Text, Duration::from_millis(30), true);
+        metrics.handler_finished(MessageType::Text, Duration::from_secs(10), false);
+
+        let text = metrics.render();
+        assert!(text.contains("threema_messages_received_total{type=\"text\"} 2\n"));
+        assert!(text.contains("threema_messages_received_total{type=\"image\"} 1\n"));
+        assert!(text.contains("threema_mac_failures_total 1\n"));
+        assert!(text.contains("threema_decrypt_errors_total 0\n"));
+        assert!(text.contains("threema_handler_errors_total{type=\"text\"} 1\n"));
+        assert!(text
+            .contains("threema_handler_duration_seconds_bucket{type=\"text\",le=\"0.025\"} 0\n"));
+        assert!(
+            text.contains("threema_handler_duration_seconds_bucket{type=\"text\",le=\"0.05\"} 1\n")
+        );
+        assert!(
+            text.contains("threema_handler_duration_seconds_bucket{type=\"text\",le=\"+Inf\"} 2\n")
+        );
+        assert!(text.contains("threema_handler_duration_seconds_count{type=\"text\"} 2\n"));
+    }
+
+    #[test]
+    fn test_type_label() {
+        assert_eq!(type_label(MessageType::Text), "text");
+        assert_eq!(type_label(MessageType::BallotCreate), "ballot_create");
+        assert_eq!(type_label(MessageType::Other(0x42)), "0x42");
+    }
+}
@@ -0,0 +1,524 @@
+//! In-memory mock of the E2E API for use in tests.
+//!
+//! [`MockE2eApi`] implements the [`E2eGateway`] and [`LookupGateway`] traits
+//! without any network access: Sent messages are recorded, blobs are stored
+//! in memory and lookups are served from configurable maps. Failures can be
+//! injected to test error handling.
+//!
+//! ```
+//! # tokio_test::block_on(async {
+//! use threema_gateway::{mock::MockE2eApi, E2eGateway, RecipientKey, SecretKey};
+//!
+//! let recipient_private_key = SecretKey::from([2; 32]);
+//! let recipient_key = RecipientKey::from(recipient_private_key.public_key());
+//!
+//! let api = MockE2eApi::new("*3MAGWID");
+//! api.set_public_key("ECHOECHO", recipient_key.clone());
+//!
+//! let msg = api.encrypt_text_msg("Hello!", &recipient_key).unwrap();
+//! api.send("ECHOECHO", &msg, true).await.unwrap();
+//!
+//! let sent = api.sent_messages();
+//! assert_eq!(sent.len(), 1);
+//! let (_, text) = sent[0].decrypt(&api.public_key(), &recipient_private_key).unwrap();
+//! assert_eq!(text, b"Hello!");
+//! # });
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, MutexGuard},
+};
+
+use bytes::Bytes;
+use crypto_box::aead::OsRng;
+
+use crate::{
+    crypto::{self, EncryptedMessage, PaddingPolicy, RecipientKey},
+    errors::{ApiError, CryptoError},
+    gateway::{self, BlobUploader, E2eGateway, LookupGateway},
+    lookup::{Capabilities, LookupCriterion},
+    types::{
+        BlobId, FileMessage, FileSendOptions, FileSource, MessageId, MessageType, SendOptions,
+        SendResult,
+    },
+    Nonce, PublicKey, SecretKey,
+};
+
+/// Number of credits a new [`MockE2eApi`] starts with.
+const DEFAULT_CREDITS: i64 = 1000;
+
+/// A message recorded by [`MockE2eApi::send`].
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    /// The recipient Threema ID.
+    pub to: String,
+    /// The encrypted message.
+    pub message: EncryptedMessage,
+    /// Whether delivery receipts were requested.
+    pub delivery_receipts: bool,
+    /// Whether push notifications were suppressed.
+    pub no_push: bool,
+    /// Whether the message was sent as group message.
+    pub group: bool,
+    /// The message ID returned to the caller.
+    pub message_id: MessageId,
+}
+
+impl SentMessage {
+    /// Decrypt the message with the private key of the recipient.
+    ///
+    /// `sender_key` is the public key of the mock API, see
+    /// [`MockE2eApi::public_key`].
+    pub fn decrypt(
+        &self,
+        sender_key: &PublicKey,
+        recipient_private_key: &SecretKey,
+    ) -> Result<(MessageType, Vec<u8>), CryptoError> {
+        crypto::decrypt(
+            &self.message.ciphertext,
+            &self.message.nonce,
+            sender_key,
+            recipient_private_key,
+        )
+    }
+}
+
+#[derive(Debug)]
+struct MockState {
+    public_keys: HashMap<String, RecipientKey>,
+    capabilities: HashMap<String, Capabilities>,
+    ids: Vec<(LookupCriterion, String)>,
+    credits: i64,
+    failures: VecDeque<ApiError>,
+    sent: Vec<SentMessage>,
+    blobs: HashMap<BlobId, Vec<u8>>,
+    counter: u64,
+}
+
+/// In-memory mock of [`E2eApi`](crate::E2eApi).
+///
+/// Every send and every blob upload costs one credit, like with the real API.
+/// Once the credits are used up, [`ApiError::NoCredits`] is returned.
+#[derive(Debug)]
+pub struct MockE2eApi {
+    id: String,
+    private_key: SecretKey,
+    state: Mutex<MockState>,
+}
+
+impl MockE2eApi {
+    /// Create a new mock API for the specified gateway ID, with a random
+    /// private key.
+    pub fn new<I: Into<String>>(id: I) -> Self {
+        Self::with_private_key(id, SecretKey::generate(&mut OsRng))
+    }
+
+    /// Create a new mock API for the specified gateway ID and private key.
+    pub fn with_private_key<I: Into<String>>(id: I, private_key: SecretKey) -> Self {
+        MockE2eApi {
+            id: id.into(),
+            private_key,
+            state: Mutex::new(MockState {
+                public_keys: HashMap::new(),
+                capabilities: HashMap::new(),
+                ids: Vec::new(),
+                credits: DEFAULT_CREDITS,
+                failures: VecDeque::new(),
+                sent: Vec::new(),
+                blobs: HashMap::new(),
+                counter: 0,
+            }),
+        }
+    }
+
+    /// Return the gateway ID.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Return the public key of the mock gateway identity.
+    pub fn public_key(&self) -> PublicKey {
+        self.private_key.public_key()
+    }
+
+    /// Set the public key returned when looking up `id`.
+    pub fn set_public_key<I: Into<String>>(&self, id: I, key: RecipientKey) {
+        self.state().public_keys.insert(id.into(), key);
+    }
+
+    /// Set the capabilities returned when looking up `id`.
+    ///
+    /// If no capabilities are set for an ID that has a public key, all
+    /// capabilities are reported.
+    pub fn set_capabilities<I: Into<String>>(&self, id: I, capabilities: Capabilities) {
+        self.state().capabilities.insert(id.into(), capabilities);
+    }
+
+    /// Set the Threema ID returned when looking up `criterion`.
+    pub fn set_id<I: Into<String>>(&self, criterion: LookupCriterion, id: I) {
+        let mut state = self.state();
+        state.ids.retain(|(c, _)| *c != criterion);
+        state.ids.push((criterion, id.into()));
+    }
+
+    /// Set the number of remaining credits.
+    pub fn set_credits(&self, credits: i64) {
+        self.state().credits = credits;
+    }
+
+    /// Make the next API call fail with `error`.
+    ///
+    /// Multiple failures can be queued, they are returned in order. Only
+    /// calls that would hit the network in the real API (lookups, sends and
+    /// blob transfers) consume failures.
+    pub fn fail_next(&self, error: ApiError) {
+        self.state().failures.push_back(error);
+    }
+
+    /// Return all messages sent so far.
+    pub fn sent_messages(&self) -> Vec<SentMessage> {
+        self.state().sent.clone()
+    }
+
+    /// Remove and return all messages sent so far.
+    pub fn take_sent_messages(&self) -> Vec<SentMessage> {
+        std::mem::take(&mut self.state().sent)
+    }
+
+    /// Store a blob, so that it can be downloaded with
+    /// [`blob_download`](E2eGateway::blob_download).
+    pub fn insert_blob(&self, blob_id: BlobId, data: Vec<u8>) {
+        self.state().blobs.insert(blob_id, data);
+    }
+
+    /// Return the blob with the specified ID, if it was uploaded or inserted.
+    pub fn blob(&self, blob_id: &BlobId) -> Option<Vec<u8>> {
+        self.state().blobs.get(blob_id).cloned()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Return the next injected failure, if any.
+    fn check_failure(state: &mut MockState) -> Result<(), ApiError> {
+        match state.failures.pop_front() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Deduct one credit, or fail if none are left.
+    fn charge(state: &mut MockState) -> Result<(), ApiError> {
+        if state.credits <= 0 {
+            return Err(ApiError::NoCredits);
+        }
+        state.credits -= 1;
+        Ok(())
+    }
+
+    fn upload(&self, data: &[u8]) -> Result<BlobId, ApiError> {
+        let mut state = self.state();
+        Self::check_failure(&mut state)?;
+        Self::charge(&mut state)?;
+        state.counter += 1;
+        let mut id = [0; 16];
+        id[8..].copy_from_slice(&state.counter.to_be_bytes());
+        let blob_id = BlobId::new(id);
+        state.blobs.insert(blob_id.clone(), data.to_vec());
+        Ok(blob_id)
+    }
+}
+
+impl LookupGateway for MockE2eApi {
+    async fn lookup_pubkey(&self, id: &str) -> Result<RecipientKey, ApiError> {
+        let mut state = self.state();
+        Self::check_failure(&mut state)?;
+        state
+            .public_keys
+            .get(id)
+            .cloned()
+            .ok_or(ApiError::IdNotFound)
+    }
+
+    async fn lookup_id(&self, criterion: &LookupCriterion) -> Result<String, ApiError> {
+        let mut state = self.state();
+        Self::check_failure(&mut state)?;
+        state
+            .ids
+            .iter()
+            .find(|(c, _)| c == criterion)
+            .map(|(_, id)| id.clone())
+            .ok_or(ApiError::IdNotFound)
+    }
+
+    async fn lookup_capabilities(&self, id: &str) -> Result<Capabilities, ApiError> {
+        let mut state = self.state();
+        Self::check_failure(&mut state)?;
+        if let Some(capabilities) = state.capabilities.get(id) {
+            return Ok(capabilities.clone());
+        }
+        if state.public_keys.contains_key(id) {
+            return "text,image,video,audio,file".parse();
+        }
+        Err(ApiError::IdNotFound)
+    }
+
+    async fn lookup_credits(&self) -> Result<i64, ApiError> {
+        let mut state = self.state();
+        Self::check_failure(&mut state)?;
+        Ok(state.credits)
+    }
+}
+
+impl E2eGateway for MockE2eApi {
+    fn encrypt_text_msg(
+        &self,
+        text: &str,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        self.encrypt(text.as_bytes(), MessageType::Text, recipient_key)
+    }
+
+    fn encrypt_image_msg(
+        &self,
+        blob_id: &BlobId,
+        img_size_bytes: u32,
+        image_data_nonce: &Nonce,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        crypto::encrypt_image_msg(
+            blob_id,
+            img_size_bytes,
+            image_data_nonce,
+            PaddingPolicy::default(),
+            &recipient_key.0,
+            &self.private_key,
+        )
+    }
+
+    fn encrypt_file_msg(
+        &self,
+        msg: &FileMessage,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        let data = serde_json::to_vec(msg).expect("Could not serialize file message");
+        self.encrypt(&data, MessageType::File, recipient_key)
+    }
+
+    fn encrypt(
+        &self,
+        raw_data: &[u8],
+        msgtype: MessageType,
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        crypto::encrypt(raw_data, msgtype, &recipient_key.0, &self.private_key)
+    }
+
+    fn encrypt_raw(
+        &self,
+        raw_data: &[u8],
+        recipient_key: &RecipientKey,
+    ) -> Result<EncryptedMessage, CryptoError> {
+        crypto::encrypt_raw(raw_data, &recipient_key.0, &self.private_key)
+    }
+
+    async fn send(
+        &self,
+        to: &str,
+        message: &EncryptedMessage,
+        delivery_receipts: bool,
+    ) -> Result<SendResult, ApiError> {
+        let options = SendOptions {
+            delivery_receipts,
+            ..Default::default()
+        };
+        self.send_with_options(to, message, options).await
+    }
+
+    async fn send_with_options(
+        &self,
+        to: &str,
+        message: &EncryptedMessage,
+        options: SendOptions,
+    ) -> Result<SendResult, ApiError> {
+        let mut state = self.state();
+        Self::check_failure(&mut state)?;
+        if !state.public_keys.contains_key(to) {
+            return Err(ApiError::BadSenderOrRecipient);
+        }
+        Self::charge(&mut state)?;
+        state.counter += 1;
+        let message_id = MessageId::new(state.counter.to_be_bytes());
+        state.sent.push(SentMessage {
+            to: to.to_string(),
+            message: message.clone(),
+            delivery_receipts: options.delivery_receipts,
+            no_push: options.no_push,
+            group: options.group,
+            message_id,
+        });
+        Ok(SendResult { message_id })
+    }
+
+    async fn send_text(&self, to: &str, text: &str) -> Result<SendResult, ApiError> {
+        let recipient_key = self.lookup_pubkey(to).await?;
+        let message = self.encrypt_text_msg(text, &recipient_key)?;
+        self.send(to, &message, true).await
+    }
+
+    async fn blob_upload(
+        &self,
+        data: &EncryptedMessage,
+        _persist: bool,
+    ) -> Result<BlobId, ApiError> {
+        self.upload(&data.ciphertext)
+    }
+
+    async fn blob_upload_raw(&self, data: &[u8], _persist: bool) -> Result<BlobId, ApiError> {
+        self.upload(data)
+    }
+
+    async fn blob_upload_bytes(&self, data: Bytes, _persist: bool) -> Result<BlobId, ApiError> {
+        self.upload(&data)
+    }
+
+    async fn blob_download(&self, blob_id: &BlobId) -> Result<Vec<u8>, ApiError> {
+        let mut state = self.state();
+        Self::check_failure(&mut state)?;
+        state.blobs.get(blob_id).cloned().ok_or(ApiError::BadBlob)
+    }
+
+    async fn send_file(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        file: FileSource,
+        options: FileSendOptions,
+    ) -> Result<SendResult, ApiError> {
+        gateway::send_file(self, to, recipient_key, file, options).await
+    }
+
+    async fn send_image(
+        &self,
+        to: &str,
+        recipient_key: &RecipientKey,
+        jpeg_data: Vec<u8>,
+    ) -> Result<SendResult, ApiError> {
+        gateway::send_image(self, to, recipient_key, jpeg_data, None).await
+    }
+}
+
+impl BlobUploader for MockE2eApi {
+    async fn upload_blob(&self, data: Bytes, _persist: bool) -> Result<BlobId, ApiError> {
+        self.upload(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileData;
+
+    fn setup() -> (MockE2eApi, SecretKey, RecipientKey) {
+        let api = MockE2eApi::new("*3MAGWID");
+        let private_key = SecretKey::from([2; 32]);
+        let key = RecipientKey::from(private_key.public_key());
+        api.set_public_key("ECHOECHO", key.clone());
+        (api, private_key, key)
+    }
+
+    #[tokio::test]
+    async fn test_send_text() {
+        let (api, private_key, key) = setup();
+        assert_eq!(api.lookup_pubkey("ECHOECHO").await.unwrap(), key);
+        let msg = api.encrypt_text_msg("hi", &key).unwrap();
+        let id = api.send("ECHOECHO", &msg, false).await.unwrap();
+
+        let sent = api.take_sent_messages();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "ECHOECHO");
+        assert_eq!(sent[0].message_id, id.message_id);
+        assert!(!sent[0].delivery_receipts);
+        let (msgtype, data) = sent[0].decrypt(&api.public_key(), &private_key).unwrap();
+        assert_eq!(msgtype, MessageType::Text);
+        assert_eq!(data, b"hi");
+        assert!(api.sent_messages().is_empty());
+        assert_eq!(api.lookup_credits().await.unwrap(), DEFAULT_CREDITS - 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_text_convenience() {
+        let (api, private_key, _) = setup();
+        api.send_text("ECHOECHO", "hello").await.unwrap();
+        let sent = api.sent_messages();
+        let (_, data) = sent[0].decrypt(&api.public_key(), &private_key).unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_send_file() {
+        let (api, private_key, key) = setup();
+        let data = FileData {
+            file: vec![1, 2, 3],
+            thumbnail: None,
+        };
+        api.send_file("ECHOECHO", &key, data.into(), FileSendOptions::default())
+            .await
+            .unwrap();
+
+        let sent = api.sent_messages();
+        let (msgtype, data) = sent[0].decrypt(&api.public_key(), &private_key).unwrap();
+        assert_eq!(msgtype, MessageType::File);
+        let msg: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        let blob_id: BlobId = msg["b"].as_str().unwrap().parse().unwrap();
+        assert!(api.blob(&blob_id).is_some());
+        assert_eq!(api.lookup_credits().await.unwrap(), DEFAULT_CREDITS - 2);
+    }
+
+    #[tokio::test]
+    async fn test_lookups() {
+        let (api, _, _) = setup();
+        assert!(api.lookup_capabilities("ECHOECHO").await.unwrap().file);
+        assert!(matches!(
+            api.lookup_capabilities("UNKNOWN1").await,
+            Err(ApiError::IdNotFound)
+        ));
+        api.set_id(LookupCriterion::Email("a@example.com".into()), "ECHOECHO");
+        assert_eq!(
+            api.lookup_id(&LookupCriterion::Email("a@example.com".into()))
+                .await
+                .unwrap(),
+            "ECHOECHO"
+        );
+        assert!(matches!(
+            api.lookup_pubkey("UNKNOWN1").await,
+            Err(ApiError::IdNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_failures() {
+        let (api, _, key) = setup();
+        let msg = api.encrypt_text_msg("hi", &key).unwrap();
+
+        api.fail_next(ApiError::ServerError);
+        assert!(matches!(
+            api.send("ECHOECHO", &msg, true).await,
+            Err(ApiError::ServerError)
+        ));
+        assert!(api.send("ECHOECHO", &msg, true).await.is_ok());
+
+        api.set_credits(0);
+        assert!(matches!(
+            api.send("ECHOECHO", &msg, true).await,
+            Err(ApiError::NoCredits)
+        ));
+        assert!(matches!(
+            api.send("UNKNOWN1", &msg, true).await,
+            Err(ApiError::BadSenderOrRecipient)
+        ));
+        assert_eq!(api.sent_messages().len(), 1);
+    }
+}
@@ -0,0 +1,795 @@
+//! Mock HTTP server implementing the Threema Gateway API.
+//!
+//! [`MockServer`] listens on a random local port and implements the gateway
+//! endpoints (sending messages, lookups, credits and blobs) with in-memory
+//! state. Point an [`ApiBuilder`](crate::ApiBuilder) at it with
+//! [`with_custom_endpoint`](crate::ApiBuilder::with_custom_endpoint) to run
+//! integration tests without network access. Since the server speaks plain
+//! HTTP, [`allow_insecure_http`](crate::ApiBuilder::allow_insecure_http)
+//! must be called as well.
+//!
+//! ```
+//! # tokio_test::block_on(async {
+//! use threema_gateway::{mock_server::MockServer, ApiBuilder, RecipientKey};
+//!
+//! let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+//! server.set_public_key("ECHOECHO", RecipientKey::from([1; 32]));
+//!
+//! let api = ApiBuilder::new("*3MAGWID", "secret")
+//!     .with_custom_endpoint(server.url())
+//!     .allow_insecure_http()
+//!     .into_simple()
+//!     .unwrap();
+//! assert_eq!(api.lookup_pubkey("ECHOECHO").await.unwrap(), RecipientKey::from([1; 32]));
+//! # });
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    io::{self, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use data_encoding::{BASE64, HEXLOWER, HEXLOWER_PERMISSIVE};
+use flate2::{write::GzEncoder, Compression};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING},
+    server::conn::http1,
+    service::service_fn,
+    HeaderMap, Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use tokio::{net::TcpListener, sync::oneshot};
+
+use crate::{
+    connection::REQUEST_ID_HEADER,
+    crypto::RecipientKey,
+    lookup::{Capabilities, LookupCriterion, BULK_LOOKUP_MAX_HASHES},
+    types::BlobId,
+};
+
+/// Number of credits a new [`MockServer`] starts with.
+const DEFAULT_CREDITS: i64 = 1000;
+
+/// A message received by the [`MockServer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReceivedMessage {
+    /// A message sent in basic mode (`send_simple`).
+    Simple {
+        /// The recipient, as passed in the `to`, `phone` or `email` field.
+        to: String,
+        /// The message text.
+        text: String,
+        /// The message ID returned to the client.
+        message_id: String,
+    },
+    /// An end-to-end encrypted message (`send_e2e`).
+    E2e {
+        /// The recipient Threema ID.
+        to: String,
+        /// The nonce.
+        nonce: Vec<u8>,
+        /// The encrypted message box.
+        ciphertext: Vec<u8>,
+        /// Whether delivery receipts were requested.
+        delivery_receipts: bool,
+        /// Whether push notifications were suppressed.
+        no_push: bool,
+        /// Whether the message was sent as group message.
+        group: bool,
+        /// The message ID returned to the client.
+        message_id: String,
+    },
+}
+
+#[derive(Debug)]
+struct ServerState {
+    id: String,
+    secret: String,
+    public_keys: HashMap<String, RecipientKey>,
+    capabilities: HashMap<String, Capabilities>,
+    ids: Vec<(LookupCriterion, String)>,
+    credits: i64,
+    failures: VecDeque<StatusCode>,
+    received: Vec<ReceivedMessage>,
+    request_ids: Vec<String>,
+    requests: Vec<String>,
+    request_headers: Vec<HeaderMap>,
+    gzip: bool,
+    blobs: HashMap<BlobId, Vec<u8>>,
+    counter: u64,
+}
+
+/// A mock Threema Gateway server with in-memory state.
+///
+/// Every message and every blob upload costs one credit. The server is shut
+/// down when the `MockServer` is dropped.
+#[derive(Debug)]
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<ServerState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    /// Start a mock server on a random local port, accepting requests with
+    /// the specified gateway ID and secret.
+    ///
+    /// This must be called from within a tokio runtime.
+    pub async fn start<I: Into<String>, S: Into<String>>(id: I, secret: S) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(ServerState {
+            id: id.into(),
+            secret: secret.into(),
+            public_keys: HashMap::new(),
+            capabilities: HashMap::new(),
+            ids: Vec::new(),
+            credits: DEFAULT_CREDITS,
+            failures: VecDeque::new(),
+            received: Vec::new(),
+            request_ids: Vec::new(),
+            requests: Vec::new(),
+            request_headers: Vec::new(),
+            gzip: false,
+            blobs: HashMap::new(),
+            counter: 0,
+        }));
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+
+        let server_state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    result = listener.accept() => match result {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            warn!("Mock server could not accept connection: {}", e);
+                            continue;
+                        }
+                    },
+                    _ = &mut shutdown_rx => break,
+                };
+                let state = server_state.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| handle(state.clone(), req));
+                    if let Err(e) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        debug!("Mock server connection error: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(MockServer {
+            addr,
+            state,
+            shutdown: Some(shutdown_tx),
+        })
+    }
+
+    /// Return the base URL of the server, to be used as custom endpoint.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Set the public key returned when looking up `id`.
+    ///
+    /// Messages can only be sent to IDs with a public key.
+    pub fn set_public_key<I: Into<String>>(&self, id: I, key: RecipientKey) {
+        self.state().public_keys.insert(id.into(), key);
+    }
+
+    /// Set the capabilities returned when looking up `id`.
+    ///
+    /// If no capabilities are set for an ID that has a public key, all
+    /// capabilities are reported.
+    pub fn set_capabilities<I: Into<String>>(&self, id: I, capabilities: Capabilities) {
+        self.state().capabilities.insert(id.into(), capabilities);
+    }
+
+    /// Set the Threema ID returned when looking up `criterion`.
+    pub fn set_id<I: Into<String>>(&self, criterion: LookupCriterion, id: I) {
+        let mut state = self.state();
+        state.ids.retain(|(c, _)| *c != criterion);
+        state.ids.push((criterion, id.into()));
+    }
+
+    /// Set the number of remaining credits.
+    pub fn set_credits(&self, credits: i64) {
+        self.state().credits = credits;
+    }
+
+    /// Return the number of remaining credits.
+    pub fn credits(&self) -> i64 {
+        self.state().credits
+    }
+
+    /// Respond to the next request with the specified status code.
+    ///
+    /// Multiple failures can be queued, they are returned in order.
+    pub fn fail_next(&self, status: u16) {
+        let status = StatusCode::from_u16(status).expect("Invalid status code");
+        self.state().failures.push_back(status);
+    }
+
+    /// Return all messages received so far.
+    pub fn received_messages(&self) -> Vec<ReceivedMessage> {
+        self.state().received.clone()
+    }
+
+    /// Return the `X-Request-Id` headers of all requests received so far.
+    pub fn request_ids(&self) -> Vec<String> {
+        self.state().request_ids.clone()
+    }
+
+    /// Return the method and path (e.g. `GET /credits`) of all requests
+    /// received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.state().requests.clone()
+    }
+
+    /// Return the headers of all requests received so far.
+    pub fn request_headers(&self) -> Vec<HeaderMap> {
+        self.state().request_headers.clone()
+    }
+
+    /// Compress the response bodies with gzip if the client accepts it.
+    ///
+    /// Disabled by default.
+    pub fn set_gzip(&self, enabled: bool) {
+        self.state().gzip = enabled;
+    }
+
+    /// Store a blob, so that it can be downloaded.
+    pub fn insert_blob(&self, blob_id: BlobId, data: Vec<u8>) {
+        self.state().blobs.insert(blob_id, data);
+    }
+
+    /// Return the blob with the specified ID, if it was uploaded or inserted.
+    pub fn blob(&self, blob_id: &BlobId) -> Option<Vec<u8>> {
+        self.state().blobs.get(blob_id).cloned()
+    }
+
+    fn state(&self) -> MutexGuard<'_, ServerState> {
+        lock(&self.state)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+fn lock(state: &Mutex<ServerState>) -> MutexGuard<'_, ServerState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+type HttpResponse = Response<Full<Bytes>>;
+
+fn response(status: StatusCode, body: impl Into<Bytes>) -> HttpResponse {
+    let mut response = Response::new(Full::new(body.into()));
+    *response.status_mut() = status;
+    response
+}
+
+fn status(status: StatusCode) -> HttpResponse {
+    response(status, Bytes::new())
+}
+
+fn parse_params(input: &[u8]) -> HashMap<String, String> {
+    form_urlencoded::parse(input).into_owned().collect()
+}
+
+async fn handle(
+    state: Arc<Mutex<ServerState>>,
+    req: Request<Incoming>,
+) -> Result<HttpResponse, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query = parse_params(req.uri().query().unwrap_or("").as_bytes());
+    let content_type = req
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned);
+    let headers = req.headers().clone();
+    let body = match req.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(status(StatusCode::BAD_REQUEST)),
+    };
+
+    let (response, gzip) = {
+        let mut state = lock(&state);
+        let accepts_gzip = headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.split(',').any(|encoding| encoding.trim() == "gzip"));
+        let gzip = state.gzip && accepts_gzip;
+        state.request_ids.extend(request_id);
+        state.requests.push(format!("{} {}", method, path));
+        state.request_headers.push(headers);
+        (
+            route(&mut state, &method, &path, &query, &content_type, &body),
+            gzip,
+        )
+    };
+    if !gzip {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => match e {},
+    };
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = encoder.write_all(&body).and_then(|()| encoder.finish());
+    match compressed {
+        Ok(compressed) => {
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            Ok(Response::from_parts(parts, Full::new(compressed.into())))
+        }
+        Err(e) => {
+            warn!("Mock server could not compress response: {}", e);
+            Ok(status(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+/// Answer a request.
+fn route(
+    state: &mut ServerState,
+    method: &Method,
+    path: &str,
+    query: &HashMap<String, String>,
+    content_type: &str,
+    body: &[u8],
+) -> HttpResponse {
+    if let Some(failure) = state.failures.pop_front() {
+        return status(failure);
+    }
+
+    let segments: Vec<String> = path
+        .trim_start_matches('/')
+        .split('/')
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    match (method, segments.as_slice()) {
+        (&Method::POST, ["send_simple"]) => send_simple(state, &parse_params(body)),
+        (&Method::POST, ["send_e2e"]) => send_e2e(state, &parse_params(body)),
+        (&Method::POST, ["upload_blob"]) => upload_blob(state, query, content_type, body),
+        (&Method::GET, ["blobs", blob_id]) => download_blob(state, query, blob_id),
+        (&Method::GET, ["pubkeys", id]) => lookup_pubkey(state, query, id),
+        (&Method::GET, ["capabilities", id]) => lookup_capabilities(state, query, id),
+        (&Method::GET, ["credits"]) => lookup_credits(state, query),
+        (&Method::GET, ["lookup", kind, value]) => lookup_id(state, query, kind, value),
+        (&Method::POST, ["lookup", "bulk"]) => lookup_bulk(state, query, body),
+        _ => status(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Check the credentials in the request parameters.
+fn authenticate(state: &ServerState, params: &HashMap<String, String>) -> Result<(), StatusCode> {
+    let from = params.get("from").map(String::as_str);
+    let secret = params.get("secret").map(String::as_str);
+    if from != Some(state.id.as_str()) || secret != Some(state.secret.as_str()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+/// Deduct one credit, or fail if none are left.
+fn charge(state: &mut ServerState) -> Result<(), StatusCode> {
+    if state.credits <= 0 {
+        return Err(StatusCode::PAYMENT_REQUIRED);
+    }
+    state.credits -= 1;
+    Ok(())
+}
+
+fn next_message_id(state: &mut ServerState) -> String {
+    state.counter += 1;
+    format!("{:016x}", state.counter)
+}
+
+macro_rules! try_status {
+    ($result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(code) => return status(code),
+        }
+    };
+}
+
+fn send_simple(state: &mut ServerState, params: &HashMap<String, String>) -> HttpResponse {
+    try_status!(authenticate(state, params));
+    let to = match (params.get("to"), params.get("phone"), params.get("email")) {
+        (Some(id), None, None) if state.public_keys.contains_key(id) => id,
+        (None, Some(phone), None) => phone,
+        (None, None, Some(email)) => email,
+        _ => return status(StatusCode::BAD_REQUEST),
+    };
+    let text = match params.get("text") {
+        Some(text) if text.len() <= 3500 => text,
+        Some(_) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+        None => return status(StatusCode::BAD_REQUEST),
+    };
+    try_status!(charge(state));
+    let message_id = next_message_id(state);
+    state.received.push(ReceivedMessage::Simple {
+        to: to.clone(),
+        text: text.clone(),
+        message_id: message_id.clone(),
+    });
+    response(StatusCode::OK, message_id)
+}
+
+fn send_e2e(state: &mut ServerState, params: &HashMap<String, String>) -> HttpResponse {
+    try_status!(authenticate(state, params));
+    let to = match params.get("to") {
+        Some(to) if state.public_keys.contains_key(to) => to.clone(),
+        _ => return status(StatusCode::BAD_REQUEST),
+    };
+    let decode = |name| {
+        params
+            .get(name)
+            .and_then(|value: &String| HEXLOWER_PERMISSIVE.decode(value.as_bytes()).ok())
+    };
+    let (nonce, ciphertext) = match (decode("nonce"), decode("box")) {
+        (Some(nonce), Some(ciphertext)) if nonce.len() == 24 => (nonce, ciphertext),
+        _ => return status(StatusCode::BAD_REQUEST),
+    };
+    if ciphertext.len() > 7812 {
+        return status(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    try_status!(charge(state));
+    let message_id = next_message_id(state);
+    state.received.push(ReceivedMessage::E2e {
+        to,
+        nonce,
+        ciphertext,
+        delivery_receipts: params.get("noDeliveryReceipts").map(String::as_str) != Some("1"),
+        no_push: params.get("noPush").map(String::as_str) == Some("1"),
+        group: params.get("group").map(String::as_str) == Some("1"),
+        message_id: message_id.clone(),
+    });
+    response(StatusCode::OK, message_id)
+}
+
+/// Extract the `blob` part from a `multipart/form-data` body.
+fn extract_blob(content_type: &str, body: &[u8]) -> Option<Vec<u8>> {
+    let boundary = content_type
+        .split(';')
+        .map(str::trim)
+        .find_map(|param| param.strip_prefix("boundary="))?
+        .trim_matches('"');
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut parts = split(body, &delimiter).into_iter().skip(1);
+    parts.find_map(|part| {
+        let header_end = find(part, b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        if !headers.contains("name=\"blob\"") {
+            return None;
+        }
+        let content = &part[header_end + 4..];
+        Some(content.strip_suffix(b"\r\n").unwrap_or(content).to_vec())
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn split<'a>(mut data: &'a [u8], delimiter: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    while let Some(pos) = find(data, delimiter) {
+        parts.push(&data[..pos]);
+        data = &data[pos + delimiter.len()..];
+    }
+    parts.push(data);
+    parts
+}
+
+fn upload_blob(
+    state: &mut ServerState,
+    query: &HashMap<String, String>,
+    content_type: &str,
+    body: &[u8],
+) -> HttpResponse {
+    try_status!(authenticate(state, query));
+    let blob = match extract_blob(content_type, body) {
+        Some(blob) if !blob.is_empty() => blob,
+        _ => return status(StatusCode::BAD_REQUEST),
+    };
+    try_status!(charge(state));
+    let blob_id = BlobId::random();
+    state.blobs.insert(blob_id.clone(), blob);
+    response(StatusCode::OK, blob_id.to_string())
+}
+
+fn download_blob(
+    state: &ServerState,
+    query: &HashMap<String, String>,
+    blob_id: &str,
+) -> HttpResponse {
+    try_status!(authenticate(state, query));
+    let blob = blob_id
+        .parse::<BlobId>()
+        .ok()
+        .and_then(|blob_id| state.blobs.get(&blob_id));
+    match blob {
+        Some(blob) => response(StatusCode::OK, blob.clone()),
+        None => status(StatusCode::NOT_FOUND),
+    }
+}
+
+fn lookup_pubkey(state: &ServerState, query: &HashMap<String, String>, id: &str) -> HttpResponse {
+    try_status!(authenticate(state, query));
+    match state.public_keys.get(id) {
+        Some(key) => response(StatusCode::OK, HEXLOWER.encode(key.as_bytes())),
+        None => status(StatusCode::NOT_FOUND),
+    }
+}
+
+fn lookup_capabilities(
+    state: &ServerState,
+    query: &HashMap<String, String>,
+    id: &str,
+) -> HttpResponse {
+    try_status!(authenticate(state, query));
+    let capabilities = match state.capabilities.get(id) {
+        Some(capabilities) => {
+            let mut list: Vec<&str> = [
+                (capabilities.text, "text"),
+                (capabilities.image, "image"),
+                (capabilities.video, "video"),
+                (capabilities.audio, "audio"),
+                (capabilities.file, "file"),
+            ]
+            .iter()
+            .filter(|(enabled, _)| *enabled)
+            .map(|(_, name)| *name)
+            .collect();
+            list.extend(capabilities.other.iter().map(String::as_str));
+            list.join(",")
+        }
+        None if state.public_keys.contains_key(id) => "text,image,video,audio,file".to_string(),
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    response(StatusCode::OK, capabilities)
+}
+
+fn lookup_credits(state: &ServerState, query: &HashMap<String, String>) -> HttpResponse {
+    try_status!(authenticate(state, query));
+    response(StatusCode::OK, state.credits.to_string())
+}
+
+fn lookup_id(
+    state: &ServerState,
+    query: &HashMap<String, String>,
+    kind: &str,
+    value: &str,
+) -> HttpResponse {
+    try_status!(authenticate(state, query));
+    let criterion = match kind {
+        "phone" => LookupCriterion::Phone(value.to_string()),
+        "phone_hash" => LookupCriterion::PhoneHash(value.to_string()),
+        "email" => LookupCriterion::Email(value.to_string()),
+        "email_hash" => LookupCriterion::EmailHash(value.to_string()),
+        _ => return status(StatusCode::NOT_FOUND),
+    };
+    match state.ids.iter().find(|(c, _)| *c == criterion) {
+        Some((_, id)) => response(StatusCode::OK, id.clone()),
+        None => status(StatusCode::NOT_FOUND),
+    }
+}
+
+fn lookup_bulk(state: &ServerState, query: &HashMap<String, String>, body: &[u8]) -> HttpResponse {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct BulkRequest {
+        #[serde(default)]
+        phone_hashes: Vec<String>,
+        #[serde(default)]
+        email_hashes: Vec<String>,
+    }
+
+    try_status!(authenticate(state, query));
+    let request: BulkRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(_) => return status(StatusCode::BAD_REQUEST),
+    };
+    if request.phone_hashes.len() + request.email_hashes.len() > BULK_LOOKUP_MAX_HASHES {
+        return status(StatusCode::BAD_REQUEST);
+    }
+    let mut found = Vec::new();
+    for (criterion, id) in &state.ids {
+        let public_key = match state.public_keys.get(id) {
+            Some(key) => BASE64.encode(key.as_bytes()),
+            None => continue,
+        };
+        let (field, hash) = match criterion.hashed() {
+            LookupCriterion::PhoneHash(hash) if request.phone_hashes.contains(&hash) => {
+                ("phoneHash", hash)
+            }
+            LookupCriterion::EmailHash(hash) if request.email_hashes.contains(&hash) => {
+                ("emailHash", hash)
+            }
+            _ => continue,
+        };
+        found.push(serde_json::json!({
+            "identity": id,
+            "publicKey": public_key,
+            field: hash,
+        }));
+    }
+    response(StatusCode::OK, serde_json::Value::from(found).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::{errors::ApiError, ApiBuilder, Recipient, SecretKey};
+
+    #[tokio::test]
+    async fn test_send_and_lookup() {
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let recipient_private_key = SecretKey::from([2; 32]);
+        let recipient_key = RecipientKey::from(recipient_private_key.public_key());
+        server.set_public_key("ECHOECHO", recipient_key.clone());
+        server.set_id(LookupCriterion::Email("a@example.com".into()), "ECHOECHO");
+
+        let private_key = SecretKey::from([1; 32]);
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(private_key.clone())
+            .into_e2e()
+            .unwrap();
+
+        assert_eq!(api.lookup_pubkey("ECHOECHO").await.unwrap(), recipient_key);
+        assert!(matches!(
+            api.lookup_pubkey("UNKNOWN1")
+                .await
+                .map_err(ApiError::into_inner),
+            Err(ApiError::IdNotFound)
+        ));
+        assert_eq!(
+            api.lookup_id(&LookupCriterion::Email("a@example.com".into()))
+                .await
+                .unwrap(),
+            "ECHOECHO"
+        );
+        assert!(api.lookup_capabilities("ECHOECHO").await.unwrap().file);
+
+        let message_id = api.send_text("ECHOECHO", "hello").await.unwrap();
+        match &server.received_messages()[..] {
+            [ReceivedMessage::E2e {
+                to,
+                nonce,
+                ciphertext,
+                message_id: id,
+                ..
+            }] => {
+                assert_eq!(to, "ECHOECHO");
+                assert_eq!(id, &message_id.message_id.to_string());
+                let (_, data) = crate::decrypt(
+                    ciphertext,
+                    crate::Nonce::from_slice(nonce),
+                    &private_key.public_key(),
+                    &recipient_private_key,
+                )
+                .unwrap();
+                assert_eq!(data, b"hello");
+            }
+            other => panic!("Unexpected messages: {:?}", other),
+        }
+        assert_eq!(api.lookup_credits().await.unwrap(), DEFAULT_CREDITS - 1);
+    }
+
+    #[tokio::test]
+    async fn test_blobs() {
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap();
+        let blob_id = api.blob_upload_raw(&[1, 2, 3], false).await.unwrap();
+        assert_eq!(server.blob(&blob_id).unwrap(), vec![1, 2, 3]);
+        assert_eq!(api.blob_download(&blob_id).await.unwrap(), vec![1, 2, 3]);
+
+        let blob_id = api
+            .blob_upload_bytes(vec![4; 1024].into(), false)
+            .await
+            .unwrap();
+        assert_eq!(server.blob(&blob_id).unwrap(), vec![4; 1024]);
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_public_key("ECHOECHO", RecipientKey::from([2; 32]));
+        let api = ApiBuilder::new("*3MAGWID", "wrong")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .into_simple()
+            .unwrap();
+        assert!(matches!(
+            api.lookup_credits().await.map_err(ApiError::into_inner),
+            Err(ApiError::BadCredentials)
+        ));
+
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .into_simple()
+            .unwrap();
+        server.fail_next(500);
+        let err = api.lookup_credits().await.unwrap_err();
+        assert!(matches!(err.inner(), ApiError::ServerError));
+        let request_id = err.request_id().unwrap().to_string();
+        assert!(err.to_string().contains(&request_id));
+        assert_eq!(server.request_ids().last(), Some(&request_id));
+
+        let err = api
+            .with_deadline(Instant::now())
+            .lookup_credits()
+            .await
+            .unwrap_err();
+        assert!(matches!(err.inner(), ApiError::DeadlineExceeded));
+        assert_eq!(
+            api.with_timeout(Duration::from_secs(10))
+                .lookup_credits()
+                .await
+                .unwrap(),
+            DEFAULT_CREDITS
+        );
+
+        server.set_credits(0);
+        assert!(matches!(
+            api.send(&Recipient::new_id("ECHOECHO"), "hi")
+                .await
+                .map_err(ApiError::into_inner),
+            Err(ApiError::NoCredits)
+        ));
+        server.set_credits(1);
+        api.send(&Recipient::new_id("ECHOECHO"), "hi")
+            .await
+            .unwrap();
+        assert!(matches!(
+            api.send(&Recipient::new_id("UNKNOWN1"), "hi")
+                .await
+                .map_err(ApiError::into_inner),
+            Err(ApiError::BadSenderOrRecipient)
+        ));
+        assert_eq!(server.credits(), 0);
+    }
+}
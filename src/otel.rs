@@ -0,0 +1,195 @@
+//! OpenTelemetry spans for the gateway requests and the webhook server.
+//!
+//! The spans are created with the global tracer provider and the trace
+//! context is propagated with the global text map propagator, both of which
+//! are configured by the application (e.g. with `opentelemetry_sdk`).
+
+use std::borrow::Cow;
+
+#[cfg(feature = "bot")]
+use opentelemetry::propagation::Extractor;
+use opentelemetry::{
+    global,
+    propagation::Injector,
+    trace::{SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Request, RequestBuilder, StatusCode,
+};
+
+use crate::{errors::ApiError, types::RequestId};
+
+/// The name of the tracer.
+const TRACER_NAME: &str = "threema-gateway";
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(feature = "bot")]
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+#[cfg(feature = "bot")]
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+/// Return the span name of a gateway request.
+///
+/// Only the first path segment is used (e.g. `GET /lookup`), the rest may
+/// contain Threema IDs or phone numbers.
+fn client_span_name(request: &Request) -> String {
+    let endpoint = request
+        .url()
+        .path_segments()
+        .and_then(|mut segments| segments.next())
+        .unwrap_or_default();
+    format!("{} /{}", request.method(), endpoint)
+}
+
+/// Start a client span for a gateway request and add the trace context
+/// headers to the request.
+pub(crate) fn start_client_span(
+    request: RequestBuilder,
+    request_id: &RequestId,
+) -> Result<(RequestBuilder, Context), ApiError> {
+    let (client, request) = request.build_split();
+    let mut request = request?;
+
+    let tracer = global::tracer(TRACER_NAME);
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", request.method().to_string()),
+        KeyValue::new("threema.request_id", request_id.to_string()),
+    ];
+    if let Some(host) = request.url().host_str() {
+        attributes.push(KeyValue::new("server.address", host.to_string()));
+    }
+    let span = tracer
+        .span_builder(client_span_name(&request))
+        .with_kind(SpanKind::Client)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &Context::current());
+    let cx = Context::current_with_span(span);
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut HeaderInjector(request.headers_mut()))
+    });
+    Ok((RequestBuilder::from_parts(client, request), cx))
+}
+
+/// Record the status code of the response in the span of `cx`.
+pub(crate) fn record_status(cx: &Context, status: StatusCode) {
+    cx.span().set_attribute(KeyValue::new(
+        "http.response.status_code",
+        i64::from(status.as_u16()),
+    ));
+}
+
+/// End the client span of `cx`, marking it as failed if the request failed.
+pub(crate) fn end_client_span(cx: &Context, error: Option<&ApiError>) {
+    let span = cx.span();
+    if let Some(e) = error {
+        span.set_status(Status::error(Cow::Owned(e.to_string())));
+    }
+    span.end();
+}
+
+/// Start a server span for a request to the webhook server, as child of
+/// the trace context in the request headers.
+#[cfg(feature = "bot")]
+pub(crate) fn start_server_span(method: &str, path: &str, headers: &HeaderMap) -> Context {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(method.to_string())
+        .with_kind(SpanKind::Server)
+        .with_attributes([
+            KeyValue::new("http.request.method", method.to_string()),
+            KeyValue::new("url.path", path.to_string()),
+        ])
+        .start_with_context(&tracer, &parent);
+    parent.with_span(span)
+}
+
+/// End the server span of `cx` with the status code of the response.
+#[cfg(feature = "bot")]
+pub(crate) fn end_server_span(cx: &Context, status: StatusCode) {
+    record_status(cx, status);
+    let span = cx.span();
+    if status.is_server_error() {
+        span.set_status(Status::error(Cow::Borrowed("")));
+    }
+    span.end();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_span_name() {
+        let client = reqwest::Client::new();
+        let request = client
+            .get("https://msgapi.threema.ch/lookup/phone/41791234567?from=*3MAGWID")
+            .build()
+            .unwrap();
+        assert_eq!(client_span_name(&request), "GET /lookup");
+    }
+
+    #[test]
+    fn test_header_injector() {
+        let mut headers = HeaderMap::new();
+        HeaderInjector(&mut headers).set("traceparent", "00-abc-def-01".to_string());
+        HeaderInjector(&mut headers).set("invalid header", "x".to_string());
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["traceparent"], "00-abc-def-01");
+    }
+
+    #[test]
+    fn test_propagation() {
+        use opentelemetry::propagation::{
+            text_map_propagator::FieldIter, Extractor, TextMapPropagator,
+        };
+
+        #[derive(Debug)]
+        struct FixedPropagator;
+
+        impl TextMapPropagator for FixedPropagator {
+            fn inject_context(&self, _cx: &Context, injector: &mut dyn Injector) {
+                injector.set("x-trace", "42".to_string());
+            }
+
+            fn extract_with_context(&self, cx: &Context, _extractor: &dyn Extractor) -> Context {
+                cx.clone()
+            }
+
+            fn fields(&self) -> FieldIter<'_> {
+                FieldIter::new(&[])
+            }
+        }
+
+        global::set_text_map_propagator(FixedPropagator);
+        let request = reqwest::Client::new().post("https://msgapi.threema.ch/send_e2e");
+        let (request, cx) = start_client_span(request, &RequestId::generate()).unwrap();
+        end_client_span(&cx, None);
+        let request = request.build().unwrap();
+        assert_eq!(request.headers()["x-trace"], "42");
+    }
+}
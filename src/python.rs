@@ -0,0 +1,407 @@
+//! Python bindings based on [PyO3](https://pyo3.rs/).
+//!
+//! This module defines the `threema_gateway` Python module with the classes
+//! `SimpleApi` and `E2eApi`. All methods that talk to the gateway are
+//! coroutines that run on a tokio runtime managed by
+//! [pyo3-async-runtimes](https://docs.rs/pyo3-async-runtimes):
+//!
+//! ```python
+//! import asyncio
+//! import threema_gateway
+//!
+//! async def main():
+//!     api = threema_gateway.E2eApi("*3MAGWID", "secret", "private-key")
+//!     message_id = await api.send_text("ECHOECHO", "Hello!")
+//!
+//! asyncio.run(main())
+//! ```
+//!
+//! To build the module, use [maturin](https://www.maturin.rs/) and enable the
+//! `extension-module` feature of PyO3:
+//!
+//! ```text
+//! maturin build --release --features python,pyo3/extension-module
+//! ```
+//!
+//! Errors are raised as `threema_gateway.GatewayError`, failed MAC checks of
+//! incoming messages as its subclass `threema_gateway.InvalidMacError`.
+
+use std::fmt::Display;
+
+use pyo3::{
+    create_exception,
+    exceptions::PyException,
+    prelude::*,
+    types::{PyBytes, PyDict},
+};
+use pyo3_async_runtimes::tokio::future_into_py;
+
+use crate::{
+    api::ApiBuilder,
+    connection::Recipient,
+    crypto::{FileData, RecipientKey},
+    errors::ApiError,
+    lookup::{Capabilities, LookupCriterion},
+    receive::IncomingMessage,
+    types::{FileSendOptions, MessageType},
+};
+
+create_exception!(
+    threema_gateway,
+    GatewayError,
+    PyException,
+    "An error returned by the Threema Gateway library."
+);
+create_exception!(
+    threema_gateway,
+    InvalidMacError,
+    GatewayError,
+    "The MAC of an incoming message is invalid."
+);
+
+/// Convert an error into a `GatewayError`.
+fn gateway_error(e: impl Display) -> PyErr {
+    GatewayError::new_err(e.to_string())
+}
+
+/// Convert an [`ApiError`] into a `GatewayError` or `InvalidMacError`.
+fn api_error(e: ApiError) -> PyErr {
+    match e.inner() {
+        ApiError::InvalidMac => InvalidMacError::new_err(e.to_string()),
+        _ => gateway_error(e),
+    }
+}
+
+/// Parse the lookup criterion `kind` (`phone`, `phone_hash`, `email` or
+/// `email_hash`).
+fn lookup_criterion(kind: &str, value: String) -> PyResult<LookupCriterion> {
+    Ok(match kind {
+        "phone" => LookupCriterion::Phone(value),
+        "phone_hash" => LookupCriterion::PhoneHash(value),
+        "email" => LookupCriterion::Email(value),
+        "email_hash" => LookupCriterion::EmailHash(value),
+        _ => return Err(gateway_error(format!("invalid lookup criterion: {}", kind))),
+    })
+}
+
+/// Convert [`Capabilities`] into a dict.
+fn capabilities_dict(capabilities: Capabilities) -> PyResult<Py<PyDict>> {
+    Python::with_gil(|py| {
+        let dict = PyDict::new(py);
+        dict.set_item("text", capabilities.text)?;
+        dict.set_item("image", capabilities.image)?;
+        dict.set_item("video", capabilities.video)?;
+        dict.set_item("audio", capabilities.audio)?;
+        dict.set_item("file", capabilities.file)?;
+        dict.set_item("other", capabilities.other)?;
+        Ok(dict.unbind())
+    })
+}
+
+/// Convert a byte vector into `bytes`.
+fn bytes(data: &[u8]) -> Py<PyBytes> {
+    Python::with_gil(|py| PyBytes::new(py, data).unbind())
+}
+
+/// Define a Python API class with the methods available on both the simple
+/// and the e2e API objects, and the additional `$methods`.
+macro_rules! py_api_class {
+    ($name:ident, { $($methods:tt)* }) => {
+        #[pymethods]
+        impl $name {
+            /// Look up the public key of a Threema ID (32 bytes).
+            fn lookup_pubkey<'py>(&self, py: Python<'py>, id: String) -> PyResult<Bound<'py, PyAny>> {
+                let api = self.inner.clone();
+                future_into_py(py, async move {
+                    let key = api.lookup_pubkey(&id).await.map_err(api_error)?;
+                    Ok(bytes(key.0.as_bytes()))
+                })
+            }
+
+            /// Look up a Threema ID. `kind` is one of `phone`, `phone_hash`,
+            /// `email` or `email_hash`.
+            fn lookup_id<'py>(
+                &self,
+                py: Python<'py>,
+                kind: &str,
+                value: String,
+            ) -> PyResult<Bound<'py, PyAny>> {
+                let api = self.inner.clone();
+                let criterion = lookup_criterion(kind, value)?;
+                future_into_py(py, async move {
+                    api.lookup_id(&criterion).await.map_err(api_error)
+                })
+            }
+
+            /// Look up the capabilities of a Threema ID as dict.
+            fn lookup_capabilities<'py>(
+                &self,
+                py: Python<'py>,
+                id: String,
+            ) -> PyResult<Bound<'py, PyAny>> {
+                let api = self.inner.clone();
+                future_into_py(py, async move {
+                    let capabilities = api.lookup_capabilities(&id).await.map_err(api_error)?;
+                    capabilities_dict(capabilities)
+                })
+            }
+
+            /// Look up the remaining credits.
+            fn lookup_credits<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+                let api = self.inner.clone();
+                future_into_py(py, async move { api.lookup_credits().await.map_err(api_error) })
+            }
+
+            $($methods)*
+        }
+    };
+}
+
+/// The simple API (with transport encryption only).
+#[pyclass(name = "SimpleApi", module = "threema_gateway", frozen)]
+pub struct PySimpleApi {
+    inner: crate::SimpleApi,
+}
+
+py_api_class!(PySimpleApi, {
+    /// Create an API object for the specified gateway ID. If `endpoint` is
+    /// not set, the default API endpoint is used.
+    #[new]
+    #[pyo3(signature = (id, secret, endpoint=None))]
+    fn new(id: &str, secret: &str, endpoint: Option<String>) -> PyResult<Self> {
+        let mut builder = ApiBuilder::new(id, secret);
+        if let Some(endpoint) = endpoint {
+            builder = builder.with_custom_endpoint(endpoint);
+        }
+        Ok(PySimpleApi {
+            inner: builder.into_simple().map_err(gateway_error)?,
+        })
+    }
+
+    /// Send a text message and return the hex encoded message ID.
+    ///
+    /// The recipient is a Threema ID, an email address or a phone number.
+    fn send<'py>(&self, py: Python<'py>, to: &str, text: String) -> PyResult<Bound<'py, PyAny>> {
+        let api = self.inner.clone();
+        let to: Recipient<'static> = to.parse().map_err(gateway_error)?;
+        future_into_py(py, async move {
+            let result = api.send(&to, &text).await.map_err(api_error)?;
+            Ok(result.message_id.to_string())
+        })
+    }
+});
+
+/// The E2E API (with end-to-end encryption).
+#[pyclass(name = "E2eApi", module = "threema_gateway", frozen)]
+pub struct PyE2eApi {
+    inner: crate::E2eApi,
+}
+
+py_api_class!(PyE2eApi, {
+    /// Create an API object for the specified gateway ID.
+    ///
+    /// `private_key` is the hex encoded private key, optionally prefixed with
+    /// `private:`. If `endpoint` is not set, the default API endpoint is used.
+    #[new]
+    #[pyo3(signature = (id, secret, private_key, endpoint=None))]
+    fn new(id: &str, secret: &str, private_key: &str, endpoint: Option<String>) -> PyResult<Self> {
+        let mut builder = ApiBuilder::new(id, secret)
+            .with_private_key_str(private_key)
+            .map_err(gateway_error)?;
+        if let Some(endpoint) = endpoint {
+            builder = builder.with_custom_endpoint(endpoint);
+        }
+        Ok(PyE2eApi {
+            inner: builder.into_e2e().map_err(gateway_error)?,
+        })
+    }
+
+    /// The hex encoded public key of the gateway identity.
+    #[getter]
+    fn public_key(&self) -> String {
+        self.inner.public_key_hex()
+    }
+
+    /// Encrypt and send a text message and return the hex encoded message
+    /// ID.
+    fn send_text<'py>(
+        &self,
+        py: Python<'py>,
+        to: String,
+        text: String,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let api = self.inner.clone();
+        future_into_py(py, async move {
+            let result = api.send_text(&to, &text).await.map_err(api_error)?;
+            Ok(result.message_id.to_string())
+        })
+    }
+
+    /// Encrypt, upload and send a file and return the hex encoded message
+    /// ID.
+    #[pyo3(signature = (to, data, media_type=None, file_name=None, caption=None))]
+    fn send_file<'py>(
+        &self,
+        py: Python<'py>,
+        to: String,
+        data: Vec<u8>,
+        media_type: Option<String>,
+        file_name: Option<String>,
+        caption: Option<String>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let api = self.inner.clone();
+        let options = FileSendOptions {
+            media_type,
+            file_name,
+            description: caption,
+            delivery_receipts: true,
+            ..Default::default()
+        };
+        future_into_py(py, async move {
+            let recipient_key = api.resolve_public_key(&to).await.map_err(api_error)?;
+            let file = FileData {
+                file: data,
+                thumbnail: None,
+            };
+            let result = api
+                .send_file(&to, &recipient_key, file, options)
+                .await
+                .map_err(api_error)?;
+            Ok(result.message_id.to_string())
+        })
+    }
+
+    /// Validate the MAC of an incoming callback request body, look up the
+    /// public key of the sender and decrypt the message.
+    fn receive<'py>(&self, py: Python<'py>, body: Vec<u8>) -> PyResult<Bound<'py, PyAny>> {
+        let api = self.inner.clone();
+        future_into_py(py, async move {
+            let msg = api.decode_incoming_message(body).map_err(api_error)?;
+            let sender_key = api.resolve_public_key(&msg.from).await.map_err(api_error)?;
+            decrypt(&api, msg, &sender_key)
+        })
+    }
+
+    /// Like `receive`, but with the public key of the sender (32 bytes)
+    /// instead of looking it up.
+    fn receive_with_key(&self, body: Vec<u8>, sender_key: Vec<u8>) -> PyResult<ReceivedMessage> {
+        let sender_key = RecipientKey::from_bytes(&sender_key).map_err(gateway_error)?;
+        let msg = self
+            .inner
+            .decode_incoming_message(body)
+            .map_err(api_error)?;
+        decrypt(&self.inner, msg, &sender_key)
+    }
+});
+
+/// A decrypted incoming message.
+#[pyclass(module = "threema_gateway", frozen, get_all)]
+#[derive(Debug)]
+pub struct ReceivedMessage {
+    /// Sender identity.
+    #[pyo3(name = "sender")]
+    pub from: String,
+    /// The gateway ID the message is addressed to.
+    pub to: String,
+    /// The hex encoded message ID.
+    pub message_id: String,
+    /// Message date set by the sender (UNIX timestamp).
+    pub date: u64,
+    /// Public nickname of the sender, if set.
+    pub nickname: Option<String>,
+    /// The message type byte.
+    pub message_type: u8,
+    /// The message payload (without the message type byte and padding).
+    pub data: Py<PyBytes>,
+    /// The text of the message, if it is a text message.
+    pub text: Option<String>,
+}
+
+#[pymethods]
+impl ReceivedMessage {
+    fn __repr__(&self) -> String {
+        format!(
+            "ReceivedMessage(sender={:?}, message_id={:?}, message_type={})",
+            self.from, self.message_id, self.message_type
+        )
+    }
+}
+
+/// Decrypt an incoming message.
+fn decrypt(
+    api: &crate::E2eApi,
+    msg: IncomingMessage,
+    sender_key: &RecipientKey,
+) -> PyResult<ReceivedMessage> {
+    let (message_type, data) = api
+        .decrypt_incoming_payload(&msg, sender_key)
+        .map_err(api_error)?;
+    let text = match message_type {
+        MessageType::Text => Some(String::from_utf8_lossy(&data).into_owned()),
+        _ => None,
+    };
+    Ok(ReceivedMessage {
+        from: msg.from,
+        to: msg.to,
+        message_id: msg.message_id,
+        date: msg.date as u64,
+        nickname: msg.nickname,
+        message_type: message_type.into(),
+        data: bytes(&data),
+        text,
+    })
+}
+
+/// The `threema_gateway` Python module.
+#[pymodule]
+fn threema_gateway(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PySimpleApi>()?;
+    m.add_class::<PyE2eApi>()?;
+    m.add_class::<ReceivedMessage>()?;
+    m.add("GatewayError", m.py().get_type::<GatewayError>())?;
+    m.add("InvalidMacError", m.py().get_type::<InvalidMacError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn api() -> PyE2eApi {
+        PyE2eApi::new(
+            test_support::GATEWAY_ID,
+            test_support::API_SECRET,
+            test_support::GATEWAY_PRIVATE_KEY,
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_receive_with_key() {
+        pyo3::prepare_freethreaded_python();
+        let msg = api()
+            .receive_with_key(
+                test_support::CALLBACK_BODY.into(),
+                test_support::recipient_public_key().as_bytes().to_vec(),
+            )
+            .unwrap();
+        assert_eq!(msg.from, test_support::RECIPIENT_ID);
+        assert_eq!(msg.message_id, test_support::CALLBACK_MESSAGE_ID);
+        assert_eq!(msg.text.as_deref(), Some(test_support::TEXT_MESSAGE));
+    }
+
+    #[test]
+    fn test_invalid_mac() {
+        pyo3::prepare_freethreaded_python();
+        let body = test_support::CALLBACK_BODY.replace("mac=b", "mac=c");
+        let err = api()
+            .receive_with_key(
+                body.into_bytes(),
+                test_support::recipient_public_key().as_bytes().to_vec(),
+            )
+            .unwrap_err();
+        Python::with_gil(|py| assert!(err.is_instance_of::<InvalidMacError>(py)));
+    }
+}
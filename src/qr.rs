@@ -0,0 +1,105 @@
+//! Rendering of the QR code used to verify the public key of the gateway
+//! identity.
+//!
+//! The Threema apps can scan this QR code to mark the gateway identity as
+//! verified (three dots). The payload is the same as returned by
+//! [`E2eApi::qr_payload`].
+
+use png::{BitDepth, ColorType, Encoder};
+use qrcode::{render::svg, Color, EcLevel, QrCode};
+
+use crate::api::E2eApi;
+
+/// Number of light modules around the code, as required by the QR code
+/// specification.
+const QUIET_ZONE: usize = 4;
+
+/// Encode the payload as QR code.
+fn encode(payload: &str) -> QrCode {
+    // The payload has a fixed length of less than 80 bytes, which always fits
+    // into a QR code.
+    QrCode::with_error_correction_level(payload, EcLevel::M).expect("QR code payload too long")
+}
+
+impl E2eApi {
+    /// Render the QR code for the verification of the public key of the
+    /// gateway identity as SVG document.
+    ///
+    /// The image is at least `min_size` pixels wide and high.
+    pub fn qr_code_svg(&self, min_size: u32) -> String {
+        encode(&self.qr_payload())
+            .render::<svg::Color>()
+            .min_dimensions(min_size, min_size)
+            .build()
+    }
+
+    /// Render the QR code for the verification of the public key of the
+    /// gateway identity as grayscale PNG image.
+    ///
+    /// Every module of the code is rendered as square of `module_size`
+    /// pixels.
+    pub fn qr_code_png(&self, module_size: u32) -> Vec<u8> {
+        let code = encode(&self.qr_payload());
+        let module_size = module_size.max(1) as usize;
+        let modules = code.width() + 2 * QUIET_ZONE;
+        let size = modules * module_size;
+        let colors = code.to_colors();
+
+        let mut pixels = vec![0xff; size * size];
+        for (i, color) in colors.iter().enumerate() {
+            if *color != Color::Dark {
+                continue;
+            }
+            let x = (i % code.width() + QUIET_ZONE) * module_size;
+            let y = (i / code.width() + QUIET_ZONE) * module_size;
+            for row in y..y + module_size {
+                pixels[row * size + x..row * size + x + module_size].fill(0);
+            }
+        }
+
+        // Writing to a `Vec` cannot fail and the image data has the size
+        // declared in the header, so encoding is infallible.
+        let mut png = Vec::new();
+        let mut encoder = Encoder::new(&mut png, size as u32, size as u32);
+        encoder.set_color(ColorType::Grayscale);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header().expect("Could not write PNG header");
+        writer
+            .write_image_data(&pixels)
+            .expect("Could not write PNG data");
+        writer.finish().expect("Could not finish PNG");
+        png
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn test_qr_code_svg() {
+        let api = test_support::api_builder().into_e2e().unwrap();
+        let svg = api.qr_code_svg(200);
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.contains("<svg"));
+    }
+
+    #[test]
+    fn test_qr_code_png() {
+        let api = test_support::api_builder().into_e2e().unwrap();
+        let png = api.qr_code_png(4);
+        let decoder = png::Decoder::new(png.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+
+        let modules = encode(&api.qr_payload()).width() + 2 * QUIET_ZONE;
+        assert_eq!(info.width as usize, modules * 4);
+        assert_eq!(info.height, info.width);
+        // The quiet zone is light, the top left finder pattern is dark.
+        assert_eq!(pixels[0], 0xff);
+        let offset = QUIET_ZONE * 4;
+        assert_eq!(pixels[offset * info.width as usize + offset], 0);
+    }
+}
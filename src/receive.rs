@@ -1,17 +1,19 @@
 //! Code related to incoming messages received from Threema Gateway.
 
-use std::{borrow::Cow, collections::HashMap};
+use std::{borrow::Cow, collections::HashMap, fmt};
 
-use crypto_box::{aead::Aead, PublicKey, SalsaBox, SecretKey};
-use crypto_secretbox::{aead::Payload, Nonce};
+use crypto_box::PublicKey;
+use crypto_secretbox::Nonce;
 use data_encoding::HEXLOWER_PERMISSIVE;
 use hmac::{Hmac, Mac};
 use serde::{Deserialize, Deserializer};
 use sha2::Sha256;
 
 use crate::{
-    crypto::NONCE_SIZE,
+    crypto::{decrypt_raw, remove_padding, NONCE_SIZE},
     errors::{ApiError, CryptoError},
+    key_provider::KeyProvider,
+    redact::Redacted,
 };
 
 type HmacSha256 = Hmac<Sha256>;
@@ -40,7 +42,8 @@ where
 ///
 /// - API docs: <https://gateway.threema.ch/de/developer/api>
 /// - E2E message format docs: <https://gateway.threema.ch/de/developer/e2e>
-#[derive(Debug, serde::Deserialize)]
+#[derive(serde::Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[serde(rename_all = "camelCase")]
 pub struct IncomingMessage {
     /// Sender identity (8 characters)
@@ -62,24 +65,192 @@ pub struct IncomingMessage {
     pub nickname: Option<String>,
 }
 
+/// Options to sanitize the `nickname` field of incoming messages.
+///
+/// The nickname is chosen by the sender, so it must be treated as untrusted
+/// input before writing it to logs or showing it in a UI. By default, the
+/// nickname is passed through unchanged.
+///
+/// Note that invalid UTF-8 sequences in the nickname are always replaced with
+/// `U+FFFD REPLACEMENT CHARACTER` when parsing the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NicknamePolicy {
+    /// Remove leading and trailing whitespace.
+    pub trim: bool,
+    /// Remove control characters (e.g. line breaks or escape sequences).
+    pub strip_control_chars: bool,
+    /// Truncate the nickname to at most this many characters.
+    pub max_chars: Option<usize>,
+}
+
+impl NicknamePolicy {
+    /// A policy that trims whitespace, removes control characters and
+    /// truncates the nickname to 32 characters.
+    pub fn strict() -> Self {
+        NicknamePolicy {
+            trim: true,
+            strip_control_chars: true,
+            max_chars: Some(32),
+        }
+    }
+
+    /// Sanitize a nickname according to this policy.
+    pub fn sanitize(&self, nickname: &str) -> String {
+        let mut sanitized: String = if self.strip_control_chars {
+            nickname.chars().filter(|c| !c.is_control()).collect()
+        } else {
+            nickname.to_string()
+        };
+        if self.trim {
+            sanitized = sanitized.trim().to_string();
+        }
+        if let Some(max_chars) = self.max_chars {
+            if let Some((index, _)) = sanitized.char_indices().nth(max_chars) {
+                sanitized.truncate(index);
+                if self.trim {
+                    sanitized.truncate(sanitized.trim_end().len());
+                }
+            }
+        }
+        sanitized
+    }
+}
+
+impl fmt::Debug for IncomingMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IncomingMessage")
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .field("message_id", &self.message_id)
+            .field("date", &self.date)
+            .field("nonce", &Redacted(&self.nonce))
+            .field("box_data", &Redacted(&self.box_data))
+            .field("nickname", &self.nickname)
+            .finish()
+    }
+}
+
 impl IncomingMessage {
+    /// The default maximum size of a callback request body in bytes.
+    ///
+    /// The box is limited to 4000 bytes by the protocol, i.e. 8000 hex
+    /// characters. The limit leaves room for the other fields and the
+    /// nickname.
+    pub const DEFAULT_MAX_SIZE: usize = 16 * 1024;
+
     /// Deserialize an incoming Threema Gateway message in
     /// `application/x-www-form-urlencoded` format.
     ///
     /// This will validate the MAC. If the MAC is invalid,
-    /// [`ApiError::InvalidMac`] will be returned.
+    /// [`ApiError::InvalidMac`] will be returned. Bodies larger than
+    /// [`DEFAULT_MAX_SIZE`](Self::DEFAULT_MAX_SIZE) are rejected with
+    /// [`ApiError::BodyTooLarge`] before parsing them, see
+    /// [`from_urlencoded_bytes_with_limit`](Self::from_urlencoded_bytes_with_limit).
     ///
     /// Note: You should probably not use this directly, but instead use
     /// [`E2eApi::decode_incoming_message`](crate::E2eApi::decode_incoming_message)!
     pub fn from_urlencoded_bytes(
         bytes: impl AsRef<[u8]>,
         api_secret: &str,
+    ) -> Result<Self, ApiError> {
+        Self::from_urlencoded_bytes_with_limit(bytes, api_secret, Self::DEFAULT_MAX_SIZE)
+    }
+
+    /// Like [`from_urlencoded_bytes`](Self::from_urlencoded_bytes), but
+    /// with a custom maximum body size in bytes.
+    pub fn from_urlencoded_bytes_with_limit(
+        bytes: impl AsRef<[u8]>,
+        api_secret: &str,
+        max_size: usize,
     ) -> Result<Self, ApiError> {
         let bytes = bytes.as_ref();
+        if bytes.len() > max_size {
+            return Err(ApiError::BodyTooLarge {
+                size: bytes.len(),
+                limit: max_size,
+            });
+        }
 
         // Unfortunately we need to parse the urlencoding twice, first to
         // validate the MAC, then to deserialize the data.
-        let values: HashMap<Cow<str>, Cow<str>> = form_urlencoded::parse(bytes).collect();
+        Self::verify_mac(bytes, api_secret)?;
+
+        // MAC is valid, we can now deserialize
+        Self::from_urlencoded_bytes_unverified(bytes)
+    }
+
+    /// Deserialize an incoming Threema Gateway message that was re-encoded
+    /// as JSON object, e.g. by a queue or an API gateway in front of the
+    /// callback server.
+    ///
+    /// The object must contain the same fields as the form encoded callback
+    /// body (including `mac`). The values must be strings, except for
+    /// `date`, which may also be a number. The MAC is validated in the same
+    /// way as by [`from_urlencoded_bytes`](Self::from_urlencoded_bytes), and
+    /// the same size limit applies.
+    pub fn from_json_bytes(bytes: impl AsRef<[u8]>, api_secret: &str) -> Result<Self, ApiError> {
+        Self::from_json_bytes_with_limit(bytes, api_secret, Self::DEFAULT_MAX_SIZE)
+    }
+
+    /// Like [`from_json_bytes`](Self::from_json_bytes), but with a custom
+    /// maximum body size in bytes.
+    pub fn from_json_bytes_with_limit(
+        bytes: impl AsRef<[u8]>,
+        api_secret: &str,
+        max_size: usize,
+    ) -> Result<Self, ApiError> {
+        let bytes = bytes.as_ref();
+        if bytes.len() > max_size {
+            return Err(ApiError::BodyTooLarge {
+                size: bytes.len(),
+                limit: max_size,
+            });
+        }
+
+        // Convert the object to the form encoding, so that the MAC is
+        // validated and the fields are parsed exactly like in callbacks sent
+        // by the gateway.
+        let fields: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(bytes)
+            .map_err(|e| ApiError::ParseError(format!("Could not parse message: {}", e)))?;
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        for (name, value) in &fields {
+            match value {
+                serde_json::Value::String(value) => form.append_pair(name, value),
+                serde_json::Value::Number(value) => form.append_pair(name, &value.to_string()),
+                serde_json::Value::Null => continue,
+                _ => {
+                    return Err(ApiError::ParseError(format!(
+                        "Invalid value for field {}",
+                        name
+                    )))
+                }
+            };
+        }
+        Self::from_urlencoded_bytes_with_limit(form.finish(), api_secret, usize::MAX)
+    }
+
+    /// Deserialize an incoming Threema Gateway message in
+    /// `application/x-www-form-urlencoded` format **without validating the
+    /// MAC**.
+    ///
+    /// The message may have been forged or tampered with! This is only meant
+    /// for test tooling and for the offline analysis of captured callback
+    /// bodies when the API secret is not available. To process callback
+    /// requests, use [`from_urlencoded_bytes`](Self::from_urlencoded_bytes).
+    pub fn from_urlencoded_bytes_unverified(bytes: impl AsRef<[u8]>) -> Result<Self, ApiError> {
+        serde_urlencoded::from_bytes(bytes.as_ref())
+            .map_err(|e| ApiError::ParseError(format!("Could not parse message: {}", e)))
+    }
+
+    /// Validate the MAC of an incoming Threema Gateway message in
+    /// `application/x-www-form-urlencoded` format, without deserializing
+    /// the message.
+    ///
+    /// This can be used to cheaply reject forged callback requests before
+    /// handing them to the application. If the MAC is invalid,
+    /// [`ApiError::InvalidMac`] will be returned.
+    pub fn verify_mac(bytes: impl AsRef<[u8]>, api_secret: &str) -> Result<(), ApiError> {
+        let values: HashMap<Cow<str>, Cow<str>> = form_urlencoded::parse(bytes.as_ref()).collect();
 
         // Decode MAC
         let mac_hex = values
@@ -114,9 +285,18 @@ impl IncomingMessage {
             return Err(ApiError::InvalidMac);
         }
 
-        // MAC is valid, we can now deserialize
-        serde_urlencoded::from_bytes(bytes)
-            .map_err(|e| ApiError::ParseError(format!("Could not parse message: {}", e)))
+        Ok(())
+    }
+
+    /// Sanitize the nickname according to the specified policy.
+    ///
+    /// If the sanitized nickname is empty, it is removed.
+    pub fn sanitize_nickname(&mut self, policy: &NicknamePolicy) {
+        self.nickname = self
+            .nickname
+            .as_deref()
+            .map(|nickname| policy.sanitize(nickname))
+            .filter(|nickname| !nickname.is_empty());
     }
 
     /// Decrypt the box using the specified keys and remove padding.
@@ -132,27 +312,16 @@ impl IncomingMessage {
     pub fn decrypt_box(
         &self,
         public_key: &PublicKey,
-        private_key: &SecretKey,
+        private_key: &(impl KeyProvider + ?Sized),
     ) -> Result<Vec<u8>, CryptoError> {
         // Decode nonce
         let nonce_bytes =
             <[u8; NONCE_SIZE]>::try_from(&self.nonce[..]).map_err(|_| CryptoError::BadNonce)?;
         let nonce: Nonce = Nonce::from(nonce_bytes);
 
-        // Decrypt bytes
-        let crypto_box: SalsaBox = SalsaBox::new(public_key, private_key);
-        let mut decrypted = crypto_box
-            .decrypt(&nonce, Payload::from(self.box_data.as_ref()))
-            .map_err(|_| CryptoError::DecryptionFailed)?;
-
-        // Remove PKCS#7 style padding
-        let padding_amount = decrypted.last().cloned().ok_or(CryptoError::BadPadding)? as usize;
-        if padding_amount >= decrypted.len() {
-            return Err(CryptoError::BadPadding);
-        }
-        decrypted.resize(decrypted.len() - padding_amount, 0);
-
-        Ok(decrypted)
+        // Decrypt bytes and remove PKCS#7 style padding
+        let decrypted = decrypt_raw(&self.box_data, &nonce, public_key, private_key)?;
+        remove_padding(decrypted)
     }
 }
 
@@ -177,6 +346,114 @@ mod tests {
             assert_eq!(msg.nickname, None);
         }
 
+        #[test]
+        fn debug_redacted() {
+            let msg =
+                IncomingMessage::from_urlencoded_bytes(TEST_PAYLOAD, TEST_MAC_SECRET).unwrap();
+            let debug = format!("{:?}", msg);
+            assert!(debug.contains("ECHOECHO"));
+            assert!(debug.contains(r#"nonce: [redacted], box_data: [redacted]"#));
+        }
+
+        #[test]
+        fn success_fixture() {
+            use crate::test_support;
+
+            let msg = IncomingMessage::from_urlencoded_bytes(
+                test_support::CALLBACK_BODY,
+                test_support::API_SECRET,
+            )
+            .unwrap();
+            assert_eq!(msg.from, test_support::RECIPIENT_ID);
+            assert_eq!(msg.to, test_support::GATEWAY_ID);
+            assert_eq!(msg.message_id, test_support::CALLBACK_MESSAGE_ID);
+            let decrypted = msg
+                .decrypt_box(
+                    &test_support::recipient_public_key(),
+                    &test_support::gateway_private_key(),
+                )
+                .unwrap();
+            assert_eq!(&decrypted[1..], test_support::TEXT_MESSAGE.as_bytes());
+        }
+
+        #[test]
+        fn verify_mac() {
+            assert!(IncomingMessage::verify_mac(TEST_PAYLOAD, TEST_MAC_SECRET).is_ok());
+            assert!(matches!(
+                IncomingMessage::verify_mac(TEST_PAYLOAD, "nevergonnaletyoudown"),
+                Err(ApiError::InvalidMac)
+            ));
+            assert!(matches!(
+                IncomingMessage::verify_mac(b"from=ECHOECHO", TEST_MAC_SECRET),
+                Err(ApiError::ParseError(_))
+            ));
+        }
+
+        #[test]
+        fn json() {
+            let json = br#"{"from": "ECHOECHO", "to": "*TESTTST", "messageId": "0102030405060708", "date": 1616950936, "nonce": "ffffffffffffffffffffffffffffffffffffffffffffffff", "box": "012345abcdef", "mac": "622b362e8353658ee649a5548acecc9ce9b88384d6b7e08e212446d68455b14e", "nickname": null}"#;
+            let msg = IncomingMessage::from_json_bytes(json, TEST_MAC_SECRET).unwrap();
+            assert_eq!(msg.from, "ECHOECHO");
+            assert_eq!(msg.date, 1616950936);
+            assert_eq!(msg.box_data, vec![0x01, 0x23, 0x45, 0xab, 0xcd, 0xef]);
+            assert_eq!(msg.nickname, None);
+
+            assert!(matches!(
+                IncomingMessage::from_json_bytes(json, "nevergonnaletyoudown"),
+                Err(ApiError::InvalidMac)
+            ));
+            assert!(matches!(
+                IncomingMessage::from_json_bytes(br#"{"from": ["ECHOECHO"]}"#, TEST_MAC_SECRET),
+                Err(ApiError::ParseError(_))
+            ));
+            assert!(matches!(
+                IncomingMessage::from_json_bytes_with_limit(json, TEST_MAC_SECRET, 10),
+                Err(ApiError::BodyTooLarge { limit: 10, .. })
+            ));
+        }
+
+        #[test]
+        fn unverified() {
+            let msg = IncomingMessage::from_urlencoded_bytes_unverified(TEST_PAYLOAD).unwrap();
+            assert_eq!(msg.from, "ECHOECHO");
+            assert_eq!(msg.box_data, vec![0x01, 0x23, 0x45, 0xab, 0xcd, 0xef]);
+
+            let forged = String::from_utf8(TEST_PAYLOAD.to_vec())
+                .unwrap()
+                .replace("mac=6", "mac=7");
+            assert!(IncomingMessage::from_urlencoded_bytes(&forged, TEST_MAC_SECRET).is_err());
+            assert!(IncomingMessage::from_urlencoded_bytes_unverified(&forged).is_ok());
+        }
+
+        #[test]
+        fn size_limit() {
+            let limit = TEST_PAYLOAD.len() - 1;
+            assert!(matches!(
+                IncomingMessage::from_urlencoded_bytes_with_limit(
+                    TEST_PAYLOAD,
+                    TEST_MAC_SECRET,
+                    limit
+                ),
+                Err(ApiError::BodyTooLarge { limit: l, .. }) if l == limit
+            ));
+
+            let mut oversized = TEST_PAYLOAD.to_vec();
+            oversized.extend_from_slice(b"&nickname=");
+            oversized.resize(IncomingMessage::DEFAULT_MAX_SIZE + 1, b'a');
+            assert!(matches!(
+                IncomingMessage::from_urlencoded_bytes(&oversized, TEST_MAC_SECRET),
+                Err(ApiError::BodyTooLarge { .. })
+            ));
+        }
+
+        #[test]
+        fn invalid_utf8_nickname() {
+            let mut payload = TEST_PAYLOAD.to_vec();
+            payload.extend_from_slice(b"&nickname=Rick%FF");
+            let msg = IncomingMessage::from_urlencoded_bytes(&payload, TEST_MAC_SECRET).unwrap();
+            assert_eq!(msg.nickname.as_deref(), Some("Rick\u{FFFD}"));
+        }
+
         #[test]
         fn invalid_mac() {
             match IncomingMessage::from_urlencoded_bytes(TEST_PAYLOAD, "nevergonnaletyoudown") {
@@ -186,10 +463,54 @@ mod tests {
         }
     }
 
+    mod nickname_policy {
+        use super::*;
+
+        #[test]
+        fn default_unchanged() {
+            let policy = NicknamePolicy::default();
+            assert_eq!(policy.sanitize(" Rick\n "), " Rick\n ");
+        }
+
+        #[test]
+        fn strict() {
+            let policy = NicknamePolicy::strict();
+            assert_eq!(
+                policy.sanitize("  Rick\x1b[31m Astley\r\n"),
+                "Rick[31m Astley"
+            );
+            assert_eq!(policy.sanitize(&"ä".repeat(40)), "ä".repeat(32));
+            assert_eq!(
+                policy.sanitize(&format!("{} b", "a".repeat(31))),
+                "a".repeat(31)
+            );
+        }
+
+        #[test]
+        fn sanitize_nickname() {
+            let mut msg = IncomingMessage {
+                from: "AAAAAAAA".into(),
+                to: "*BBBBBBB".into(),
+                message_id: "00112233".into(),
+                date: 0,
+                nonce: vec![],
+                box_data: vec![],
+                nickname: Some(" \n ".into()),
+            };
+            msg.sanitize_nickname(&NicknamePolicy::default());
+            assert_eq!(msg.nickname.as_deref(), Some(" \n "));
+            msg.sanitize_nickname(&NicknamePolicy::strict());
+            assert_eq!(msg.nickname, None);
+        }
+    }
+
     mod decrypt_box {
-        use crypto_secretbox::aead::OsRng;
+        use crypto_secretbox::aead::{OsRng, Payload};
 
-        use crypto_box::aead::AeadCore;
+        use crypto_box::{
+            aead::{Aead, AeadCore},
+            SalsaBox, SecretKey,
+        };
 
         use super::*;
 
@@ -283,4 +604,41 @@ mod tests {
             assert_eq!(err, CryptoError::BadPadding);
         }
     }
+
+    #[cfg(feature = "arbitrary")]
+    mod arbitrary_roundtrip {
+        use arbitrary::{Arbitrary, Unstructured};
+        use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+        use super::*;
+        use crate::test_support;
+
+        #[test]
+        fn urlencoded() {
+            let mut rng = StdRng::seed_from_u64(0);
+            let mut data = vec![0; 1024];
+            for _ in 0..100 {
+                rng.fill_bytes(&mut data);
+                let msg = IncomingMessage::arbitrary(&mut Unstructured::new(&data)).unwrap();
+                let body = test_support::callback_body(
+                    &msg.from,
+                    &msg.to,
+                    &msg.message_id,
+                    msg.date as u64,
+                    &msg.nonce,
+                    &msg.box_data,
+                    msg.nickname.as_deref(),
+                    "secret",
+                );
+                let parsed = IncomingMessage::from_urlencoded_bytes(body, "secret").unwrap();
+                assert_eq!(parsed.from, msg.from);
+                assert_eq!(parsed.to, msg.to);
+                assert_eq!(parsed.message_id, msg.message_id);
+                assert_eq!(parsed.date, msg.date);
+                assert_eq!(parsed.nonce, msg.nonce);
+                assert_eq!(parsed.box_data, msg.box_data);
+                assert_eq!(parsed.nickname, msg.nickname);
+            }
+        }
+    }
 }
@@ -0,0 +1,25 @@
+//! Redaction of secrets in `Debug` output.
+
+use std::fmt;
+
+/// Wrapper that hides its content in `Debug` output.
+///
+/// Used in the `Debug` implementations of types holding the API secret,
+/// private keys, nonces or encrypted message data, so that these values
+/// never end up in logs.
+pub(crate) struct Redacted<T>(pub(crate) T);
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+/// Wrapper that only shows the length of its content in `Debug` output.
+pub(crate) struct RedactedBytes<'a>(pub(crate) &'a [u8]);
+
+impl fmt::Debug for RedactedBytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[redacted, {} bytes]", self.0.len())
+    }
+}
@@ -0,0 +1,123 @@
+//! Reporting of errors to an error tracker.
+//!
+//! An [`ErrorReporter`] registered with `with_error_reporter` on an API
+//! object is called for every failed send, lookup and blob transfer, and for
+//! every incoming message that could not be decoded (e.g. because of an
+//! invalid MAC) or decrypted. This allows forwarding the errors to an error
+//! tracker like Sentry without wrapping every call. By default, errors are
+//! not reported anywhere.
+//!
+//! ```no_run
+//! use threema_gateway::{
+//!     errors::ApiError,
+//!     reporting::{ErrorContext, ErrorReporter},
+//!     ApiBuilder,
+//! };
+//!
+//! struct LogReporter;
+//!
+//! impl ErrorReporter for LogReporter {
+//!     fn report(&self, error: &ApiError, context: &ErrorContext<'_>) {
+//!         eprintln!("{:?} failed: {}", context.operation, error);
+//!     }
+//! }
+//!
+//! let api = ApiBuilder::new("*3MAGWID", "secret")
+//!     .into_simple()
+//!     .unwrap()
+//!     .with_error_reporter(LogReporter);
+//! ```
+
+use std::sync::Arc;
+
+use crate::{audit::AuditOperation, errors::ApiError, types::RequestId};
+
+/// Information about the operation that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorContext<'a> {
+    /// The kind of operation.
+    pub operation: AuditOperation,
+    /// The identifier the operation refers to (the recipient, sender, lookup
+    /// criterion or blob ID), if known.
+    ///
+    /// Unlike in the [`audit`](crate::audit) events, the identifier is not
+    /// hashed. Remove it before sending the report to a third party if it
+    /// must not leave the application.
+    pub subject: Option<&'a str>,
+    /// The ID of the gateway request that failed, if the error occurred
+    /// during a request.
+    pub request_id: Option<&'a RequestId>,
+}
+
+/// Receiver of the errors of an API object.
+///
+/// The reporter is called synchronously when an operation fails, so it
+/// should not block for long.
+pub trait ErrorReporter: Send + Sync {
+    /// Report an error.
+    fn report(&self, error: &ApiError, context: &ErrorContext<'_>);
+}
+
+impl<R: ErrorReporter + ?Sized> ErrorReporter for Arc<R> {
+    fn report(&self, error: &ApiError, context: &ErrorContext<'_>) {
+        (**self).report(error, context)
+    }
+}
+
+#[cfg(all(test, feature = "mock-server"))]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{mock_server::MockServer, ApiBuilder, RecipientKey, SecretKey, SendOptions};
+
+    #[tokio::test]
+    async fn test_error_reporter() {
+        type Report = (AuditOperation, Option<String>, bool, String);
+
+        struct Reports(Mutex<Vec<Report>>);
+
+        impl ErrorReporter for Reports {
+            fn report(&self, error: &ApiError, context: &ErrorContext<'_>) {
+                self.0.lock().unwrap().push((
+                    context.operation,
+                    context.subject.map(str::to_string),
+                    context.request_id.is_some(),
+                    error.inner().to_string(),
+                ));
+            }
+        }
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        server.set_public_key("ECHOECHO", RecipientKey::from([2; 32]));
+        let reports = Arc::new(Reports(Mutex::default()));
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap()
+            .with_error_reporter(reports.clone());
+
+        let message = api
+            .encrypt_text_msg("hi", &RecipientKey::from([2; 32]))
+            .unwrap();
+        server.fail_next(500);
+        assert!(api
+            .send_with_options("ECHOECHO", &message, SendOptions::default())
+            .await
+            .is_err());
+        assert!(api.lookup_pubkey("NOBODY00").await.is_err());
+        assert!(api.decode_incoming_message(b"from=ECHOECHO").is_err());
+
+        let reports = reports.0.lock().unwrap();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].0, AuditOperation::Send);
+        assert_eq!(reports[0].1.as_deref(), Some("ECHOECHO"));
+        assert!(reports[0].2);
+        assert_eq!(reports[1].0, AuditOperation::Lookup);
+        assert_eq!(reports[1].1.as_deref(), Some("NOBODY00"));
+        assert_eq!(reports[2].0, AuditOperation::Receive);
+        assert!(!reports[2].2);
+    }
+}
@@ -0,0 +1,165 @@
+//! Retrying of failed requests.
+
+use std::{future::Future, time::Duration};
+
+use crate::errors::ApiError;
+
+/// Policy for retrying failed blob transfers.
+///
+/// Blob downloads are retried on all transient failures (connection errors,
+/// timeouts and internal server errors). Blob uploads are only retried if the
+/// connection to the server could not be established, because otherwise the
+/// server might already have accepted the upload.
+///
+/// The delay between two attempts starts at `initial_backoff` and is doubled
+/// after every failed attempt, up to `max_backoff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the initial attempt.
+    pub max_retries: u32,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between two attempts.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn no_retries() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Return the delay before the specified retry (starting at 1).
+    fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Run `operation` until it succeeds, until it fails with an error that is
+/// not retryable, or until the retries allowed by `policy` are exhausted.
+pub(crate) async fn with_retry<T, F, Fut>(
+    policy: &RetryPolicy,
+    is_retryable: impl Fn(&ApiError) -> bool,
+    mut operation: F,
+) -> Result<T, ApiError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ApiError>>,
+{
+    let mut retry = 0;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if retry < policy.max_retries && is_retryable(&e) => {
+                retry += 1;
+                let backoff = policy.backoff(retry);
+                debug!(
+                    "Request failed ({}), retrying in {} ms ({}/{})",
+                    e,
+                    backoff.as_millis(),
+                    retry,
+                    policy.max_retries
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(100), Duration::from_millis(500));
+    }
+
+    fn instant_policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let attempts = Cell::new(0);
+        let result = with_retry(
+            &instant_policy(3),
+            |_| true,
+            || async {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() < 3 {
+                    Err(ApiError::ServerError)
+                } else {
+                    Ok(42)
+                }
+            },
+        )
+        .await;
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_exhausted() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = with_retry(
+            &instant_policy(2),
+            |_| true,
+            || async {
+                attempts.set(attempts.get() + 1);
+                Err(ApiError::ServerError)
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(ApiError::ServerError)));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_if_not_retryable() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = with_retry(
+            &instant_policy(2),
+            |e| matches!(e, ApiError::ServerError),
+            || async {
+                attempts.set(attempts.get() + 1);
+                Err(ApiError::BadBlob)
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(ApiError::BadBlob)));
+        assert_eq!(attempts.get(), 1);
+    }
+}
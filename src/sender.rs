@@ -0,0 +1,297 @@
+//! Sending messages from a stream.
+//!
+//! The [`MessageSender`] implements [`Sink`] for [`OutgoingMessage`]s, so
+//! that a stream of encrypted messages can be sent with the stream
+//! combinators instead of managing the send tasks manually. Up to a
+//! configurable number of messages are sent concurrently.
+//!
+//! ```no_run
+//! use futures_util::{stream, StreamExt};
+//! use threema_gateway::{
+//!     errors::ApiError,
+//!     sender::{MessageSender, OutgoingMessage},
+//!     E2eApi, RecipientKey,
+//! };
+//!
+//! # async fn f(api: E2eApi, recipients: Vec<(String, RecipientKey)>) -> Result<(), ApiError> {
+//! let sender = MessageSender::new(api.clone()).with_max_concurrent_sends(8);
+//! stream::iter(recipients)
+//!     .map(|(id, key)| {
+//!         api.encrypt_text_msg("Maintenance tonight", &key)
+//!             .map(|message| OutgoingMessage::new(id, message))
+//!             .map_err(ApiError::from)
+//!     })
+//!     .forward(sender)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! If a message cannot be sent, the sink fails with the error of that
+//! message once the messages that are already being sent have completed.
+//! To keep track of the outcome of every message, register
+//! [`on_send`](crate::E2eApi::on_send) and
+//! [`on_error`](crate::E2eApi::on_error) callbacks on the API object.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::{stream::FuturesUnordered, Sink, StreamExt};
+
+use crate::{
+    api::E2eApi,
+    crypto::EncryptedMessage,
+    errors::ApiError,
+    types::{SendOptions, SendResult},
+};
+
+/// The default maximum number of concurrent sends.
+pub const DEFAULT_MAX_CONCURRENT_SENDS: usize = 4;
+
+type SendFuture = Pin<Box<dyn Future<Output = Result<SendResult, ApiError>> + Send>>;
+
+/// An encrypted message and its recipient.
+#[derive(Debug, Clone)]
+pub struct OutgoingMessage {
+    /// The Threema ID of the recipient.
+    pub to: String,
+    /// The message, encrypted for the recipient.
+    pub message: EncryptedMessage,
+    /// The send options.
+    pub options: SendOptions,
+}
+
+impl OutgoingMessage {
+    /// Create a message with the default [`SendOptions`].
+    pub fn new<S: Into<String>>(to: S, message: EncryptedMessage) -> Self {
+        OutgoingMessage {
+            to: to.into(),
+            message,
+            options: SendOptions::default(),
+        }
+    }
+
+    /// Set the send options.
+    pub fn with_options(mut self, options: SendOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// A [`Sink`] that sends [`OutgoingMessage`]s with
+/// [`E2eApi::send_with_options`].
+pub struct MessageSender {
+    api: E2eApi,
+    max_concurrent_sends: usize,
+    in_flight: FuturesUnordered<SendFuture>,
+    /// The first error of a completed send, returned by the next poll.
+    error: Option<ApiError>,
+}
+
+impl MessageSender {
+    /// Create a sender with the default concurrency limit.
+    pub fn new(api: E2eApi) -> Self {
+        MessageSender {
+            api,
+            max_concurrent_sends: DEFAULT_MAX_CONCURRENT_SENDS,
+            in_flight: FuturesUnordered::new(),
+            error: None,
+        }
+    }
+
+    /// Set the maximum number of messages that are sent concurrently.
+    /// Further messages are accepted once a send has completed.
+    ///
+    /// Panics if `max_concurrent_sends` is 0.
+    pub fn with_max_concurrent_sends(mut self, max_concurrent_sends: usize) -> Self {
+        assert!(max_concurrent_sends > 0, "Concurrency limit must not be 0");
+        self.max_concurrent_sends = max_concurrent_sends;
+        self
+    }
+
+    /// Drive the sends until at most `max_in_flight` are left, then return
+    /// the first error of the completed sends.
+    ///
+    /// Once a send failed, all other sends are driven to completion before
+    /// the error is returned, so that dropping the sink after the error
+    /// doesn't cancel them.
+    fn poll_sends(
+        &mut self,
+        cx: &mut Context<'_>,
+        max_in_flight: usize,
+    ) -> Poll<Result<(), ApiError>> {
+        loop {
+            let max_in_flight = match self.error {
+                Some(_) => 0,
+                None => max_in_flight,
+            };
+            match self.in_flight.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(_))) => {}
+                Poll::Ready(Some(Err(e))) => {
+                    self.error.get_or_insert(e);
+                }
+                Poll::Ready(None) => break,
+                Poll::Pending if self.in_flight.len() <= max_in_flight => break,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        match self.error.take() {
+            Some(e) => Poll::Ready(Err(e)),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl Sink<OutgoingMessage> for MessageSender {
+    type Error = ApiError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ApiError>> {
+        let max_in_flight = self.max_concurrent_sends - 1;
+        self.poll_sends(cx, max_in_flight)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: OutgoingMessage) -> Result<(), ApiError> {
+        let api = self.api.clone();
+        self.in_flight.push(Box::pin(async move {
+            api.send_with_options(&item.to, &item.message, item.options)
+                .await
+        }));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ApiError>> {
+        self.poll_sends(cx, 0)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ApiError>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl fmt::Debug for MessageSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageSender")
+            .field("api", &self.api)
+            .field("max_concurrent_sends", &self.max_concurrent_sends)
+            .field("in_flight", &self.in_flight.len())
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::task::noop_waker_ref;
+
+    use super::*;
+    use crate::{types::MessageId, ApiBuilder, SecretKey};
+
+    #[cfg(feature = "mock-server")]
+    fn api(server: &crate::mock_server::MockServer) -> E2eApi {
+        server.set_public_key("ECHOECHO", crate::RecipientKey::from([2; 32]));
+        ApiBuilder::new("*3MAGWID", "secret")
+            .with_custom_endpoint(server.url())
+            .allow_insecure_http()
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap()
+    }
+
+    #[cfg(feature = "mock-server")]
+    fn outgoing(api: &E2eApi) -> OutgoingMessage {
+        let msg = api
+            .encrypt_text_msg("hi", &crate::RecipientKey::from([2; 32]))
+            .unwrap();
+        OutgoingMessage::new("ECHOECHO", msg)
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_send_all() {
+        use futures_util::{stream, SinkExt};
+
+        use crate::mock_server::MockServer;
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let api = api(&server);
+        let messages = |count: usize| {
+            let api = api.clone();
+            stream::iter(0..count).map(move |_| Ok(outgoing(&api)))
+        };
+
+        let sender = MessageSender::new(api.clone()).with_max_concurrent_sends(2);
+        messages(5).forward(sender).await.unwrap();
+        assert_eq!(server.received_messages().len(), 5);
+
+        // The error of a failed send is returned by the sink
+        server.fail_next(400);
+        let mut sender = MessageSender::new(api.clone()).with_max_concurrent_sends(1);
+        let result = sender.send_all(&mut messages(3)).await;
+        assert!(matches!(
+            result.map_err(ApiError::into_inner),
+            Err(ApiError::BadSenderOrRecipient)
+        ));
+        assert_eq!(server.received_messages().len(), 5);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        use futures_util::SinkExt;
+
+        use crate::mock_server::MockServer;
+
+        let server = MockServer::start("*3MAGWID", "secret").await.unwrap();
+        let api = api(&server);
+        let mut sender = MessageSender::new(api.clone()).with_max_concurrent_sends(2);
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // The sends cannot complete before the test yields to the runtime
+        for _ in 0..2 {
+            assert!(matches!(
+                Pin::new(&mut sender).poll_ready(&mut cx),
+                Poll::Ready(Ok(()))
+            ));
+            Pin::new(&mut sender).start_send(outgoing(&api)).unwrap();
+        }
+        assert!(Pin::new(&mut sender).poll_ready(&mut cx).is_pending());
+        assert_eq!(sender.in_flight.len(), 2);
+
+        sender.flush().await.unwrap();
+        assert!(sender.in_flight.is_empty());
+        assert_eq!(server.received_messages().len(), 2);
+    }
+
+    #[test]
+    fn test_error_waits_for_other_sends() {
+        let api = ApiBuilder::new("*3MAGWID", "secret")
+            .with_private_key(SecretKey::from([1; 32]))
+            .into_e2e()
+            .unwrap();
+        let mut sender = MessageSender::new(api).with_max_concurrent_sends(4);
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        sender
+            .in_flight
+            .push(Box::pin(async { Err(ApiError::BadSenderOrRecipient) }));
+        sender.in_flight.push(Box::pin(async move {
+            done_rx.await.unwrap();
+            Ok(SendResult {
+                message_id: MessageId::new([1; 8]),
+            })
+        }));
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // The error is held back while the other send is in flight
+        assert!(Pin::new(&mut sender).poll_ready(&mut cx).is_pending());
+        done_tx.send(()).unwrap();
+        assert!(matches!(
+            Pin::new(&mut sender).poll_ready(&mut cx),
+            Poll::Ready(Err(ApiError::BadSenderOrRecipient))
+        ));
+        assert!(sender.in_flight.is_empty());
+    }
+}
@@ -0,0 +1,418 @@
+//! Tracking the delivery status of sent messages.
+//!
+//! A [`StatusTracker`] records the IDs of sent messages in a [`StatusStore`].
+//! When the delivery receipts sent back by the recipients are fed into the
+//! tracker, it updates the status of the corresponding messages and notifies
+//! the registered callbacks.
+//!
+//! ```no_run
+//! use threema_gateway::{
+//!     status::{DeliveryReceipt, MemoryStatusStore, StatusTracker},
+//!     E2eApi, MessageType,
+//! };
+//!
+//! # async fn f(api: E2eApi, body: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+//! let tracker = StatusTracker::new(MemoryStatusStore::default())
+//!     .on_change(|message_id, status| println!("{}: {:?}", message_id, status));
+//!
+//! // Track sent messages
+//! let result = api.send_text("ECHOECHO", "Hello").await?;
+//! tracker.track("ECHOECHO", &result).await?;
+//!
+//! // Feed incoming delivery receipts into the tracker
+//! let msg = api.decode_incoming_message(body)?;
+//! let sender_key = api.lookup_pubkey(&msg.from).await?;
+//! let data = api.decrypt_incoming_message(&msg, &sender_key)?;
+//! if let Some((&msgtype, payload)) = data.split_first() {
+//!     if MessageType::from(msgtype) == MessageType::DeliveryReceipt {
+//!         let receipt = DeliveryReceipt::from_bytes(payload)?;
+//!         tracker.process_receipt(&msg.from, &receipt).await?;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    fmt,
+    future::Future,
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::{
+    errors::ApiError,
+    types::{MessageId, SendResult},
+};
+
+/// The delivery status of a sent message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageStatus {
+    /// The message was accepted by the gateway.
+    Sent,
+    /// The message was received by the recipient's device.
+    Delivered,
+    /// The message was read by the recipient.
+    Read,
+    /// The recipient acknowledged the message.
+    Acknowledged,
+    /// The recipient declined the message.
+    Declined,
+}
+
+impl MessageStatus {
+    /// The position of the status in the message lifecycle. Acknowledged and
+    /// declined messages are on the same level, since the recipient may
+    /// change their reaction.
+    fn level(self) -> u8 {
+        match self {
+            MessageStatus::Sent => 0,
+            MessageStatus::Delivered => 1,
+            MessageStatus::Read => 2,
+            MessageStatus::Acknowledged | MessageStatus::Declined => 3,
+        }
+    }
+
+    /// Whether a message with this status may transition to `next`.
+    fn can_become(self, next: MessageStatus) -> bool {
+        next != self && next.level() >= self.level()
+    }
+}
+
+/// A delivery receipt, i.e. the payload of a message of type
+/// [`MessageType::DeliveryReceipt`](crate::MessageType::DeliveryReceipt).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryReceipt {
+    /// The status reported by the recipient.
+    pub status: MessageStatus,
+    /// The IDs of the messages the receipt refers to.
+    pub message_ids: Vec<MessageId>,
+}
+
+impl DeliveryReceipt {
+    /// Parse a delivery receipt from the decrypted message data (without the
+    /// message type byte), as returned by [`decrypt`](crate::decrypt).
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ApiError> {
+        let (status, ids) = data
+            .split_first()
+            .ok_or_else(|| ApiError::ParseError("Empty delivery receipt".to_string()))?;
+        let status = match status {
+            0x01 => MessageStatus::Delivered,
+            0x02 => MessageStatus::Read,
+            0x03 => MessageStatus::Acknowledged,
+            0x04 => MessageStatus::Declined,
+            other => {
+                return Err(ApiError::ParseError(format!(
+                    "Unknown delivery receipt status: {:#04x}",
+                    other
+                )))
+            }
+        };
+        if ids.len() % 8 != 0 {
+            return Err(ApiError::ParseError(
+                "Invalid message ID length in delivery receipt".to_string(),
+            ));
+        }
+        let message_ids = ids
+            .chunks_exact(8)
+            .map(|id| MessageId::new(id.try_into().expect("Chunk has 8 bytes")))
+            .collect();
+        Ok(DeliveryReceipt {
+            status,
+            message_ids,
+        })
+    }
+
+    /// Serialize the receipt (without the message type byte), so that it can
+    /// be encrypted with [`MessageType::DeliveryReceipt`](crate::MessageType::DeliveryReceipt).
+    ///
+    /// Return `None` if the status is [`MessageStatus::Sent`], which cannot
+    /// be reported in a receipt.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        let status = match self.status {
+            MessageStatus::Sent => return None,
+            MessageStatus::Delivered => 0x01,
+            MessageStatus::Read => 0x02,
+            MessageStatus::Acknowledged => 0x03,
+            MessageStatus::Declined => 0x04,
+        };
+        let mut data = Vec::with_capacity(1 + 8 * self.message_ids.len());
+        data.push(status);
+        for message_id in &self.message_ids {
+            data.extend_from_slice(&message_id.0);
+        }
+        Some(data)
+    }
+}
+
+/// A message recorded by the [`StatusTracker`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackedMessage {
+    /// The Threema ID the message was sent to.
+    pub recipient: String,
+    /// The current status of the message.
+    pub status: MessageStatus,
+}
+
+/// Storage for the status of sent messages, used by the [`StatusTracker`].
+pub trait StatusStore: Send + Sync {
+    /// Error returned if store operations fail
+    type Error: Error + Send + Sync + 'static;
+
+    /// Store the message with the specified ID, replacing an existing entry.
+    fn store(
+        &self,
+        message_id: &MessageId,
+        message: &TrackedMessage,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Retrieve the message with the specified ID.
+    fn load(
+        &self,
+        message_id: &MessageId,
+    ) -> impl Future<Output = Result<Option<TrackedMessage>, Self::Error>> + Send;
+}
+
+/// A [`StatusStore`] that keeps the messages in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStatusStore {
+    messages: Mutex<HashMap<MessageId, TrackedMessage>>,
+}
+
+impl MemoryStatusStore {
+    fn messages(&self) -> MutexGuard<'_, HashMap<MessageId, TrackedMessage>> {
+        self.messages
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl StatusStore for MemoryStatusStore {
+    type Error = Infallible;
+
+    async fn store(
+        &self,
+        message_id: &MessageId,
+        message: &TrackedMessage,
+    ) -> Result<(), Self::Error> {
+        self.messages().insert(*message_id, message.clone());
+        Ok(())
+    }
+
+    async fn load(&self, message_id: &MessageId) -> Result<Option<TrackedMessage>, Self::Error> {
+        Ok(self.messages().get(message_id).cloned())
+    }
+}
+
+type StatusCallback = Box<dyn Fn(&MessageId, MessageStatus) + Send + Sync>;
+
+/// Correlates delivery receipts with sent messages.
+///
+/// See the [module documentation](self) for an example.
+pub struct StatusTracker<S> {
+    store: S,
+    callbacks: Vec<StatusCallback>,
+}
+
+impl<S: StatusStore> StatusTracker<S> {
+    /// Create a tracker that records the messages in `store`.
+    pub fn new(store: S) -> Self {
+        StatusTracker {
+            store,
+            callbacks: Vec::new(),
+        }
+    }
+
+    /// Register a callback that is called whenever the status of a message
+    /// changes (including when a message is tracked).
+    pub fn on_change<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&MessageId, MessageStatus) + Send + Sync + 'static,
+    {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Return a reference to the underlying store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Record a message that was sent to `recipient`.
+    pub async fn track(&self, recipient: &str, result: &SendResult) -> Result<(), S::Error> {
+        let message = TrackedMessage {
+            recipient: recipient.to_string(),
+            status: MessageStatus::Sent,
+        };
+        self.store.store(&result.message_id, &message).await?;
+        self.notify(&result.message_id, message.status);
+        Ok(())
+    }
+
+    /// Return the status of a tracked message.
+    pub async fn status(&self, message_id: &MessageId) -> Result<Option<MessageStatus>, S::Error> {
+        Ok(self
+            .store
+            .load(message_id)
+            .await?
+            .map(|message| message.status))
+    }
+
+    /// Update the status of the messages referenced by a delivery receipt
+    /// sent by `from`.
+    ///
+    /// Messages that are not tracked or that were sent to another recipient
+    /// are ignored, as are receipts that would move a message back in its
+    /// lifecycle (e.g. from read to delivered). Return the messages whose
+    /// status changed.
+    pub async fn process_receipt(
+        &self,
+        from: &str,
+        receipt: &DeliveryReceipt,
+    ) -> Result<Vec<MessageId>, S::Error> {
+        let mut changed = Vec::new();
+        for message_id in &receipt.message_ids {
+            let mut message = match self.store.load(message_id).await? {
+                Some(message) if message.recipient == from => message,
+                Some(_) => {
+                    warn!(
+                        "Ignoring delivery receipt from {} for foreign message",
+                        from
+                    );
+                    continue;
+                }
+                None => continue,
+            };
+            if !message.status.can_become(receipt.status) {
+                continue;
+            }
+            message.status = receipt.status;
+            self.store.store(message_id, &message).await?;
+            self.notify(message_id, message.status);
+            changed.push(*message_id);
+        }
+        Ok(changed)
+    }
+
+    fn notify(&self, message_id: &MessageId, status: MessageStatus) {
+        for callback in &self.callbacks {
+            callback(message_id, status);
+        }
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for StatusTracker<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StatusTracker")
+            .field("store", &self.store)
+            .field("callbacks", &self.callbacks.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn result(id: u8) -> SendResult {
+        SendResult {
+            message_id: MessageId::new([id; 8]),
+        }
+    }
+
+    fn receipt(status: MessageStatus, ids: &[u8]) -> DeliveryReceipt {
+        DeliveryReceipt {
+            status,
+            message_ids: ids.iter().map(|id| MessageId::new([*id; 8])).collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_receipt() {
+        let mut data = vec![0x02];
+        data.extend_from_slice(&[1; 8]);
+        data.extend_from_slice(&[2; 8]);
+        assert_eq!(
+            DeliveryReceipt::from_bytes(&data).unwrap(),
+            receipt(MessageStatus::Read, &[1, 2])
+        );
+        assert_eq!(receipt(MessageStatus::Read, &[1, 2]).to_bytes(), Some(data));
+        assert_eq!(receipt(MessageStatus::Sent, &[1]).to_bytes(), None);
+
+        assert!(DeliveryReceipt::from_bytes(&[]).is_err());
+        assert!(DeliveryReceipt::from_bytes(&[0x05]).is_err());
+        assert!(DeliveryReceipt::from_bytes(&[0x01, 1, 2, 3]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_status_transitions() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let tracker = StatusTracker::new(MemoryStatusStore::default()).on_change({
+            let changes = changes.clone();
+            move |id, status| changes.lock().unwrap().push((*id, status))
+        });
+        tracker.track("ECHOECHO", &result(1)).await.unwrap();
+        tracker.track("ECHOECHO", &result(2)).await.unwrap();
+        let id = result(1).message_id;
+        assert_eq!(
+            tracker.status(&id).await.unwrap(),
+            Some(MessageStatus::Sent)
+        );
+
+        let changed = tracker
+            .process_receipt("ECHOECHO", &receipt(MessageStatus::Read, &[1, 3]))
+            .await
+            .unwrap();
+        assert_eq!(changed, vec![id]);
+        assert_eq!(
+            tracker.status(&id).await.unwrap(),
+            Some(MessageStatus::Read)
+        );
+
+        // A late delivery receipt doesn't move the message back
+        let changed = tracker
+            .process_receipt("ECHOECHO", &receipt(MessageStatus::Delivered, &[1]))
+            .await
+            .unwrap();
+        assert!(changed.is_empty());
+
+        // Acknowledged messages can still be declined
+        for status in [MessageStatus::Acknowledged, MessageStatus::Declined] {
+            tracker
+                .process_receipt("ECHOECHO", &receipt(status, &[1]))
+                .await
+                .unwrap();
+            assert_eq!(tracker.status(&id).await.unwrap(), Some(status));
+        }
+
+        assert_eq!(
+            changes.lock().unwrap().as_slice(),
+            &[
+                (id, MessageStatus::Sent),
+                (result(2).message_id, MessageStatus::Sent),
+                (id, MessageStatus::Read),
+                (id, MessageStatus::Acknowledged),
+                (id, MessageStatus::Declined),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_receipt_from_other_sender() {
+        let tracker = StatusTracker::new(MemoryStatusStore::default());
+        tracker.track("ECHOECHO", &result(1)).await.unwrap();
+        let changed = tracker
+            .process_receipt("MALLORY1", &receipt(MessageStatus::Read, &[1]))
+            .await
+            .unwrap();
+        assert!(changed.is_empty());
+        assert_eq!(
+            tracker.status(&result(1).message_id).await.unwrap(),
+            Some(MessageStatus::Sent)
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! Type erasure for the pluggable stores (caches, message history, contacts,
+//! sessions and the send ledger).
+//!
+//! The store traits use `impl Future` return types, so they are not object
+//! safe. [`shared_store!`] defines an object safe mirror of a store trait and
+//! a cheaply clonable `Shared*` wrapper around a boxed store, which can be
+//! kept in the API objects and the bot.
+
+use std::{error::Error, future::Future, pin::Pin};
+
+pub(crate) type BoxError = Box<dyn Error + Send + Sync>;
+pub(crate) type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A store, implementing the object safe mirror of its trait.
+pub(crate) struct Erased<S>(pub(crate) S);
+
+/// Define a `Shared*` wrapper for a store trait.
+///
+/// Every listed method must exist on the store trait, take only reference
+/// arguments and return `Result<$ret, Self::Error>`. The wrapper has a
+/// method with the same signature that returns the error as [`BoxError`].
+macro_rules! shared_store {
+    (
+        $(#[$attr:meta])*
+        $shared:ident($dyn:ident): $store:ident {
+            $(fn $method:ident($($arg:ident: &$ty:ty),*) -> $ret:ty;)*
+        }
+    ) => {
+        #[doc = concat!("Object safe variant of [`", stringify!($store), "`].")]
+        trait $dyn: Send + Sync {
+            $(
+                fn $method<'a>(
+                    &'a self,
+                    $($arg: &'a $ty),*
+                ) -> $crate::store::BoxFuture<'a, Result<$ret, $crate::store::BoxError>>;
+            )*
+        }
+
+        impl<S: $store> $dyn for $crate::store::Erased<S> {
+            $(
+                fn $method<'a>(
+                    &'a self,
+                    $($arg: &'a $ty),*
+                ) -> $crate::store::BoxFuture<'a, Result<$ret, $crate::store::BoxError>> {
+                    Box::pin(async move {
+                        $store::$method(&self.0, $($arg),*)
+                            .await
+                            .map_err(Into::into)
+                    })
+                }
+            )*
+        }
+
+        $(#[$attr])*
+        #[derive(Clone)]
+        pub(crate) struct $shared(std::sync::Arc<dyn $dyn>);
+
+        impl $shared {
+            pub(crate) fn new<S: $store + 'static>(store: S) -> Self {
+                $shared(std::sync::Arc::new($crate::store::Erased(store)))
+            }
+
+            $(
+                pub(crate) async fn $method(
+                    &self,
+                    $($arg: &$ty),*
+                ) -> Result<$ret, $crate::store::BoxError> {
+                    self.0.$method($($arg),*).await
+                }
+            )*
+        }
+
+        impl std::fmt::Debug for $shared {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(stringify!($shared))
+            }
+        }
+    };
+}
+
+pub(crate) use shared_store;
@@ -0,0 +1,249 @@
+//! [proptest](https://docs.rs/proptest) strategies for the data types.
+//!
+//! The strategies generate valid values (e.g. file messages that pass the
+//! [`FileMessageBuilder`] validation) and can be used to write property based
+//! tests of encode/decode invariants.
+//!
+//! ```
+//! use proptest::prelude::*;
+//! use threema_gateway::{strategies, BlobId};
+//!
+//! proptest!(|(blob_id in strategies::blob_id())| {
+//!     let parsed: BlobId = blob_id.to_string().parse().unwrap();
+//!     prop_assert_eq!(parsed, blob_id);
+//! });
+//! ```
+
+use proptest::{option, prelude::*};
+
+use crate::{
+    crypto::{Key, RecipientKey},
+    lookup::LookupCriterion,
+    test_support,
+    types::{BlobId, FileMessage, FileMessageBuilder, MessageId, MessageType, RenderingType},
+};
+
+/// A Threema ID: 8 characters, uppercase letters and digits.
+pub fn threema_id() -> impl Strategy<Value = String> {
+    "[0-9A-Z]{8}"
+}
+
+/// A gateway ID: 8 characters, starting with `*`.
+pub fn gateway_id() -> impl Strategy<Value = String> {
+    "\\*[0-9A-Z]{7}"
+}
+
+/// A random [`BlobId`].
+pub fn blob_id() -> impl Strategy<Value = BlobId> {
+    any::<[u8; 16]>().prop_map(BlobId::new)
+}
+
+/// A random [`MessageId`].
+pub fn message_id() -> impl Strategy<Value = MessageId> {
+    any::<[u8; 8]>().prop_map(MessageId::new)
+}
+
+/// A random symmetric [`Key`].
+pub fn key() -> impl Strategy<Value = Key> {
+    any::<[u8; 32]>().prop_map(Key::from)
+}
+
+/// A random [`RecipientKey`].
+pub fn recipient_key() -> impl Strategy<Value = RecipientKey> {
+    any::<[u8; 32]>().prop_map(RecipientKey::from)
+}
+
+/// Any [`MessageType`].
+pub fn message_type() -> impl Strategy<Value = MessageType> {
+    any::<u8>().prop_map(MessageType::from)
+}
+
+/// Any [`RenderingType`].
+pub fn rendering_type() -> impl Strategy<Value = RenderingType> {
+    prop_oneof![
+        Just(RenderingType::File),
+        Just(RenderingType::Media),
+        Just(RenderingType::Sticker),
+    ]
+}
+
+/// A media type like `image/jpeg`.
+pub fn media_type() -> impl Strategy<Value = String> {
+    "(application|audio|image|text|video)/[a-z0-9.+-]{1,20}"
+}
+
+/// Any [`LookupCriterion`].
+pub fn lookup_criterion() -> impl Strategy<Value = LookupCriterion> {
+    prop_oneof![
+        "[1-9][0-9]{6,14}".prop_map(LookupCriterion::Phone),
+        "[0-9a-f]{64}".prop_map(LookupCriterion::PhoneHash),
+        "[a-z0-9.]{1,20}@[a-z0-9]{1,20}\\.[a-z]{2,6}".prop_map(LookupCriterion::Email),
+        "[0-9a-f]{64}".prop_map(LookupCriterion::EmailHash),
+    ]
+}
+
+/// A valid [`FileMessage`].
+///
+/// Media metadata is only set for the rendering types that allow it, and
+/// stickers are PNG images.
+pub fn file_message() -> impl Strategy<Value = FileMessage> {
+    (
+        (blob_id(), key(), media_type(), any::<u32>()),
+        option::of((blob_id(), media_type())),
+        option::of(".{0,50}"),
+        option::of(".{0,200}"),
+        rendering_type(),
+        option::of(any::<bool>()),
+        option::of((1..10_000u32, 1..10_000u32)),
+        option::of(0.0..36_000.0f32),
+        option::of("[0-9a-f]{32}"),
+    )
+        .prop_map(
+            |(
+                (blob_id, key, media_type, size),
+                thumbnail,
+                file_name,
+                description,
+                rendering_type,
+                animated,
+                dimensions,
+                duration,
+                correlation_id,
+            )| {
+                let media_type = match rendering_type {
+                    RenderingType::Sticker => "image/png".to_string(),
+                    _ => media_type,
+                };
+                let mut builder = FileMessageBuilder::new(blob_id, key, media_type, size)
+                    .thumbnail_opt(thumbnail)
+                    .file_name_opt(file_name)
+                    .description_opt(description)
+                    .rendering_type(rendering_type)
+                    .correlation_id_opt(correlation_id);
+                if rendering_type != RenderingType::File {
+                    if let Some(animated) = animated {
+                        builder = builder.animated(animated);
+                    }
+                    if let Some((height, width)) = dimensions {
+                        builder = builder.dimensions(height, width);
+                    }
+                }
+                if let (RenderingType::Media, Some(duration)) = (rendering_type, duration) {
+                    builder = builder.duration(duration);
+                }
+                builder.build().expect("Generated invalid file message")
+            },
+        )
+}
+
+/// A capabilities string as returned by the capabilities lookup, e.g.
+/// `text,image,file`.
+pub fn capabilities_str() -> impl Strategy<Value = String> {
+    proptest::collection::vec(
+        prop_oneof![
+            Just("text".to_string()),
+            Just("image".to_string()),
+            Just("video".to_string()),
+            Just("audio".to_string()),
+            Just("file".to_string()),
+            "[a-z]{1,10}",
+        ],
+        0..8,
+    )
+    .prop_map(|capabilities| capabilities.join(","))
+}
+
+/// The body of a callback request with a valid MAC for `api_secret`.
+///
+/// The box contains random bytes, i.e. it cannot be decrypted.
+pub fn callback_body(api_secret: &'static str) -> impl Strategy<Value = String> {
+    (
+        threema_id(),
+        gateway_id(),
+        "[0-9a-f]{16}",
+        any::<u32>(),
+        any::<[u8; 24]>(),
+        proptest::collection::vec(any::<u8>(), 16..512),
+        option::of(".{0,32}"),
+    )
+        .prop_map(
+            move |(from, to, message_id, date, nonce, box_data, nickname)| {
+                test_support::callback_body(
+                    &from,
+                    &to,
+                    &message_id,
+                    date.into(),
+                    &nonce,
+                    &box_data,
+                    nickname.as_deref(),
+                    api_secret,
+                )
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lookup::Capabilities, IncomingMessage};
+
+    proptest! {
+        #[test]
+        fn test_blob_id_roundtrip(blob_id in blob_id()) {
+            let parsed: BlobId = blob_id.to_string().parse().unwrap();
+            prop_assert_eq!(parsed, blob_id);
+        }
+
+        #[test]
+        fn test_message_id_roundtrip(message_id in message_id()) {
+            let parsed: MessageId = message_id.to_string().parse().unwrap();
+            prop_assert_eq!(parsed, message_id);
+        }
+
+        #[test]
+        fn test_key_roundtrip(key in key()) {
+            let hex = serde_json::to_value(&key).unwrap();
+            let parsed: Key = hex.as_str().unwrap().parse().unwrap();
+            prop_assert_eq!(parsed, key);
+        }
+
+        #[test]
+        fn test_recipient_key_roundtrip(key in recipient_key()) {
+            let parsed: RecipientKey = key.to_hex_string().parse().unwrap();
+            prop_assert_eq!(parsed, key);
+        }
+
+        #[test]
+        fn test_message_type_roundtrip(msgtype in message_type()) {
+            prop_assert_eq!(MessageType::from(u8::from(msgtype)), msgtype);
+        }
+
+        #[test]
+        fn test_file_message_serialize(msg in file_message()) {
+            let value = serde_json::to_value(&msg).unwrap();
+            prop_assert!(value.get("b").unwrap().is_string());
+            prop_assert!(value.get("k").unwrap().is_string());
+        }
+
+        #[test]
+        fn test_file_message_roundtrip(msg in file_message()) {
+            let data = serde_json::to_vec(&msg).unwrap();
+            let parsed = FileMessage::from_json(&data).unwrap();
+            prop_assert_eq!(serde_json::to_vec(&parsed).unwrap(), data);
+        }
+
+        #[test]
+        fn test_capabilities_parse(s in capabilities_str()) {
+            let capabilities: Capabilities = s.parse().unwrap();
+            for capability in s.split(',').filter(|c| !c.is_empty()) {
+                prop_assert!(capabilities.can(capability));
+            }
+        }
+
+        #[test]
+        fn test_callback_body_parse(body in callback_body("secret")) {
+            prop_assert!(IncomingMessage::from_urlencoded_bytes(&body, "secret").is_ok());
+            prop_assert!(IncomingMessage::from_urlencoded_bytes(&body, "other").is_err());
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Text templates with per-recipient variables.
+//!
+//! A [`Template`] contains placeholders like `{name}` that are replaced with
+//! the variables of a recipient when it is rendered. Literal braces are
+//! written as `{{` and `}}`.
+//!
+//! ```
+//! use std::collections::HashMap;
+//!
+//! use threema_gateway::template::Template;
+//!
+//! let template = Template::parse("Hello {name}, your code is {code}")?;
+//! let variables = HashMap::from([
+//!     ("name".to_string(), "Alice".to_string()),
+//!     ("code".to_string(), "1234".to_string()),
+//! ]);
+//! assert_eq!(
+//!     template.render(&variables)?,
+//!     "Hello Alice, your code is 1234"
+//! );
+//! # Ok::<(), threema_gateway::errors::TemplateError>(())
+//! ```
+//!
+//! With the `parallel` feature, [`E2eApi::encrypt_template_bulk`] renders and
+//! encrypts a template for many recipients.
+//!
+//! [`E2eApi::encrypt_template_bulk`]: crate::E2eApi::encrypt_template_bulk
+
+use std::{collections::HashMap, str::FromStr};
+
+use crate::errors::TemplateError;
+
+/// The maximum length (in bytes) of a rendered text message.
+pub const MAX_TEXT_LENGTH: usize = 3500;
+
+/// A part of a parsed template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Variable(String),
+}
+
+/// A parsed text template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parse a template.
+    ///
+    /// Variable names may only contain ASCII letters, digits and
+    /// underscores.
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.char_indices().peekable();
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '{' if chars.next_if(|(_, c)| *c == '{').is_some() => literal.push('{'),
+                '}' if chars.next_if(|(_, c)| *c == '}').is_some() => literal.push('}'),
+                '{' => {
+                    let end = template[pos..]
+                        .find('}')
+                        .ok_or(TemplateError::UnclosedPlaceholder(pos))?;
+                    let name = &template[pos + 1..pos + end];
+                    if name.is_empty()
+                        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    {
+                        return Err(TemplateError::InvalidVariableName(name.to_string()));
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Variable(name.to_string()));
+                    while chars.next_if(|(i, _)| *i <= pos + end).is_some() {}
+                }
+                '}' => return Err(TemplateError::UnmatchedBrace(pos)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    /// Return the names of the variables used in the template.
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Variable(name) => Some(name.as_str()),
+            Part::Literal(_) => None,
+        })
+    }
+
+    /// Replace the placeholders with the values of the variables.
+    ///
+    /// Fails if a variable is missing or if the rendered text is longer than
+    /// [`MAX_TEXT_LENGTH`] bytes.
+    pub fn render(&self, variables: &HashMap<String, String>) -> Result<String, TemplateError> {
+        let text = self.render_unchecked(variables)?;
+        check_length(&text)?;
+        Ok(text)
+    }
+
+    /// Like [`render`](Self::render), but without checking the length.
+    pub(crate) fn render_unchecked(
+        &self,
+        variables: &HashMap<String, String>,
+    ) -> Result<String, TemplateError> {
+        let mut text = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => text.push_str(literal),
+                Part::Variable(name) => text.push_str(
+                    variables
+                        .get(name)
+                        .ok_or_else(|| TemplateError::MissingVariable(name.clone()))?,
+                ),
+            }
+        }
+        Ok(text)
+    }
+}
+
+/// Fail if `text` is longer than [`MAX_TEXT_LENGTH`] bytes.
+pub(crate) fn check_length(text: &str) -> Result<(), TemplateError> {
+    if text.len() > MAX_TEXT_LENGTH {
+        return Err(TemplateError::TooLong {
+            length: text.len(),
+            limit: MAX_TEXT_LENGTH,
+        });
+    }
+    Ok(())
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_render() {
+        let template = Template::parse("{{{greeting}}} {name}!").unwrap();
+        assert_eq!(
+            template.variables().collect::<Vec<_>>(),
+            ["greeting", "name"]
+        );
+        let text = template
+            .render(&vars(&[("greeting", "Hi"), ("name", "{code}")]))
+            .unwrap();
+        assert_eq!(text, "{Hi} {code}!");
+        assert_eq!(
+            template.render(&vars(&[("greeting", "Hi")])),
+            Err(TemplateError::MissingVariable("name".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Template::parse("Hello {name"),
+            Err(TemplateError::UnclosedPlaceholder(6))
+        );
+        assert_eq!(
+            Template::parse("a } b"),
+            Err(TemplateError::UnmatchedBrace(2))
+        );
+        assert_eq!(
+            Template::parse("{first name}"),
+            Err(TemplateError::InvalidVariableName("first name".into()))
+        );
+        assert_eq!(
+            Template::parse("{}"),
+            Err(TemplateError::InvalidVariableName("".into()))
+        );
+    }
+
+    #[test]
+    fn test_length_after_substitution() {
+        let template = Template::parse("Code: {code}").unwrap();
+        let max = "x".repeat(MAX_TEXT_LENGTH - 6);
+        assert!(template.render(&vars(&[("code", &max)])).is_ok());
+        let too_long = "x".repeat(MAX_TEXT_LENGTH);
+        assert_eq!(
+            template.render(&vars(&[("code", &too_long)])),
+            Err(TemplateError::TooLong {
+                length: MAX_TEXT_LENGTH + 6,
+                limit: MAX_TEXT_LENGTH
+            })
+        );
+    }
+}
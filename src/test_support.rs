@@ -0,0 +1,238 @@
+//! Fixtures for tests.
+//!
+//! This module contains fixed identities and keypairs, a known-good
+//! encrypted message and the corresponding MAC'd callback request body, as
+//! well as helpers to generate valid incoming message payloads. The values
+//! are used by the tests of this crate and can be used by downstream tests as
+//! well.
+//!
+//! ```
+//! use threema_gateway::{test_support, IncomingMessage};
+//!
+//! let msg = IncomingMessage::from_urlencoded_bytes(
+//!     test_support::CALLBACK_BODY,
+//!     test_support::API_SECRET,
+//! )
+//! .unwrap();
+//! let data = msg
+//!     .decrypt_box(
+//!         &test_support::recipient_public_key(),
+//!         &test_support::gateway_private_key(),
+//!     )
+//!     .unwrap();
+//! assert_eq!(&data[1..], test_support::TEXT_MESSAGE.as_bytes());
+//! ```
+
+use data_encoding::HEXLOWER;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{api::ApiBuilder, crypto::RecipientKey, PublicKey, SecretKey};
+
+/// The gateway ID.
+pub const GATEWAY_ID: &str = "*3MAGWID";
+
+/// The API secret of the gateway ID.
+pub const API_SECRET: &str = "hihghrg98h00ghrg";
+
+/// The hex encoded private key of the gateway ID.
+pub const GATEWAY_PRIVATE_KEY: &str =
+    "998730fbcac1c57dbb181139de41d12835b3fae6af6acdf6ce91670262e88453";
+
+/// The hex encoded public key of the gateway ID.
+pub const GATEWAY_PUBLIC_KEY: &str =
+    "93b1fff198fa07b189dd78f9eb428a629ce6eae5d162f82f9bf0a8fb345bab75";
+
+/// The ID of the (Threema app) user communicating with the gateway ID.
+pub const RECIPIENT_ID: &str = "ECHOECHO";
+
+/// The hex encoded private key of the recipient.
+pub const RECIPIENT_PRIVATE_KEY: &str =
+    "7ad31aa0c306ec21e4fbc7f638ae6b352fdf0fe5d48c47dec505ad84ed958c42";
+
+/// The hex encoded public key of the recipient.
+pub const RECIPIENT_PUBLIC_KEY: &str =
+    "284f8f90303a6869b98c9e70a09b197bf43e0d69cfb5e18887f08f4608122d36";
+
+/// The text of the known-good text message sent by the recipient to the
+/// gateway ID.
+pub const TEXT_MESSAGE: &str = "Hello from Threema!";
+
+/// The hex encoded nonce of the known-good text message.
+pub const TEXT_MESSAGE_NONCE: &str = "845804ce02477a124e5c583c1626d524342eb2b9bf8e64ae";
+
+/// The hex encoded box of the known-good text message, encrypted by the
+/// recipient for the gateway ID. It contains the message type byte, the
+/// text and four bytes of padding.
+pub const TEXT_MESSAGE_BOX: &str =
+    "89178af5826c4306b9a4845f0820f0b8a3713cfef01fe19d0528c7eaf3060b22856d4635bb2738ef";
+
+/// The message ID of the known-good callback request.
+pub const CALLBACK_MESSAGE_ID: &str = "0102030405060708";
+
+/// The date of the known-good callback request.
+pub const CALLBACK_DATE: u64 = 1616950936;
+
+/// The body of a callback request delivering the known-good text message,
+/// with a valid MAC for [`API_SECRET`].
+pub const CALLBACK_BODY: &str = "from=ECHOECHO&to=*3MAGWID&messageId=0102030405060708&date=1616950936&nonce=845804ce02477a124e5c583c1626d524342eb2b9bf8e64ae&box=89178af5826c4306b9a4845f0820f0b8a3713cfef01fe19d0528c7eaf3060b22856d4635bb2738ef&mac=ba89de9999855a2c61024760d301dac075d0e1d65143bd6225ab7be61ca0816d";
+
+fn decode_key(hex: &str) -> [u8; 32] {
+    let mut key = [0; 32];
+    HEXLOWER
+        .decode_mut(hex.as_bytes(), &mut key)
+        .expect("Invalid fixture key");
+    key
+}
+
+/// Return the private key of the gateway ID.
+pub fn gateway_private_key() -> SecretKey {
+    SecretKey::from(decode_key(GATEWAY_PRIVATE_KEY))
+}
+
+/// Return the public key of the gateway ID.
+pub fn gateway_public_key() -> PublicKey {
+    PublicKey::from(decode_key(GATEWAY_PUBLIC_KEY))
+}
+
+/// Return the private key of the recipient.
+pub fn recipient_private_key() -> SecretKey {
+    SecretKey::from(decode_key(RECIPIENT_PRIVATE_KEY))
+}
+
+/// Return the public key of the recipient.
+pub fn recipient_public_key() -> PublicKey {
+    PublicKey::from(decode_key(RECIPIENT_PUBLIC_KEY))
+}
+
+/// Return the public key of the recipient as [`RecipientKey`].
+pub fn recipient_key() -> RecipientKey {
+    RecipientKey::from(recipient_public_key())
+}
+
+/// Return an [`ApiBuilder`] for the gateway ID, with the private key set.
+pub fn api_builder() -> ApiBuilder {
+    ApiBuilder::new(GATEWAY_ID, API_SECRET).with_private_key(gateway_private_key())
+}
+
+/// Calculate the hex encoded MAC of a callback request.
+pub fn callback_mac(
+    from: &str,
+    to: &str,
+    message_id: &str,
+    date: u64,
+    nonce_hex: &str,
+    box_hex: &str,
+    api_secret: &str,
+) -> String {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(api_secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    for value in [from, to, message_id, &date.to_string(), nonce_hex, box_hex] {
+        hmac.update(value.as_bytes());
+    }
+    HEXLOWER.encode(&hmac.finalize().into_bytes())
+}
+
+/// Build the body of a callback request with a valid MAC.
+#[cfg(feature = "receive")]
+pub fn callback_body(
+    from: &str,
+    to: &str,
+    message_id: &str,
+    date: u64,
+    nonce: &[u8],
+    box_data: &[u8],
+    nickname: Option<&str>,
+    api_secret: &str,
+) -> String {
+    let nonce_hex = HEXLOWER.encode(nonce);
+    let box_hex = HEXLOWER.encode(box_data);
+    let mac = callback_mac(from, to, message_id, date, &nonce_hex, &box_hex, api_secret);
+    let mut serializer = form_urlencoded::Serializer::new(String::new());
+    serializer
+        .append_pair("from", from)
+        .append_pair("to", to)
+        .append_pair("messageId", message_id)
+        .append_pair("date", &date.to_string())
+        .append_pair("nonce", &nonce_hex)
+        .append_pair("box", &box_hex);
+    if let Some(nickname) = nickname {
+        serializer.append_pair("nickname", nickname);
+    }
+    serializer.append_pair("mac", &mac);
+    serializer.finish()
+}
+
+/// Build the body of a callback request delivering a text message from the
+/// recipient to the gateway ID, encrypted with a random nonce.
+#[cfg(feature = "receive")]
+pub fn text_callback_body(text: &str) -> String {
+    let encrypted = crate::crypto::encrypt(
+        text.as_bytes(),
+        crate::types::MessageType::Text,
+        &gateway_public_key(),
+        &recipient_private_key(),
+    )
+    .expect("Encryption failed");
+    callback_body(
+        RECIPIENT_ID,
+        GATEWAY_ID,
+        CALLBACK_MESSAGE_ID,
+        CALLBACK_DATE,
+        &encrypted.nonce,
+        &encrypted.ciphertext,
+        None,
+        API_SECRET,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keypairs() {
+        assert_eq!(gateway_private_key().public_key(), gateway_public_key());
+        assert_eq!(recipient_private_key().public_key(), recipient_public_key());
+    }
+
+    #[test]
+    fn test_text_message() {
+        let nonce = crate::Nonce::from(
+            <[u8; 24]>::try_from(HEXLOWER.decode(TEXT_MESSAGE_NONCE.as_bytes()).unwrap()).unwrap(),
+        );
+        let (msgtype, data) = crate::decrypt(
+            &HEXLOWER.decode(TEXT_MESSAGE_BOX.as_bytes()).unwrap(),
+            &nonce,
+            &recipient_public_key(),
+            &gateway_private_key(),
+        )
+        .unwrap();
+        assert_eq!(msgtype, crate::MessageType::Text);
+        assert_eq!(data, TEXT_MESSAGE.as_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "receive")]
+    fn test_callback_body() {
+        let body = callback_body(
+            RECIPIENT_ID,
+            GATEWAY_ID,
+            CALLBACK_MESSAGE_ID,
+            CALLBACK_DATE,
+            &HEXLOWER.decode(TEXT_MESSAGE_NONCE.as_bytes()).unwrap(),
+            &HEXLOWER.decode(TEXT_MESSAGE_BOX.as_bytes()).unwrap(),
+            None,
+            API_SECRET,
+        );
+        assert_eq!(body, CALLBACK_BODY);
+
+        let body = text_callback_body("Hi!");
+        let api = api_builder().into_e2e().unwrap();
+        let msg = api.decode_incoming_message(body).unwrap();
+        let data = api
+            .decrypt_incoming_message(&msg, &recipient_key())
+            .unwrap();
+        assert_eq!(data, b"\x01Hi!");
+    }
+}
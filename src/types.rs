@@ -1,46 +1,168 @@
-use std::{default::Default, fmt, str::FromStr};
+use std::{
+    borrow::Cow,
+    default::Default,
+    ffi::OsStr,
+    fmt, fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::{
     errors::{ApiError, FileMessageBuilderError},
-    Key,
+    FileData, Key,
 };
 
-/// A message type.
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum MessageType {
+/// Define the [`MessageType`] enum and the conversions from and to the type
+/// byte.
+macro_rules! message_types {
+    ($($(#[$attr:meta])* $variant:ident = $code:literal,)*) => {
+        /// A message type.
+        ///
+        /// The message type is the first byte of the decrypted message. Type
+        /// bytes that are not known to this library are represented as
+        /// [`MessageType::Other`].
+        ///
+        /// Message types are compared by their type byte, so
+        /// `MessageType::Other(0x17)` is equal to [`MessageType::File`].
+        /// However, `MessageType::from` always returns the named variant for
+        /// a known type byte, so `match` patterns must use the named
+        /// variants.
+        #[derive(Debug, Copy, Clone)]
+        #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+        pub enum MessageType {
+            $($(#[$attr])* $variant,)*
+            /// Another message type
+            Other(u8),
+        }
+
+        impl From<MessageType> for u8 {
+            fn from(val: MessageType) -> Self {
+                match val {
+                    $(MessageType::$variant => $code,)*
+                    MessageType::Other(msgtype_byte) => msgtype_byte,
+                }
+            }
+        }
+
+        impl From<u8> for MessageType {
+            fn from(val: u8) -> Self {
+                match val {
+                    $($code => MessageType::$variant,)*
+                    msgtype_byte => MessageType::Other(msgtype_byte),
+                }
+            }
+        }
+    };
+}
+
+impl PartialEq for MessageType {
+    fn eq(&self, other: &Self) -> bool {
+        u8::from(*self) == u8::from(*other)
+    }
+}
+
+impl Eq for MessageType {}
+
+impl Hash for MessageType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        u8::from(*self).hash(state)
+    }
+}
+
+message_types! {
     /// Text message
-    Text,
+    Text = 0x01,
     /// Image message (deprecated)
-    Image,
+    Image = 0x02,
+    /// Location message
+    Location = 0x10,
     /// Video message (deprecated)
-    Video,
+    Video = 0x13,
+    /// Audio message (deprecated)
+    Audio = 0x14,
+    /// Create a ballot
+    BallotCreate = 0x15,
+    /// Vote in a ballot
+    BallotVote = 0x16,
     /// File message
-    File,
+    File = 0x17,
+    /// Set the profile picture of a contact
+    ContactSetPhoto = 0x18,
+    /// Delete the profile picture of a contact
+    ContactDeletePhoto = 0x19,
+    /// Request the profile picture of a contact
+    ContactRequestPhoto = 0x1a,
+    /// Group text message
+    GroupText = 0x41,
+    /// Group location message
+    GroupLocation = 0x42,
+    /// Group image message (deprecated)
+    GroupImage = 0x43,
+    /// Group video message (deprecated)
+    GroupVideo = 0x44,
+    /// Group audio message (deprecated)
+    GroupAudio = 0x45,
+    /// Group file message
+    GroupFile = 0x46,
+    /// Create a group or update its members
+    GroupCreate = 0x4a,
+    /// Rename a group
+    GroupRename = 0x4b,
+    /// Leave a group
+    GroupLeave = 0x4c,
+    /// Set the picture of a group
+    GroupSetPhoto = 0x50,
+    /// Request the group information from the creator
+    GroupRequestSync = 0x51,
+    /// Create a ballot in a group
+    GroupBallotCreate = 0x52,
+    /// Vote in a group ballot
+    GroupBallotVote = 0x53,
+    /// Delete the picture of a group
+    GroupDeletePhoto = 0x54,
+    /// VoIP call offer
+    VoipCallOffer = 0x60,
+    /// VoIP call answer
+    VoipCallAnswer = 0x61,
+    /// VoIP ICE candidates
+    VoipIceCandidates = 0x62,
+    /// VoIP call hangup
+    VoipCallHangup = 0x63,
+    /// VoIP call ringing
+    VoipCallRinging = 0x64,
     /// Delivery receipt
-    DeliveryReceipt,
-    /// Another message type
-    Other(u8),
+    DeliveryReceipt = 0x80,
+    /// Group delivery receipt
+    GroupDeliveryReceipt = 0x81,
+    /// Typing indicator
+    TypingIndicator = 0x90,
 }
 
-impl From<MessageType> for u8 {
-    fn from(val: MessageType) -> Self {
-        match val {
-            MessageType::Text => 0x01,
-            MessageType::Image => 0x02,
-            MessageType::Video => 0x13,
-            MessageType::File => 0x17,
-            MessageType::DeliveryReceipt => 0x80,
-            MessageType::Other(msgtype_byte) => msgtype_byte,
-        }
+impl MessageType {
+    /// Whether the message type is known to this library, i.e. isn't
+    /// [`MessageType::Other`].
+    ///
+    /// Note: Converting a type byte with `MessageType::from` never fails,
+    /// since unknown type bytes are mapped to [`MessageType::Other`].
+    pub fn is_known(&self) -> bool {
+        !matches!(MessageType::from(u8::from(*self)), MessageType::Other(_))
+    }
+
+    /// Whether the message is sent to a group.
+    pub fn is_group(&self) -> bool {
+        matches!(u8::from(*self), 0x41..=0x5f | 0x81)
     }
 }
 
 /// The rendering type influences how a file message is displayed on the device
 /// of the recipient.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum RenderingType {
     /// Display as default file message
     #[default]
@@ -61,14 +183,35 @@ impl From<RenderingType> for u8 {
     }
 }
 
+impl From<u8> for RenderingType {
+    /// Unknown rendering types are treated as [`RenderingType::File`].
+    fn from(val: u8) -> Self {
+        match val {
+            1 => RenderingType::Media,
+            2 => RenderingType::Sticker,
+            _ => RenderingType::File,
+        }
+    }
+}
+
 impl Serialize for RenderingType {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         serializer.serialize_u8((*self).into())
     }
 }
 
+impl<'de> Deserialize<'de> for RenderingType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u8::deserialize(deserializer).map(RenderingType::from)
+    }
+}
+
 /// A file message.
-#[derive(Debug, Serialize)]
+///
+/// To send a file message, create it with the [`FileMessageBuilder`]. Received
+/// file messages can be parsed with [`FileMessage::from_json`].
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct FileMessage {
     #[serde(rename = "b")]
     file_blob_id: BlobId,
@@ -95,19 +238,26 @@ pub struct FileMessage {
     description: Option<String>,
 
     #[serde(rename = "j")]
+    #[serde(default)]
     rendering_type: RenderingType,
     #[serde(rename = "i")]
+    #[serde(default)]
     legacy_rendering_type: u8,
 
     #[serde(rename = "x")]
     #[serde(skip_serializing_if = "Option::is_none")]
     metadata: Option<FileMetadata>,
+
+    #[serde(rename = "c")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<String>,
 }
 
 /// Metadata for a file message (depending on media type).
 ///
 /// This data is intended to enhance the layout logic.
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 struct FileMetadata {
     #[serde(rename = "a")]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -134,6 +284,63 @@ impl FileMetadata {
 }
 
 impl FileMessage {
+    /// Parse a received file message from the decrypted message data
+    /// (without the message type byte).
+    pub fn from_json(data: &[u8]) -> Result<Self, ApiError> {
+        serde_json::from_slice(data)
+            .map_err(|e| ApiError::ParseError(format!("Could not parse file message: {}", e)))
+    }
+
+    /// The blob ID of the encrypted file data.
+    pub fn file_blob_id(&self) -> &BlobId {
+        &self.file_blob_id
+    }
+
+    /// The media type of the file.
+    pub fn media_type(&self) -> &str {
+        &self.file_media_type
+    }
+
+    /// The blob ID of the encrypted thumbnail, if any.
+    pub fn thumbnail_blob_id(&self) -> Option<&BlobId> {
+        self.thumbnail_blob_id.as_ref()
+    }
+
+    /// The media type of the thumbnail, if any.
+    pub fn thumbnail_media_type(&self) -> Option<&str> {
+        self.thumbnail_media_type.as_deref()
+    }
+
+    /// The key used to encrypt the file data and the thumbnail.
+    pub fn blob_encryption_key(&self) -> &Key {
+        &self.blob_encryption_key
+    }
+
+    /// The file name, if any.
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    /// The file size in bytes, as declared by the sender.
+    pub fn file_size_bytes(&self) -> u32 {
+        self.file_size_bytes
+    }
+
+    /// The file description / caption, if any.
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// The rendering type.
+    pub fn rendering_type(&self) -> RenderingType {
+        self.rendering_type
+    }
+
+    /// The correlation ID, if any.
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+
     /// Shortcut for [`FileMessageBuilder::new`](struct.FileMessageBuilder.html#method.new).
     pub fn builder(
         file_blob_id: BlobId,
@@ -150,6 +357,9 @@ impl FileMessage {
     }
 }
 
+/// The media types allowed for stickers (which must support transparency).
+const STICKER_MEDIA_TYPES: [&str; 2] = ["image/png", "image/webp"];
+
 /// Builder for [`FileMessage`](struct.FileMessage.html).
 pub struct FileMessageBuilder {
     file_blob_id: BlobId,
@@ -162,6 +372,7 @@ pub struct FileMessageBuilder {
     description: Option<String>,
     rendering_type: RenderingType,
     metadata: Option<FileMetadata>,
+    correlation_id: Option<String>,
 }
 
 impl FileMessageBuilder {
@@ -197,9 +408,37 @@ impl FileMessageBuilder {
             description: None,
             rendering_type: RenderingType::File,
             metadata: None,
+            correlation_id: None,
         }
     }
 
+    /// Create a new [`FileMessage`] builder for the file at `path`.
+    ///
+    /// The media type (guessed from the file extension), the file name and
+    /// the file size are filled in automatically. Like with
+    /// [`FileMessageBuilder::new`], the file data must already be encrypted
+    /// with `blob_encryption_key` and uploaded to the blob server.
+    pub fn from_path(
+        file_blob_id: BlobId,
+        blob_encryption_key: Key,
+        path: impl AsRef<Path>,
+    ) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file_size_bytes = u32::try_from(fs::metadata(path)?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "File is too large"))?;
+        let media_type = mime_guess::from_path(path)
+            .first_or_octet_stream()
+            .to_string();
+        let file_name = path.file_name().and_then(OsStr::to_str);
+        Ok(Self::new(
+            file_blob_id,
+            blob_encryption_key,
+            media_type,
+            file_size_bytes,
+        )
+        .file_name_opt(file_name))
+    }
+
     /// Ensure that an (empty) metadata field is set and return a mutable
     /// reference ot it.
     fn ensure_metadata(&mut self) -> &mut FileMetadata {
@@ -273,6 +512,38 @@ impl FileMessageBuilder {
         self
     }
 
+    /// Send the file as sticker.
+    ///
+    /// Sets the rendering type to [`RenderingType::Sticker`]. Stickers must
+    /// be PNG or WebP images (which support transparency) and may not have
+    /// a duration, [`build`](Self::build) fails otherwise.
+    pub fn sticker(self) -> Self {
+        self.rendering_type(RenderingType::Sticker)
+    }
+
+    /// Set the correlation ID.
+    ///
+    /// File messages with the same correlation ID (e.g. images that are sent
+    /// as an album) are grouped by the recipient clients. Use a random string
+    /// of up to 32 characters, e.g. from [`FileMessageBuilder::random_correlation_id`].
+    pub fn correlation_id(self, correlation_id: impl Into<String>) -> Self {
+        self.correlation_id_opt(Some(correlation_id))
+    }
+
+    /// Set the correlation ID from an Option.
+    ///
+    /// See [`FileMessageBuilder::correlation_id`].
+    pub fn correlation_id_opt(mut self, correlation_id: Option<impl Into<String>>) -> Self {
+        self.correlation_id = correlation_id.map(Into::into);
+        self
+    }
+
+    /// Generate a random correlation ID (32 hex characters) that can be set
+    /// on related file messages.
+    pub fn random_correlation_id() -> String {
+        HEXLOWER.encode(&rand::random::<[u8; 16]>())
+    }
+
     /// Mark this file message as animated.
     ///
     /// May only be used for files with rendering type `Media` or `Sticker`.
@@ -299,11 +570,52 @@ impl FileMessageBuilder {
         self
     }
 
+    /// Set the duration of this file message by extracting it from the
+    /// (unencrypted) audio or video file data.
+    ///
+    /// If the duration cannot be determined, the builder is returned
+    /// unchanged. See [`media::extract_duration`](crate::media::extract_duration)
+    /// for the supported formats.
+    #[cfg(feature = "media-duration")]
+    pub fn duration_from_data(self, data: &[u8]) -> Self {
+        match crate::media::extract_duration(data, &self.file_media_type) {
+            Some(seconds) => self.duration(seconds),
+            None => self,
+        }
+    }
+
+    /// Mark this file message as animated if the (unencrypted) image data
+    /// is an animated GIF, APNG or WebP image.
+    ///
+    /// Otherwise, the builder is returned unchanged. See
+    /// [`image::detect_animation`](crate::image::detect_animation).
+    #[cfg(feature = "image")]
+    pub fn animated_from_data(self, data: &[u8]) -> Self {
+        match crate::image::detect_animation(data) {
+            Some(true) => self.animated(true),
+            _ => self,
+        }
+    }
+
     /// Create a [`FileMessage`] from this builder.
     ///
     /// [`FileMessage`]: struct.FileMessage.html
     pub fn build(self) -> Result<FileMessage, FileMessageBuilderError> {
         // Validate some metadata combinations
+        if matches!(&self.correlation_id, Some(id) if id.is_empty() || id.chars().count() > 32) {
+            return Err(FileMessageBuilderError::InvalidCorrelationId);
+        }
+        if self.rendering_type == RenderingType::Sticker {
+            let essence = self.file_media_type.split(';').next().unwrap_or("").trim();
+            if !STICKER_MEDIA_TYPES
+                .iter()
+                .any(|media_type| essence.eq_ignore_ascii_case(media_type))
+            {
+                return Err(FileMessageBuilderError::InvalidStickerMediaType(
+                    self.file_media_type,
+                ));
+            }
+        }
         if let Some(metadata) = &self.metadata {
             if self.rendering_type == RenderingType::File
                 && (metadata.animated.is_some()
@@ -347,12 +659,119 @@ impl FileMessageBuilder {
                 _ => 0,
             },
             metadata: self.metadata,
+            correlation_id: self.correlation_id,
         })
     }
 }
 
+/// The file to be sent with [`E2eApi::send_file`](crate::E2eApi::send_file).
+#[derive(Clone)]
+pub enum FileSource {
+    /// Raw file bytes and optionally a thumbnail.
+    Data(FileData),
+    /// Path to a file on the filesystem.
+    ///
+    /// If not specified explicitly in the [`FileSendOptions`], the file name
+    /// and media type are derived from the path.
+    Path(PathBuf),
+}
+
+impl From<FileData> for FileSource {
+    fn from(data: FileData) -> Self {
+        FileSource::Data(data)
+    }
+}
+
+impl From<PathBuf> for FileSource {
+    fn from(path: PathBuf) -> Self {
+        FileSource::Path(path)
+    }
+}
+
+impl From<&Path> for FileSource {
+    fn from(path: &Path) -> Self {
+        FileSource::Path(path.to_path_buf())
+    }
+}
+
+impl FileSource {
+    /// Read the file data and fill in the file name and media type in the
+    /// options if they aren't set yet.
+    pub(crate) fn resolve(self, options: &mut FileSendOptions) -> io::Result<FileData> {
+        match self {
+            FileSource::Data(data) => Ok(data),
+            FileSource::Path(path) => {
+                if options.file_name.is_none() {
+                    options.file_name = path
+                        .file_name()
+                        .and_then(OsStr::to_str)
+                        .map(ToOwned::to_owned);
+                }
+                if options.media_type.is_none() {
+                    options.media_type = mime_guess::from_path(&path)
+                        .first()
+                        .map(|mime| mime.to_string());
+                }
+                Ok(FileData {
+                    file: fs::read(&path)?,
+                    thumbnail: None,
+                })
+            }
+        }
+    }
+}
+
+/// Options for [`E2eApi::send_with_options`](crate::E2eApi::send_with_options).
+///
+/// All flags default to `false`. New flags may be added in the future, so
+/// construct the options with `..Default::default()`:
+///
+/// ```
+/// use threema_gateway::SendOptions;
+///
+/// let options = SendOptions {
+///     no_push: true,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SendOptions {
+    /// Whether the recipient should send delivery receipts. Disable this for
+    /// one-way communication where the delivery receipts would be discarded.
+    pub delivery_receipts: bool,
+    /// Don't send a push notification to the recipient. Useful for messages
+    /// that don't need to be shown to the user immediately.
+    pub no_push: bool,
+    /// Whether the message is a group message.
+    pub group: bool,
+}
+
+/// Options for [`E2eApi::send_file`](crate::E2eApi::send_file).
+#[derive(Debug, Clone, Default)]
+pub struct FileSendOptions {
+    /// Media type of the file. Defaults to `application/octet-stream` unless
+    /// it can be derived from the file path.
+    pub media_type: Option<String>,
+    /// Media type of the thumbnail. Defaults to `image/jpeg`.
+    pub thumbnail_media_type: Option<String>,
+    /// File name shown to the recipient.
+    pub file_name: Option<String>,
+    /// File description / caption.
+    pub description: Option<String>,
+    /// How the file message should be rendered.
+    pub rendering_type: RenderingType,
+    /// Whether the recipient should send delivery receipts.
+    pub delivery_receipts: bool,
+    /// Whether the uploaded blobs should persist after they were downloaded.
+    pub persist: bool,
+    /// Correlation ID to group related files, see
+    /// [`FileMessageBuilder::correlation_id`].
+    pub correlation_id: Option<String>,
+}
+
 /// A 16-byte blob ID.
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct BlobId(pub [u8; 16]);
 
 impl BlobId {
@@ -360,6 +779,11 @@ impl BlobId {
     pub fn new(id: [u8; 16]) -> Self {
         BlobId(id)
     }
+
+    /// Create a random BlobId (e.g. for tests and mock servers).
+    pub fn random() -> Self {
+        BlobId(rand::random())
+    }
 }
 
 impl FromStr for BlobId {
@@ -391,6 +815,79 @@ impl Serialize for BlobId {
     }
 }
 
+impl<'de> Deserialize<'de> for BlobId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id: Cow<'de, str> = Deserialize::deserialize(deserializer)?;
+        id.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// An 8-byte message ID, as assigned by the gateway server.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct MessageId(pub [u8; 8]);
+
+impl MessageId {
+    /// Create a new MessageId.
+    pub fn new(id: [u8; 8]) -> Self {
+        MessageId(id)
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = ApiError;
+
+    /// Create a new MessageId from a 16 character hexadecimal String.
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        let bytes = HEXLOWER_PERMISSIVE
+            .decode(id.as_bytes())
+            .map_err(|_| ApiError::BadMessageId)?;
+        let arr = <[u8; 8]>::try_from(bytes).map_err(|_| ApiError::BadMessageId)?;
+        Ok(MessageId(arr))
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", HEXLOWER.encode(&self.0))
+    }
+}
+
+impl Serialize for MessageId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&HEXLOWER.encode(&self.0))
+    }
+}
+
+/// A correlation ID, generated for every HTTP request to the gateway.
+///
+/// The ID is sent in the `X-Request-Id` header, included in the log output
+/// and attached to errors (see [`ApiError::request_id`]), so that failed
+/// requests can be correlated with application logs and support tickets.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct RequestId(pub [u8; 16]);
+
+impl RequestId {
+    /// Generate a new random RequestId.
+    pub fn generate() -> Self {
+        RequestId(rand::random())
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", HEXLOWER.encode(&self.0))
+    }
+}
+
+/// The result of successfully sending a message.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SendResult {
+    /// The ID of the sent message. Delivery receipts refer to the message
+    /// by this ID.
+    pub message_id: MessageId,
+}
+
 #[cfg(test)]
 mod test {
     use std::collections::HashMap;
@@ -399,6 +896,38 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn test_blob_id_serde() {
+        let blob_id = BlobId::random();
+        let json = json::to_string(&blob_id).unwrap();
+        assert_eq!(json::from_str::<BlobId>(&json).unwrap(), blob_id);
+        assert_ne!(BlobId::random(), blob_id);
+        assert!(json::from_str::<BlobId>(r#""0123""#).is_err());
+        assert!(json::from_str::<BlobId>("42").is_err());
+    }
+
+    #[test]
+    fn test_message_type_codes() {
+        assert_eq!(MessageType::from(0x10), MessageType::Location);
+        assert_eq!(MessageType::from(0x41), MessageType::GroupText);
+        assert_eq!(MessageType::from(0x90), MessageType::TypingIndicator);
+        assert_eq!(u8::from(MessageType::GroupDeliveryReceipt), 0x81);
+        assert_eq!(MessageType::from(0xff), MessageType::Other(0xff));
+        assert!(MessageType::VoipCallOffer.is_known());
+        assert!(!MessageType::Other(0xff).is_known());
+        assert!(MessageType::GroupFile.is_group());
+        assert!(!MessageType::File.is_group());
+        for code in 0..=u8::MAX {
+            assert_eq!(u8::from(MessageType::from(code)), code);
+            assert_eq!(u8::from(MessageType::Other(code)), code);
+            assert_eq!(MessageType::from(code), MessageType::Other(code));
+        }
+        assert!(MessageType::Other(0x17).is_known());
+        let types: std::collections::HashSet<_> =
+            [MessageType::File, MessageType::Other(0x17)].into();
+        assert_eq!(types.len(), 1);
+    }
+
     #[test]
     fn test_blob_id_from_str() {
         assert!(BlobId::from_str("0123456789abcdef0123456789abcdef").is_ok());
@@ -413,6 +942,22 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_message_id_from_str() {
+        assert_eq!(
+            MessageId::from_str("0001020304050aff").unwrap(),
+            MessageId::new([0, 1, 2, 3, 4, 5, 0xa, 0xff])
+        );
+        assert!(MessageId::from_str("0001020304050AFF").is_ok());
+        assert!(MessageId::from_str("0001020304050a").is_err());
+        assert!(MessageId::from_str("0001020304050aff00").is_err());
+        assert!(MessageId::from_str("0001020304050afg").is_err());
+        assert_eq!(
+            MessageId::new([0, 1, 2, 3, 4, 5, 0xa, 0xff]).to_string(),
+            "0001020304050aff"
+        );
+    }
+
     #[test]
     fn test_serialize_to_string_minimal() {
         let key = Key::from([
@@ -431,6 +976,7 @@ mod test {
             rendering_type: RenderingType::File,
             legacy_rendering_type: 0,
             metadata: None,
+            correlation_id: None,
         };
         let data = json::to_string(&msg).unwrap();
         let deserialized: HashMap<String, json::Value> = json::from_str(&data).unwrap();
@@ -476,11 +1022,12 @@ mod test {
                 width: Some(240),
                 duration_seconds: Some(12.7),
             }),
+            correlation_id: Some("0123abcd".into()),
         };
         let data = json::to_string(&msg).unwrap();
         let deserialized: HashMap<String, json::Value> = json::from_str(&data).unwrap();
 
-        assert_eq!(deserialized.keys().len(), 11);
+        assert_eq!(deserialized.keys().len(), 12);
         assert_eq!(
             deserialized.get("b").unwrap(),
             "0123456789abcdef0123456789abcdef"
@@ -504,6 +1051,7 @@ mod test {
         assert_eq!(deserialized.get("x").unwrap().get("h").unwrap(), 320);
         assert_eq!(deserialized.get("x").unwrap().get("w").unwrap(), 240);
         assert_eq!(deserialized.get("x").unwrap().get("d").unwrap(), 12.7);
+        assert_eq!(deserialized.get("c").unwrap(), "0123abcd");
     }
 
     #[test]
@@ -533,5 +1081,155 @@ mod test {
         assert_eq!(msg.description, Some("An image file".to_string()));
         assert_eq!(msg.rendering_type, RenderingType::Media);
         assert_eq!(msg.legacy_rendering_type, 1);
+        assert_eq!(msg.correlation_id, None);
+    }
+
+    #[test]
+    fn test_builder_sticker() {
+        let msg = FileMessage::builder(BlobId::random(), Key::generate(), "image/PNG", 2048)
+            .sticker()
+            .animated(false)
+            .build()
+            .unwrap();
+        assert_eq!(msg.rendering_type, RenderingType::Sticker);
+        assert_eq!(msg.legacy_rendering_type, 0);
+
+        let jpeg = FileMessage::builder(BlobId::random(), Key::generate(), "image/jpeg", 2048)
+            .sticker()
+            .build();
+        assert_eq!(
+            jpeg.unwrap_err(),
+            FileMessageBuilderError::InvalidStickerMediaType("image/jpeg".into())
+        );
+
+        let with_duration =
+            FileMessage::builder(BlobId::random(), Key::generate(), "image/webp", 2048)
+                .sticker()
+                .duration(1.5)
+                .build();
+        assert!(matches!(
+            with_duration,
+            Err(FileMessageBuilderError::IllegalCombination(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "image")]
+    fn test_builder_animated_from_data() {
+        let gif = b"GIF89a\x01\x00\x01\x00\x00\x00\x00\
+            \x2c\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02\x44\x01\x00\
+            \x2c\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02\x44\x01\x00\x3b";
+        let msg = FileMessage::builder(BlobId::random(), Key::generate(), "image/gif", 2048)
+            .rendering_type(RenderingType::Media)
+            .animated_from_data(gif)
+            .build()
+            .unwrap();
+        assert_eq!(msg.metadata.and_then(|m| m.animated), Some(true));
+
+        let msg = FileMessage::builder(BlobId::random(), Key::generate(), "image/jpeg", 2048)
+            .rendering_type(RenderingType::Media)
+            .animated_from_data(b"\xff\xd8\xff\xe0")
+            .build()
+            .unwrap();
+        assert!(msg.metadata.is_none());
+    }
+
+    #[test]
+    fn test_builder_correlation_id() {
+        let correlation_id = FileMessageBuilder::random_correlation_id();
+        assert_eq!(correlation_id.len(), 32);
+        let msg = FileMessage::builder(BlobId::random(), Key::generate(), "image/jpeg", 2048)
+            .correlation_id(correlation_id.clone())
+            .build()
+            .unwrap();
+        let serialized = json::to_value(&msg).unwrap();
+        assert_eq!(serialized.get("c").unwrap(), &json::json!(correlation_id));
+
+        let too_long = FileMessage::builder(BlobId::random(), Key::generate(), "image/jpeg", 2048)
+            .correlation_id("x".repeat(33))
+            .build();
+        assert!(matches!(
+            too_long,
+            Err(FileMessageBuilderError::InvalidCorrelationId)
+        ));
+    }
+
+    #[test]
+    fn test_file_source_resolve_path() {
+        let path = std::env::temp_dir().join("threema-gateway-test-resolve.pdf");
+        fs::write(&path, [1, 2, 3]).unwrap();
+
+        // Derive file name and media type from path
+        let mut options = FileSendOptions::default();
+        let data = FileSource::from(path.as_path())
+            .resolve(&mut options)
+            .unwrap();
+        assert_eq!(data.file, vec![1, 2, 3]);
+        assert!(data.thumbnail.is_none());
+        assert_eq!(
+            options.file_name.as_deref(),
+            Some("threema-gateway-test-resolve.pdf")
+        );
+        assert_eq!(options.media_type.as_deref(), Some("application/pdf"));
+
+        // Explicit options take precedence
+        let mut options = FileSendOptions {
+            media_type: Some("text/plain".into()),
+            file_name: Some("other.txt".into()),
+            ..Default::default()
+        };
+        FileSource::from(path.clone())
+            .resolve(&mut options)
+            .unwrap();
+        assert_eq!(options.file_name.as_deref(), Some("other.txt"));
+        assert_eq!(options.media_type.as_deref(), Some("text/plain"));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_builder_from_path() {
+        let path = std::env::temp_dir().join("threema-gateway-test-from-path.png");
+        fs::write(&path, [0; 1337]).unwrap();
+
+        let key: Key = [0; 32].into();
+        let file_blob_id = BlobId::from_str("0123456789abcdef0123456789abcdef").unwrap();
+        let msg = FileMessageBuilder::from_path(file_blob_id.clone(), key, &path)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(msg.file_blob_id, file_blob_id);
+        assert_eq!(msg.file_media_type, "image/png");
+        assert_eq!(
+            msg.file_name,
+            Some("threema-gateway-test-from-path.png".to_string())
+        );
+        assert_eq!(msg.file_size_bytes, 1337);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "arbitrary")]
+    fn test_serialize_arbitrary() {
+        use arbitrary::{Arbitrary, Unstructured};
+        use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut data = vec![0; 512];
+        for _ in 0..100 {
+            rng.fill_bytes(&mut data);
+            let msg = FileMessage::arbitrary(&mut Unstructured::new(&data)).unwrap();
+            let serialized = json::to_string(&msg).unwrap();
+            let deserialized: HashMap<String, json::Value> = json::from_str(&serialized).unwrap();
+            assert_eq!(
+                deserialized.get("b").unwrap(),
+                &json::Value::from(msg.file_blob_id.to_string())
+            );
+            assert_eq!(
+                deserialized.get("s").unwrap(),
+                &json::Value::from(msg.file_size_bytes)
+            );
+        }
     }
 }
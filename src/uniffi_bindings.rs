@@ -0,0 +1,236 @@
+//! [UniFFI](https://mozilla.github.io/uniffi-rs/) bindings for the high level
+//! E2E API.
+//!
+//! The types in this module (together with [`LookupCriterion`],
+//! [`Capabilities`] and the error types) are exported through UniFFI, so that
+//! Kotlin, Swift and Python bindings can be generated from the compiled
+//! library:
+//!
+//! ```text
+//! cargo rustc --release --features uniffi --crate-type cdylib
+//! cargo run --features uniffi-bindgen --bin uniffi-bindgen -- \
+//!     generate --library target/release/libthreema_gateway.so \
+//!     --language kotlin --out-dir out
+//! ```
+//!
+//! The async methods run on a tokio runtime managed by UniFFI, they are
+//! exposed as `suspend` functions in Kotlin, `async` functions in Swift and
+//! coroutines in Python.
+
+use std::sync::Arc;
+
+use crate::{
+    api::{ApiBuilder, E2eApi},
+    crypto::{FileData, RecipientKey},
+    errors::{ApiBuilderError, ApiError},
+    lookup::{Capabilities, LookupCriterion},
+    receive::IncomingMessage,
+    types::{FileSendOptions, MessageType},
+};
+
+/// Options for [`GatewayApi::send_file`].
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct FileOptions {
+    /// Media type of the file. Defaults to `application/octet-stream`.
+    #[uniffi(default = None)]
+    pub media_type: Option<String>,
+    /// File name shown to the recipient.
+    #[uniffi(default = None)]
+    pub file_name: Option<String>,
+    /// File description / caption.
+    #[uniffi(default = None)]
+    pub description: Option<String>,
+}
+
+/// A decrypted incoming message.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ReceivedMessage {
+    /// Sender identity.
+    pub from: String,
+    /// The gateway ID the message is addressed to.
+    pub to: String,
+    /// The hex encoded message ID.
+    pub message_id: String,
+    /// Message date set by the sender (UNIX timestamp).
+    pub date: u64,
+    /// Public nickname of the sender, if set.
+    pub nickname: Option<String>,
+    /// The message type byte.
+    pub message_type: u8,
+    /// The message payload (without the message type byte and padding).
+    pub data: Vec<u8>,
+    /// The text of the message, if it is a text message.
+    pub text: Option<String>,
+}
+
+/// An E2E API object.
+#[derive(Debug, uniffi::Object)]
+pub struct GatewayApi {
+    inner: E2eApi,
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl GatewayApi {
+    /// Create an API object for the specified gateway ID.
+    ///
+    /// `private_key` is the hex encoded private key, optionally prefixed with
+    /// `private:`. If `endpoint` is not set, the default API endpoint is used.
+    #[uniffi::constructor(default(endpoint = None))]
+    pub fn new(
+        id: String,
+        secret: String,
+        private_key: String,
+        endpoint: Option<String>,
+    ) -> Result<Arc<Self>, ApiBuilderError> {
+        let mut builder = ApiBuilder::new(id, secret).with_private_key_str(&private_key)?;
+        if let Some(endpoint) = endpoint {
+            builder = builder.with_custom_endpoint(endpoint);
+        }
+        Ok(Arc::new(GatewayApi {
+            inner: builder.into_e2e()?,
+        }))
+    }
+
+    /// Return the hex encoded public key of the gateway identity.
+    pub fn public_key_hex(&self) -> String {
+        self.inner.public_key_hex()
+    }
+
+    /// Look up the public key of a Threema ID (32 bytes).
+    pub async fn lookup_pubkey(&self, id: String) -> Result<Vec<u8>, ApiError> {
+        let key = self.inner.lookup_pubkey(&id).await?;
+        Ok(key.0.as_bytes().to_vec())
+    }
+
+    /// Look up a Threema ID by phone number or email address.
+    pub async fn lookup_id(&self, criterion: LookupCriterion) -> Result<String, ApiError> {
+        self.inner.lookup_id(&criterion).await
+    }
+
+    /// Look up the capabilities of a Threema ID.
+    pub async fn lookup_capabilities(&self, id: String) -> Result<Capabilities, ApiError> {
+        self.inner.lookup_capabilities(&id).await
+    }
+
+    /// Look up the remaining credits.
+    pub async fn lookup_credits(&self) -> Result<i64, ApiError> {
+        self.inner.lookup_credits().await
+    }
+
+    /// Encrypt and send a text message and return the hex encoded message ID.
+    pub async fn send_text(&self, to: String, text: String) -> Result<String, ApiError> {
+        let result = self.inner.send_text(&to, &text).await?;
+        Ok(result.message_id.to_string())
+    }
+
+    /// Encrypt, upload and send a file and return the hex encoded message ID.
+    pub async fn send_file(
+        &self,
+        to: String,
+        data: Vec<u8>,
+        options: FileOptions,
+    ) -> Result<String, ApiError> {
+        let recipient_key = self.inner.resolve_public_key(&to).await?;
+        let options = FileSendOptions {
+            media_type: options.media_type,
+            file_name: options.file_name,
+            description: options.description,
+            delivery_receipts: true,
+            ..Default::default()
+        };
+        let file = FileData {
+            file: data,
+            thumbnail: None,
+        };
+        let result = self
+            .inner
+            .send_file(&to, &recipient_key, file, options)
+            .await?;
+        Ok(result.message_id.to_string())
+    }
+
+    /// Validate the MAC of an incoming callback request body, look up the
+    /// public key of the sender and decrypt the message.
+    pub async fn receive(&self, body: Vec<u8>) -> Result<ReceivedMessage, ApiError> {
+        let msg = self.inner.decode_incoming_message(body)?;
+        let sender_key = self.inner.resolve_public_key(&msg.from).await?;
+        self.decrypt(msg, &sender_key)
+    }
+
+    /// Like [`receive`](Self::receive), but with the public key of the
+    /// sender (32 bytes) instead of looking it up.
+    pub fn receive_with_key(
+        &self,
+        body: Vec<u8>,
+        sender_key: Vec<u8>,
+    ) -> Result<ReceivedMessage, ApiError> {
+        let sender_key = RecipientKey::from_bytes(&sender_key)?;
+        let msg = self.inner.decode_incoming_message(body)?;
+        self.decrypt(msg, &sender_key)
+    }
+}
+
+impl GatewayApi {
+    /// Decrypt an incoming message.
+    fn decrypt(
+        &self,
+        msg: IncomingMessage,
+        sender_key: &RecipientKey,
+    ) -> Result<ReceivedMessage, ApiError> {
+        let (message_type, data) = self.inner.decrypt_incoming_payload(&msg, sender_key)?;
+        let text = match message_type {
+            MessageType::Text => Some(String::from_utf8_lossy(&data).into_owned()),
+            _ => None,
+        };
+        Ok(ReceivedMessage {
+            from: msg.from,
+            to: msg.to,
+            message_id: msg.message_id,
+            date: msg.date as u64,
+            nickname: msg.nickname,
+            message_type: message_type.into(),
+            data,
+            text,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn api() -> Arc<GatewayApi> {
+        GatewayApi::new(
+            test_support::GATEWAY_ID.into(),
+            test_support::API_SECRET.into(),
+            test_support::GATEWAY_PRIVATE_KEY.into(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_new() {
+        assert_eq!(api().public_key_hex(), test_support::GATEWAY_PUBLIC_KEY);
+        assert!(matches!(
+            GatewayApi::new("*3MAGWID".into(), "secret".into(), "abc".into(), None),
+            Err(ApiBuilderError::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_receive_with_key() {
+        let msg = api()
+            .receive_with_key(
+                test_support::CALLBACK_BODY.into(),
+                test_support::recipient_public_key().as_bytes().to_vec(),
+            )
+            .unwrap();
+        assert_eq!(msg.from, test_support::RECIPIENT_ID);
+        assert_eq!(msg.message_id, test_support::CALLBACK_MESSAGE_ID);
+        assert_eq!(msg.date, test_support::CALLBACK_DATE);
+        assert_eq!(msg.message_type, 0x01);
+        assert_eq!(msg.text.as_deref(), Some(test_support::TEXT_MESSAGE));
+    }
+}
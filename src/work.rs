@@ -0,0 +1,286 @@
+//! Client for the Threema Work API.
+//!
+//! Organizations that operate both a gateway ID and a Threema Work
+//! subscription can use the [`WorkApi`] to list the credentials and users of
+//! the subscription and to resolve Threema IDs to Work users. Requests are
+//! authenticated with the API key of the Work subscription (which is
+//! different from the gateway secret).
+//!
+//! The Work API object can share the HTTP client with a gateway API object:
+//!
+//! ```no_run
+//! # tokio_test::block_on(async {
+//! use threema_gateway::ApiBuilder;
+//!
+//! let api = ApiBuilder::new("*3MAGWID", "secret").into_simple().unwrap();
+//! let work = api.work_api("work-api-key");
+//! for user in work.users().await.unwrap() {
+//!     println!("{}: {:?}", user.threema_id, user.nickname);
+//! }
+//! # })
+//! ```
+
+use std::{
+    borrow::Cow,
+    time::{Duration, Instant},
+};
+
+use reqwest::Client;
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    connection::{map_response_code, send_request, Timeouts},
+    errors::ApiError,
+    hooks::Hooks,
+};
+
+/// The URL of the Threema Work API.
+pub const WORK_API_URL: &str = "https://work.threema.ch/api/v1";
+
+/// The header carrying the API key.
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// A user of a Threema Work subscription.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkUser {
+    /// The ID of the user in the Work API.
+    pub id: String,
+    /// The Threema ID of the user.
+    pub threema_id: String,
+    /// The public nickname of the user.
+    pub nickname: Option<String>,
+    /// The first name of the user.
+    pub first_name: Option<String>,
+    /// The last name of the user.
+    pub last_name: Option<String>,
+    /// The customer specific identifier of the user.
+    pub csi: Option<String>,
+    /// The app version the user is running.
+    pub version: Option<String>,
+    /// The ID of the credentials used by the user.
+    pub credentials_id: Option<String>,
+}
+
+/// Credentials (login) of a Threema Work subscription.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkCredentials {
+    /// The ID of the credentials in the Work API.
+    pub id: String,
+    /// The username.
+    pub username: String,
+    /// The number of licenses that can be used with the credentials.
+    pub license_amount: Option<u32>,
+    /// The number of licenses that are in use.
+    pub usage: Option<u32>,
+    /// Whether the credentials are locked to the first device they are used
+    /// on.
+    #[serde(default)]
+    pub lock: bool,
+}
+
+/// A link to a related resource, used for pagination.
+#[derive(Debug, Deserialize)]
+struct Link {
+    #[serde(rename = "ref")]
+    rel: String,
+    link: String,
+}
+
+/// A page of users.
+#[derive(Debug, Deserialize)]
+struct UsersPage {
+    users: Vec<WorkUser>,
+    #[serde(rename = "_links", default)]
+    links: Vec<Link>,
+}
+
+/// A page of credentials.
+#[derive(Debug, Deserialize)]
+struct CredentialsPage {
+    credentials: Vec<WorkCredentials>,
+    #[serde(rename = "_links", default)]
+    links: Vec<Link>,
+}
+
+/// Return the URL of the next page, if any.
+fn next_link(links: Vec<Link>) -> Option<String> {
+    links
+        .into_iter()
+        .find(|link| link.rel == "next")
+        .map(|link| link.link)
+}
+
+/// Struct to talk to the Threema Work API.
+#[derive(Debug, Clone)]
+pub struct WorkApi {
+    api_key: String,
+    endpoint: Cow<'static, str>,
+    client: Client,
+    deadline: Option<Instant>,
+}
+
+impl WorkApi {
+    /// Initialize the Work API with the API key of the Work subscription.
+    pub fn new<K: Into<String>>(api_key: K) -> Self {
+        let client = Timeouts::default()
+            .client_builder()
+            .build()
+            .expect("Could not build client");
+        Self::with_client(api_key, client)
+    }
+
+    /// Initialize the Work API with the API key and a custom HTTP client.
+    pub fn with_client<K: Into<String>>(api_key: K, client: Client) -> Self {
+        WorkApi {
+            api_key: api_key.into(),
+            endpoint: Cow::Borrowed(WORK_API_URL),
+            client,
+            deadline: None,
+        }
+    }
+
+    /// Set a custom API endpoint (without trailing slash).
+    pub fn with_custom_endpoint<E: Into<Cow<'static, str>>>(mut self, endpoint: E) -> Self {
+        self.endpoint = endpoint.into();
+        self
+    }
+
+    /// Return a copy of the API object whose calls fail with
+    /// [`ApiError::DeadlineExceeded`] if they don't complete before
+    /// `deadline`.
+    pub fn with_deadline(&self, deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self.clone()
+        }
+    }
+
+    /// Return a copy of the API object whose calls fail with
+    /// [`ApiError::DeadlineExceeded`] if they don't complete within
+    /// `timeout`, starting now.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Fetch and deserialize the resource at `url`.
+    async fn get<T: DeserializeOwned>(&self, url: &str) -> Result<T, ApiError> {
+        let request = self.client.get(url).header(API_KEY_HEADER, &self.api_key);
+        send_request(
+            request,
+            self.deadline,
+            &Hooks::default(),
+            |res| async move {
+                map_response_code(res.status(), None)?;
+                let body = res.bytes().await?;
+                serde_json::from_slice(&body).map_err(|e| {
+                    ApiError::ParseError(format!("Could not parse Work API response: {}", e))
+                })
+            },
+        )
+        .await
+    }
+
+    /// List all users of the subscription.
+    pub async fn users(&self) -> Result<Vec<WorkUser>, ApiError> {
+        let mut users = Vec::new();
+        let mut url = Some(format!("{}/users", self.endpoint));
+        while let Some(page_url) = url {
+            let page: UsersPage = self.get(&page_url).await?;
+            users.extend(page.users);
+            url = next_link(page.links);
+        }
+        Ok(users)
+    }
+
+    /// Fetch a user by its Work API ID.
+    pub async fn user(&self, user_id: &str) -> Result<WorkUser, ApiError> {
+        self.get(&format!("{}/users/{}", self.endpoint, user_id))
+            .await
+    }
+
+    /// Resolve a Threema ID to a user of the subscription.
+    ///
+    /// Returns `None` if the Threema ID doesn't belong to the subscription.
+    pub async fn resolve_threema_id(&self, threema_id: &str) -> Result<Option<WorkUser>, ApiError> {
+        let threema_id = threema_id.to_uppercase();
+        Ok(self
+            .users()
+            .await?
+            .into_iter()
+            .find(|user| user.threema_id == threema_id))
+    }
+
+    /// List all credentials of the subscription.
+    pub async fn credentials(&self) -> Result<Vec<WorkCredentials>, ApiError> {
+        let mut credentials = Vec::new();
+        let mut url = Some(format!("{}/credentials", self.endpoint));
+        while let Some(page_url) = url {
+            let page: CredentialsPage = self.get(&page_url).await?;
+            credentials.extend(page.credentials);
+            url = next_link(page.links);
+        }
+        Ok(credentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_users_page() {
+        let json = r#"{
+            "users": [
+                {
+                    "id": "u1",
+                    "threemaId": "ABCD1234",
+                    "nickname": "Alice",
+                    "firstName": "Alice",
+                    "lastName": null,
+                    "csi": "1234",
+                    "category": [],
+                    "version": "5.0",
+                    "createdAt": "2024-01-01T00:00:00Z"
+                },
+                {"id": "u2", "threemaId": "EFGH5678"}
+            ],
+            "_links": [
+                {"ref": "prev", "link": "https://work.threema.ch/api/v1/users?page=0"},
+                {"ref": "next", "link": "https://work.threema.ch/api/v1/users?page=2"}
+            ]
+        }"#;
+        let page: UsersPage = serde_json::from_str(json).unwrap();
+        assert_eq!(page.users.len(), 2);
+        assert_eq!(page.users[0].threema_id, "ABCD1234");
+        assert_eq!(page.users[0].nickname.as_deref(), Some("Alice"));
+        assert_eq!(page.users[0].last_name, None);
+        assert_eq!(page.users[1].nickname, None);
+        assert_eq!(
+            next_link(page.links).as_deref(),
+            Some("https://work.threema.ch/api/v1/users?page=2")
+        );
+    }
+
+    #[test]
+    fn test_parse_credentials_page() {
+        let json = r#"{
+            "credentials": [
+                {"id": "c1", "username": "alice", "password": "s3cr3t", "licenseAmount": 3, "usage": 1, "lock": true}
+            ]
+        }"#;
+        let page: CredentialsPage = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            page.credentials,
+            vec![WorkCredentials {
+                id: "c1".into(),
+                username: "alice".into(),
+                license_amount: Some(3),
+                usage: Some(1),
+                lock: true,
+            }]
+        );
+        assert_eq!(next_link(page.links), None);
+    }
+}